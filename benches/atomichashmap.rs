@@ -0,0 +1,122 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use std::convert::TryInto;
+use std::thread;
+
+use atomics_rs::{AtomicHashMap, AtomicRobinHoodMap};
+
+/// Number of threads counting at the same time
+const THREADS: u64 = 4;
+
+/// Number of additions done by each thread
+const ADDS: u64 = 100_000;
+
+/// Each thread hammers its own key in a table small enough that the keys sit in
+/// neighbouring slots
+fn count(hashtable: &AtomicHashMap) {
+    thread::scope(|scope| {
+        for key in 1..=THREADS {
+            scope.spawn(move || {
+                for _ in 0..ADDS {
+                    hashtable.add_to(key, 1).unwrap();
+                }
+            });
+        }
+    });
+}
+
+fn bench_padding(c: &mut Criterion) {
+    let mut group = c.benchmark_group("contended_counters");
+
+    let compact: AtomicHashMap = AtomicHashMap::new(1 << 4).unwrap();
+    group.bench_function("compact", |b| b.iter(|| count(&compact)));
+
+    let padded: AtomicHashMap = AtomicHashMap::builder(1 << 4).padded(true).build().unwrap();
+    group.bench_function("padded", |b| b.iter(|| count(&padded)));
+
+    group.finish();
+}
+
+/// Look up every key of a table filled to 7/8 of its capacity, where long probes
+/// dominate the cost of a lookup
+fn bench_high_load(c: &mut Criterion) {
+    let size: u64 = 1 << 16;
+    let keys = size / 8 * 7;
+
+    let hashtable: AtomicHashMap = AtomicHashMap::new(size as usize).unwrap();
+    for key in 1..=keys {
+        hashtable.insert(key, key).unwrap();
+    }
+
+    let robinhood: AtomicRobinHoodMap = AtomicRobinHoodMap::new(size as usize).unwrap();
+    for key in 1..=keys {
+        robinhood.insert(key, key).unwrap();
+    }
+
+    let mut group = c.benchmark_group("high_load");
+    group.bench_function("get_hit", |b| b.iter(|| {
+        for key in 1..=keys {
+            black_box(hashtable.get(&key));
+        }
+    }));
+    group.bench_function("get_miss", |b| b.iter(|| {
+        for key in keys + 1..=keys * 2 {
+            black_box(hashtable.get(&key));
+        }
+    }));
+    group.bench_function("robinhood_get_hit", |b| b.iter(|| {
+        for key in 1..=keys {
+            black_box(robinhood.get(&key));
+        }
+    }));
+    group.bench_function("robinhood_get_miss", |b| b.iter(|| {
+        for key in keys + 1..=keys * 2 {
+            black_box(robinhood.get(&key));
+        }
+    }));
+    group.finish();
+}
+
+/// Look up blocks of 4096 random keys in a table much larger than the caches, one key
+/// at a time and as a batch. Each iteration moves on to the next of many blocks so
+/// that the slots of a block aren't still cached from the iteration before.
+fn bench_batch(c: &mut Criterion) {
+    let size: u64 = 1 << 22;
+    let block: usize = 4096;
+
+    let hashtable: AtomicHashMap = AtomicHashMap::new(size as usize).unwrap();
+    for key in 1..=size / 2 {
+        hashtable.insert(key, key).unwrap();
+    }
+
+    // Scatter the blocks over the whole table
+    let keys: Vec<u64> = (0..size / 2)
+        .map(|x| x.wrapping_mul(0x9e37_79b9_7f4a_7c15) % (size / 2) + 1)
+        .collect();
+    let mut blocks = keys.chunks(block).cycle();
+
+    let mut group = c.benchmark_group("batch");
+    group.bench_function("get", |b| b.iter(|| {
+        for key in blocks.next().unwrap() {
+            black_box(hashtable.get(key));
+        }
+    }));
+    group.bench_function("get_batch", |b| b.iter(|| {
+        black_box(hashtable.get_batch(blocks.next().unwrap()));
+    }));
+    group.bench_function("get_prefetched", |b| b.iter(|| {
+        for key in blocks.next().unwrap() {
+            black_box(hashtable.get_prefetched(key));
+        }
+    }));
+    group.bench_function("get_many", |b| b.iter(|| {
+        for keys in blocks.next().unwrap().chunks_exact(8) {
+            let keys: [u64; 8] = keys.try_into().unwrap();
+            black_box(hashtable.get_many(keys));
+        }
+    }));
+    group.finish();
+}
+
+criterion_group!(benches, bench_padding, bench_high_load, bench_batch);
+criterion_main!(benches);
@@ -0,0 +1,177 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion,
+                Throughput};
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::thread;
+
+use atomics_rs::{AtomicHashMap, ShardedAtomicHashMap};
+use chashmap::CHashMap;
+use dashmap::DashMap;
+
+/// Number of operations done per iteration, split between the threads
+const OPS: u64 = 1 << 16;
+
+/// Thread counts each workload is measured at
+const THREADS: [u64; 5] = [1, 2, 4, 8, 16];
+
+/// The operations measured, implemented for each map compared
+trait BenchMap: Sync {
+    fn new(size: usize) -> Self;
+    fn insert(&self, key: u64, value: u64);
+    fn get(&self, key: u64) -> Option<u64>;
+}
+
+impl BenchMap for AtomicHashMap {
+    fn new(size: usize) -> Self {
+        AtomicHashMap::with_capacity(size).unwrap()
+    }
+
+    fn insert(&self, key: u64, value: u64) {
+        AtomicHashMap::insert(self, key, value).unwrap();
+    }
+
+    fn get(&self, key: u64) -> Option<u64> {
+        AtomicHashMap::get(self, &key)
+    }
+}
+
+impl BenchMap for ShardedAtomicHashMap {
+    fn new(size: usize) -> Self {
+        ShardedAtomicHashMap::new(size, 16).unwrap()
+    }
+
+    fn insert(&self, key: u64, value: u64) {
+        ShardedAtomicHashMap::insert(self, key, value).unwrap();
+    }
+
+    fn get(&self, key: u64) -> Option<u64> {
+        ShardedAtomicHashMap::get(self, &key)
+    }
+}
+
+impl BenchMap for Mutex<HashMap<u64, u64>> {
+    fn new(size: usize) -> Self {
+        Mutex::new(HashMap::with_capacity(size))
+    }
+
+    fn insert(&self, key: u64, value: u64) {
+        self.lock().unwrap().insert(key, value);
+    }
+
+    fn get(&self, key: u64) -> Option<u64> {
+        self.lock().unwrap().get(&key).copied()
+    }
+}
+
+impl BenchMap for CHashMap<u64, u64> {
+    fn new(size: usize) -> Self {
+        CHashMap::with_capacity(size)
+    }
+
+    fn insert(&self, key: u64, value: u64) {
+        CHashMap::insert(self, key, value);
+    }
+
+    fn get(&self, key: u64) -> Option<u64> {
+        CHashMap::get(self, &key).map(|value| *value)
+    }
+}
+
+impl BenchMap for cht::HashMap<u64, u64> {
+    fn new(size: usize) -> Self {
+        cht::HashMap::with_capacity(size)
+    }
+
+    fn insert(&self, key: u64, value: u64) {
+        cht::HashMap::insert(self, key, value);
+    }
+
+    fn get(&self, key: u64) -> Option<u64> {
+        cht::HashMap::get(self, &key)
+    }
+}
+
+impl BenchMap for DashMap<u64, u64> {
+    fn new(size: usize) -> Self {
+        DashMap::with_capacity(size)
+    }
+
+    fn insert(&self, key: u64, value: u64) {
+        DashMap::insert(self, key, value);
+    }
+
+    fn get(&self, key: u64) -> Option<u64> {
+        DashMap::get(self, &key).map(|value| *value)
+    }
+}
+
+/// Run `op` on `threads` threads, each given its own share of the keys `1..=OPS`
+fn run<M: BenchMap, F>(map: &M, threads: u64, op: F) where F: Fn(&M, u64) + Sync {
+    let per_thread = OPS / threads;
+    thread::scope(|scope| {
+        for id in 0..threads {
+            let op = &op;
+            scope.spawn(move || {
+                for key in id * per_thread + 1..=(id + 1) * per_thread {
+                    op(map, key);
+                }
+            });
+        }
+    });
+}
+
+/// Scramble `key` over `1..=OPS` so that threads of the mixed workload touch each
+/// other's keys
+fn scramble(key: u64) -> u64 {
+    key.wrapping_mul(0x9e37_79b9_7f4a_7c15) % OPS + 1
+}
+
+fn bench_map<M: BenchMap>(c: &mut Criterion, name: &str) {
+    let mut group = c.benchmark_group(format!("compare/{}", name));
+    group.throughput(Throughput::Elements(OPS));
+
+    let filled = M::new(OPS as usize * 2);
+    for key in 1..=OPS {
+        filled.insert(key, key);
+    }
+
+    for &threads in THREADS.iter() {
+        group.bench_with_input(BenchmarkId::new("insert", threads), &threads, |b, &threads| {
+            b.iter_batched(|| M::new(OPS as usize * 2),
+                           |map| run(&map, threads, |map, key| map.insert(key, key)),
+                           BatchSize::LargeInput)
+        });
+
+        group.bench_with_input(BenchmarkId::new("get", threads), &threads, |b, &threads| {
+            b.iter(|| run(&filled, threads, |map, key| {
+                black_box(map.get(key));
+            }))
+        });
+
+        // 90% lookups and 10% overwrites of keys spread over the whole table
+        group.bench_with_input(BenchmarkId::new("mixed", threads), &threads, |b, &threads| {
+            b.iter(|| run(&filled, threads, |map, key| {
+                if key % 10 == 0 {
+                    map.insert(scramble(key), key);
+                } else {
+                    black_box(map.get(scramble(key)));
+                }
+            }))
+        });
+    }
+
+    group.finish();
+}
+
+fn bench_compare(c: &mut Criterion) {
+    bench_map::<AtomicHashMap>(c, "atomichashmap");
+    bench_map::<ShardedAtomicHashMap>(c, "sharded");
+    bench_map::<Mutex<HashMap<u64, u64>>>(c, "mutex_hashmap");
+    bench_map::<CHashMap<u64, u64>>(c, "chashmap");
+    bench_map::<cht::HashMap<u64, u64>>(c, "cht");
+    bench_map::<DashMap<u64, u64>>(c, "dashmap");
+}
+
+criterion_group!(benches, bench_compare);
+criterion_main!(benches);
@@ -0,0 +1,174 @@
+//! Replay an arbitrary sequence of operations against an `AtomicHashMap` and a
+//! `HashMap`, checking that both agree after every operation. Inserting a new key into
+//! a full table is the only place they may differ, and only in that the new key is
+//! rejected.
+//!
+//! The map is built with arbitrary sentinels and probe limits, and optionally hashes
+//! keys to themselves so that inputs pick the start of each probe directly, down to
+//! the last slots of the table.
+
+#![no_main]
+
+use std::collections::HashMap;
+use std::hash::{BuildHasher, BuildHasherDefault, Hasher};
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+
+use atomics_rs::{AtomicHashMap, AtomicHashMapError, ProbeStrategy};
+
+/// Hasher giving back the key as its own hash
+#[derive(Default)]
+struct IdentityHasher(u64);
+
+impl Hasher for IdentityHasher {
+    fn write(&mut self, _bytes: &[u8]) {
+        unreachable!("Keys are hashed as a single u64");
+    }
+
+    fn write_u64(&mut self, val: u64) {
+        self.0 = val;
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Arbitrary, Debug)]
+enum Op {
+    Insert(u64, u64),
+    Get(u64),
+    Remove(u64),
+    GetOrInsert(u64, u64),
+    AddTo(u64, u64),
+    Update(u64, u64),
+    UpdateIfEq(u64, u64, u64),
+    RetainBelow(u64),
+    Compact,
+    Clear
+}
+
+#[derive(Arbitrary, Debug)]
+enum Probe {
+    Linear,
+    Quadratic,
+    DoubleHash
+}
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    size_log2: u8,
+    empty_key: u64,
+    tombstone_key: u64,
+    identity_hash: bool,
+    probe: Probe,
+    max_probe: Option<u8>,
+    ops: Vec<Op>
+}
+
+/// Check a write of `key` that either succeeded with `res`, or was rejected because
+/// the key is a sentinel or there is no room for it
+fn check_write<T, F>(res: Result<T, AtomicHashMapError>, key: u64, sentinels: (u64, u64),
+                     oracle: &mut HashMap<u64, u64>, apply: F)
+        where T: PartialEq + std::fmt::Debug, F: FnOnce(&mut HashMap<u64, u64>) -> T {
+    match res {
+        Err(AtomicHashMapError::InvalidKey) => {
+            assert!(key == sentinels.0 || key == sentinels.1);
+        }
+        Err(AtomicHashMapError::Full) => {
+            // Only new keys can be rejected
+            assert!(!oracle.contains_key(&key));
+        }
+        Err(err) => panic!("Unexpected error {:?}", err),
+        Ok(value) => assert_eq!(value, apply(oracle))
+    }
+}
+
+fn run<S: BuildHasher>(mut map: AtomicHashMap<u64, u64, S>, ops: Vec<Op>) {
+    let sentinels = map.sentinels();
+    let mut oracle: HashMap<u64, u64> = HashMap::new();
+
+    for op in ops {
+        match op {
+            Op::Insert(key, value) => {
+                check_write(map.insert(key, value), key, sentinels, &mut oracle,
+                            |oracle| oracle.insert(key, value));
+            }
+            Op::Get(key) => assert_eq!(map.get(&key), oracle.get(&key).copied()),
+            Op::Remove(key) => assert_eq!(map.remove(key), oracle.remove(&key)),
+            Op::GetOrInsert(key, value) => {
+                check_write(map.get_or_insert(key, value), key, sentinels, &mut oracle,
+                            |oracle| *oracle.entry(key).or_insert(value));
+            }
+            Op::AddTo(key, delta) => {
+                check_write(map.add_to(key, delta), key, sentinels, &mut oracle, |oracle| {
+                    let value = oracle.entry(key).or_insert(0);
+                    let prev = *value;
+                    *value = value.wrapping_add(delta);
+                    prev
+                });
+            }
+            Op::Update(key, value) => {
+                let expected = oracle.get_mut(&key).map(|curr| std::mem::replace(curr, value));
+                assert_eq!(map.update(key, |_| value), expected);
+            }
+            Op::UpdateIfEq(key, expected, new) => {
+                let res = oracle.get_mut(&key).map(|curr| {
+                    if *curr == expected {
+                        Ok(std::mem::replace(curr, new))
+                    } else {
+                        Err(*curr)
+                    }
+                });
+                assert_eq!(map.update_if_eq(key, expected, new), res);
+            }
+            Op::RetainBelow(limit) => {
+                map.retain(|_, value| value < limit);
+                oracle.retain(|_, value| *value < limit);
+            }
+            Op::Compact => map.compact(),
+            Op::Clear => {
+                map.clear_mut();
+                oracle.clear();
+            }
+        }
+
+        assert_eq!(map.len(), oracle.len() as u64);
+    }
+
+    let mut entries = map.to_vec();
+    entries.sort();
+    let mut expected: Vec<(u64, u64)> = oracle.into_iter().collect();
+    expected.sort();
+    assert_eq!(entries, expected);
+}
+
+fuzz_target!(|input: Input| {
+    let size = 1 << (input.size_log2 % 8 + 1);
+    let probe = match input.probe {
+        Probe::Linear => ProbeStrategy::Linear,
+        Probe::Quadratic => ProbeStrategy::Quadratic,
+        Probe::DoubleHash => ProbeStrategy::DoubleHash
+    };
+
+    let mut builder = AtomicHashMap::builder(size)
+        .sentinels(input.empty_key, input.tombstone_key)
+        .probe(probe);
+    if let Some(max_probe) = input.max_probe {
+        builder = builder.max_probe(max_probe as usize);
+    }
+
+    if input.identity_hash {
+        let hasher = BuildHasherDefault::<IdentityHasher>::default();
+        match builder.hasher(hasher).build() {
+            Ok(map) => run(map, input.ops),
+            Err(err) => assert_eq!(err, AtomicHashMapError::InvalidKey)
+        }
+    } else {
+        match builder.build() {
+            Ok(map) => run(map, input.ops),
+            Err(err) => assert_eq!(err, AtomicHashMapError::InvalidKey)
+        }
+    }
+});
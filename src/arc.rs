@@ -0,0 +1,272 @@
+//! Atomic cell holding an `Arc<T>`, for publishing shared snapshots to readers
+//!
+//! The cell keeps one strong reference to its current value as a raw pointer in an
+//! `AtomicPtr`. `load` reads the pointer and clones the `Arc` by incrementing its
+//! strong count, and `store` swaps in a new pointer. The catch is the window between
+//! a reader loading the pointer and incrementing the count: a writer that replaced
+//! the pointer and dropped the cell's reference right away could free the value in
+//! that window.
+//!
+//! The cell closes the window with its own `Collector` from the `epoch` module.
+//! Readers stay pinned from loading the pointer until the count is incremented, and
+//! writers defer dropping the cell's reference to the replaced value until every
+//! reader pinned at the time has unpinned. Readers never wait on writers or on each
+//! other, and a writer only waits for the compare-exchange of the global epoch.
+//!
+//! Like `Rcu`, a thread that uses the cell often registers a handle once and pins it
+//! around `load_guarded` and `swap_guarded`, so that its garbage stays in the bag of
+//! its handle and is collected every few swaps. `load`, `store` and `swap` register
+//! a handle for the one call instead, which walks the participants of the collector
+//! and leaves the garbage of a swap to be adopted by the next collection.
+
+use alloc::sync::Arc;
+use core::mem::ManuallyDrop;
+
+use crate::sync::atomic::{AtomicPtr, Ordering};
+
+use crate::epoch::{Collector, Guard, LocalHandle};
+
+/// Atomic `Arc<T>` that can be loaded and replaced by any thread without locks
+pub struct AtomicArc<T: Send + Sync + 'static> {
+    /// Value of the strong reference owned by the cell
+    ptr: AtomicPtr<T>,
+
+    /// Reclaims the references to replaced values
+    collector: Collector
+}
+
+impl<T: Send + Sync + 'static> AtomicArc<T> {
+    /// Construct a cell holding `value`
+    pub fn new(value: Arc<T>) -> AtomicArc<T> {
+        AtomicArc {
+            ptr: AtomicPtr::new(Arc::into_raw(value) as *mut T),
+            collector: Collector::new()
+        }
+    }
+
+    /// Register a handle for a thread using the cell, to be pinned around
+    /// `load_guarded` and `swap_guarded`
+    pub fn register(&self) -> LocalHandle<'_> {
+        self.collector.register()
+    }
+
+    /// Get a clone of the `Arc` in the cell, registering and pinning a handle for it
+    pub fn load(&self) -> Arc<T> {
+        let handle = self.register();
+        let guard = handle.pin();
+        self.load_guarded(&guard)
+    }
+
+    /// Get a clone of the `Arc` in the cell while pinned by `guard`.
+    /// NOTE: Panics if `guard` doesn't come from a handle of this cell.
+    pub fn load_guarded(&self, guard: &Guard<'_>) -> Arc<T> {
+        assert!(core::ptr::eq(guard.collector(), &self.collector),
+                "Guard of another AtomicArc");
+
+        let ptr = self.ptr.load(Ordering::Acquire);
+
+        // SAFETY: The cell's reference to `ptr` is only dropped once no thread pinned
+        // before it was replaced is still pinned, so the value is still alive. The
+        // clone takes a reference of our own.
+        let value = ManuallyDrop::new(unsafe { Arc::from_raw(ptr) });
+        Arc::clone(&value)
+    }
+
+    /// Put `value` in the cell
+    pub fn store(&self, value: Arc<T>) {
+        drop(self.swap(value));
+    }
+
+    /// Put `value` in the cell while pinned by `guard`
+    /// NOTE: Panics if `guard` doesn't come from a handle of this cell.
+    pub fn store_guarded(&self, guard: &Guard<'_>, value: Arc<T>) {
+        drop(self.swap_guarded(guard, value));
+    }
+
+    /// Put `value` in the cell, returning the `Arc` it replaced, registering and
+    /// pinning a handle for it
+    pub fn swap(&self, value: Arc<T>) -> Arc<T> {
+        let handle = self.register();
+        let guard = handle.pin();
+        let old = self.swap_guarded(&guard, value);
+        drop(guard);
+
+        // The handle goes away with the call, free what of its garbage is ready now
+        // rather than leave all of it to be adopted
+        handle.collect();
+        old
+    }
+
+    /// Put `value` in the cell while pinned by `guard`, returning the `Arc` it
+    /// replaced. The cell's reference to the replaced value is dropped by a later
+    /// collection of the handle of `guard`.
+    /// NOTE: Panics if `guard` doesn't come from a handle of this cell.
+    pub fn swap_guarded(&self, guard: &Guard<'_>, value: Arc<T>) -> Arc<T> {
+        assert!(core::ptr::eq(guard.collector(), &self.collector),
+                "Guard of another AtomicArc");
+
+        let old = self.ptr.swap(Arc::into_raw(value) as *mut T, Ordering::AcqRel);
+
+        // SAFETY: The swap took the cell's reference to `old` out of the cell
+        let old = unsafe { Arc::from_raw(old) };
+
+        // Readers that loaded the old pointer may still be about to clone it, so the
+        // cell's reference is only dropped once they are done
+        let ret = Arc::clone(&old);
+        guard.defer(move || drop(old));
+        ret
+    }
+
+    /// Take the `Arc` out of the cell
+    pub fn into_inner(self) -> Arc<T> {
+        let mut cell = ManuallyDrop::new(self);
+        let ptr = cell.ptr.load(Ordering::Relaxed);
+
+        // SAFETY: The cell is never used again, its collector is dropped here and its
+        // reference to `ptr` is handed to the caller
+        unsafe {
+            core::ptr::drop_in_place(&mut cell.collector);
+            Arc::from_raw(ptr)
+        }
+    }
+}
+
+impl<T: Send + Sync + Default + 'static> Default for AtomicArc<T> {
+    fn default() -> AtomicArc<T> {
+        AtomicArc::new(Arc::new(T::default()))
+    }
+}
+
+impl<T: Send + Sync + 'static> Drop for AtomicArc<T> {
+    fn drop(&mut self) {
+        // SAFETY: The cell owns a reference to its current value. References to
+        // replaced values are dropped with the collector.
+        drop(unsafe { Arc::from_raw(self.ptr.load(Ordering::Relaxed)) });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    /// Value that counts its drops
+    struct Tracked(u64, Arc<AtomicUsize>);
+
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            self.1.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_atomic_arc() {
+        let cell = AtomicArc::new(Arc::new(1));
+        assert_eq!(*cell.load(), 1);
+
+        let first = cell.load();
+        cell.store(Arc::new(2));
+        assert_eq!(*first, 1);
+        assert_eq!(*cell.load(), 2);
+        assert_eq!(*cell.swap(Arc::new(3)), 2);
+
+        let value = cell.into_inner();
+        assert_eq!(*value, 3);
+        assert_eq!(Arc::strong_count(&value), 1);
+
+        let cell = AtomicArc::<Vec<u8>>::default();
+        assert!(cell.load().is_empty());
+    }
+
+    #[test]
+    fn test_atomic_arc_drop() {
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let cell = AtomicArc::new(Arc::new(Tracked(0, dropped.clone())));
+
+        // A snapshot outlives its replacement in the cell
+        let snapshot = cell.load();
+        for i in 1..=10 {
+            cell.store(Arc::new(Tracked(i, dropped.clone())));
+        }
+        assert_eq!(snapshot.0, 0);
+        assert_eq!(cell.load().0, 10);
+
+        drop(cell);
+        assert_eq!(dropped.load(Ordering::Relaxed), 10);
+        drop(snapshot);
+        assert_eq!(dropped.load(Ordering::Relaxed), 11);
+    }
+
+    #[test]
+    fn test_atomic_arc_guarded() {
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let cell = AtomicArc::new(Arc::new(Tracked(0, dropped.clone())));
+
+        // One handle for every call, whose bag collects the replaced values as it goes
+        let handle = cell.register();
+        for i in 1..=1000 {
+            let guard = handle.pin();
+            assert_eq!(cell.load_guarded(&guard).0, i - 1);
+            cell.store_guarded(&guard, Arc::new(Tracked(i, dropped.clone())));
+        }
+        // All but the values replaced in the last couple of collections are gone
+        assert!(dropped.load(Ordering::Relaxed) > 800);
+
+        let guard = handle.pin();
+        assert_eq!(cell.swap_guarded(&guard, Arc::new(Tracked(0, dropped.clone()))).0,
+                   1000);
+        drop(guard);
+        drop(handle);
+
+        drop(cell);
+        assert_eq!(dropped.load(Ordering::Relaxed), 1002);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_atomic_arc_foreign_guard() {
+        let cell = AtomicArc::new(Arc::new(1));
+        let other = Collector::new();
+        let handle = other.register();
+        cell.load_guarded(&handle.pin());
+    }
+
+    #[test]
+    fn test_atomic_arc_threads() {
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let cell = AtomicArc::new(Arc::new(Tracked(0, dropped.clone())));
+
+        // Writers publish new values while readers load them, each reader seeing the
+        // values of each writer in order
+        thread::scope(|scope| {
+            for thread in 0..2u64 {
+                let (cell, dropped) = (&cell, &dropped);
+                scope.spawn(move || {
+                    for i in 1..=5_000 {
+                        cell.store(Arc::new(Tracked(thread << 32 | i, dropped.clone())));
+                    }
+                });
+            }
+
+            for _ in 0..2 {
+                let cell = &cell;
+                scope.spawn(move || {
+                    let mut last = [0; 2];
+                    for _ in 0..10_000 {
+                        let value = cell.load().0;
+                        if value != 0 {
+                            let thread = (value >> 32) as usize;
+                            assert!(value & 0xffff_ffff >= last[thread]);
+                            last[thread] = value & 0xffff_ffff;
+                        }
+                    }
+                });
+            }
+        });
+
+        drop(cell);
+        assert_eq!(dropped.load(Ordering::Relaxed), 10_001);
+    }
+}
@@ -0,0 +1,218 @@
+//! Lock-free bump arena for scratch data shared between threads
+//!
+//! One buffer allocated up front and an atomic offset of its first unused byte.
+//! `alloc` rounds the offset up to the requested alignment and bumps it past the
+//! requested size with a compare-exchange, so every call gets a range of the buffer
+//! no other call can get, and hands that range out as a `&mut [u8]`. Nothing is
+//! freed on its own: once the buffer is used up, `alloc` returns `Full` until
+//! `reset`, which takes the arena by `&mut` and so only runs once every slice it
+//! handed out is gone. That suits data built up during one run, like the inputs and
+//! traces of one fuzzing campaign, and thrown away as a whole before the next.
+//!
+//! The buffer is zeroed when it is allocated and `reset` zeroes the part that was
+//! used, so every slice starts out zeroed. The offset is only a counter: the bytes
+//! of a slice are published to other threads the same way as any other `&mut` data.
+
+use alloc::alloc::{self as heap, Layout};
+use core::ptr::{self, NonNull};
+use core::slice;
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::backoff::Backoff;
+use crate::error::AtomicHashMapError;
+
+/// Alignment of the buffer, so that alignments up to a cache line need no padding
+/// at its start
+const BUFFER_ALIGN: usize = 64;
+
+/// Fixed-capacity arena handing out byte slices to any number of threads
+pub struct AtomicArena {
+    buffer: NonNull<u8>,
+    capacity: usize,
+
+    /// Offset of the first byte not handed out yet
+    head: AtomicUsize
+}
+
+// SAFETY: The arena owns its buffer, and the ranges it hands out never overlap
+unsafe impl Send for AtomicArena {}
+unsafe impl Sync for AtomicArena {}
+
+impl AtomicArena {
+    /// Construct an arena of `capacity` bytes. Returns `InvalidCapacity` if the
+    /// buffer can't be laid out.
+    pub fn new(capacity: usize) -> Result<AtomicArena, AtomicHashMapError> {
+        let layout = Layout::from_size_align(capacity, BUFFER_ALIGN)
+            .map_err(|_| AtomicHashMapError::InvalidCapacity)?;
+
+        let buffer = if capacity == 0 {
+            NonNull::dangling()
+        } else {
+            // SAFETY: The layout has a non-zero size
+            let ptr = unsafe { heap::alloc_zeroed(layout) };
+            NonNull::new(ptr).unwrap_or_else(|| heap::handle_alloc_error(layout))
+        };
+
+        Ok(AtomicArena { buffer, capacity, head: AtomicUsize::new(0) })
+    }
+
+    /// Hand out `size` zeroed bytes aligned to `align`, or return `Full` if the rest
+    /// of the buffer is too small for them. Panics if `align` isn't a power of two.
+    #[allow(clippy::mut_from_ref)]
+    pub fn alloc(&self, size: usize, align: usize)
+            -> Result<&mut [u8], AtomicHashMapError> {
+        assert!(align.is_power_of_two(), "Alignment {} isn't a power of two", align);
+
+        let base = self.buffer.as_ptr() as usize;
+        let mut backoff = Backoff::new();
+        let mut head = self.head.load(Ordering::Relaxed);
+        let start = loop {
+            // Pad the start up to the alignment of its address
+            let start = base.checked_add(head)
+                .and_then(|addr| addr.checked_add(align - 1))
+                .map(|addr| (addr & !(align - 1)) - base);
+            let end = start.and_then(|start| start.checked_add(size))
+                .filter(|&end| end <= self.capacity);
+            let (start, end) = match (start, end) {
+                (Some(start), Some(end)) => (start, end),
+                _ => return Err(AtomicHashMapError::Full)
+            };
+
+            match self.head.compare_exchange_weak(head, end, Ordering::Relaxed,
+                                                  Ordering::Relaxed) {
+                Ok(_) => break start,
+                Err(curr) => head = curr
+            }
+
+            backoff.spin();
+        };
+
+        // SAFETY: The range is within the buffer, zeroed, and handed out to this call
+        // alone until `reset`, which needs every slice to be gone
+        Ok(unsafe { slice::from_raw_parts_mut(self.buffer.as_ptr().add(start), size) })
+    }
+
+    /// Get the number of bytes handed out, padding included. Only a hint while other
+    /// threads allocate.
+    pub fn used(&self) -> usize {
+        self.head.load(Ordering::Relaxed)
+    }
+
+    /// Get the number of bytes left to hand out, before padding. Only a hint while
+    /// other threads allocate.
+    pub fn remaining(&self) -> usize {
+        self.capacity - self.used()
+    }
+
+    /// Get the size of the buffer in bytes
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Take back every slice handed out, zeroing the bytes that were used
+    pub fn reset(&mut self) {
+        let used = *self.head.get_mut();
+
+        // SAFETY: The first `used` bytes are within the buffer, and no slice of them
+        // is alive since the arena is borrowed mutably
+        unsafe { ptr::write_bytes(self.buffer.as_ptr(), 0, used); }
+        *self.head.get_mut() = 0;
+    }
+}
+
+impl Drop for AtomicArena {
+    fn drop(&mut self) {
+        if self.capacity > 0 {
+            // SAFETY: The buffer was allocated in `new` with this layout
+            unsafe {
+                let layout = Layout::from_size_align_unchecked(self.capacity,
+                                                               BUFFER_ALIGN);
+                heap::dealloc(self.buffer.as_ptr(), layout);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    #[test]
+    fn test_arena() {
+        let mut arena = AtomicArena::new(256).unwrap();
+        let a = arena.alloc(3, 1).unwrap();
+        a.copy_from_slice(b"abc");
+
+        // Padded up to the alignment
+        let b = arena.alloc(8, 8).unwrap();
+        assert_eq!(b.as_ptr() as usize % 8, 0);
+        assert_eq!(b, &[0; 8]);
+        b.fill(0xff);
+        assert_eq!(a, b"abc");
+        assert_eq!(arena.used(), 16);
+
+        let c = arena.alloc(192, 64).unwrap();
+        assert_eq!(c.as_ptr() as usize % 64, 0);
+        assert_eq!(arena.remaining(), 0);
+        assert_eq!(arena.alloc(1, 1), Err(AtomicHashMapError::Full));
+        assert_eq!(arena.alloc(0, 1).map(|empty| empty.len()), Ok(0));
+        assert_eq!(arena.alloc(usize::MAX, 1), Err(AtomicHashMapError::Full));
+
+        // Every slice is zeroed again after a reset
+        arena.reset();
+        assert_eq!(arena.used(), 0);
+        assert_eq!(arena.alloc(256, 1).unwrap(), &[0; 256][..]);
+
+        let empty = AtomicArena::new(0).unwrap();
+        assert_eq!(empty.alloc(1, 1), Err(AtomicHashMapError::Full));
+        assert_eq!(AtomicArena::new(usize::MAX).err(),
+                   Some(AtomicHashMapError::InvalidCapacity));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_arena_invalid_align() {
+        let arena = AtomicArena::new(16).unwrap();
+        let _ = arena.alloc(1, 3);
+    }
+
+    #[test]
+    fn test_arena_threads() {
+        let mut arena = AtomicArena::new(64 * 1024).unwrap();
+
+        for round in 1..=2u8 {
+            // Threads fill their slices with their own byte until the arena is full,
+            // and no slice is ever overwritten by another thread
+            let filled = thread::scope(|scope| {
+                let threads = (0..4u8).map(|thread| {
+                    let arena = &arena;
+                    scope.spawn(move || {
+                        let mut slices = Vec::new();
+                        let mut size = 1;
+                        while let Ok(slice) = arena.alloc(size, 8) {
+                            assert!(slice.iter().all(|&byte| byte == 0));
+                            slice.fill(round << 4 | thread);
+                            slices.push(slice);
+                            size = size % 61 + 1;
+                        }
+
+                        slices.iter()
+                            .inspect(|slice| {
+                                assert!(slice.iter().all(|&b| b == round << 4 | thread))
+                            })
+                            .map(|slice| slice.len())
+                            .sum::<usize>()
+                    })
+                }).collect::<Vec<_>>();
+
+                threads.into_iter().map(|thread| thread.join().unwrap()).sum::<usize>()
+            });
+
+            assert!(filled > 0 && filled <= arena.capacity());
+            arena.reset();
+        }
+    }
+}
@@ -0,0 +1,454 @@
+//! 128-bit atomic integer, for wide entries and tagged pointers
+//!
+//! Stable Rust has no `AtomicU128`, but most 64-bit machines can compare-exchange
+//! two adjacent words at once. `AtomicU128` uses that instruction where it exists and
+//! a sequence lock everywhere else:
+//!
+//! * On x86_64 it is `lock cmpxchg16b`, when the target enables it or, with `std`,
+//!   when the CPU running the program reports it. A load is a compare-exchange of 0
+//!   with 0, which fails or changes nothing but returns the value atomically.
+//! * On aarch64 it is a loop of `ldaxp` and `stlxp`, part of every ARMv8 CPU. A pair
+//!   load is only single-copy atomic if the exclusive store after it succeeds, so a
+//!   failed compare stores back the value it read.
+//! * Elsewhere, writers take a sequence lock next to the value and readers retry
+//!   copies that overlapped a writer, like `AtomicCell` does for values without a
+//!   native atomic. Readers never write, but the operations are not lock-free.
+//!
+//! Every operation is built on a load and a compare-exchange. Loads are `Acquire`,
+//! and a successful compare-exchange is `AcqRel` on the sequence lock and sequentially
+//! consistent with the native instructions.
+
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::mem::MaybeUninit;
+use core::ptr;
+
+use core::sync::atomic::{self, AtomicUsize, Ordering};
+
+use crate::backoff::Backoff;
+
+/// Unsigned 128-bit integer that can be shared and updated between threads
+#[repr(C, align(16))]
+pub struct AtomicU128 {
+    /// Aligned for the double-word compare-exchange
+    value: UnsafeCell<u128>,
+
+    /// Sequence lock used without a double-word compare-exchange, odd while a writer
+    /// holds it
+    seq: AtomicUsize
+}
+
+// SAFETY: The value is only accessed through atomic instructions or under the
+// sequence lock
+unsafe impl Sync for AtomicU128 {}
+
+impl AtomicU128 {
+    /// Construct an atomic integer holding `value`
+    pub const fn new(value: u128) -> AtomicU128 {
+        AtomicU128 { value: UnsafeCell::new(value), seq: AtomicUsize::new(0) }
+    }
+
+    /// Returns true if operations use the double-word compare-exchange of the CPU
+    /// rather than the sequence lock
+    pub fn is_lock_free() -> bool {
+        native::available()
+    }
+
+    /// Get the value
+    pub fn load(&self) -> u128 {
+        if native::available() {
+            // SAFETY: The value is aligned and only accessed atomically
+            match unsafe { native::compare_exchange(self.value.get(), 0, 0) } {
+                Ok(value) | Err(value) => value
+            }
+        } else {
+            self.load_locked()
+        }
+    }
+
+    /// Store `value`
+    pub fn store(&self, value: u128) {
+        self.swap(value);
+    }
+
+    /// Store `value`, returning the value it replaced
+    pub fn swap(&self, value: u128) -> u128 {
+        match self.fetch_update(|_| Some(value)) {
+            Ok(old) | Err(old) => old
+        }
+    }
+
+    /// Store `new` if the value is `current`. Returns the previous value, as `Ok` if
+    /// it was replaced and `Err` otherwise.
+    pub fn compare_exchange(&self, current: u128, new: u128) -> Result<u128, u128> {
+        if native::available() {
+            // SAFETY: The value is aligned and only accessed atomically
+            unsafe { native::compare_exchange(self.value.get(), current, new) }
+        } else {
+            self.compare_exchange_locked(current, new)
+        }
+    }
+
+    /// Replace the value with `f` of it, retrying if another thread changes it in
+    /// between. Returns the value replaced, or `Err` with the current value if `f`
+    /// returns `None`.
+    pub fn fetch_update<F>(&self, mut f: F) -> Result<u128, u128>
+            where F: FnMut(u128) -> Option<u128> {
+        let mut backoff = Backoff::new();
+        let mut current = self.load();
+        while let Some(new) = f(current) {
+            match self.compare_exchange(current, new) {
+                Ok(old) => return Ok(old),
+                Err(curr) => current = curr
+            }
+
+            backoff.spin();
+        }
+
+        Err(current)
+    }
+
+    /// Add `delta` to the value, wrapping around on overflow. Returns the previous
+    /// value.
+    pub fn fetch_add(&self, delta: u128) -> u128 {
+        match self.fetch_update(|value| Some(value.wrapping_add(delta))) {
+            Ok(old) | Err(old) => old
+        }
+    }
+
+    /// Subtract `delta` from the value, wrapping around on overflow. Returns the
+    /// previous value.
+    pub fn fetch_sub(&self, delta: u128) -> u128 {
+        match self.fetch_update(|value| Some(value.wrapping_sub(delta))) {
+            Ok(old) | Err(old) => old
+        }
+    }
+
+    /// Get a mutable reference to the value, which no other thread can access
+    pub fn get_mut(&mut self) -> &mut u128 {
+        self.value.get_mut()
+    }
+
+    /// Take the value out of the atomic
+    pub fn into_inner(self) -> u128 {
+        self.value.into_inner()
+    }
+
+    /// Copy the value under the sequence lock, retrying copies that overlapped a
+    /// writer
+    fn load_locked(&self) -> u128 {
+        let mut backoff = Backoff::new();
+        loop {
+            let seq = self.seq.load(Ordering::Acquire);
+            if seq & 1 == 0 {
+                // SAFETY: The copy may be torn, so it is only used once the sequence
+                // shows no writer ran
+                let value = unsafe {
+                    ptr::read_volatile(self.value.get() as *const MaybeUninit<u128>)
+                };
+
+                atomic::fence(Ordering::Acquire);
+                if self.seq.load(Ordering::Relaxed) == seq {
+                    // SAFETY: No writer ran while the value was copied
+                    return unsafe { value.assume_init() };
+                }
+            }
+
+            // A writer holds the lock, wait for it to finish
+            backoff.snooze();
+        }
+    }
+
+    /// Compare-exchange the value under the sequence lock
+    fn compare_exchange_locked(&self, current: u128, new: u128) -> Result<u128, u128> {
+        let mut backoff = Backoff::new();
+        let seq = loop {
+            let seq = self.seq.load(Ordering::Relaxed);
+            if seq & 1 == 0 {
+                let res = self.seq.compare_exchange_weak(seq, seq + 1, Ordering::Acquire,
+                                                         Ordering::Relaxed);
+                if res.is_ok() {
+                    break seq;
+                }
+            }
+
+            backoff.snooze();
+        };
+
+        // Order the odd sequence before the write of the value
+        atomic::fence(Ordering::Release);
+
+        // SAFETY: Writers are excluded by the lock and readers check the sequence
+        let old = unsafe { ptr::read(self.value.get()) };
+        let res = if old == current {
+            // SAFETY: As above
+            unsafe { ptr::write_volatile(self.value.get(), new); }
+            Ok(old)
+        } else {
+            Err(old)
+        };
+
+        self.seq.store(seq.wrapping_add(2), Ordering::Release);
+        res
+    }
+}
+
+impl Default for AtomicU128 {
+    fn default() -> AtomicU128 {
+        AtomicU128::new(0)
+    }
+}
+
+impl From<u128> for AtomicU128 {
+    fn from(value: u128) -> AtomicU128 {
+        AtomicU128::new(value)
+    }
+}
+
+impl fmt::Debug for AtomicU128 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.load(), f)
+    }
+}
+
+/// Double-word compare-exchange of x86_64
+#[cfg(target_arch = "x86_64")]
+mod native {
+    use core::arch::asm;
+
+    /// Returns true if the CPU has `cmpxchg16b`
+    #[inline]
+    pub(super) fn available() -> bool {
+        #[cfg(target_feature = "cmpxchg16b")]
+        return true;
+
+        // The detection caches its answer, so this is one relaxed load
+        #[cfg(all(not(target_feature = "cmpxchg16b"), feature = "std"))]
+        return std::is_x86_feature_detected!("cmpxchg16b");
+
+        #[cfg(all(not(target_feature = "cmpxchg16b"), not(feature = "std")))]
+        return false;
+    }
+
+    /// Store `new` at `dst` if it holds `current`, returning the value it held
+    ///
+    /// # Safety
+    ///
+    /// `dst` must be valid, 16-byte aligned and only accessed atomically, and the CPU
+    /// must have `cmpxchg16b`
+    #[inline]
+    pub(super) unsafe fn compare_exchange(dst: *mut u128, current: u128, new: u128)
+            -> Result<u128, u128> {
+        let prev_lo: u64;
+        let prev_hi: u64;
+        let ok: u8;
+
+        // SAFETY: Guaranteed by the caller. `rbx` is reserved by LLVM, so the low half
+        // of `new` is swapped into it for the instruction and swapped back after.
+        unsafe {
+            asm!(
+                "xchg {new_lo}, rbx",
+                "lock cmpxchg16b xmmword ptr [{dst}]",
+                "sete {ok}",
+                "mov rbx, {new_lo}",
+                dst = in(reg) dst,
+                new_lo = inout(reg) new as u64 => _,
+                ok = out(reg_byte) ok,
+                in("rcx") (new >> 64) as u64,
+                inout("rax") current as u64 => prev_lo,
+                inout("rdx") (current >> 64) as u64 => prev_hi,
+                options(nostack)
+            );
+        }
+
+        let prev = (u128::from(prev_hi) << 64) | u128::from(prev_lo);
+        if ok != 0 {
+            Ok(prev)
+        } else {
+            Err(prev)
+        }
+    }
+}
+
+/// Double-word compare-exchange of aarch64
+#[cfg(target_arch = "aarch64")]
+mod native {
+    use core::arch::asm;
+
+    /// Exclusive pair loads and stores are part of every ARMv8 CPU
+    #[inline]
+    pub(super) fn available() -> bool {
+        true
+    }
+
+    /// Store `new` at `dst` if it holds `current`, returning the value it held
+    ///
+    /// # Safety
+    ///
+    /// `dst` must be valid, 16-byte aligned and only accessed atomically
+    #[inline]
+    pub(super) unsafe fn compare_exchange(dst: *mut u128, current: u128, new: u128)
+            -> Result<u128, u128> {
+        let prev_lo: u64;
+        let prev_hi: u64;
+
+        // SAFETY: Guaranteed by the caller. The pair is only known to have been read
+        // atomically once an exclusive store to it succeeds, so on a mismatch the
+        // value read is stored back, retrying if that store fails too.
+        unsafe {
+            asm!(
+                "2:",
+                "ldaxp {prev_lo}, {prev_hi}, [{dst}]",
+                "cmp {prev_lo}, {current_lo}",
+                "ccmp {prev_hi}, {current_hi}, #0, eq",
+                "b.ne 3f",
+                "stlxp {status:w}, {new_lo}, {new_hi}, [{dst}]",
+                "cbnz {status:w}, 2b",
+                "b 4f",
+                "3:",
+                "stlxp {status:w}, {prev_lo}, {prev_hi}, [{dst}]",
+                "cbnz {status:w}, 2b",
+                "4:",
+                dst = in(reg) dst,
+                current_lo = in(reg) current as u64,
+                current_hi = in(reg) (current >> 64) as u64,
+                new_lo = in(reg) new as u64,
+                new_hi = in(reg) (new >> 64) as u64,
+                prev_lo = out(reg) prev_lo,
+                prev_hi = out(reg) prev_hi,
+                status = out(reg) _,
+                options(nostack)
+            );
+        }
+
+        let prev = (u128::from(prev_hi) << 64) | u128::from(prev_lo);
+        if prev == current {
+            Ok(prev)
+        } else {
+            Err(prev)
+        }
+    }
+}
+
+/// No double-word compare-exchange, every operation takes the sequence lock
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod native {
+    #[inline]
+    pub(super) fn available() -> bool {
+        false
+    }
+
+    /// Never called, since `available` is false
+    #[inline]
+    pub(super) unsafe fn compare_exchange(_dst: *mut u128, _current: u128, _new: u128)
+            -> Result<u128, u128> {
+        unreachable!("No double-word compare-exchange on this target")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    /// Value whose halves are both `x`, so that a torn read shows as unequal halves
+    fn doubled(x: u64) -> u128 {
+        (u128::from(x) << 64) | u128::from(x)
+    }
+
+    #[test]
+    fn test_atomic_u128() {
+        let mut atomic = AtomicU128::new(u128::from(u64::MAX));
+        assert_eq!(atomic.fetch_add(1), u128::from(u64::MAX));
+        assert_eq!(atomic.load(), 1 << 64);
+        assert_eq!(atomic.fetch_sub(1), 1 << 64);
+
+        assert_eq!(atomic.compare_exchange(5, 6), Err(u128::from(u64::MAX)));
+        assert_eq!(atomic.compare_exchange(u128::from(u64::MAX), u128::MAX),
+                   Ok(u128::from(u64::MAX)));
+        assert_eq!(atomic.swap(doubled(7)), u128::MAX);
+        assert_eq!(atomic.fetch_update(|_| None), Err(doubled(7)));
+
+        atomic.store(3);
+        *atomic.get_mut() += 1;
+        assert_eq!(format!("{:?}", atomic), "4");
+        assert_eq!(atomic.into_inner(), 4);
+
+        // Without `std` there is no runtime detection to compare against
+        #[cfg(all(target_arch = "x86_64", feature = "std"))]
+        assert_eq!(AtomicU128::is_lock_free(),
+                   std::is_x86_feature_detected!("cmpxchg16b"));
+    }
+
+    #[test]
+    fn test_atomic_u128_locked() {
+        // The sequence lock works the same on targets with a native compare-exchange
+        let atomic = AtomicU128::new(doubled(1));
+        assert_eq!(atomic.compare_exchange_locked(0, 1), Err(doubled(1)));
+        assert_eq!(atomic.compare_exchange_locked(doubled(1), u128::MAX),
+                   Ok(doubled(1)));
+        assert_eq!(atomic.load_locked(), u128::MAX);
+    }
+
+    #[test]
+    fn test_atomic_u128_threads() {
+        let atomic = AtomicU128::new(0);
+
+        // Increments carry into the high half while readers check for torn values
+        thread::scope(|scope| {
+            for _ in 0..2 {
+                let atomic = &atomic;
+                scope.spawn(move || {
+                    for _ in 0..10_000 {
+                        atomic.fetch_add((1 << 64) | 1);
+                    }
+                });
+            }
+
+            for _ in 0..2 {
+                let atomic = &atomic;
+                scope.spawn(move || {
+                    for _ in 0..10_000 {
+                        let value = atomic.load();
+                        assert_eq!(value >> 64, value & u128::from(u64::MAX));
+                    }
+                });
+            }
+        });
+
+        assert_eq!(atomic.into_inner(), doubled(20_000));
+    }
+
+    #[test]
+    fn test_atomic_u128_locked_threads() {
+        let atomic = AtomicU128::new(0);
+
+        thread::scope(|scope| {
+            for _ in 0..2 {
+                let atomic = &atomic;
+                scope.spawn(move || {
+                    for _ in 0..10_000 {
+                        let mut current = atomic.load_locked();
+                        while let Err(curr) = atomic.compare_exchange_locked(
+                                current, current + doubled(1)) {
+                            current = curr;
+                        }
+                    }
+                });
+            }
+
+            for _ in 0..2 {
+                let atomic = &atomic;
+                scope.spawn(move || {
+                    for _ in 0..10_000 {
+                        let value = atomic.load_locked();
+                        assert_eq!(value >> 64, value & u128::from(u64::MAX));
+                    }
+                });
+            }
+        });
+
+        assert_eq!(atomic.load_locked(), doubled(20_000));
+    }
+}
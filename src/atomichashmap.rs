@@ -1,36 +1,458 @@
-use std::boxed::Box;
+//! Lock-free open addressing hashmap over an array of atomic key/value buckets
+//!
+//! # Memory ordering
+//!
+//! Every slot is a key word and a value word. The orderings used on them give the
+//! following happens-before edges:
+//!
+//! * Claiming a key is a `compare_exchange` (`SeqCst` on success, `Acquire` on
+//!   failure) of an empty or tombstone key. Probing loads keys with `Acquire`, so a
+//!   thread that finds a key sees the claim, and a thread whose claim fails sees the
+//!   key that beat it.
+//! * Two threads can still claim the same key in different slots when one of them
+//!   reuses a tombstone left behind a slot the other already moved past. After every
+//!   claim, the probe of the key is scanned again with `SeqCst` loads, so that of two
+//!   such claims at least one sees the other, and the copy nearest the start of the
+//!   probe is kept while the other is backed out before it is ever published.
+//! * Every value write (`insert`, `get_or_insert_with`, `add_to`, `update`,
+//!   `update_if_eq`) is a `Release` store or an `AcqRel` read-modify-write, and every
+//!   value read is an `Acquire` load. A thread that reads a value sees everything the
+//!   writing thread did before writing it.
+//! * The key is claimed before the value is written, so each slot also has a state
+//!   word holding a published flag. The claiming thread sets it with `Release` after
+//!   writing the first value, and a key is only considered in the map once the flag
+//!   is observed with `Acquire`. A thread that finds a key therefore always sees its
+//!   first value, which makes `get` linearizable with `insert`.
+//! * `remove`, `retain`, `drain` and `clear` take a slot by clearing its published
+//!   flag with a CAS of the state word, so only one of them can win a given entry.
+//!   The CAS also bumps a generation counter in the rest of the word, and the key is
+//!   checked between loading the state and the CAS, so it fails if the slot changed
+//!   hands in between and never unpublishes another key even briefly. The winner
+//!   resets the value before releasing the key with a `Release` write, so a thread
+//!   claiming the released slot writes its value after the reset and never has it
+//!   wiped in its place.
+//! * The orderings on values above are those of the default
+//!   `OrderingProfile::AcquireRelease` and can be weakened or strengthened per map.
+//!   Keys and state words always use the orderings above, so a key is still
+//!   never seen before its first value under `OrderingProfile::Relaxed`.
+//! * Each slot also has a control byte holding a tag of its key, which lets probes
+//!   skip slots holding other keys without loading them. The claiming thread tags a
+//!   slot after claiming its key and before publishing it, and whoever releases a key
+//!   first resets its control byte, so a tag is never left on a slot its key has
+//!   left. Control bytes are read with `Acquire` and written with `AcqRel`.
+//! * The element count is `Relaxed` and only ordered with itself. `len` is a
+//!   statistic, not a synchronization point.
+//!
+//! # Progress
+//!
+//! * Lookups (`get`, `get_key_value`, `contains_key`, `slot`) are wait-free. They
+//!   visit each slot of the table at most once and never retry or wait.
+//! * `remove`, `swap`, `update_if_eq` and the operations of a `SlotRef` are wait-free
+//!   as well: one lookup followed by a single read-modify-write.
+//! * `update`, `upsert` and the other read-modify-write loops are lock-free. A
+//!   compare-exchange only fails because another thread changed the value.
+//! * Inserts (`insert`, `get_or_insert`, `add_to`, `upsert`, ...) are lock-free as long
+//!   as no other thread stalls between claiming a key and publishing its value. An
+//!   insert retries when a tombstone it was about to reuse is taken by another thread,
+//!   or when the key it found is removed, and waits when it finds its key claimed but
+//!   not published yet, including a copy claimed at the same time as its own. That
+//!   window is a single store for `insert`, but spans `init` for `get_or_insert_with`
+//!   and the whole life of a `Reservation`.
+//! * `try_insert` and `try_update` never wait on another thread and give up with
+//!   `AtomicHashMapError::Contended` after losing `TRY_RETRIES` races, which bounds
+//!   their work by the size of the table.
 
-use core::sync::atomic::{Ordering, AtomicU64};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::hash::{BuildHasher, Hasher};
+use core::fmt;
+use core::iter::FromIterator;
+use core::marker::PhantomData;
 
-/// Integer Hash function from MurmurHash3's integer finalizer
-pub fn hash_key(val: u64) -> u64 {
-    let mut res = val;
-    res ^= res >> 33;
-    res = res.wrapping_mul(0xff51afd7ed558ccd);
-    res ^= res >> 33;
-    res = res.wrapping_mul(0xc4ceb9fe1a85ec53);
-    res ^= res >> 33;
-    res
+use crate::sync::atomic::{Ordering, AtomicBool, AtomicU64};
+
+use crate::backoff::Backoff;
+use crate::control::{self, ControlBytes, GROUP_WIDTH};
+use crate::eviction::EvictionPolicy;
+#[cfg(feature = "metrics")]
+use crate::metrics::Metrics;
+use crate::metrics::Counters;
+use crate::placement::Placement;
+use crate::pod::PodU64;
+use crate::probe::{self, retry, Publish, Slot, Table};
+
+pub use crate::error::AtomicHashMapError;
+pub use crate::hasher::{hash_key, unhash_key, BuildMurmurHasher, MurmurHasher};
+pub use crate::ordering::OrderingProfile;
+
+/// Default key marker for a slot that has never been claimed
+const EMPTY_KEY: u64 = 0;
+
+/// Default key marker for a slot whose entry has been removed. Probes continue past
+/// it, but insert is free to claim it for a new key.
+const TOMBSTONE_KEY: u64 = u64::MAX;
+
+/// Lock-free hashmap from keys of type `K` to values of type `V`
+///
+/// Keys and values are stored as the `u64` produced by `PodU64::to_u64`, so any type
+/// that fits in 64 bits can be used without manual bit packing at the call site. Keys
+/// are compared by that representation and hashed with `S`, which hashes it as a
+/// single `u64`.
+pub struct AtomicHashMap<K: PodU64 = u64, V: PodU64 = u64, S = BuildMurmurHasher> {
+    buckets: Box<[Bucket]>,
+    size: usize,
+
+    /// Number of buckets per slot. Slots after the first bucket of each stride are
+    /// never used and only pad slots out to a cache line each.
+    stride: usize,
+
+    /// Tag of the key in each slot, used to probe slots a group at a time
+    ctrl: ControlBytes,
+
+    /// Capacity asked for at construction, which may be less than `size` if it was
+    /// rounded up to a power of two. Set to `size` when the table is resized.
+    requested_size: usize,
+
+    /// Number of keys currently in the table
+    count: AtomicU64,
+
+    /// Raw key marking a slot that has never been claimed
+    empty_key: u64,
+
+    /// Raw key marking a slot whose entry has been removed
+    tombstone_key: u64,
+
+    /// Builds the hasher used to find the start of the probe for each key
+    hasher: S,
+
+    /// Memory orderings used for values
+    ordering: OrderingProfile,
+
+    /// Order in which probes visit groups of slots
+    probe: ProbeStrategy,
+
+    /// Number of slots an insert probes before giving up, if limited
+    max_probe: Option<usize>,
+
+    /// Where tables are allocated
+    placement: Placement,
+
+    /// Picks the entry to evict when a new key doesn't fit, if any
+    eviction: Option<Arc<dyn EvictionPolicy<K, V>>>,
+
+    /// Probe lengths and lost races, only counted with the `metrics` feature
+    counters: Counters,
+
+    _types: PhantomData<(K, V)>
+}
+
+unsafe impl<K: PodU64, V: PodU64, S: Send> Send for AtomicHashMap<K, V, S> {}
+unsafe impl<K: PodU64, V: PodU64, S: Sync> Sync for AtomicHashMap<K, V, S> {}
+
+/// Order in which a probe visits the groups of 16 slots of an `AtomicHashMap`
+///
+/// Slots within a group are always checked in order, the strategy only picks which
+/// group comes next. Every strategy visits each group exactly once before giving up,
+/// and tables of at most 16 slots are a single group, so all strategies behave the
+/// same for them.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeStrategy {
+    /// Visit the groups following the start one after the other. Best cache
+    /// behavior, but keys hashing near each other pile up into long clusters. The
+    /// default.
+    #[default]
+    Linear,
+
+    /// Jump 1, 2, 3, ... groups further at each step, so that keys starting in
+    /// neighbouring groups soon take different paths
+    Quadratic,
+
+    /// Jump by a stride taken from the hash of the key, so that keys starting in the
+    /// same group take different paths too. Most resistant to clustering under
+    /// adversarial keys, at the cost of touching a new region of memory at every step.
+    DoubleHash
 }
 
-pub struct AtomicHashMap {
-    keys:   Box<[AtomicU64]>,
-    values: Box<[AtomicU64]>,
-    size: usize
+/// Round a requested capacity up to the nearest valid table size
+fn round_capacity(size: usize) -> Result<usize, AtomicHashMapError> {
+    size.max(2).checked_next_power_of_two().ok_or(AtomicHashMapError::InvalidCapacity)
 }
 
-unsafe impl Send for AtomicHashMap {}
-unsafe impl Sync for AtomicHashMap {}
+/// One slot of the table. The key, value and flags share a bucket so a
+/// lookup touches a single cache line: buckets are aligned so that two of them fill a
+/// 64-byte line and none straddles two lines. A padded map uses every other bucket so
+/// that no two slots share a line.
+#[repr(C, align(32))]
+struct Bucket {
+    key: AtomicU64,
+    value: AtomicU64,
+
+    /// `PUBLISHED` once the first value of a claimed slot has been written, and the
+    /// number of times the slot was unpublished above its state bits. A key whose
+    /// slot isn't published yet is treated as not inserted.
+    state: AtomicU64,
+
+    /// CLOCK bit of an `AtomicLruCache`, set when its entry is read and cleared once
+    /// every entry around it has been read too
+    referenced: AtomicBool
+}
+
+impl Bucket {
+    /// Allocate `size` unclaimed buckets marked with `empty_key`, placed as asked
+    fn new_table(size: usize, empty_key: u64, placement: Placement) -> Box<[Bucket]> {
+        let mut buckets = placement.alloc_vec(size);
+        for _ in 0..size {
+            buckets.push(Bucket {
+                key: AtomicU64::new(empty_key),
+                value: AtomicU64::new(0),
+                state: AtomicU64::new(0),
+                referenced: AtomicBool::new(false)
+            });
+        }
+
+        buckets.into_boxed_slice()
+    }
 
-#[derive(Debug, PartialEq, Eq)]
-pub enum AtomicHashMapError {
-    Full
 }
 
-impl AtomicHashMap {
+/// Number of buckets per slot of a padded map, filling a 64-byte cache line. Buckets
+/// of loom's atomics are larger than a cache line and get no padding.
+const PADDED_STRIDE: usize = if core::mem::size_of::<Bucket>() >= 64 {
+    1
+} else {
+    64 / core::mem::size_of::<Bucket>()
+};
+
+/// Number of keys ahead of the current one whose first slot batch operations prefetch
+const PREFETCH_DISTANCE: usize = 16;
+
+/// Most entries along the probe of a new key offered to the eviction policy
+const EVICTION_CANDIDATES: usize = 16;
+
+/// Most races a `try_` operation loses to other threads before giving up with
+/// `AtomicHashMapError::Contended`
+pub const TRY_RETRIES: usize = 8;
+
+/// Most passes over the table `snapshot` makes while looking for two that agree
+#[cfg(feature = "std")]
+const SNAPSHOT_PASSES: usize = 4;
+
+impl<K: PodU64, V: PodU64> AtomicHashMap<K, V> {
     /// Construct a new AtomicHashMap with a given size.
-    /// NOTE: Size must be a power of two.
-    pub fn new(size: usize) -> AtomicHashMap {
+    /// NOTE: Size must be a power of two, otherwise `InvalidCapacity` is returned.
+    ///
+    /// Empty and removed slots are marked with the keys packed into 0 and `u64::MAX`,
+    /// which can't be stored. Integers are zero-extended when packed, so this reserves
+    /// 0 of every integer type, but `u64::MAX` only for 64-bit keys: `u64::MAX`, and
+    /// -1 as an `i64` or `isize`. Use `with_sentinels` to store those keys.
+    pub fn new(size: usize) -> Result<AtomicHashMap<K, V>, AtomicHashMapError> {
+        AtomicHashMap::with_hasher(size, BuildMurmurHasher::default())
+    }
+
+    pub fn with_capacity(size: usize) -> Result<AtomicHashMap<K, V>, AtomicHashMapError> {
+        AtomicHashMap::new(size)
+    }
+
+    /// Construct a new AtomicHashMap with room for at least `size` keys, rounding the
+    /// number of slots up to the next power of two. The requested size is available
+    /// from `requested_capacity`.
+    pub fn new_rounded(size: usize) -> Result<AtomicHashMap<K, V>, AtomicHashMapError> {
+        AtomicHashMap::builder(size).round_up(true).build()
+    }
+
+    /// Start configuring a new AtomicHashMap with a given size. See 
+    /// `AtomicHashMapBuilder` for the available options.
+    pub fn builder(size: usize) -> AtomicHashMapBuilder<K, V> {
+        AtomicHashMapBuilder::new(size)
+    }
+
+    /// Construct a new AtomicHashMap with a given size, using `empty_key` and
+    /// `tombstone_key` to mark empty and removed slots instead of 0 and `u64::MAX`
+    ///
+    /// The two sentinels are the only keys that can't be stored in the map, so this
+    /// makes a key of 0 usable when some other key is known never to occur.
+    /// NOTE: Size must be a power of two, otherwise `InvalidCapacity` is returned.
+    pub fn with_sentinels(size: usize, empty_key: K, tombstone_key: K) 
+            -> Result<AtomicHashMap<K, V>, AtomicHashMapError> {
+        AtomicHashMap::with_hasher_and_sentinels(size, BuildMurmurHasher::default(), 
+                                                 empty_key, tombstone_key)
+    }
+}
+
+impl<K: PodU64, V: PodU64, S> AtomicHashMap<K, V, S> {
+    /// Returns true if the raw key read from a slot is a stored key rather than one of
+    /// the sentinels
+    fn is_live(&self, key: u64) -> bool {
+        key != self.empty_key && key != self.tombstone_key
+    }
+
+    /// Get the `(empty, tombstone)` sentinel keys marking empty and removed slots
+    ///
+    /// The default tombstone of `u64::MAX` isn't the packing of any key narrower than
+    /// 64 bits, so for those it is returned truncated to the key type even though that
+    /// key can be stored.
+    pub fn sentinels(&self) -> (K, K) {
+        (K::from_u64(self.empty_key), K::from_u64(self.tombstone_key))
+    }
+
+    /// Get the bucket of the slot at `index`
+    #[inline]
+    fn bucket(&self, index: usize) -> &Bucket {
+        &self.buckets[index * self.stride]
+    }
+
+    /// Returns true if every slot has a cache line to itself, see
+    /// `AtomicHashMapBuilder::padded`
+    pub fn is_padded(&self) -> bool {
+        self.stride > 1
+    }
+
+    /// Get the number of groups an insert probes past the first one
+    fn probe_limit(&self) -> usize {
+        self.max_probe.map_or(usize::MAX, |slots| slots.div_ceil(GROUP_WIDTH))
+    }
+
+    /// Get the value word of the slot at `index`
+    #[inline]
+    pub(crate) fn value_word(&self, index: usize) -> &AtomicU64 {
+        &self.bucket(index).value
+    }
+
+    /// Make inserts give up after probing `slots` slots, see
+    /// `AtomicHashMapBuilder::max_probe`
+    pub(crate) fn limit_probe(&mut self, slots: usize) {
+        self.max_probe = Some(slots);
+    }
+
+    /// Read the entry stored in the slot at `index`, if any
+    #[cfg(feature = "rayon")]
+    pub(crate) fn entry_at(&self, index: usize) -> Option<(K, V)> {
+        let key = self.bucket(index).key.load(Ordering::Acquire);
+        if !self.is_live(key) || !probe::is_published(self, index) {
+            return None;
+        }
+
+        Some((K::from_u64(key), self.read_value(index, key)?))
+    }
+
+    /// Read the value of the published slot at `index` holding `key`. Returns `None`
+    /// if the entry was removed while reading it, since the value read may then be the
+    /// reset done by the removal rather than a value that was ever stored.
+    pub(crate) fn read_value(&self, index: usize, key: u64) -> Option<V> {
+        let value = self.bucket(index).value.load(self.ordering.load());
+
+        // A removal unpublishes the slot before resetting its value, and releases the
+        // key after
+        if !probe::is_published(self, index)
+                || self.bucket(index).key.load(Ordering::Acquire) != key {
+            return None;
+        }
+
+        Some(V::from_u64(value))
+    }
+
+}
+
+impl<K: PodU64, V: PodU64, S> Table for AtomicHashMap<K, V, S> {
+    type Key = AtomicU64;
+    type State = AtomicU64;
+    type Probe<'a> = TableProbe<'a> where Self: 'a;
+
+    #[inline]
+    fn key_word(&self, index: usize) -> &AtomicU64 {
+        &self.bucket(index).key
+    }
+
+    #[inline]
+    fn state_word(&self, index: usize) -> &AtomicU64 {
+        &self.bucket(index).state
+    }
+
+    fn raw_sentinels(&self) -> (u64, u64) {
+        (self.empty_key, self.tombstone_key)
+    }
+
+    fn probe_slots(&self, hash: u64, all: bool) -> TableProbe<'_> {
+        if all {
+            return TableProbe {
+                slots: self.ctrl.probe_all(hash, self.probe, self.probe_limit()),
+                steps: None
+            };
+        }
+
+        // Slots whose tag shows they hold another key are skipped by the probe
+        TableProbe {
+            slots: self.ctrl.probe(hash, control::tag(hash), self.probe,
+                                   self.probe_limit()),
+            steps: Some(self.counters.probe())
+        }
+    }
+
+    fn claimed(&self, index: usize, hash: u64) {
+        self.ctrl.set(index, control::tag(hash));
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn released(&self, index: usize) {
+        self.ctrl.free(index);
+        self.bucket(index).referenced.store(false, Ordering::Relaxed);
+        self.count.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn cas_failure(&self) {
+        self.counters.cas_failure();
+    }
+}
+
+/// Slots visited by a probe of the claim protocol, counted in the metrics of the map
+/// unless the probe visits every slot
+pub(crate) struct TableProbe<'a> {
+    slots: control::Probe<'a>,
+    steps: Option<crate::metrics::Probe<'a>>
+}
+
+impl<'a> Iterator for TableProbe<'a> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        let index = self.slots.next()?;
+        if let Some(steps) = &mut self.steps {
+            steps.step();
+        }
+
+        Some(index)
+    }
+}
+
+impl<K: PodU64, V: PodU64, S: BuildHasher> AtomicHashMap<K, V, S> {
+    /// Construct a new AtomicHashMap with a given size, hashing keys with `hasher`.
+    /// NOTE: Size must be a power of two, otherwise `InvalidCapacity` is returned.
+    pub fn with_hasher(size: usize, hasher: S) 
+            -> Result<AtomicHashMap<K, V, S>, AtomicHashMapError> {
+        AtomicHashMap::with_stride(size, hasher, EMPTY_KEY, TOMBSTONE_KEY, 1,
+                                   Placement::default())
+    }
+
+    /// Construct a new AtomicHashMap with a given size, hashing keys with `hasher` and
+    /// marking empty and removed slots with `empty_key` and `tombstone_key`.
+    /// NOTE: Size must be a power of two, otherwise `InvalidCapacity` is returned.
+    pub fn with_hasher_and_sentinels(size: usize, hasher: S, empty_key: K, tombstone_key: K)
+            -> Result<AtomicHashMap<K, V, S>, AtomicHashMapError> {
+        AtomicHashMap::with_stride(size, hasher, empty_key.to_u64(),
+                                   tombstone_key.to_u64(), 1, Placement::default())
+    }
+
+    /// Construct a new AtomicHashMap storing each slot `stride` buckets apart, with
+    /// its tables allocated according to `placement`, marking empty and removed slots
+    /// with the raw keys `empty_key` and `tombstone_key`
+    fn with_stride(size: usize, hasher: S, empty_key: u64, tombstone_key: u64,
+                   stride: usize, placement: Placement)
+            -> Result<AtomicHashMap<K, V, S>, AtomicHashMapError> {
+        if empty_key == tombstone_key {
+            return Err(AtomicHashMapError::InvalidKey);
+        }
+
         let mut good_size = false;
         for i in 1..64 {
             if size == 1 << i {
@@ -40,171 +462,3243 @@ impl AtomicHashMap {
         } 
         
         if !good_size {
-            panic!("Size of AtomicHashMap must be a power of two");
+            return Err(AtomicHashMapError::InvalidCapacity);
         }
 
-        let mut keys = Vec::with_capacity(size);
-        for _ in 0..size {
-            keys.push(AtomicU64::new(0));
-        }
+        Ok(AtomicHashMap {
+            buckets: Bucket::new_table(size * stride, empty_key, placement),
+            size,
+            stride,
+            ctrl: ControlBytes::new(size, placement),
+            requested_size: size,
+            count: AtomicU64::new(0),
+            empty_key,
+            tombstone_key,
+            hasher,
+            ordering: OrderingProfile::default(),
+            probe: ProbeStrategy::default(),
+            max_probe: None,
+            placement,
+            eviction: None,
+            counters: Counters::default(),
+            _types: PhantomData
+        })
+    }
 
-        let mut values = Vec::with_capacity(size);
-        for _ in 0..size {
-            values.push(AtomicU64::new(0));
-        }
+    pub fn with_capacity_and_hasher(size: usize, hasher: S) 
+            -> Result<AtomicHashMap<K, V, S>, AtomicHashMapError> {
+        AtomicHashMap::with_hasher(size, hasher)
+    }
+
+    /// Get the hasher used to hash keys
+    pub fn hasher(&self) -> &S {
+        &self.hasher
+    }
+
+    /// Get the memory orderings used for values
+    pub fn ordering_profile(&self) -> OrderingProfile {
+        self.ordering
+    }
+
+    /// Get the order in which probes visit groups of slots
+    pub fn probe_strategy(&self) -> ProbeStrategy {
+        self.probe
+    }
+
+    /// Get the number of slots an insert probes before returning
+    /// `AtomicHashMapError::Full`, if limited. See `AtomicHashMapBuilder::max_probe`.
+    pub fn max_probe(&self) -> Option<usize> {
+        self.max_probe
+    }
 
-        AtomicHashMap {
-            keys: keys.into_boxed_slice(),
-            values: values.into_boxed_slice(),
-            size 
+    /// Get the raw slot representation of `key`, which must not be a sentinel
+    pub(crate) fn raw_key(&self, key: K) -> Result<u64, AtomicHashMapError> {
+        let key = key.to_u64();
+        if !self.is_live(key) {
+            return Err(AtomicHashMapError::InvalidKey);
         }
+
+        Ok(key)
     }
 
-    pub fn with_capacity(size: usize) -> AtomicHashMap {
-        AtomicHashMap::new(size)
+    /// Hash the raw `key`. The low bits give the index of the first slot to probe and
+    /// the high bits its control byte tag.
+    pub(crate) fn hash(&self, key: u64) -> u64 {
+        let mut hasher = self.hasher.build_hasher();
+        hasher.write_u64(key);
+        hasher.finish()
     }
 
     /// Atomically set a key:value in the hashmap
     ///
-    /// The search for an empty slot or the valid key is linear in the array of keys.
+    /// The search for an empty slot or the valid key is linear in the array of buckets.
     /// For efficiency, the start of the search is pseudo random based on the key
-    /// and the MurmurHash3 hashing function.
-    pub fn insert(&self, key: u64, new_value: u64) -> Result<(), AtomicHashMapError> {
-        assert!(key != 0, "AtomicHashMap cannot have a key with value 0");
-        // Get a hash of the key
-        let start_index = hash_key(key) as usize;
+    /// and the MurmurHash3 hashing function, and slots are checked a group of 16 at a
+    /// time against the control byte tag of the key. Groups are visited in the order
+    /// given by the `ProbeStrategy` of the map.
+    ///
+    /// If the key is not already present, the first tombstone found along the probe
+    /// is reused before falling back to the empty slot that ended the probe.
+    ///
+    /// Returns the value previously stored for this key, swapped out atomically with
+    /// the new value, or `None` if the key was newly inserted. A new key becomes
+    /// visible to other threads together with its value.
+    pub fn insert(&self, key: K, new_value: V) 
+            -> Result<Option<V>, AtomicHashMapError> {
+        self.insert_within(key, new_value, None)
+    }
 
-        // Start somewhere in the middle of the values based on the hash of the key
-        for index in start_index..(start_index+self.size) {
-            // Since the total capacity is a power of two,`subtract 1 | and` gives us 
-            // an easy modulo of the total capacity
-            let index = index & (self.size - 1);
+    /// Atomically set a key:value in the hashmap like `insert`, without ever waiting
+    /// on another thread
+    ///
+    /// Returns `AtomicHashMapError::Contended` if the key is claimed by another thread
+    /// that hasn't published its value yet, or after losing `TRY_RETRIES` races for a
+    /// slot.
+    pub fn try_insert(&self, key: K, new_value: V)
+            -> Result<Option<V>, AtomicHashMapError> {
+        self.insert_within(key, new_value, Some(TRY_RETRIES))
+    }
 
-            let curr_key = self.keys[index].load(Ordering::Acquire);
-            if curr_key != key {
-                if curr_key != 0 {
-                    // This key is already taken.. continue
-                    continue;
-                }
+    /// Insert `new_value` for `key`, retrying at most `retries` times if bounded
+    fn insert_within(&self, key: K, new_value: V, retries: Option<usize>)
+            -> Result<Option<V>, AtomicHashMapError> {
+        let key = self.raw_key(key)?;
 
-                let prev_key = self.keys[index].compare_and_swap(0, key, Ordering::AcqRel);
-                if prev_key != 0 && prev_key != key {
-                    // This key was stored out from under us, can't store there now.. 
-                    continue
-                }
+        // Either successfuly found an empty slot, or successfully found the slot
+        // previously storing this key.. 
+        match self.claim_slot_within(key, retries)? {
+            Slot::Found(index) => {
+                let prev_value = self.bucket(index).value.swap(new_value.to_u64(), 
+                                                                self.ordering.rmw());
+                Ok(Some(V::from_u64(prev_value)))
+            }
+            Slot::Claimed(index) => {
+                self.bucket(index).value.store(new_value.to_u64(), self.ordering.store());
+                probe::publish(self, index);
+                Ok(None)
             }
-            
-            // Either successfuly found an empty slot, or successfully found the slot
-            // previously storing this key.. 
-            self.values[index].store(new_value, Ordering::Release);
-            return Ok(())
         }
+    }
 
-        return Err(AtomicHashMapError::Full);
+    /// Find the published slot holding `key`, claiming a tombstone or an empty slot
+    /// for it if it isn't in the table yet. A claimed slot must be published by the
+    /// caller once its value is written.
+    ///
+    /// If there is no room for the key and the map has an eviction policy, an entry
+    /// along its probe is evicted to make room.
+    fn claim_slot(&self, key: u64) -> Result<Slot, AtomicHashMapError> {
+        self.claim_slot_within(key, None)
     }
 
-    /// Atomically get a value from the hashmap
-    pub fn get(&self, key: &u64) -> Option<u64> {
-        assert!(*key != 0, "AtomicHashMap cannot have a key with value 0");
-        // Get a hash of the key
-        let start_index = hash_key(*key) as usize;
+    /// Same as `claim_slot`, but with a bound of `retries` races lost, which also
+    /// keeps it from waiting on a found slot to be published
+    fn claim_slot_within(&self, key: u64, mut retries: Option<usize>)
+            -> Result<Slot, AtomicHashMapError> {
+        let mut backoff = Backoff::new();
+        loop {
+            match probe::probe_slot(self, key, self.hash(key), &mut retries) {
+                Ok(Slot::Found(index)) if retries.is_some() => {
+                    let published = probe::is_published(self, index);
+                    if self.bucket(index).key.load(Ordering::Acquire) == key {
+                        if !published {
+                            return Err(AtomicHashMapError::Contended);
+                        }
+                        return Ok(Slot::Found(index));
+                    }
+
+                    // The key was removed after we found it, look for it again
+                }
+                // The key was removed while we waited for it, look for it again
+                Ok(Slot::Found(index)) if !probe::wait_published(self, index, key) => {}
+                Err(AtomicHashMapError::Full) if self.evict(key) => {}
+                Err(AtomicHashMapError::Full) => {
+                    self.counters.full_error();
+                    return Err(AtomicHashMapError::Full);
+                }
+                slot => return slot
+            }
 
-        // Start somewhere in the middle of the values based on the hash of the key
-        for index in start_index..(start_index+self.size) {
-            // Since the total capacity is a power of two,`subtract 1 | and` gives us 
-            // an easy modulo of the total capacity
-            let index = index & (self.size - 1);
+            retry(&mut retries)?;
+            backoff.spin();
+        }
+    }
 
-            if self.keys[index].load(Ordering::Acquire) != *key {
-                // Didn't find the wanted key at this index.. continue
+    /// Evict the entry chosen by the eviction policy of the map among the first live
+    /// entries along the probe of `key`. Returns false if there is no policy or no
+    /// entry to evict.
+    fn evict(&self, key: u64) -> bool {
+        let policy = match &self.eviction {
+            Some(policy) => policy,
+            None => return false
+        };
+
+        let mut slots = Vec::with_capacity(EVICTION_CANDIDATES);
+        let mut candidates = Vec::with_capacity(EVICTION_CANDIDATES);
+        for index in self.ctrl.probe_all(self.hash(key), self.probe, self.probe_limit()) {
+            let curr_key = self.bucket(index).key.load(Ordering::Acquire);
+            if !self.is_live(curr_key)
+                    || !probe::is_published(self, index) {
                 continue;
             }
 
-            let res = self.values[index].load(Ordering::Acquire);
+            let value = self.bucket(index).value.load(self.ordering.load());
+            slots.push((index, curr_key, value));
+            candidates.push((K::from_u64(curr_key), V::from_u64(value)));
+            if slots.len() == EVICTION_CANDIDATES {
+                break;
+            }
+        }
 
-            // Found the correct index for this key, return the value
-            return Some(res);
+        if slots.is_empty() {
+            // Every slot within reach is still being inserted
+            return false;
         }
 
-        return None;
+        // An entry that changed since it was read is left alone, and the insert simply
+        // probes again
+        let choice = policy.choose(&candidates).min(slots.len() - 1);
+        let (index, curr_key, value) = slots[choice];
+        let _ = self.remove_if(index, curr_key, value);
+        true
     }
 
-    /// Get the number of elements currently in the hashtable
-    pub fn len(&self) -> u64 {
-        let mut count = 0;
+    /// Atomically get a value from the hashmap
+    ///
+    /// A key equal to one of the sentinels can never be stored, so lookups and removals
+    /// of one simply find nothing.
+    pub fn get(&self, key: &K) -> Option<V> {
+        let key = self.raw_key(*key).ok()?;
 
-        // Start somewhere in the middle of the values based on the hash of the key
-        for index in 0..self.size {
-            let key = self.keys[index].load(Ordering::Relaxed);
-            let value = self.values[index].load(Ordering::Relaxed);
-            if key != 0 && value != 0 {
-                count += 1;
+        let index = self.find_published(key)?;
+
+        // Found the correct index for this key, return the value
+        self.read_value(index, key)
+    }
+
+    /// Atomically get the stored key and its value from the hashmap
+    ///
+    /// The stored key is rebuilt from the raw `u64` in its slot, which for the `PodU64`
+    /// types of the crate is always equal to `key`.
+    pub fn get_key_value(&self, key: &K) -> Option<(K, V)> {
+        let key = self.raw_key(*key).ok()?;
+
+        let index = self.find_published(key)?;
+        let value = self.read_value(index, key)?;
+        Some((K::from_u64(key), value))
+    }
+
+    /// Find the slot of `key` once to then operate on its value without probing again
+    ///
+    /// Returns `None` if the key isn't in the hashmap.
+    pub fn slot(&self, key: &K) -> Option<SlotRef<'_, K, V, S>> {
+        let key = self.raw_key(*key).ok()?;
+
+        let index = self.find_published(key)?;
+        Some(SlotRef { map: self, index, key })
+    }
+
+    /// Check if a key is in the hashmap. Only the key array is probed, the value is
+    /// never loaded.
+    pub fn contains_key(&self, key: &K) -> bool {
+        match self.raw_key(*key) {
+            Ok(key) => self.find_published(key).is_some(),
+            Err(_) => false
+        }
+    }
+
+    /// Get the values of many keys at once, in the order of `keys`
+    ///
+    /// Keys are looked up in the order of their first slot, prefetching the slots of
+    /// the keys a few places ahead, so that the cache misses of a large batch overlap
+    /// instead of being paid one after the other.
+    pub fn get_batch(&self, keys: &[K]) -> Vec<Option<V>> {
+        let mut values = alloc::vec![None; keys.len()];
+        self.for_each_in_slot_order(keys.iter().copied(), |pos| {
+            values[pos] = self.get(&keys[pos]);
+        });
+
+        values
+    }
+
+    /// Atomically set many key:value pairs at once, returning what `insert` returns for
+    /// each of them in the order of `items`
+    ///
+    /// Items are inserted in the order of their first slot, prefetching the slots of
+    /// the items a few places ahead, see `get_batch`. Items with the same key are
+    /// still inserted in the order they are given, so the last one wins.
+    pub fn insert_batch(&self, items: &[(K, V)])
+            -> Vec<Result<Option<V>, AtomicHashMapError>> {
+        let mut results: Vec<_> = items.iter().map(|_| Ok(None)).collect();
+        self.for_each_in_slot_order(items.iter().map(|&(key, _)| key), |pos| {
+            let (key, value) = items[pos];
+            results[pos] = self.insert(key, value);
+        });
+
+        results
+    }
+
+    /// Call `f` with the position of each of `keys`, sorted by the index of their
+    /// first slot and then by position, prefetching the first slot of the key
+    /// `PREFETCH_DISTANCE` places ahead
+    fn for_each_in_slot_order<I, F>(&self, keys: I, mut f: F)
+            where I: Iterator<Item = K>, F: FnMut(usize) {
+        let mask = self.size as u64 - 1;
+        let mut order: Vec<(u64, usize)> = keys.map(|key| self.hash(key.to_u64()))
+            .enumerate()
+            .map(|(pos, hash)| (hash, pos))
+            .collect();
+        order.sort_unstable_by_key(|&(hash, pos)| (hash & mask, pos));
+
+        for (i, &(_, pos)) in order.iter().enumerate() {
+            if let Some(&(hash, _)) = order.get(i + PREFETCH_DISTANCE) {
+                self.prefetch(hash);
             }
+
+            f(pos);
         }
+    }
 
-        count
+    /// Hint that the first slot of a probe for `hash`, and its control bytes, are
+    /// about to be read
+    #[inline]
+    fn prefetch(&self, hash: u64) {
+        self.ctrl.prefetch(hash);
+        control::prefetch(self.bucket(hash as usize & (self.size - 1)));
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Get the value for `key`, inserting `default` if the key isn't in the hashmap
+    ///
+    /// The slot is reserved atomically, so when many threads race on the same key
+    /// only the thread that claims the slot stores its value.
+    pub fn get_or_insert(&self, key: K, default: V) -> Result<V, AtomicHashMapError> {
+        self.get_or_insert_with(key, || default)
+    }
 
-    #[test]
-    fn test_blanket_insert_get() {
-        let size: u64 = 1 << 10;
-        let hashtable = AtomicHashMap::new(size as usize);
+    /// Get the value for `key`, inserting the result of `init` if the key isn't in the
+    /// hashmap
+    ///
+    /// `init` is only called by the thread that claims the slot for this key, so it
+    /// runs at most once per key no matter how many threads race on it. Other threads
+    /// inserting the key while `init` runs wait for its value, while `get` keeps
+    /// reporting the key as missing. If `init` panics, the key is left in the map
+    /// with a value of 0.
+    pub fn get_or_insert_with<F>(&self, key: K, init: F) -> Result<V, AtomicHashMapError>
+            where F: FnOnce() -> V {
+        let key = self.raw_key(key)?;
 
-        for x in 1..=size {
-            // Don't care about the Full case in the test
-            let _ = hashtable.insert(x, x); 
+        loop {
+            match self.claim_slot(key)? {
+                Slot::Found(index) => {
+                    // The key may be removed between finding it and reading its value,
+                    // in which case the value read is the reset done by the removal
+                    if let Some(value) = self.read_value(index, key) {
+                        return Ok(value);
+                    }
+                }
+                Slot::Claimed(index) => {
+                    let _publish = Publish(self, index);
+                    let value = init();
+                    self.bucket(index).value.store(value.to_u64(), self.ordering.store());
+                    return Ok(value);
+                }
+            }
         }
+    }
 
-        for x in 1..=size {
-            assert_eq!(hashtable.get(&x).unwrap_or(0xffffffff), x);
+    /// Claim `key` without a value yet, so that the value can be computed by exactly
+    /// one thread and filled in later with `Reservation::fill`
+    ///
+    /// While the reservation is pending, `get` reports the key as missing and other
+    /// threads inserting or reserving it wait for the reservation to be filled or
+    /// aborted. Dropping the reservation without filling it aborts it.
+    ///
+    /// Returns `None` if the key already has a value.
+    pub fn reserve(&self, key: K)
+            -> Result<Option<Reservation<'_, K, V, S>>, AtomicHashMapError> {
+        let key = self.raw_key(key)?;
+
+        match self.claim_slot(key)? {
+            Slot::Found(_) => Ok(None),
+            Slot::Claimed(index) => Ok(Some(Reservation { map: self, index, key }))
         }
     }
 
-    #[test]
-    fn test_threads() {
-        use std::thread;
-        use std::sync::Arc;
+    /// Atomically replace the value for an existing `key` with `new` if it currently
+    /// equals `expected`
+    ///
+    /// Mirrors `AtomicU64::compare_exchange`: the inner result is `Ok` with the
+    /// previous value if the swap happened, or `Err` with the current value if it
+    /// didn't. Returns `None` if the key isn't in the hashmap. Values are compared by
+    /// their `PodU64` representation, so e.g. `-0.0` and `0.0` are not equal.
+    pub fn update_if_eq(&self, key: K, expected: V, new: V) -> Option<Result<V, V>> {
+        let key = self.raw_key(key).ok()?;
 
-        let size: u64 = 1 << 12;
-        let hashtable = Arc::new(AtomicHashMap::new(size as usize));
+        let index = self.find_published(key)?;
+        Some(self.bucket(index).value.compare_exchange(expected.to_u64(), new.to_u64(),
+                                                        self.ordering.rmw(),
+                                                        self.ordering.load())
+             .map(V::from_u64)
+             .map_err(V::from_u64))
+    }
 
-        let mut threads = Vec::new();
-        for i in 0..10 {
-            let hashtable_i = hashtable.clone();
-            let t = thread::spawn(move || {
-                for x in 1..=size {
-                    // Don't care about the FULL error in this test
-                    let _ = hashtable_i.insert(x, i * x); 
+    /// Atomically update the value for an existing `key` with the result of `f`
+    ///
+    /// `f` is given the current value and returns the new one. The update is done in a
+    /// compare-exchange loop, so `f` may be called more than once if other threads
+    /// modify the value concurrently and should be free of side effects.
+    ///
+    /// Returns the value `f` was successfully applied to, or `None` if the key isn't
+    /// in the hashmap.
+    pub fn update<F>(&self, key: K, mut f: F) -> Option<V>
+            where F: FnMut(V) -> V {
+        let key = self.raw_key(key).ok()?;
+
+        let index = self.find_published(key)?;
+
+        let mut curr_value = self.bucket(index).value.load(self.ordering.load());
+        loop {
+            let new_value = f(V::from_u64(curr_value)).to_u64();
+            match self.bucket(index).value.compare_exchange_weak(curr_value, new_value,
+                                                                  self.ordering.rmw(),
+                                                                  self.ordering.load()) {
+                Ok(prev_value) => return Some(V::from_u64(prev_value)),
+                // Value changed out from under us, try again with the new value
+                Err(prev_value) => {
+                    self.counters.cas_failure();
+                    curr_value = prev_value;
                 }
-            });
-            threads.push(t);
+            }
         }
+    }
 
-        for t in threads {
-            let _ = t.join();
+    /// Atomically update the value for an existing `key` with the result of `f` like
+    /// `update`, but give up after `TRY_RETRIES` failed compare-exchanges
+    ///
+    /// Returns the value `f` was successfully applied to, `None` if the key isn't in
+    /// the hashmap, or `AtomicHashMapError::Contended` if other threads kept changing
+    /// the value.
+    pub fn try_update<F>(&self, key: K, mut f: F) -> Result<Option<V>, AtomicHashMapError>
+            where F: FnMut(V) -> V {
+        let key = match self.raw_key(key) {
+            Ok(key) => key,
+            Err(_) => return Ok(None)
+        };
+
+        let index = match self.find_published(key) {
+            Some(index) => index,
+            None => return Ok(None)
+        };
+
+        let mut retries = Some(TRY_RETRIES);
+        let mut curr_value = self.bucket(index).value.load(self.ordering.load());
+        loop {
+            let new_value = f(V::from_u64(curr_value)).to_u64();
+            // Strong CAS so that only real races count against the retries
+            match self.bucket(index).value.compare_exchange(curr_value, new_value,
+                                                             self.ordering.rmw(),
+                                                             self.ordering.load()) {
+                Ok(prev_value) => return Ok(Some(V::from_u64(prev_value))),
+                Err(prev_value) => {
+                    self.counters.cas_failure();
+                    curr_value = prev_value;
+                }
+            }
+
+            retry(&mut retries)?;
         }
     }
 
-    #[test]
-    fn test_full() {
-        let size: u64 = 1 << 4;
-        let hashtable = AtomicHashMap::new(size as usize);
+    /// Atomically replace the value of `key` with `new` if the key is in the hashmap,
+    /// without inserting it otherwise
+    ///
+    /// Returns the value that was replaced, or `None` if the key isn't in the hashmap.
+    /// Of many threads swapping the same key, each gets back the value swapped in
+    /// just before its own, so e.g. exactly one of them sees the value before a
+    /// "claimed" marker was first swapped in.
+    pub fn swap(&self, key: K, new: V) -> Option<V> {
+        let key = self.raw_key(key).ok()?;
 
-        // Insert one element and ensure it inserted fine
-        assert_eq!(hashtable.insert(10000, 10), Ok(()));
+        let index = self.find_published(key)?;
+        let prev_value = self.bucket(index).value.swap(new.to_u64(), self.ordering.rmw());
+        Some(V::from_u64(prev_value))
+    }
 
-        // Fill the remaining slots and ensure they were inserted fine
-        for x in 1..=(size-1) {
-            // Don't care about the Full case in the test
-            assert_eq!(hashtable.insert(x, x), Ok(()));
+    /// Atomically insert `new` for `key`, or merge it into the current value with
+    /// `merge(old, new)` if the key is already in the hashmap
+    ///
+    /// Concurrent upserts of the same key combine their values instead of the last
+    /// writer overwriting the others, e.g. with a sum, a max or a bitwise or. The
+    /// merge is done in a compare-exchange loop, so `merge` may be called more than
+    /// once and should be free of side effects.
+    ///
+    /// Returns the value `new` was merged into, or `None` if the key was newly
+    /// inserted.
+    pub fn upsert<F>(&self, key: K, new: V, mut merge: F)
+            -> Result<Option<V>, AtomicHashMapError>
+            where F: FnMut(V, V) -> V {
+        self.upsert_with(key, new, |old| Some(merge(old, new)))
+    }
+
+    /// Insert `key` with `init`, or update its value with `f` in a compare-exchange
+    /// loop over the raw value. `f` returns `None` to leave the value as is.
+    ///
+    /// Returns the value `f` was applied to, or `None` if the key was newly inserted.
+    fn upsert_with<F>(&self, key: K, init: V, mut f: F)
+            -> Result<Option<V>, AtomicHashMapError>
+            where F: FnMut(V) -> Option<V> {
+        let key = self.raw_key(key)?;
+
+        let index = match self.claim_slot(key)? {
+            Slot::Found(index) => index,
+            Slot::Claimed(index) => {
+                self.bucket(index).value.store(init.to_u64(), self.ordering.store());
+                probe::publish(self, index);
+                return Ok(None);
+            }
+        };
+
+        let value = &self.bucket(index).value;
+        let mut curr_value = value.load(self.ordering.load());
+        loop {
+            let new_value = match f(V::from_u64(curr_value)) {
+                Some(new_value) => new_value.to_u64(),
+                None => return Ok(Some(V::from_u64(curr_value)))
+            };
+
+            match value.compare_exchange_weak(curr_value, new_value, self.ordering.rmw(),
+                                              self.ordering.load()) {
+                Ok(prev_value) => return Ok(Some(V::from_u64(prev_value))),
+                // Value changed out from under us, try again with the new value
+                Err(prev_value) => {
+                    self.counters.cas_failure();
+                    curr_value = prev_value;
+                }
+            }
         }
+    }
 
-        // Ensure if we insert one more element that we are full
-        assert_eq!(hashtable.insert(20000, 10), Err(AtomicHashMapError::Full));
+    /// Atomically remove a key from the hashmap, returning its value if it was present
+    ///
+    /// The slot is marked with a tombstone rather than emptied so that probes for 
+    /// keys stored past it still find them. Tombstoned slots are reused by `insert`.
+    pub fn remove(&self, key: K) -> Option<V> {
+        let key = self.raw_key(key).ok()?;
+
+        let index = self.find_slot(key)?;
+        if !probe::unpublish(self, index, key) {
+            // Another thread removed this key first
+            return None;
+        }
+
+        // Take the value before releasing the key so that a new key claiming the 
+        // tombstone never has its value taken by us
+        let value = self.bucket(index).value.swap(0, self.ordering.rmw());
+        probe::release(self, index, key);
+        Some(V::from_u64(value))
+    }
+
+    /// Atomically remove a key from the hashmap and return its value, in the same single
+    /// probe as `remove`
+    pub fn take(&self, key: K) -> Option<V> {
+        self.remove(key)
+    }
+
+    /// Remove every entry for which `f(key, value)` returns false
+    ///
+    /// Each failing entry is only removed if its value is still the one `f` was called
+    /// with. If another thread changes the value in the meantime, `f` is called again
+    /// with the new value. Keys inserted concurrently may or may not be visited.
+    pub fn retain<F>(&self, mut f: F) where F: FnMut(K, V) -> bool {
+        for index in 0..self.size {
+            let key = self.bucket(index).key.load(Ordering::Acquire);
+            if !self.is_live(key) {
+                continue;
+            }
+
+            if !probe::is_published(self, index) {
+                continue;
+            }
+
+            let mut value = self.bucket(index).value.load(self.ordering.load());
+            loop {
+                // The key was removed out from under us
+                if self.bucket(index).key.load(Ordering::Acquire) != key {
+                    break;
+                }
+
+                if f(K::from_u64(key), V::from_u64(value)) {
+                    break;
+                }
+
+                match self.remove_if(index, key, value) {
+                    Ok(_) => break,
+                    Err(new_value) => value = new_value
+                }
+            }
+        }
+    }
+
+    /// Get the value of `key` and set the CLOCK bit of its slot, see `AtomicLruCache`
+    pub(crate) fn get_referenced(&self, key: &K) -> Option<V> {
+        let key = self.raw_key(*key).ok()?;
+
+        let index = self.find_published(key)?;
+        let value = self.read_value(index, key)?;
+
+        // Only write the bit when it changes, so that hot entries read by many
+        // threads don't keep invalidating each other's copy of the line
+        let referenced = &self.bucket(index).referenced;
+        if !referenced.load(Ordering::Relaxed) {
+            referenced.store(true, Ordering::Relaxed);
+        }
+
+        Some(value)
+    }
+
+    /// Evict the first entry along the probe of `key` whose CLOCK bit isn't set. If
+    /// the first `EVICTION_CANDIDATES` live entries are all referenced, their bits are
+    /// cleared and the first of them is evicted. Returns false if there is no entry to
+    /// evict.
+    pub(crate) fn evict_unreferenced(&self, key: K) -> bool {
+        let key = match self.raw_key(key) {
+            Ok(key) => key,
+            Err(_) => return false
+        };
+
+        let mut referenced = [0; EVICTION_CANDIDATES];
+        let mut seen = 0;
+        let mut first = None;
+        for index in self.ctrl.probe_all(self.hash(key), self.probe, self.probe_limit()) {
+            let curr_key = self.bucket(index).key.load(Ordering::Acquire);
+            if !self.is_live(curr_key)
+                    || !probe::is_published(self, index) {
+                continue;
+            }
+
+            let value = self.bucket(index).value.load(self.ordering.load());
+            if !self.bucket(index).referenced.load(Ordering::Relaxed) {
+                // An entry that changed since it was read is left alone, and the insert
+                // simply probes again
+                let _ = self.remove_if(index, curr_key, value);
+                return true;
+            }
+
+            first.get_or_insert((index, curr_key, value));
+            referenced[seen] = index;
+            seen += 1;
+            if seen == EVICTION_CANDIDATES {
+                break;
+            }
+        }
+
+        let (index, curr_key, value) = match first {
+            Some(first) => first,
+            // Every slot within reach is still being inserted
+            None => return false
+        };
+
+        // Every candidate was used since the last time this neighbourhood filled up,
+        // start their second chance over
+        for &index in &referenced[..seen] {
+            self.bucket(index).referenced.store(false, Ordering::Relaxed);
+        }
+
+        let _ = self.remove_if(index, curr_key, value);
+        true
+    }
+
+    /// Remove `key` from the slot at `index` if its raw value is still `value`
+    ///
+    /// Returns whether the key was removed, false meaning another thread removed it
+    /// first, or the current value if it changed.
+    fn remove_if(&self, index: usize, key: u64, value: u64) -> Result<bool, u64> {
+        // Take the value we checked before releasing the key, same as `remove`. Strong
+        // CAS so a spurious failure doesn't read as the value having changed
+        let taken = probe::unpublish_if(self, index, key, || {
+            self.bucket(index).value
+                .compare_exchange(value, 0, self.ordering.rmw(), self.ordering.load())
+                .map(|_| ())
+                .inspect_err(|_| self.counters.cas_failure())
+        })?;
+
+        if taken {
+            probe::release(self, index, key);
+        }
+
+        Ok(taken)
+    }
+
+    /// Find the slot holding `key`, if its value has been published
+    fn find_published(&self, key: u64) -> Option<usize> {
+        let index = self.find_slot(key)?;
+        if !probe::is_published(self, index) {
+            // Still being inserted
+            return None;
+        }
+
+        Some(index)
+    }
+
+    /// Find the slot currently holding `key`
+    fn find_slot(&self, key: u64) -> Option<usize> {
+        // Get a hash of the key
+        let hash = self.hash(key);
+
+        // Start somewhere in the middle of the values based on the hash of the key,
+        // only checking the slots whose tag doesn't rule the key out
+        // Keys are never stored past the probe limit, but a lookup still stops at the
+        // first empty slot so no limit is needed
+        let mut steps = self.counters.probe();
+        for index in self.ctrl.probe(hash, control::tag(hash), self.probe, usize::MAX) {
+            steps.step();
+            let curr_key = self.bucket(index).key.load(Ordering::Acquire);
+            if curr_key == key {
+                return Some(index);
+            }
+
+            if curr_key == self.empty_key {
+                // Keys are never stored past an empty slot, so the key isn't here
+                return None;
+            }
+
+            // Either another key or a tombstone at this index.. continue
+        }
+
+        None
+    }
+
+    /// Find the slot currently holding `key`, whose hash is `hash`, prefetching each
+    /// slot of the probe while the one before it is compared
+    fn find_slot_prefetched(&self, key: u64, hash: u64) -> Option<usize> {
+        let mut probe = self.ctrl.probe(hash, control::tag(hash), self.probe, usize::MAX)
+            .peekable();
+
+        let mut steps = self.counters.probe();
+        while let Some(index) = probe.next() {
+            steps.step();
+            if let Some(&next) = probe.peek() {
+                control::prefetch(self.bucket(next));
+            }
+
+            let curr_key = self.bucket(index).key.load(Ordering::Acquire);
+            if curr_key == key {
+                return Some(index);
+            }
+
+            if curr_key == self.empty_key {
+                return None;
+            }
+        }
+
+        None
+    }
+
+    /// Get the value of the raw `key`, whose hash is `hash` and whose first slot has
+    /// already been prefetched
+    fn get_prefetched_hashed(&self, key: u64, hash: u64) -> Option<V> {
+        let index = self.find_slot_prefetched(key, hash)?;
+        if !probe::is_published(self, index) {
+            // Still being inserted
+            return None;
+        }
+
+        self.read_value(index, key)
+    }
+
+    /// Atomically get a value from the hashmap, prefetching each slot of the probe
+    /// while the one before it is compared
+    ///
+    /// Each slot holds its key and value on the same cache line, so this overlaps the
+    /// cache misses along a probe. It pays off for random lookups in tables much
+    /// larger than the caches, while in small or hot tables the hints only cost time.
+    pub fn get_prefetched(&self, key: &K) -> Option<V> {
+        let key = self.raw_key(*key).ok()?;
+        let hash = self.hash(key);
+        self.prefetch(hash);
+
+        self.get_prefetched_hashed(key, hash)
+    }
+
+    /// Atomically get the values of `N` keys, prefetching the first slot of every key
+    /// before looking any of them up
+    ///
+    /// The cache misses of all `N` lookups then overlap, without the allocation and
+    /// sort of `get_batch`. Keys equal to a sentinel are never found.
+    pub fn get_many<const N: usize>(&self, keys: [K; N]) -> [Option<V>; N] {
+        let hashes = keys.map(|key| {
+            let hash = self.hash(key.to_u64());
+            self.prefetch(hash);
+            hash
+        });
+
+        core::array::from_fn(|i| {
+            let key = self.raw_key(keys[i]).ok()?;
+            self.get_prefetched_hashed(key, hashes[i])
+        })
+    }
+
+    /// Iterate over the `(key, value)` pairs currently in the hashmap
+    ///
+    /// The iterator walks the slots in table order and is not a snapshot of the whole
+    /// table. Each slot is read on its own: a key is only yielded if it was still in
+    /// its slot after its value was read, but keys inserted or removed concurrently
+    /// may or may not be seen, and a key moved by a remove/insert race can be seen
+    /// twice.
+    pub fn iter(&self) -> Iter<'_, K, V, S> {
+        Iter {
+            map: self,
+            index: 0
+        }
+    }
+
+    /// Iterate over the keys currently in the hashmap, with the same concurrency
+    /// semantics as `iter`
+    pub fn keys(&self) -> Keys<'_, K, V, S> {
+        Keys { inner: self.iter() }
+    }
+
+    /// Iterate over the values currently in the hashmap, with the same concurrency
+    /// semantics as `iter`
+    pub fn values(&self) -> Values<'_, K, V, S> {
+        Values { inner: self.iter() }
+    }
+
+    /// Collect the `(key, value)` pairs currently in the hashmap, in table order
+    pub fn to_vec(&self) -> Vec<(K, V)> {
+        self.iter().collect()
+    }
+
+    /// Copy the live entries into a `HashMap`
+    ///
+    /// This is the intended way to post-process results once worker threads are done,
+    /// at which point the copy is exact. While other threads are still writing, the
+    /// table is read in full passes until two passes in a row agree, giving up after
+    /// `SNAPSHOT_PASSES` passes. A copy taken during a lull in writes is then a
+    /// consistent view of the map, while one taken under constant writes is only as
+    /// good as a single pass of `iter`.
+    #[cfg(feature = "std")]
+    pub fn snapshot(&self) -> std::collections::HashMap<K, V>
+            where K: core::hash::Hash + Eq {
+        let pass = || -> Vec<(u64, u64)> {
+            self.iter().map(|(key, value)| (key.to_u64(), value.to_u64())).collect()
+        };
+
+        let mut entries = pass();
+        for _ in 1..SNAPSHOT_PASSES {
+            let next = pass();
+            if next == entries {
+                break;
+            }
+
+            entries = next;
+        }
+
+        entries.into_iter().map(|(key, value)| (K::from_u64(key), V::from_u64(value)))
+            .collect()
+    }
+
+    /// Empty the hashmap, yielding each `(key, value)` pair as it is taken out
+    ///
+    /// Each entry is taken atomically, so it is yielded at most once even if another
+    /// thread is removing it at the same time. Writes to a value racing with its slot
+    /// being taken may be lost, and keys still being inserted are left in place. If
+    /// the iterator is dropped early, the remaining entries are still taken out.
+    ///
+    /// Like `remove`, every entry taken out leaves a tombstone behind, which inserts
+    /// reuse. Use `clear_mut` or `compact` to reset the slots to empty.
+    pub fn drain(&self) -> Drain<'_, K, V, S> {
+        Drain {
+            map: self,
+            index: 0
+        }
+    }
+
+    /// Get the number of elements currently in the hashtable
+    ///
+    /// The count is maintained as keys are claimed and removed, so this is a single 
+    /// load rather than a scan of the table.
+    pub fn len(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Remove every key from the hashtable, leaving a tombstone in each slot that held
+    /// one
+    ///
+    /// Slots are cleared one at a time. Operations running concurrently with `clear`
+    /// see a partially cleared table, and keys inserted while it runs may or may not
+    /// survive it. Slots are only reset to empty with exclusive access, since another
+    /// thread may be probing past them, so use `clear_mut` to get rid of the
+    /// tombstones as well when it is available.
+    pub fn clear(&self) {
+        for _ in self.drain() {}
+    }
+
+    /// Remove every key from the hashtable. Exclusive access means only relaxed stores
+    /// are needed, making this much faster than `clear`.
+    pub fn clear_mut(&mut self) {
+        let empty_key = self.empty_key;
+        for bucket in self.buckets.iter() {
+            bucket.key.store(empty_key, Ordering::Relaxed);
+            bucket.value.store(0, Ordering::Relaxed);
+            bucket.state.store(0, Ordering::Relaxed);
+            bucket.referenced.store(false, Ordering::Relaxed);
+        }
+
+        self.ctrl.clear_mut();
+
+        self.count.store(0, Ordering::Relaxed);
+    }
+
+    /// Rebuild the hashtable at the smallest power-of-two size that keeps it at most
+    /// half full, rehashing the live entries and dropping every tombstone
+    ///
+    /// The table never grows, so a table that is already more than half full only has
+    /// its tombstones cleared out. With a probe limit, the smallest size at which every
+    /// entry is within the limit is used, and the table is left as is if there is
+    /// none.
+    pub fn compact(&mut self) {
+        let live = self.len() as usize;
+        let mut new_size = round_capacity(live * 2).unwrap_or(self.size).min(self.size);
+
+        loop {
+            if let Some((buckets, ctrl)) = self.rehash(new_size) {
+                self.replace_table(buckets, ctrl, new_size);
+                return;
+            }
+
+            if new_size == self.size {
+                return;
+            }
+
+            new_size *= 2;
+        }
+    }
+
+    /// Swap in a rehashed table of `size` slots. A table that changes size no longer
+    /// has the capacity asked for at construction, so that becomes `size` as well.
+    fn replace_table(&mut self, buckets: Box<[Bucket]>, ctrl: ControlBytes, size: usize) {
+        if size != self.size {
+            self.requested_size = size;
+        }
+
+        self.buckets = buckets;
+        self.ctrl = ctrl;
+        self.size = size;
+    }
+
+    /// Rehash the live entries into a table of at least twice the size, doubling it
+    /// until every entry fits within the probe limit
+    fn grow(&mut self) {
+        let mut new_size = self.size;
+        loop {
+            new_size = new_size.checked_mul(2).expect("AtomicHashMap size overflowed");
+            if let Some((buckets, ctrl)) = self.rehash(new_size) {
+                self.replace_table(buckets, ctrl, new_size);
+                return;
+            }
+        }
+    }
+
+    /// Rehash the live entries into a new table of `size` slots. Returns `None` if an
+    /// entry can't be placed within the probe limit.
+    fn rehash(&self, size: usize) -> Option<(Box<[Bucket]>, ControlBytes)> {
+        let stride = self.stride;
+        let buckets = Bucket::new_table(size * stride, self.empty_key, self.placement);
+        let mut ctrl = ControlBytes::new(size, self.placement);
+
+        // The new table isn't shared yet, so it is filled with relaxed stores
+        for index in 0..self.size {
+            let old_bucket = self.bucket(index);
+            let key = old_bucket.key.load(Ordering::Relaxed);
+            if !self.is_live(key) {
+                continue;
+            }
+
+            // Follow the same probe as an insert, which stops at the first empty slot
+            let hash = self.hash(key);
+            let tag = control::tag(hash);
+            let new_index = ctrl.probe(hash, tag, self.probe, self.probe_limit())
+                .find(|&index| {
+                    buckets[index * stride].key.load(Ordering::Relaxed) == self.empty_key
+                })?;
+
+            let new_bucket = &buckets[new_index * stride];
+            new_bucket.key.store(key, Ordering::Relaxed);
+            new_bucket.value.store(old_bucket.value.load(Ordering::Relaxed), Ordering::Relaxed);
+            new_bucket.state.store(old_bucket.state.load(Ordering::Relaxed),
+                                   Ordering::Relaxed);
+            new_bucket.referenced.store(old_bucket.referenced.load(Ordering::Relaxed),
+                                        Ordering::Relaxed);
+            ctrl.set_mut(new_index, tag);
+        }
+
+        Some((buckets, ctrl))
+    }
+
+    /// Returns true if the hashtable has no elements
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the total number of slots in the hashtable
+    pub fn capacity(&self) -> usize {
+        self.size
+    }
+
+    /// Get the capacity asked for when the hashtable was constructed. This is only
+    /// different from `capacity` if it was rounded up to a power of two. Once
+    /// `compact` or `extend` resizes the table, this is its new capacity.
+    pub fn requested_capacity(&self) -> usize {
+        self.requested_size
+    }
+
+    /// Get an upper bound on the number of keys that can still be inserted before
+    /// `insert` returns `AtomicHashMapError::Full`. Tombstoned slots count as free
+    /// since they are reused by `insert`.
+    ///
+    /// With a `max_probe` limit, a key can only go in the free slots within reach of
+    /// its probe, so an insert may return `Full` well before this drops to 0.
+    pub fn remaining_capacity(&self) -> usize {
+        self.size.saturating_sub(self.len() as usize)
+    }
+
+    /// Returns true if every slot holds a key, so that no new key can be inserted
+    ///
+    /// A map that isn't full may still reject some keys with `AtomicHashMapError::Full`
+    /// when it has a `max_probe` limit, see `remaining_capacity`.
+    pub fn is_full(&self) -> bool {
+        self.remaining_capacity() == 0
+    }
+
+    /// Gather occupancy and probe length statistics by scanning the whole table
+    ///
+    /// The probe length of a key is the number of slots a lookup of it checks, which
+    /// is at least 1. Slots whose tag rules the key out are skipped without being
+    /// checked and don't count. The scan isn't a snapshot, so entries moving while it
+    /// runs may be counted twice or not at all.
+    pub fn stats(&self) -> MapStats {
+        let mut stats = MapStats {
+            capacity: self.size,
+            live: 0,
+            tombstones: 0,
+            probe_lengths: Vec::new()
+        };
+
+        for index in 0..self.size {
+            let key = self.bucket(index).key.load(Ordering::Acquire);
+            if key == self.tombstone_key {
+                stats.tombstones += 1;
+                continue;
+            }
+
+            if !self.is_live(key) {
+                continue;
+            }
+
+            stats.live += 1;
+
+            // Replay the lookup of this key, which is missing if it moved meanwhile
+            let hash = self.hash(key);
+            let tag = control::tag(hash);
+            let mut probe = self.ctrl.probe(hash, tag, self.probe, usize::MAX);
+            if let Some(checked) = probe.position(|probe_index| probe_index == index) {
+                if stats.probe_lengths.len() <= checked {
+                    stats.probe_lengths.resize(checked + 1, 0);
+                }
+                stats.probe_lengths[checked] += 1;
+            }
+        }
+
+        stats
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl<K: PodU64, V: PodU64, S> AtomicHashMap<K, V, S> {
+    /// Read the probe and contention counters of the map, see `src/metrics.rs`
+    pub fn metrics(&self) -> Metrics {
+        self.counters.snapshot()
+    }
+
+    /// Reset the probe and contention counters of the map to 0
+    pub fn reset_metrics(&self) {
+        self.counters.reset();
+    }
+}
+
+/// Occupancy and probe length statistics of an `AtomicHashMap`, gathered by
+/// `AtomicHashMap::stats`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MapStats {
+    /// Total number of slots
+    pub capacity: usize,
+
+    /// Number of slots holding a key
+    pub live: usize,
+
+    /// Number of slots holding a tombstone
+    pub tombstones: usize,
+
+    /// Histogram of probe lengths: `probe_lengths[n]` keys are found by checking
+    /// `n + 1` slots
+    pub probe_lengths: Vec<usize>
+}
+
+impl MapStats {
+    /// Get the fraction of slots holding a key
+    pub fn load_factor(&self) -> f64 {
+        self.live as f64 / self.capacity as f64
+    }
+
+    /// Get the fraction of slots holding a key or a tombstone, which is what probes
+    /// for missing keys have to get past
+    pub fn occupancy(&self) -> f64 {
+        (self.live + self.tombstones) as f64 / self.capacity as f64
+    }
+
+    /// Get the longest probe length of any key, or 0 for an empty table
+    pub fn max_probe_length(&self) -> usize {
+        self.probe_lengths.len()
+    }
+
+    /// Get the average probe length over every key, or 0 for an empty table
+    pub fn mean_probe_length(&self) -> f64 {
+        let (keys, total) = self.probe_lengths.iter().enumerate()
+            .fold((0, 0), |(keys, total), (checked, count)| {
+                (keys + count, total + (checked + 1) * count)
+            });
+
+        if keys == 0 {
+            return 0.0;
+        }
+
+        total as f64 / keys as f64
+    }
+}
+
+impl<K: PodU64, S: BuildHasher> AtomicHashMap<K, u64, S> {
+    /// Atomically add `delta` to the value for `key`, inserting the key with a value of
+    /// `delta` if it isn't in the hashmap yet. The addition wraps around on overflow.
+    ///
+    /// Returns the value before the addition, which is 0 for a newly inserted key.
+    pub fn add_to(&self, key: K, delta: u64) -> Result<u64, AtomicHashMapError> {
+        let key = self.raw_key(key)?;
+
+        // A freshly claimed slot always holds a value of 0, so adding to it is the
+        // same as storing `delta`
+        match self.claim_slot(key)? {
+            Slot::Found(index) => {
+                Ok(self.bucket(index).value.fetch_add(delta, self.ordering.rmw()))
+            }
+            Slot::Claimed(index) => {
+                let prev_value = self.bucket(index).value.fetch_add(delta, 
+                                                                     self.ordering.rmw());
+                probe::publish(self, index);
+                Ok(prev_value)
+            }
+        }
+    }
+}
+
+impl<K: PodU64, V: PodU64 + Ord, S: BuildHasher> AtomicHashMap<K, V, S> {
+    /// Atomically lower the value for `key` to `value` if it is smaller, inserting the
+    /// key with `value` if it isn't in the hashmap yet
+    ///
+    /// Values are compared as `V`, so e.g. `i64` values are compared as signed. The
+    /// value is left untouched if it is already no larger than `value`.
+    ///
+    /// Returns the value before the update, or `None` if the key was newly inserted.
+    pub fn min_to(&self, key: K, value: V) -> Result<Option<V>, AtomicHashMapError> {
+        self.upsert_with(key, value, |curr| (value < curr).then_some(value))
+    }
+
+    /// Atomically raise the value for `key` to `value` if it is larger, inserting the
+    /// key with `value` if it isn't in the hashmap yet
+    ///
+    /// Values are compared as `V`, so e.g. `i64` values are compared as signed. The
+    /// value is left untouched if it is already no smaller than `value`.
+    ///
+    /// Returns the value before the update, or `None` if the key was newly inserted.
+    pub fn max_to(&self, key: K, value: V) -> Result<Option<V>, AtomicHashMapError> {
+        self.upsert_with(key, value, |curr| (value > curr).then_some(value))
+    }
+}
+
+impl<K: PodU64, S: BuildHasher> AtomicHashMap<K, f64, S> {
+    /// Atomically add `delta` to the value for `key`, inserting the key with a value of
+    /// `delta` if it isn't in the hashmap yet
+    ///
+    /// Returns the value before the addition, which is 0.0 for a newly inserted key.
+    pub fn add_to_f64(&self, key: K, delta: f64) -> Result<f64, AtomicHashMapError> {
+        let prev_value = self.upsert_with(key, delta, |curr| Some(curr + delta))?;
+        Ok(prev_value.unwrap_or(0.0))
+    }
+
+    /// Atomically raise the value for `key` to `value` if it is larger, inserting the
+    /// key with `value` if it isn't in the hashmap yet
+    ///
+    /// Returns the value before the update, or `None` if the key was newly inserted.
+    /// Follows `f64::max`, so a NaN is only kept if both values are NaN.
+    pub fn max_f64(&self, key: K, value: f64) -> Result<Option<f64>, AtomicHashMapError> {
+        self.upsert_with(key, value, |curr| {
+            let new = curr.max(value);
+            (new.to_bits() != curr.to_bits()).then_some(new)
+        })
+    }
+
+    /// Atomically lower the value for `key` to `value` if it is smaller, inserting the
+    /// key with `value` if it isn't in the hashmap yet
+    ///
+    /// Returns the value before the update, or `None` if the key was newly inserted.
+    /// Follows `f64::min`, so a NaN is only kept if both values are NaN.
+    pub fn min_f64(&self, key: K, value: f64) -> Result<Option<f64>, AtomicHashMapError> {
+        self.upsert_with(key, value, |curr| {
+            let new = curr.min(value);
+            (new.to_bits() != curr.to_bits()).then_some(new)
+        })
+    }
+}
+
+/// Builder for an `AtomicHashMap`, collecting its configuration in one place
+///
+/// ```
+/// use atomics_rs::AtomicHashMap;
+///
+/// let map: AtomicHashMap = AtomicHashMap::builder(1000)
+///     .round_up(true)
+///     .sentinels(u64::MAX - 1, u64::MAX)
+///     .build()
+///     .unwrap();
+///
+/// assert_eq!(map.capacity(), 1024);
+/// map.insert(0, 1).unwrap();
+/// ```
+pub struct AtomicHashMapBuilder<K: PodU64 = u64, V: PodU64 = u64, S = BuildMurmurHasher> {
+    size: usize,
+    round_up: bool,
+    /// Raw sentinels, kept in their `u64` form so that the default tombstone of
+    /// `u64::MAX` stays out of reach of keys narrower than 64 bits
+    empty_key: u64,
+    tombstone_key: u64,
+    hasher: S,
+    ordering: OrderingProfile,
+    probe: ProbeStrategy,
+    max_probe: Option<usize>,
+    padded: bool,
+    placement: Placement,
+    eviction: Option<Arc<dyn EvictionPolicy<K, V>>>,
+    _value: PhantomData<V>
+}
+
+impl<K: PodU64, V: PodU64> AtomicHashMapBuilder<K, V> {
+    /// Start configuring a map with `size` slots, the default hasher and the default
+    /// sentinels
+    pub fn new(size: usize) -> AtomicHashMapBuilder<K, V> {
+        AtomicHashMapBuilder {
+            size,
+            round_up: false,
+            empty_key: EMPTY_KEY,
+            tombstone_key: TOMBSTONE_KEY,
+            hasher: BuildMurmurHasher::default(),
+            ordering: OrderingProfile::default(),
+            probe: ProbeStrategy::default(),
+            max_probe: None,
+            padded: false,
+            placement: Placement::default(),
+            eviction: None,
+            _value: PhantomData
+        }
+    }
+}
+
+impl<K: PodU64, V: PodU64, S: BuildHasher> AtomicHashMapBuilder<K, V, S> {
+    /// Round the size up to the next power of two instead of rejecting it
+    pub fn round_up(mut self, round_up: bool) -> Self {
+        self.round_up = round_up;
+        self
+    }
+
+    /// Mark empty and removed slots with `empty_key` and `tombstone_key`
+    pub fn sentinels(mut self, empty_key: K, tombstone_key: K) -> Self {
+        self.empty_key = empty_key.to_u64();
+        self.tombstone_key = tombstone_key.to_u64();
+        self
+    }
+
+    /// Mark empty and removed slots with the raw keys of `sentinels`, such as the
+    /// `raw_sentinels` of another map
+    pub(crate) fn raw_sentinels(mut self, sentinels: (u64, u64)) -> Self {
+        (self.empty_key, self.tombstone_key) = sentinels;
+        self
+    }
+
+    /// Use the memory orderings of `ordering` for values
+    pub fn ordering(mut self, ordering: OrderingProfile) -> Self {
+        self.ordering = ordering;
+        self
+    }
+
+    /// Visit groups of slots in the order given by `probe`
+    pub fn probe(mut self, probe: ProbeStrategy) -> Self {
+        self.probe = probe;
+        self
+    }
+
+    /// Make an insert return `AtomicHashMapError::Full` once it has probed `slots`
+    /// slots without finding room for its key, instead of scanning the whole table
+    ///
+    /// The limit is rounded up to whole groups of 16 slots, and only applies to
+    /// inserts: lookups already stop at the first empty slot.
+    pub fn max_probe(mut self, slots: usize) -> Self {
+        self.max_probe = Some(slots);
+        self
+    }
+
+    /// Give every slot a cache line of its own
+    ///
+    /// Threads hammering neighbouring slots, e.g. counters updated with `add_to`, then
+    /// no longer invalidate each other's cache lines. This costs twice the memory and
+    /// makes each probe step touch a new line, so it only pays off for small, hot
+    /// tables.
+    pub fn padded(mut self, padded: bool) -> Self {
+        self.padded = padded;
+        self
+    }
+
+    /// Ask for the tables to be backed by transparent huge pages
+    ///
+    /// Tables spanning hundreds of megabytes otherwise spend much of each probe on
+    /// TLB misses. This only has an effect on Linux with the `hugepages` feature, and
+    /// the map falls back to normal pages if the kernel can't provide huge ones.
+    pub fn huge_pages(mut self, huge_pages: bool) -> Self {
+        self.placement.huge_pages = huge_pages;
+        self
+    }
+
+    /// Allocate the tables on NUMA node `node`, whichever thread builds the map
+    ///
+    /// Threads running on that node then access the table without going through the
+    /// interconnect, see `ShardedAtomicHashMap::per_numa_node` to spread a map over
+    /// every node. This only has an effect on Linux with the `numa` feature, and
+    /// pages spill over to other nodes once `node` is full.
+    pub fn numa_node(mut self, node: usize) -> Self {
+        self.placement.numa_node = Some(node);
+        self
+    }
+
+    /// Evict an entry chosen by `policy` when a new key doesn't fit, instead of
+    /// returning `AtomicHashMapError::Full`
+    ///
+    /// Only the first live entries along the probe of the new key are candidates, see
+    /// `src/eviction.rs`.
+    pub fn eviction<P: EvictionPolicy<K, V> + 'static>(mut self, policy: P) -> Self {
+        self.eviction = Some(Arc::new(policy));
+        self
+    }
+
+    /// Hash keys with `hasher`
+    pub fn hasher<S2: BuildHasher>(self, hasher: S2) -> AtomicHashMapBuilder<K, V, S2> {
+        AtomicHashMapBuilder {
+            size: self.size,
+            round_up: self.round_up,
+            empty_key: self.empty_key,
+            tombstone_key: self.tombstone_key,
+            hasher,
+            ordering: self.ordering,
+            probe: self.probe,
+            max_probe: self.max_probe,
+            padded: self.padded,
+            placement: self.placement,
+            eviction: self.eviction,
+            _value: PhantomData
+        }
+    }
+
+    /// Validate the configuration and construct the map
+    pub fn build(self) -> Result<AtomicHashMap<K, V, S>, AtomicHashMapError> {
+        let size = if self.round_up { round_capacity(self.size)? } else { self.size };
+
+        let stride = if self.padded { PADDED_STRIDE } else { 1 };
+        let mut map = AtomicHashMap::with_stride(size, self.hasher, self.empty_key,
+                                                 self.tombstone_key, stride,
+                                                 self.placement)?;
+        map.requested_size = self.size;
+        map.ordering = self.ordering;
+        map.probe = self.probe;
+        map.max_probe = self.max_probe;
+        map.eviction = self.eviction;
+        Ok(map)
+    }
+}
+
+/// Handle to the slot of one key of an `AtomicHashMap`, created by
+/// `AtomicHashMap::slot`
+///
+/// Every operation checks that the slot still holds the key, for the cost of two
+/// loads from the cache line it is about to touch anyway, and returns `None` once the
+/// key has been removed. The handle then stays dead unless the same key is inserted
+/// back into the same slot.
+pub struct SlotRef<'a, K: PodU64, V: PodU64, S> {
+    map: &'a AtomicHashMap<K, V, S>,
+    index: usize,
+
+    /// Raw key the slot was resolved for
+    key: u64
+}
+
+impl<'a, K: PodU64, V: PodU64, S> SlotRef<'a, K, V, S> {
+    /// Get the key of the slot
+    pub fn key(&self) -> K {
+        K::from_u64(self.key)
+    }
+
+    /// Get the index of the slot in the table
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Get the value in the slot of this key if it is still published
+    fn value(&self) -> Option<&'a AtomicU64> {
+        let bucket = self.map.bucket(self.index);
+        if !probe::is_published(self.map, self.index)
+                || bucket.key.load(Ordering::Acquire) != self.key {
+            return None;
+        }
+
+        Some(&bucket.value)
+    }
+
+    /// Atomically get the value of the key
+    pub fn load(&self) -> Option<V> {
+        self.map.read_value(self.index, self.key)
+    }
+
+    /// Atomically replace the value of the key, returning the previous one
+    pub fn store(&self, value: V) -> Option<V> {
+        let prev_value = self.value()?.swap(value.to_u64(), self.map.ordering.rmw());
+        Some(V::from_u64(prev_value))
+    }
+
+    /// Atomically replace the value of the key with `new` if it currently equals
+    /// `expected`, see `AtomicHashMap::update_if_eq`
+    pub fn cas(&self, expected: V, new: V) -> Option<Result<V, V>> {
+        Some(self.value()?.compare_exchange(expected.to_u64(), new.to_u64(),
+                                             self.map.ordering.rmw(),
+                                             self.map.ordering.load())
+             .map(V::from_u64)
+             .map_err(V::from_u64))
+    }
+}
+
+impl<'a, K: PodU64, S> SlotRef<'a, K, u64, S> {
+    /// Atomically add `delta` to the value of the key, wrapping around on overflow.
+    /// Returns the value before the addition.
+    pub fn fetch_add(&self, delta: u64) -> Option<u64> {
+        Some(self.value()?.fetch_add(delta, self.map.ordering.rmw()))
+    }
+}
+
+/// Key claimed in an `AtomicHashMap` whose value is still to be filled in, created by
+/// `AtomicHashMap::reserve`
+pub struct Reservation<'a, K: PodU64, V: PodU64, S> {
+    map: &'a AtomicHashMap<K, V, S>,
+    index: usize,
+
+    /// Raw key claimed
+    key: u64
+}
+
+impl<'a, K: PodU64, V: PodU64, S> Reservation<'a, K, V, S> {
+    /// Get the key that was reserved
+    pub fn key(&self) -> K {
+        K::from_u64(self.key)
+    }
+
+    /// Store `value` for the key and publish it, waking up threads waiting on it
+    pub fn fill(self, value: V) {
+        let bucket = self.map.bucket(self.index);
+        bucket.value.store(value.to_u64(), self.map.ordering.store());
+        probe::publish(self.map, self.index);
+        core::mem::forget(self);
+    }
+
+    /// Give up on the key, leaving it out of the map. One of the threads waiting on it
+    /// gets to claim it instead.
+    pub fn abort(self) {
+        // Release the slot in `drop`
+    }
+}
+
+impl<'a, K: PodU64, V: PodU64, S> Drop for Reservation<'a, K, V, S> {
+    fn drop(&mut self) {
+        // The slot was never published and its value never written, so it can be
+        // released as is
+        probe::release(self.map, self.index, self.key);
+    }
+}
+
+/// Iterator over the live entries of an `AtomicHashMap`, created by
+/// `AtomicHashMap::iter`
+pub struct Iter<'a, K: PodU64, V: PodU64, S> {
+    map: &'a AtomicHashMap<K, V, S>,
+    index: usize
+}
+
+impl<'a, K: PodU64, V: PodU64, S> Iterator for Iter<'a, K, V, S> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        while self.index < self.map.size {
+            let index = self.index;
+            self.index += 1;
+
+            let key = self.map.bucket(index).key.load(Ordering::Acquire);
+            let published = probe::is_published(self.map, index);
+            if !self.map.is_live(key) || !published {
+                continue;
+            }
+
+            // The slot may be removed (and possibly reused) while reading the value
+            if let Some(value) = self.map.read_value(index, key) {
+                return Some((K::from_u64(key), value));
+            }
+        }
+
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.map.size - self.index))
+    }
+}
+
+/// Iterator over the keys of an `AtomicHashMap`, created by `AtomicHashMap::keys`
+pub struct Keys<'a, K: PodU64, V: PodU64, S> {
+    inner: Iter<'a, K, V, S>
+}
+
+impl<'a, K: PodU64, V: PodU64, S> Iterator for Keys<'a, K, V, S> {
+    type Item = K;
+
+    fn next(&mut self) -> Option<K> {
+        self.inner.next().map(|(key, _)| key)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+/// Iterator over the values of an `AtomicHashMap`, created by `AtomicHashMap::values`
+pub struct Values<'a, K: PodU64, V: PodU64, S> {
+    inner: Iter<'a, K, V, S>
+}
+
+impl<'a, K: PodU64, V: PodU64, S> Iterator for Values<'a, K, V, S> {
+    type Item = V;
+
+    fn next(&mut self) -> Option<V> {
+        self.inner.next().map(|(_, value)| value)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+/// Draining iterator over the entries of an `AtomicHashMap`, created by
+/// `AtomicHashMap::drain`
+pub struct Drain<'a, K: PodU64, V: PodU64, S> {
+    map: &'a AtomicHashMap<K, V, S>,
+    index: usize
+}
+
+impl<'a, K: PodU64, V: PodU64, S> Iterator for Drain<'a, K, V, S> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        while self.index < self.map.size {
+            let index = self.index;
+            self.index += 1;
+
+            let key = self.map.bucket(index).key.load(Ordering::Acquire);
+            if !self.map.is_live(key) {
+                continue;
+            }
+
+            // Only the thread that unpublishes a slot may release it
+            if !probe::unpublish(self.map, index, key) {
+                continue;
+            }
+
+            // Take the value before releasing the key, same as `remove`. The slot is
+            // left as a tombstone, since emptying it would cut off the probes of keys
+            // stored past it that other threads may still be looking up or inserting.
+            let value = self.map.bucket(index).value.swap(0, self.map.ordering.rmw());
+            probe::release(self.map, index, key);
+            return Some((K::from_u64(key), V::from_u64(value)));
+        }
+
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.map.size - self.index))
+    }
+}
+
+impl<'a, K: PodU64, V: PodU64, S> Drop for Drain<'a, K, V, S> {
+    fn drop(&mut self) {
+        // Empty out whatever wasn't consumed
+        for _ in self {}
+    }
+}
+
+impl<'a, K: PodU64, V: PodU64, S: BuildHasher> IntoIterator for &'a AtomicHashMap<K, V, S> {
+    type Item = (K, V);
+    type IntoIter = Iter<'a, K, V, S>;
+
+    fn into_iter(self) -> Iter<'a, K, V, S> {
+        self.iter()
+    }
+}
+
+/// Collect into a table of twice as many slots as there are items, rounded up to a
+/// power of two
+///
+/// Panics if a key is one of the default sentinels, 0 and `u64::MAX`.
+impl<K: PodU64, V: PodU64, S: BuildHasher + Default> FromIterator<(K, V)>
+        for AtomicHashMap<K, V, S> {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let items: Vec<(K, V)> = iter.into_iter().collect();
+        let size = round_capacity(items.len() * 2).expect("Too many items for AtomicHashMap");
+
+        let mut map = AtomicHashMap::with_hasher(size, S::default())
+            .expect("Rounded capacity is valid");
+        map.extend(items);
+        map
+    }
+}
+
+/// Insert every item, doubling the size of the table whenever it fills up
+///
+/// Panics if a key is one of the sentinels of the map.
+impl<K: PodU64, V: PodU64, S: BuildHasher> Extend<(K, V)> for AtomicHashMap<K, V, S> {
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        for (key, value) in iter {
+            loop {
+                match self.insert(key, value) {
+                    Ok(_) => break,
+                    Err(AtomicHashMapError::Full) => self.grow(),
+                    Err(err) => panic!("Failed to extend AtomicHashMap: {}", err)
+                }
+            }
+        }
+    }
+}
+
+/// Copies the live entries into a freshly allocated table of the same size and
+/// configuration
+///
+/// Entries are read one slot at a time like `iter`, so cloning a map that is written
+/// to concurrently gives a copy that may or may not include the concurrent writes.
+/// Entries that no longer fit once the table has filled up with such writes are left
+/// out of the copy.
+impl<K: PodU64, V: PodU64, S: BuildHasher + Clone> Clone for AtomicHashMap<K, V, S> {
+    fn clone(&self) -> Self {
+        let map = AtomicHashMap {
+            buckets: Bucket::new_table(self.size * self.stride, self.empty_key,
+                                       self.placement),
+            size: self.size,
+            stride: self.stride,
+            ctrl: ControlBytes::new(self.size, self.placement),
+            requested_size: self.requested_size,
+            count: AtomicU64::new(0),
+            empty_key: self.empty_key,
+            tombstone_key: self.tombstone_key,
+            hasher: self.hasher.clone(),
+            ordering: self.ordering,
+            probe: self.probe,
+            max_probe: self.max_probe,
+            placement: self.placement,
+            eviction: self.eviction.clone(),
+            counters: Counters::default(),
+            _types: PhantomData
+        };
+
+        for (key, value) in self.iter() {
+            let _ = map.insert(key, value);
+        }
+
+        map
+    }
+}
+
+/// Lists the live entries of a map for the alternate `Debug` form
+struct Entries<'a, K: PodU64, V: PodU64, S>(&'a AtomicHashMap<K, V, S>);
+
+impl<'a, K, V, S> fmt::Debug for Entries<'a, K, V, S>
+        where K: PodU64 + fmt::Debug, V: PodU64 + fmt::Debug, S: BuildHasher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.0.iter()).finish()
+    }
+}
+
+/// Shows the capacity, live count and load factor. The alternate form `{:#?}` also
+/// lists the live entries, read one slot at a time like `iter`.
+impl<K, V, S> fmt::Debug for AtomicHashMap<K, V, S>
+        where K: PodU64 + fmt::Debug, V: PodU64 + fmt::Debug, S: BuildHasher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let len = self.len();
+        let alternate = f.alternate();
+        let mut state = f.debug_struct("AtomicHashMap");
+        state.field("capacity", &self.size)
+             .field("len", &len)
+             .field("load_factor", &(len as f64 / self.size as f64));
+
+        if alternate {
+            state.field("entries", &Entries(self));
+        }
+
+        state.finish()
+    }
+}
+
+/// Shows the occupancy of the map as `AtomicHashMap(len/capacity, load%)`
+impl<K: PodU64, V: PodU64, S: BuildHasher> fmt::Display for AtomicHashMap<K, V, S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let len = self.len();
+        write!(f, "AtomicHashMap({}/{}, {:.1}% load)", len, self.size,
+               len as f64 * 100.0 / self.size as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blanket_insert_get() {
+        let size: u64 = 1 << 10;
+        let hashtable = AtomicHashMap::new(size as usize).unwrap();
+
+        for x in 1..=size {
+            // Don't care about the Full case in the test
+            let _ = hashtable.insert(x, x); 
+        }
+
+        for x in 1..=size {
+            assert_eq!(hashtable.get(&x).unwrap_or(0xffffffff), x);
+        }
+    }
+
+    #[test]
+    fn test_threads() {
+        use std::thread;
+        use std::sync::Arc;
+
+        let size: u64 = 1 << 12;
+        let hashtable = Arc::new(AtomicHashMap::new(size as usize).unwrap());
+
+        let mut threads = Vec::new();
+        for i in 0..10 {
+            let hashtable_i = hashtable.clone();
+            let t = thread::spawn(move || {
+                for x in 1..=size {
+                    // Don't care about the FULL error in this test
+                    let _ = hashtable_i.insert(x, i * x); 
+                }
+            });
+            threads.push(t);
+        }
+
+        for t in threads {
+            let _ = t.join();
+        }
+    }
+
+    #[test]
+    fn test_full() {
+        let size: u64 = 1 << 4;
+        let hashtable = AtomicHashMap::new(size as usize).unwrap();
+
+        // Insert one element and ensure it inserted fine
+        assert_eq!(hashtable.insert(10000, 10), Ok(None));
+
+        // Fill the remaining slots and ensure they were inserted fine
+        for x in 1..=(size-1) {
+            // Don't care about the Full case in the test
+            assert_eq!(hashtable.insert(x, x), Ok(None));
+        }
+
+        // Ensure if we insert one more element that we are full
+        assert_eq!(hashtable.insert(20000, 10), Err(AtomicHashMapError::Full));
+    }
+
+    #[test]
+    fn test_remove() {
+        let size: u64 = 1 << 10;
+        let hashtable = AtomicHashMap::new(size as usize).unwrap();
+
+        for x in 1..=size {
+            assert_eq!(hashtable.insert(x, x), Ok(None));
+        }
+
+        // Remove every other key
+        for x in (1..=size).step_by(2) {
+            assert_eq!(hashtable.remove(x), Some(x));
+            assert_eq!(hashtable.remove(x), None);
+        }
+
+        // Keys stored past the tombstones must still be found
+        for x in 1..=size {
+            if x % 2 == 1 {
+                assert_eq!(hashtable.get(&x), None);
+            } else {
+                assert_eq!(hashtable.get(&x), Some(x));
+            }
+        }
+
+        assert_eq!(hashtable.len(), size / 2);
+    }
+
+    #[test]
+    fn test_remove_reuses_tombstones() {
+        let size: u64 = 1 << 4;
+        let hashtable = AtomicHashMap::new(size as usize).unwrap();
+
+        for x in 1..=size {
+            assert_eq!(hashtable.insert(x, x), Ok(None));
+        }
+        assert_eq!(hashtable.insert(20000, 10), Err(AtomicHashMapError::Full));
+
+        // A full table with a tombstone has room for exactly one new key
+        assert_eq!(hashtable.remove(5), Some(5));
+        assert_eq!(hashtable.insert(20000, 10), Ok(None));
+        assert_eq!(hashtable.insert(30000, 10), Err(AtomicHashMapError::Full));
+        assert_eq!(hashtable.get(&20000), Some(10));
+
+        // Re-inserting an existing key doesn't need a free slot
+        assert_eq!(hashtable.insert(20000, 11), Ok(Some(10)));
+        assert_eq!(hashtable.get(&20000), Some(11));
+    }
+
+    #[test]
+    fn test_threads_no_duplicates() {
+        use core::hash::BuildHasherDefault;
+        use std::thread;
+
+        /// Sends every key down the same probe, so that removing one key opens a
+        /// tombstone in front of inserts of all the others
+        #[derive(Default)]
+        struct Collide;
+
+        impl Hasher for Collide {
+            fn finish(&self) -> u64 {
+                0
+            }
+
+            fn write(&mut self, _bytes: &[u8]) {}
+        }
+
+        let hashtable: AtomicHashMap<u64, u64, BuildHasherDefault<Collide>> =
+            AtomicHashMap::with_hasher(1 << 6, BuildHasherDefault::default()).unwrap();
+
+        for round in 0..200 {
+            for x in 100..116 {
+                hashtable.insert(x, round).unwrap();
+            }
+
+            // Two threads insert the same keys while a third removes the keys in front
+            // of them, and each key still ends up in a single slot
+            thread::scope(|scope| {
+                for _ in 0..2 {
+                    scope.spawn(|| {
+                        for x in 1..=8 {
+                            hashtable.insert(x, round).unwrap();
+                        }
+                    });
+                }
+
+                scope.spawn(|| {
+                    for x in 100..116 {
+                        assert_eq!(hashtable.remove(x), Some(round));
+                    }
+                });
+            });
+
+            let entries = hashtable.to_vec();
+            assert_eq!(entries.len(), 8);
+            assert_eq!(hashtable.len(), 8);
+            for x in 1..=8 {
+                assert_eq!(entries.iter().filter(|&&(key, _)| key == x).count(), 1);
+                assert_eq!(hashtable.remove(x), Some(round));
+                assert_eq!(hashtable.remove(x), None);
+            }
+        }
+    }
+
+    #[test]
+    fn test_unpublish_other_key() {
+        let hashtable: AtomicHashMap = AtomicHashMap::new(1 << 4).unwrap();
+        hashtable.insert(2, 20).unwrap();
+        let index = hashtable.find_slot(2).unwrap();
+
+        // A removal of key 1 still holding the index of its old slot, now reused by
+        // key 2, leaves the slot alone
+        assert!(!probe::unpublish(&hashtable, index, 1));
+        assert!(probe::is_published(&hashtable, index));
+        assert_eq!(hashtable.get(&2), Some(20));
+
+        // Each time the slot is unpublished, its state moves on to a new generation
+        let state = hashtable.bucket(index).state.load(Ordering::Relaxed);
+        assert_eq!(hashtable.remove(2), Some(20));
+        assert_eq!(hashtable.insert(2, 21), Ok(None));
+        assert_eq!(hashtable.find_slot(2), Some(index));
+        assert_ne!(hashtable.bucket(index).state.load(Ordering::Relaxed), state);
+    }
+
+    #[test]
+    fn test_insert_returns_previous() {
+        let hashtable: AtomicHashMap = AtomicHashMap::new(1 << 4).unwrap();
+
+        assert_eq!(hashtable.insert(1, 10), Ok(None));
+        assert_eq!(hashtable.insert(1, 11), Ok(Some(10)));
+        assert_eq!(hashtable.insert(1, 12), Ok(Some(11)));
+
+        // A removed key is inserted fresh again
+        assert_eq!(hashtable.remove(1), Some(12));
+        assert_eq!(hashtable.insert(1, 13), Ok(None));
+    }
+
+    #[test]
+    fn test_get_or_insert() {
+        let hashtable: AtomicHashMap = AtomicHashMap::new(1 << 4).unwrap();
+
+        assert_eq!(hashtable.get_or_insert(1, 10), Ok(10));
+        assert_eq!(hashtable.get_or_insert(1, 20), Ok(10));
+        assert_eq!(hashtable.get_or_insert_with(2, || 30), Ok(30));
+        assert_eq!(hashtable.get_or_insert_with(2, || panic!("already inserted")), Ok(30));
+        assert_eq!(hashtable.get(&1), Some(10));
+    }
+
+    #[test]
+    fn test_get_or_insert_with_threads() {
+        use std::thread;
+        use std::sync::Arc;
+        use std::sync::atomic::AtomicUsize;
+
+        let size: u64 = 1 << 10;
+        let hashtable = Arc::new(AtomicHashMap::new(size as usize).unwrap());
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let mut threads = Vec::new();
+        for _ in 0..8 {
+            let hashtable_i = hashtable.clone();
+            let calls_i = calls.clone();
+            let t = thread::spawn(move || {
+                for x in 1..=size {
+                    let _ = hashtable_i.get_or_insert_with(x, || {
+                        calls_i.fetch_add(1, Ordering::SeqCst);
+                        x
+                    });
+                }
+            });
+            threads.push(t);
+        }
+
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        // Every key was initialized exactly once
+        assert_eq!(calls.load(Ordering::SeqCst), size as usize);
+        for x in 1..=size {
+            assert_eq!(hashtable.get(&x), Some(x));
+        }
+    }
+
+    #[test]
+    fn test_get_or_insert_with_remove_threads() {
+        use std::thread;
+
+        let hashtable: AtomicHashMap = AtomicHashMap::new(1 << 4).unwrap();
+
+        // Values are never 0, so a 0 could only be the reset done by a removal
+        thread::scope(|scope| {
+            for _ in 0..2 {
+                scope.spawn(|| {
+                    for _ in 0..10_000 {
+                        assert_eq!(hashtable.get_or_insert_with(1, || 7), Ok(7));
+                    }
+                });
+            }
+
+            scope.spawn(|| {
+                for _ in 0..10_000 {
+                    assert!(matches!(hashtable.remove(1), None | Some(7)));
+                }
+            });
+        });
+    }
+
+    #[test]
+    fn test_add_to() {
+        let hashtable: AtomicHashMap = AtomicHashMap::new(1 << 4).unwrap();
+
+        assert_eq!(hashtable.add_to(1, 5), Ok(0));
+        assert_eq!(hashtable.add_to(1, 5), Ok(5));
+        assert_eq!(hashtable.get(&1), Some(10));
+
+        // Addition wraps around
+        assert_eq!(hashtable.add_to(1, u64::MAX), Ok(10));
+        assert_eq!(hashtable.get(&1), Some(9));
+    }
+
+    #[test]
+    fn test_add_to_threads() {
+        use std::thread;
+        use std::sync::Arc;
+
+        let size: u64 = 1 << 10;
+        let hashtable = Arc::new(AtomicHashMap::new(size as usize).unwrap());
+
+        let mut threads = Vec::new();
+        for _ in 0..8 {
+            let hashtable_i = hashtable.clone();
+            let t = thread::spawn(move || {
+                for x in 1..=size {
+                    hashtable_i.add_to(x, x).unwrap();
+                }
+            });
+            threads.push(t);
+        }
+
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        // No increments were lost, including the ones racing to claim each key
+        for x in 1..=size {
+            assert_eq!(hashtable.get(&x), Some(x * 8));
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_snapshot() {
+        use std::thread;
+        use std::sync::Arc;
+
+        let hashtable: Arc<AtomicHashMap<u32, u32>> =
+            Arc::new(AtomicHashMap::new(1 << 10).unwrap());
+
+        let threads: Vec<_> = (0..4).map(|t| {
+            let hashtable = hashtable.clone();
+            thread::spawn(move || {
+                for x in 1..=100 {
+                    hashtable.insert(t * 100 + x, x).unwrap();
+                }
+                hashtable.remove(t * 100 + 1);
+            })
+        }).collect();
+
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        let snapshot = hashtable.snapshot();
+        assert_eq!(snapshot.len(), 396);
+        assert_eq!(snapshot.get(&2), Some(&2));
+        assert_eq!(snapshot.get(&301), None);
+        assert_eq!(snapshot.get(&400), Some(&100));
+    }
+
+    #[test]
+    fn test_from_iter_extend() {
+        let mut hashtable: AtomicHashMap = (1..=100).map(|x| (x, x * 2)).collect();
+        assert_eq!(hashtable.capacity(), 256);
+        assert_eq!(hashtable.len(), 100);
+        assert_eq!(hashtable.get(&50), Some(100));
+
+        // Extending past the capacity grows the table
+        hashtable.extend((101..=300).map(|x| (x, x * 2)));
+        assert_eq!(hashtable.capacity(), 512);
+        assert_eq!(hashtable.requested_capacity(), 512);
+        assert_eq!(hashtable.len(), 300);
+        for x in 1..=300 {
+            assert_eq!(hashtable.get(&x), Some(x * 2));
+        }
+
+        let empty: AtomicHashMap = std::iter::empty().collect();
+        assert_eq!(empty.capacity(), 2);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_extend_sentinel() {
+        let mut hashtable: AtomicHashMap = AtomicHashMap::new(1 << 4).unwrap();
+        hashtable.extend(vec![(1, 1), (0, 1)]);
+    }
+
+    #[test]
+    fn test_clone() {
+        let hashtable: AtomicHashMap = AtomicHashMap::builder(1 << 4)
+            .sentinels(1, 2)
+            .probe(ProbeStrategy::Quadratic)
+            .max_probe(8)
+            .build()
+            .unwrap();
+        for x in 3..10 {
+            hashtable.insert(x, x * 10).unwrap();
+        }
+        hashtable.remove(5);
+
+        let copy = hashtable.clone();
+        assert_eq!(copy.capacity(), hashtable.capacity());
+        assert_eq!(copy.sentinels(), (1, 2));
+        assert_eq!(copy.len(), 6);
+        assert_eq!(copy.stats().tombstones, 0);
+
+        // The copy is independent of the original
+        copy.insert(20, 200).unwrap();
+        hashtable.insert(3, 0).unwrap();
+        assert_eq!(copy.get(&3), Some(30));
+        assert_eq!(hashtable.get(&20), None);
+
+        let mut entries = copy.to_vec();
+        entries.sort();
+        assert_eq!(entries, vec![(3, 30), (4, 40), (6, 60), (7, 70), (8, 80), (9, 90),
+                                 (20, 200)]);
+    }
+
+    #[test]
+    fn test_fmt() {
+        let hashtable: AtomicHashMap = AtomicHashMap::new(1 << 3).unwrap();
+        hashtable.insert(1, 10).unwrap();
+        hashtable.insert(2, 20).unwrap();
+
+        assert_eq!(format!("{:?}", hashtable),
+                   "AtomicHashMap { capacity: 8, len: 2, load_factor: 0.25 }");
+        assert_eq!(format!("{}", hashtable), "AtomicHashMap(2/8, 25.0% load)");
+
+        let pretty = format!("{:#?}", hashtable);
+        assert!(pretty.contains("entries: {"));
+        assert!(pretty.contains("1: 10,"));
+        assert!(pretty.contains("2: 20,"));
+    }
+
+    #[test]
+    fn test_get_key_value_take() {
+        let hashtable: AtomicHashMap<i32, u64> =
+            AtomicHashMap::with_sentinels(1 << 4, i32::MIN, i32::MAX).unwrap();
+        hashtable.insert(-5, 50).unwrap();
+        hashtable.insert(0, 7).unwrap();
+
+        assert_eq!(hashtable.get_key_value(&-5), Some((-5, 50)));
+        assert_eq!(hashtable.get_key_value(&0), Some((0, 7)));
+        assert_eq!(hashtable.get_key_value(&3), None);
+        assert_eq!(hashtable.get_key_value(&i32::MIN), None);
+
+        assert_eq!(hashtable.take(-5), Some(50));
+        assert_eq!(hashtable.take(-5), None);
+        assert_eq!(hashtable.get_key_value(&-5), None);
+        assert_eq!(hashtable.len(), 1);
+    }
+
+    #[test]
+    fn test_slot() {
+        let hashtable: AtomicHashMap = AtomicHashMap::new(1 << 4).unwrap();
+        assert!(hashtable.slot(&1).is_none());
+
+        hashtable.insert(1, 10).unwrap();
+        let slot = hashtable.slot(&1).unwrap();
+        assert_eq!(slot.key(), 1);
+        for _ in 0..5 {
+            slot.fetch_add(2).unwrap();
+        }
+        assert_eq!(slot.load(), Some(20));
+        assert_eq!(slot.store(30), Some(20));
+        assert_eq!(slot.cas(31, 32), Some(Err(30)));
+        assert_eq!(slot.cas(30, 32), Some(Ok(30)));
+        assert_eq!(hashtable.get(&1), Some(32));
+
+        // A removed key leaves the handle dead
+        assert_eq!(hashtable.remove(1), Some(32));
+        assert_eq!(slot.load(), None);
+        assert_eq!(slot.store(1), None);
+        assert_eq!(slot.fetch_add(1), None);
+        assert_eq!(hashtable.get(&1), None);
+
+        hashtable.insert(2, 0).unwrap();
+        assert_eq!(slot.cas(0, 1), None);
+    }
+
+    #[test]
+    fn test_reserve() {
+        use std::sync::atomic::AtomicUsize;
+        use std::thread;
+
+        let hashtable: AtomicHashMap = AtomicHashMap::new(1 << 4).unwrap();
+        let reservation = hashtable.reserve(1).unwrap().unwrap();
+        assert_eq!(reservation.key(), 1);
+        assert_eq!(hashtable.get(&1), None);
+        assert_eq!(hashtable.len(), 1);
+        reservation.fill(10);
+        assert_eq!(hashtable.get(&1), Some(10));
+        assert!(hashtable.reserve(1).unwrap().is_none());
+
+        // Aborted and dropped reservations leave the key out
+        hashtable.reserve(2).unwrap().unwrap().abort();
+        drop(hashtable.reserve(3).unwrap().unwrap());
+        assert_eq!(hashtable.len(), 1);
+        assert!(!hashtable.contains_key(&2));
+        assert!(!hashtable.contains_key(&3));
+        assert_eq!(hashtable.reserve(u64::MAX).err(),
+                   Some(AtomicHashMapError::InvalidKey));
+
+        // The value of each key is computed once, even though the first thread to
+        // reserve a key gives up on it half the time
+        let hashtable: AtomicHashMap = AtomicHashMap::new(1 << 10).unwrap();
+        let computed = AtomicUsize::new(0);
+        thread::scope(|scope| {
+            for thread in 0..4 {
+                let hashtable = &hashtable;
+                let computed = &computed;
+                scope.spawn(move || {
+                    for key in 1..=500 {
+                        if let Some(reservation) = hashtable.reserve(key).unwrap() {
+                            if (key + thread) % 2 == 0 && thread < 3 {
+                                reservation.abort();
+                                continue;
+                            }
+                            computed.fetch_add(1, Ordering::Relaxed);
+                            reservation.fill(key * 2);
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(computed.load(Ordering::Relaxed), 500);
+        for key in 1..=500 {
+            assert_eq!(hashtable.get(&key), Some(key * 2));
+        }
+    }
+
+    #[test]
+    fn test_try_ops() {
+        let hashtable: AtomicHashMap = AtomicHashMap::new(1 << 4).unwrap();
+
+        // A key claimed by another thread is never waited on
+        let reservation = hashtable.reserve(1).unwrap().unwrap();
+        assert_eq!(hashtable.try_insert(1, 10), Err(AtomicHashMapError::Contended));
+        assert_eq!(hashtable.try_insert(2, 20), Ok(None));
+        reservation.fill(11);
+        assert_eq!(hashtable.try_insert(1, 12), Ok(Some(11)));
+
+        assert_eq!(hashtable.try_update(3, |x| x + 1), Ok(None));
+        assert_eq!(hashtable.try_update(1, |x| x + 1), Ok(Some(12)));
+        assert_eq!(hashtable.get(&1), Some(13));
+
+        // A value changed under every attempt is retried exactly `TRY_RETRIES` times
+        let mut calls = 0;
+        let res = hashtable.try_update(2, |x| {
+            calls += 1;
+            hashtable.swap(2, x + 100);
+            x + 1
+        });
+        assert_eq!(res, Err(AtomicHashMapError::Contended));
+        assert_eq!(calls, TRY_RETRIES + 1);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_metrics() {
+        let hashtable: AtomicHashMap = AtomicHashMap::new(1 << 4).unwrap();
+        for key in 1..=16 {
+            hashtable.insert(key, key).unwrap();
+        }
+        assert_eq!(hashtable.metrics().probes, 16);
+        assert!(hashtable.metrics().probe_steps >= 16);
+        assert_eq!(hashtable.insert(17, 17), Err(AtomicHashMapError::Full));
+        assert_eq!(hashtable.metrics().full_errors, 1);
+
+        hashtable.reset_metrics();
+        assert_eq!(hashtable.get(&1), Some(1));
+        let mut first = true;
+        assert_eq!(hashtable.update(1, |x| {
+            // The first attempt loses to this write
+            if first {
+                hashtable.swap(1, x + 1);
+                first = false;
+            }
+            x * 2
+        }), Some(2));
+        let metrics = hashtable.metrics();
+        assert_eq!(metrics.cas_failures, 1);
+        assert!(metrics.mean_probe_length() >= 1.0);
+        assert_eq!(metrics.full_errors, 0);
+    }
+
+    #[test]
+    fn test_swap() {
+        use std::sync::Arc;
+        use std::thread;
+
+        const CLAIMED: u64 = u64::MAX;
+
+        let hashtable: Arc<AtomicHashMap> = Arc::new(AtomicHashMap::new(1 << 8).unwrap());
+        assert_eq!(hashtable.swap(1, 5), None);
+        assert_eq!(hashtable.get(&1), None);
+        assert_eq!(hashtable.swap(0, 5), None);
+
+        for item in 1..=100 {
+            hashtable.insert(item, item * 10).unwrap();
+        }
+        assert_eq!(hashtable.swap(1, 11), Some(10));
+        assert_eq!(hashtable.get(&1), Some(11));
+
+        // Each work item is claimed by exactly one thread
+        let threads: Vec<_> = (0..8).map(|_| {
+            let hashtable = hashtable.clone();
+            thread::spawn(move || {
+                (2..=100).filter(|&item| hashtable.swap(item, CLAIMED) != Some(CLAIMED))
+                    .count()
+            })
+        }).collect();
+
+        let claimed: usize = threads.into_iter().map(|t| t.join().unwrap()).sum();
+        assert_eq!(claimed, 99);
+    }
+
+    #[test]
+    fn test_upsert() {
+        use std::sync::Arc;
+        use std::thread;
+
+        let hashtable: Arc<AtomicHashMap> = Arc::new(AtomicHashMap::new(1 << 6).unwrap());
+        assert_eq!(hashtable.upsert(1, 0b01, |old, new| old | new), Ok(None));
+        assert_eq!(hashtable.upsert(1, 0b10, |old, new| old | new), Ok(Some(0b01)));
+        assert_eq!(hashtable.get(&1), Some(0b11));
+        assert_eq!(hashtable.upsert(0, 1, |old, _| old), Err(AtomicHashMapError::InvalidKey));
+
+        // Every thread sets its own bit of the same coverage words
+        let threads: Vec<_> = (0..8).map(|bit| {
+            let hashtable = hashtable.clone();
+            thread::spawn(move || {
+                for key in 2..=20 {
+                    hashtable.upsert(key, 1 << bit, |old, new| old | new).unwrap();
+                }
+            })
+        }).collect();
+
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        for key in 2..=20 {
+            assert_eq!(hashtable.get(&key), Some(0xff));
+        }
+    }
+
+    #[test]
+    fn test_min_max_to() {
+        use std::sync::Arc;
+        use std::thread;
+
+        let hashtable: AtomicHashMap<u64, i64> = AtomicHashMap::new(1 << 4).unwrap();
+        assert_eq!(hashtable.min_to(1, 5), Ok(None));
+        assert_eq!(hashtable.min_to(1, 7), Ok(Some(5)));
+        assert_eq!(hashtable.min_to(1, -3), Ok(Some(5)));
+        assert_eq!(hashtable.get(&1), Some(-3));
+        assert_eq!(hashtable.max_to(2, -10), Ok(None));
+        assert_eq!(hashtable.max_to(2, -20), Ok(Some(-10)));
+        assert_eq!(hashtable.max_to(2, 4), Ok(Some(-10)));
+        assert_eq!(hashtable.get(&2), Some(4));
+
+        // Fastest and slowest time per input, reported by many threads at once
+        let times: Arc<AtomicHashMap> = Arc::new(AtomicHashMap::new(1 << 6).unwrap());
+        let threads: Vec<_> = (0..8u64).map(|id| {
+            let times = times.clone();
+            thread::spawn(move || {
+                for time in 0..1000 {
+                    let time = (time * 7 + id * 131) % 1000 + 100;
+                    times.min_to(1, time).unwrap();
+                    times.max_to(2, time).unwrap();
+                }
+            })
+        }).collect();
+
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        assert_eq!(times.get(&1), Some(100));
+        assert_eq!(times.get(&2), Some(1099));
+    }
+
+    #[test]
+    fn test_f64_values() {
+        use std::thread;
+        use std::sync::Arc;
+
+        let hashtable: AtomicHashMap<u64, f64> = AtomicHashMap::new(1 << 4).unwrap();
+
+        assert_eq!(hashtable.add_to_f64(1, 1.5), Ok(0.0));
+        assert_eq!(hashtable.add_to_f64(1, -0.25), Ok(1.5));
+        assert_eq!(hashtable.get(&1), Some(1.25));
+
+        assert_eq!(hashtable.max_f64(2, 3.0), Ok(None));
+        assert_eq!(hashtable.max_f64(2, 1.0), Ok(Some(3.0)));
+        assert_eq!(hashtable.max_f64(2, 7.5), Ok(Some(3.0)));
+        assert_eq!(hashtable.max_f64(2, f64::NAN), Ok(Some(7.5)));
+        assert_eq!(hashtable.get(&2), Some(7.5));
+
+        assert_eq!(hashtable.min_f64(3, 3.0), Ok(None));
+        assert_eq!(hashtable.min_f64(3, 4.0), Ok(Some(3.0)));
+        assert_eq!(hashtable.min_f64(3, -1.0), Ok(Some(3.0)));
+        assert_eq!(hashtable.get(&3), Some(-1.0));
+
+        // No additions are lost to racing compare-exchanges
+        let hashtable = Arc::new(hashtable);
+        let threads: Vec<_> = (0..8).map(|t| {
+            let hashtable = hashtable.clone();
+            thread::spawn(move || {
+                for x in 0..1000 {
+                    hashtable.add_to_f64(4, 0.5).unwrap();
+                    hashtable.max_f64(5, (t * 1000 + x) as f64).unwrap();
+                }
+            })
+        }).collect();
+
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        assert_eq!(hashtable.get(&4), Some(4000.0));
+        assert_eq!(hashtable.get(&5), Some(7999.0));
+    }
+
+    #[test]
+    fn test_update_if_eq() {
+        let hashtable: AtomicHashMap = AtomicHashMap::new(1 << 4).unwrap();
+
+        assert_eq!(hashtable.update_if_eq(1, 0, 1), None);
+
+        assert_eq!(hashtable.insert(1, 10), Ok(None));
+        assert_eq!(hashtable.update_if_eq(1, 10, 11), Some(Ok(10)));
+        assert_eq!(hashtable.update_if_eq(1, 10, 12), Some(Err(11)));
+        assert_eq!(hashtable.get(&1), Some(11));
+    }
+
+    #[test]
+    fn test_update_threads() {
+        use std::thread;
+        use std::sync::Arc;
+
+        let size: u64 = 1 << 8;
+        let hashtable = Arc::new(AtomicHashMap::new(size as usize).unwrap());
+        assert_eq!(hashtable.update(1, |old| old + 1), None);
+
+        for x in 1..=size {
+            hashtable.insert(x, 0).unwrap();
+        }
+
+        let mut threads = Vec::new();
+        for i in 0..8 {
+            let hashtable_i = hashtable.clone();
+            let t = thread::spawn(move || {
+                for x in 1..=size {
+                    // Keep the max of every thread's value and set one bit per thread
+                    hashtable_i.update(x, |old| (old.max(i << 8) & !0xff) | (old & 0xff) | (1 << i))
+                        .unwrap();
+                }
+            });
+            threads.push(t);
+        }
+
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        for x in 1..=size {
+            assert_eq!(hashtable.get(&x), Some((7 << 8) | 0xff));
+        }
+    }
+
+    #[test]
+    fn test_iter() {
+        let size: u64 = 1 << 8;
+        let hashtable = AtomicHashMap::new(size as usize).unwrap();
+        assert_eq!(hashtable.iter().next(), None);
+
+        for x in 1..=(size / 2) {
+            hashtable.insert(x, x * 2).unwrap();
+        }
+        assert_eq!(hashtable.remove(1), Some(2));
+
+        let mut entries: Vec<(u64, u64)> = hashtable.iter().collect();
+        entries.sort();
+
+        let expected: Vec<(u64, u64)> = (2..=(size / 2)).map(|x| (x, x * 2)).collect();
+        assert_eq!(entries, expected);
+        assert_eq!((&hashtable).into_iter().count(), expected.len());
+    }
+
+    #[test]
+    fn test_keys_values_to_vec() {
+        let hashtable: AtomicHashMap = AtomicHashMap::new(1 << 4).unwrap();
+        for x in 1..=4 {
+            hashtable.insert(x, x + 100).unwrap();
+        }
+
+        let mut keys: Vec<u64> = hashtable.keys().collect();
+        keys.sort();
+        assert_eq!(keys, vec![1, 2, 3, 4]);
+
+        let mut values: Vec<u64> = hashtable.values().collect();
+        values.sort();
+        assert_eq!(values, vec![101, 102, 103, 104]);
+
+        let mut entries = hashtable.to_vec();
+        entries.sort();
+        assert_eq!(entries, vec![(1, 101), (2, 102), (3, 103), (4, 104)]);
+    }
+
+    #[test]
+    fn test_contains_key() {
+        let hashtable: AtomicHashMap = AtomicHashMap::new(1 << 4).unwrap();
+        assert!(!hashtable.contains_key(&1));
+
+        hashtable.insert(1, 0).unwrap();
+        assert!(hashtable.contains_key(&1));
+        assert!(!hashtable.contains_key(&2));
+
+        hashtable.remove(1);
+        assert!(!hashtable.contains_key(&1));
+    }
+
+    #[test]
+    fn test_capacity() {
+        let size: u64 = 1 << 4;
+        let hashtable = AtomicHashMap::new(size as usize).unwrap();
+        assert_eq!(hashtable.capacity(), size as usize);
+        assert_eq!(hashtable.remaining_capacity(), size as usize);
+        assert!(hashtable.is_empty());
+        assert!(!hashtable.is_full());
+
+        for x in 1..=size {
+            hashtable.insert(x, x).unwrap();
+        }
+        assert_eq!(hashtable.remaining_capacity(), 0);
+        assert!(!hashtable.is_empty());
+        assert!(hashtable.is_full());
+
+        hashtable.remove(1);
+        assert_eq!(hashtable.remaining_capacity(), 1);
+        assert!(!hashtable.is_full());
+    }
+
+    #[test]
+    fn test_len_threads() {
+        use std::thread;
+        use std::sync::Arc;
+
+        let size: u64 = 1 << 10;
+        let hashtable = Arc::new(AtomicHashMap::new(size as usize).unwrap());
+
+        // Keys with a value of 0 are still counted
+        hashtable.insert(1, 0).unwrap();
+        assert_eq!(hashtable.len(), 1);
+
+        let mut threads = Vec::new();
+        for _ in 0..8 {
+            let hashtable_i = hashtable.clone();
+            let t = thread::spawn(move || {
+                for x in 1..=size {
+                    hashtable_i.insert(x, x).unwrap();
+                }
+                for x in (1..=size).step_by(2) {
+                    hashtable_i.remove(x);
+                }
+            });
+            threads.push(t);
+        }
+
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        assert_eq!(hashtable.len(), size / 2);
+        assert_eq!(hashtable.iter().count() as u64, size / 2);
+    }
+
+    #[test]
+    fn test_clear() {
+        let size: u64 = 1 << 4;
+        let mut hashtable = AtomicHashMap::new(size as usize).unwrap();
+
+        for _ in 0..2 {
+            for x in 1..=size {
+                hashtable.insert(x, x).unwrap();
+            }
+            hashtable.remove(1);
+            assert!(!hashtable.is_full());
+
+            hashtable.clear();
+            assert!(hashtable.is_empty());
+            assert_eq!(hashtable.get(&2), None);
+            assert_eq!(hashtable.stats().tombstones, size as usize);
+        }
+
+        for x in 1..=size {
+            hashtable.insert(x, x).unwrap();
+        }
+        hashtable.clear_mut();
+        assert!(hashtable.is_empty());
+        assert_eq!(hashtable.iter().count(), 0);
+        assert_eq!(hashtable.stats().tombstones, 0);
+
+        // The whole table is usable again
+        for x in 1..=size {
+            assert_eq!(hashtable.insert(x + 100, x), Ok(None));
+        }
+    }
+
+    #[test]
+    fn test_retain() {
+        let size: u64 = 1 << 8;
+        let hashtable = AtomicHashMap::new(size as usize).unwrap();
+
+        for x in 1..=size {
+            hashtable.insert(x, x * 10).unwrap();
+        }
+
+        // Drop every entry with an odd key or a value over 2000
+        hashtable.retain(|key, value| key % 2 == 0 && value <= 2000);
+
+        for x in 1..=size {
+            let expected = if x % 2 == 0 && x * 10 <= 2000 { Some(x * 10) } else { None };
+            assert_eq!(hashtable.get(&x), expected);
+        }
+        assert_eq!(hashtable.len(), 100);
+    }
+
+    #[test]
+    fn test_retain_threads() {
+        let size: u64 = 1 << 10;
+        let hashtable = AtomicHashMap::new(size as usize * 2).unwrap();
+        for x in 1..=size {
+            hashtable.insert(x, 0).unwrap();
+        }
+
+        std::thread::scope(|scope| {
+            // Retain drops entries holding 0 but gives each one another value first, so
+            // it always loses the check of the value and leaves the entry in place
+            let reinserted = scope.spawn(|| {
+                let mut reinserted = 0;
+                hashtable.retain(|key, value| {
+                    if value == 0 && hashtable.insert(key, 1).unwrap().is_none() {
+                        // Removed below before we got to it
+                        reinserted += 1;
+                    }
+                    value != 0
+                });
+                reinserted
+            });
+
+            // A key retain leaves in place is never seen missing, and a removal
+            // racing with its check isn't lost
+            for x in 1..=size {
+                assert!(matches!(hashtable.get(&x), Some(0) | Some(1)));
+                assert!(matches!(hashtable.remove(x), Some(0) | Some(1)));
+            }
+
+            assert_eq!(hashtable.len(), reinserted.join().unwrap());
+        });
+    }
+
+    #[test]
+    fn test_drain() {
+        let size: u64 = 1 << 8;
+        let hashtable = AtomicHashMap::new(size as usize).unwrap();
+
+        for x in 1..=size {
+            hashtable.insert(x, x + 1).unwrap();
+        }
+        hashtable.remove(1);
+
+        let mut entries: Vec<(u64, u64)> = hashtable.drain().collect();
+        entries.sort();
+        let expected: Vec<(u64, u64)> = (2..=size).map(|x| (x, x + 1)).collect();
+        assert_eq!(entries, expected);
+        assert!(hashtable.is_empty());
+
+        // Every slot taken out is left as a tombstone
+        assert_eq!(hashtable.stats().tombstones, size as usize);
+
+        // Keys probed past a slot already drained are still found while the drain runs
+        for x in 1..=size {
+            hashtable.insert(x, x).unwrap();
+        }
+        let mut drain = hashtable.drain();
+        let (taken, _) = drain.next().unwrap();
+        for x in (1..=size).filter(|&x| x != taken) {
+            assert_eq!(hashtable.get(&x), Some(x));
+        }
+
+        // Dropping a partially consumed drain still empties the table
+        drop(drain);
+        assert!(hashtable.is_empty());
+        assert_eq!(hashtable.iter().count(), 0);
+    }
+
+    #[test]
+    fn test_pod_values() {
+        let hashtable: AtomicHashMap<u64, (u32, u32)> = AtomicHashMap::new(1 << 4).unwrap();
+
+        assert_eq!(hashtable.insert(1, (2, 3)), Ok(None));
+        assert_eq!(hashtable.insert(1, (4, 5)), Ok(Some((2, 3))));
+        assert_eq!(hashtable.update(1, |(a, b)| (b, a)), Some((4, 5)));
+        assert_eq!(hashtable.get(&1), Some((5, 4)));
+        assert_eq!(hashtable.to_vec(), vec![(1, (5, 4))]);
+
+        let hashtable: AtomicHashMap<u64, f64> = AtomicHashMap::new(1 << 4).unwrap();
+        assert_eq!(hashtable.get_or_insert(1, -1.5), Ok(-1.5));
+        assert_eq!(hashtable.update_if_eq(1, -1.5, 2.25), Some(Ok(-1.5)));
+        assert_eq!(hashtable.remove(1), Some(2.25));
+    }
+
+    #[test]
+    fn test_murmur_hasher_matches_hash_key() {
+        for x in [1u64, 2, 0xdead_beef, u64::MAX - 1].iter() {
+            let mut hasher = MurmurHasher::default();
+            hasher.write_u64(*x);
+            assert_eq!(hasher.finish(), hash_key(*x));
+        }
+    }
+
+    #[test]
+    fn test_generic_keys_and_hasher() {
+        use std::collections::hash_map::RandomState;
+
+        let hashtable: AtomicHashMap<(u32, u32), u8, RandomState> = 
+            AtomicHashMap::with_hasher(1 << 6, RandomState::new()).unwrap();
+
+        for x in 0..32u32 {
+            assert_eq!(hashtable.insert((x, x + 1), x as u8), Ok(None));
+        }
+
+        for x in 0..32u32 {
+            assert_eq!(hashtable.get(&(x, x + 1)), Some(x as u8));
+            assert!(!hashtable.contains_key(&(x + 1, x)));
+        }
+
+        assert_eq!(hashtable.remove((3, 4)), Some(3));
+        let mut keys: Vec<(u32, u32)> = hashtable.keys().collect();
+        keys.sort();
+        assert_eq!(keys.len(), 31);
+        assert_eq!(keys[0], (0, 1));
+    }
+
+    #[test]
+    fn test_sentinels() {
+        let size: u64 = 1 << 6;
+        let hashtable: AtomicHashMap = 
+            AtomicHashMap::with_sentinels(size as usize, u64::MAX - 1, u64::MAX).unwrap();
+
+        // Key 0 is a normal key now
+        for x in 0..(size - 1) {
+            assert_eq!(hashtable.insert(x, x + 1), Ok(None));
+        }
+        assert_eq!(hashtable.get(&0), Some(1));
+        assert_eq!(hashtable.remove(0), Some(1));
+        assert_eq!(hashtable.get(&0), None);
+        assert_eq!(hashtable.insert(0, 5), Ok(None));
+
+        let mut keys: Vec<u64> = hashtable.keys().collect();
+        keys.sort();
+        assert_eq!(keys, (0..(size - 1)).collect::<Vec<u64>>());
+
+        hashtable.clear();
+        assert!(hashtable.is_empty());
+        assert_eq!(hashtable.insert(0, 5), Ok(None));
+    }
+
+    #[test]
+    fn test_invalid_key() {
+        let hashtable: AtomicHashMap = AtomicHashMap::with_sentinels(1 << 4, 7, 8).unwrap();
+        assert_eq!(hashtable.insert(7, 1), Err(AtomicHashMapError::InvalidKey));
+        assert_eq!(hashtable.add_to(8, 1), Err(AtomicHashMapError::InvalidKey));
+        assert_eq!(hashtable.get_or_insert(8, 1), Err(AtomicHashMapError::InvalidKey));
+
+        // Sentinels are never in the map
+        assert_eq!(hashtable.get(&7), None);
+        assert!(!hashtable.contains_key(&8));
+        assert_eq!(hashtable.remove(8), None);
+
+        let hashtable: AtomicHashMap = AtomicHashMap::new(1 << 4).unwrap();
+        assert_eq!(hashtable.insert(0, 1), Err(AtomicHashMapError::InvalidKey));
+        assert_eq!(hashtable.insert(u64::MAX, 1), Err(AtomicHashMapError::InvalidKey));
+    }
+
+    #[test]
+    fn test_invalid_construction() {
+        assert_eq!(AtomicHashMap::<u64, u64>::new(1000).err(), 
+                   Some(AtomicHashMapError::InvalidCapacity));
+        assert_eq!(AtomicHashMap::<u64, u64>::new(0).err(), 
+                   Some(AtomicHashMapError::InvalidCapacity));
+        assert_eq!(AtomicHashMap::<u64, u64>::with_sentinels(1 << 4, 3, 3).err(), 
+                   Some(AtomicHashMapError::InvalidKey));
+    }
+
+    #[test]
+    fn test_signed_keys() {
+        // Narrow keys are zero-extended, so -1 is only reserved for 64-bit keys
+        let hashtable: AtomicHashMap<i32, u64> = AtomicHashMap::new(1 << 4).unwrap();
+        assert_eq!(hashtable.insert(-1, 1), Ok(None));
+        assert_eq!(hashtable.insert(i32::MIN, 2), Ok(None));
+        assert_eq!(hashtable.get(&-1), Some(1));
+        assert_eq!(hashtable.remove(-1), Some(1));
+        assert_eq!(hashtable.insert(0, 1), Err(AtomicHashMapError::InvalidKey));
+
+        let hashtable: AtomicHashMap<i64, u64> = AtomicHashMap::new(1 << 4).unwrap();
+        assert_eq!(hashtable.insert(-1, 1), Err(AtomicHashMapError::InvalidKey));
+        assert_eq!(hashtable.insert(-2, 1), Ok(None));
+    }
+
+    #[test]
+    fn test_new_rounded() {
+        let hashtable: AtomicHashMap = AtomicHashMap::new_rounded(1000).unwrap();
+        assert_eq!(hashtable.capacity(), 1024);
+        assert_eq!(hashtable.requested_capacity(), 1000);
+
+        let hashtable: AtomicHashMap = AtomicHashMap::new_rounded(64).unwrap();
+        assert_eq!(hashtable.capacity(), 64);
+        assert_eq!(hashtable.requested_capacity(), 64);
+
+        let hashtable: AtomicHashMap = AtomicHashMap::new_rounded(0).unwrap();
+        assert_eq!(hashtable.capacity(), 2);
+
+        assert_eq!(AtomicHashMap::<u64, u64>::new_rounded(usize::MAX).err(), 
+                   Some(AtomicHashMapError::InvalidCapacity));
+    }
+
+    #[test]
+    fn test_builder() {
+        use std::collections::hash_map::RandomState;
+
+        let hashtable: AtomicHashMap<u64, u64, RandomState> = AtomicHashMap::builder(100)
+            .round_up(true)
+            .sentinels(5, 6)
+            .hasher(RandomState::new())
+            .build()
+            .unwrap();
+
+        assert_eq!(hashtable.capacity(), 128);
+        assert_eq!(hashtable.requested_capacity(), 100);
+        assert_eq!(hashtable.insert(0, 1), Ok(None));
+        assert_eq!(hashtable.insert(5, 1), Err(AtomicHashMapError::InvalidKey));
+
+        // Invalid combinations are rejected by build
+        assert_eq!(AtomicHashMapBuilder::<u64, u64>::new(100).build().err(),
+                   Some(AtomicHashMapError::InvalidCapacity));
+        assert_eq!(AtomicHashMapBuilder::<u64, u64>::new(128).sentinels(1, 1).build().err(),
+                   Some(AtomicHashMapError::InvalidKey));
+    }
+
+    #[test]
+    fn test_eviction() {
+        use crate::eviction::{LowestValueEviction, RandomEviction};
+        use std::thread;
+
+        let hashtable: AtomicHashMap = AtomicHashMap::builder(1 << 4)
+            .eviction(LowestValueEviction)
+            .build()
+            .unwrap();
+        for key in 1..=16 {
+            hashtable.insert(key, key).unwrap();
+        }
+
+        // The lowest value makes room for the new key
+        assert_eq!(hashtable.insert(17, 100), Ok(None));
+        assert_eq!(hashtable.get(&1), None);
+        assert_eq!(hashtable.get(&17), Some(100));
+        assert_eq!(hashtable.len(), 16);
+        assert_eq!(hashtable.get_or_insert(18, 200), Ok(200));
+        assert_eq!(hashtable.get(&2), None);
+
+        // Evicted entries come from the probe of the new key, so they stay within the
+        // probe limit
+        let hashtable: AtomicHashMap = AtomicHashMap::builder(1 << 8)
+            .max_probe(16)
+            .eviction(RandomEviction::default())
+            .build()
+            .unwrap();
+        thread::scope(|scope| {
+            for thread in 0..4 {
+                let hashtable = &hashtable;
+                scope.spawn(move || {
+                    for key in 1..=1000 {
+                        hashtable.insert(thread * 1000 + key, key).unwrap();
+                    }
+                });
+            }
+        });
+        assert!(hashtable.len() <= 1 << 8);
+        assert_eq!(hashtable.iter().count() as u64, hashtable.len());
+    }
+
+    #[test]
+    fn test_compact() {
+        let size: u64 = 1 << 10;
+        let mut hashtable = AtomicHashMap::new(size as usize).unwrap();
+
+        for x in 1..=size {
+            hashtable.insert(x, x + 7).unwrap();
+        }
+
+        // Leave only a handful of live keys behind a sea of tombstones
+        for x in 1..=size {
+            if x % 100 != 0 {
+                hashtable.remove(x);
+            }
+        }
+
+        hashtable.compact();
+        assert_eq!(hashtable.capacity(), 32);
+        assert_eq!(hashtable.requested_capacity(), 32);
+        assert_eq!(hashtable.len(), 10);
+        for x in 1..=size {
+            let expected = if x % 100 == 0 { Some(x + 7) } else { None };
+            assert_eq!(hashtable.get(&x), expected);
+        }
+
+        // The compacted table is fully usable
+        for x in 1..=6 {
+            assert_eq!(hashtable.insert(x, x), Ok(None));
+        }
+
+        // A table more than half full keeps its size
+        hashtable.compact();
+        assert_eq!(hashtable.capacity(), 32);
+        assert_eq!(hashtable.len(), 16);
+    }
+
+    #[test]
+    fn test_publish_value() {
+        use std::thread;
+        use std::sync::Arc;
+
+        let size: u64 = 1 << 12;
+        let hashtable: Arc<AtomicHashMap> = Arc::new(AtomicHashMap::new(size as usize).unwrap());
+
+        let writer = {
+            let hashtable = hashtable.clone();
+            thread::spawn(move || {
+                for x in 1..size {
+                    hashtable.insert(x, x).unwrap();
+                }
+            })
+        };
+
+        // A key is never seen before its value
+        let mut readers = Vec::new();
+        for _ in 0..4 {
+            let hashtable = hashtable.clone();
+            readers.push(thread::spawn(move || {
+                for x in 1..size {
+                    while !hashtable.contains_key(&x) {}
+                    assert_eq!(hashtable.get(&x), Some(x));
+                }
+            }));
+        }
+
+        writer.join().unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_ordering_profile() {
+        let hashtable: AtomicHashMap = AtomicHashMap::new(1 << 4).unwrap();
+        assert_eq!(hashtable.ordering_profile(), OrderingProfile::AcquireRelease);
+
+        for profile in [OrderingProfile::Relaxed, OrderingProfile::SeqCst].iter() {
+            let hashtable: AtomicHashMap = AtomicHashMap::builder(1 << 4)
+                .ordering(*profile)
+                .build()
+                .unwrap();
+            assert_eq!(hashtable.ordering_profile(), *profile);
+
+            assert_eq!(hashtable.add_to(5, 2), Ok(0));
+            assert_eq!(hashtable.add_to(5, 3), Ok(2));
+            assert_eq!(hashtable.update(5, |x| x * 2), Some(5));
+            assert_eq!(hashtable.remove(5), Some(10));
+        }
+    }
+
+    #[test]
+    fn test_stats() {
+        let hashtable: AtomicHashMap = AtomicHashMap::new(1 << 6).unwrap();
+        let stats = hashtable.stats();
+        assert_eq!(stats.live, 0);
+        assert_eq!(stats.max_probe_length(), 0);
+        assert_eq!(stats.mean_probe_length(), 0.0);
+
+        for x in 1..=48 {
+            hashtable.insert(x, x).unwrap();
+        }
+        for x in 1..=8 {
+            hashtable.remove(x);
+        }
+
+        let stats = hashtable.stats();
+        assert_eq!(stats.capacity, 1 << 6);
+        assert_eq!(stats.live, 40);
+        assert_eq!(stats.tombstones, 8);
+        assert_eq!(stats.load_factor(), 40.0 / 64.0);
+        assert_eq!(stats.occupancy(), 48.0 / 64.0);
+
+        // Every key shows up in the histogram exactly once
+        assert_eq!(stats.probe_lengths.iter().sum::<usize>(), 40);
+        assert!(stats.probe_lengths[0] > 0);
+        assert!(stats.mean_probe_length() >= 1.0);
+        assert!(stats.max_probe_length() as f64 >= stats.mean_probe_length());
+    }
+
+    #[test]
+    fn test_max_probe() {
+        let mut hashtable: AtomicHashMap = AtomicHashMap::builder(1 << 10)
+            .max_probe(16)
+            .build()
+            .unwrap();
+        assert_eq!(hashtable.max_probe(), Some(16));
+
+        // Fails long before every slot is used
+        let mut inserted = Vec::new();
+        for x in 1..=1 << 10 {
+            match hashtable.insert(x, x) {
+                Ok(None) => inserted.push(x),
+                Err(AtomicHashMapError::Full) => break,
+                res => panic!("Unexpected insert result {:?}", res)
+            }
+        }
+        assert!(inserted.len() < 1 << 10);
+
+        // The remaining capacity is only an upper bound under a probe limit
+        assert!(!hashtable.is_full());
+        assert_eq!(hashtable.remaining_capacity(), (1 << 10) - inserted.len());
+
+        // Keys already in the table are still found and updated
+        for x in inserted.iter() {
+            assert_eq!(hashtable.insert(*x, x + 1), Ok(Some(*x)));
+        }
+
+        for x in inserted.iter().skip(1) {
+            hashtable.remove(*x);
+        }
+
+        hashtable.compact();
+        assert_eq!(hashtable.to_vec(), vec![(1, 2)]);
+        assert_eq!(hashtable.max_probe(), Some(16));
+    }
+
+    /// Any of the probe strategies
+    fn any_strategy() -> impl proptest::strategy::Strategy<Value = ProbeStrategy> {
+        use proptest::strategy::Just;
+
+        proptest::prop_oneof![Just(ProbeStrategy::Linear), Just(ProbeStrategy::Quadratic),
+                              Just(ProbeStrategy::DoubleHash)]
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn test_inserted_keys_retrievable(
+                size_log2 in 1..=10u32,
+                strategy in any_strategy(),
+                max_probe in proptest::option::of(1..=1024usize),
+                narrow in proptest::bool::ANY,
+                keys in proptest::collection::vec(1..u64::MAX, 1..4096)) {
+            let size = 1 << size_log2;
+            let mut builder = AtomicHashMap::builder(size).probe(strategy);
+            if let Some(max_probe) = max_probe {
+                builder = builder.max_probe((max_probe - 1) % size + 1);
+            }
+            let hashtable: AtomicHashMap = builder.build().unwrap();
+
+            // Keys from a small range collide often, keys from the whole range probe
+            // from anywhere in the table
+            let mut inserted = std::collections::HashMap::new();
+            let mut full = false;
+            for (count, &key) in keys.iter().enumerate() {
+                let key = if narrow { key % (size as u64 * 4) + 1 } else { key };
+                match hashtable.insert(key, !key) {
+                    Ok(prev) => {
+                        proptest::prop_assert_eq!(prev, inserted.insert(key, !key));
+                    }
+                    Err(AtomicHashMapError::Full) => {
+                        full = true;
+                        break;
+                    }
+                    Err(err) => panic!("Unexpected error {:?}", err)
+                }
+
+                // Everything inserted so far is still there
+                if count % 64 == 0 {
+                    for (key, value) in inserted.iter() {
+                        proptest::prop_assert_eq!(hashtable.get(key), Some(*value));
+                    }
+                }
+            }
+
+            if full && max_probe.is_none() {
+                proptest::prop_assert_eq!(inserted.len(), size);
+            }
+            proptest::prop_assert_eq!(hashtable.len(), inserted.len() as u64);
+            for (key, value) in inserted.iter() {
+                proptest::prop_assert_eq!(hashtable.get(key), Some(*value));
+            }
+        }
+    }
+
+    #[test]
+    fn test_probe_strategy() {
+        let hashtable: AtomicHashMap = AtomicHashMap::new(1 << 4).unwrap();
+        assert_eq!(hashtable.probe_strategy(), ProbeStrategy::Linear);
+
+        for strategy in [ProbeStrategy::Quadratic, ProbeStrategy::DoubleHash].iter() {
+            let mut hashtable: AtomicHashMap = AtomicHashMap::builder(1 << 8)
+                .probe(*strategy)
+                .build()
+                .unwrap();
+            assert_eq!(hashtable.probe_strategy(), *strategy);
+
+            // Every slot is reachable
+            for x in 1..=1 << 8 {
+                assert_eq!(hashtable.insert(x, x), Ok(None));
+            }
+            assert_eq!(hashtable.insert(0x1000, 1), Err(AtomicHashMapError::Full));
+
+            for x in (2..=1 << 8).step_by(2) {
+                assert_eq!(hashtable.remove(x), Some(x));
+            }
+
+            // Lookups still follow the same probe once tombstones are dropped
+            hashtable.compact();
+            for x in 1..=1 << 8 {
+                let expected = if x % 2 == 1 { Some(x) } else { None };
+                assert_eq!(hashtable.get(&x), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_batch() {
+        let hashtable: AtomicHashMap = AtomicHashMap::new(1 << 12).unwrap();
+
+        let items: Vec<(u64, u64)> = (1..=1000).map(|x| (x, x * 3)).collect();
+        let results = hashtable.insert_batch(&items);
+        assert!(results.iter().all(|res| *res == Ok(None)));
+        assert_eq!(hashtable.len(), 1000);
+
+        // Duplicates keep the order they were given in, and errors stay in place
+        let results = hashtable.insert_batch(&[(5, 1), (0, 1), (5, 2), (2000, 7)]);
+        assert_eq!(results, vec![Ok(Some(15)), Err(AtomicHashMapError::InvalidKey),
+                                 Ok(Some(1)), Ok(None)]);
+        assert_eq!(hashtable.get(&5), Some(2));
+
+        let keys: Vec<u64> = (0..=1001).rev().collect();
+        let values = hashtable.get_batch(&keys);
+        for (key, value) in keys.iter().zip(values) {
+            let expected = match *key {
+                0 | 1001 => None,
+                5 => Some(2),
+                key => Some(key * 3)
+            };
+            assert_eq!(value, expected);
+        }
+
+        assert!(hashtable.get_batch(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_get_prefetched() {
+        use crate::rng::Rng;
+
+        let mut rng = Rng::new(0x5eed);
+        let hashtable: AtomicHashMap = AtomicHashMap::builder(1 << 10)
+            .probe(ProbeStrategy::DoubleHash)
+            .build()
+            .unwrap();
+
+        // Fill the table most of the way so probes are long, with tombstones on them
+        for key in 1..=900 {
+            hashtable.insert(key, key + 1).unwrap();
+        }
+        for key in (1..=900).step_by(3) {
+            hashtable.remove(key);
+        }
+
+        for _ in 0..1000 {
+            let key = rng.below(1200);
+            assert_eq!(hashtable.get_prefetched(&key), hashtable.get(&key));
+        }
+
+        let keys = [0, 1, 2, 3, 899, 900, 901, u64::MAX];
+        assert_eq!(hashtable.get_many(keys), keys.map(|key| hashtable.get(&key)));
+        assert_eq!(hashtable.get_many(keys)[..4], [None, None, Some(3), Some(4)]);
+        assert_eq!(hashtable.get_many::<0>([]), []);
+    }
+
+    #[test]
+    fn test_huge_pages() {
+        // Large enough to span several huge pages
+        let mut hashtable: AtomicHashMap = AtomicHashMap::builder(1 << 18)
+            .huge_pages(true)
+            .build()
+            .unwrap();
+        for x in 1..=1000 {
+            hashtable.insert(x, x * 2).unwrap();
+        }
+        for x in 1..=500 {
+            hashtable.remove(x);
+        }
+
+        // Rebuilt tables keep the setting
+        hashtable.compact();
+        assert_eq!(hashtable.len(), 500);
+        assert_eq!(hashtable.get(&1000), Some(2000));
+        assert_eq!(hashtable.clone().get(&501), Some(1002));
+    }
+
+    #[test]
+    fn test_padded() {
+        let hashtable: AtomicHashMap = AtomicHashMap::builder(1 << 5)
+            .padded(true)
+            .build()
+            .unwrap();
+        assert!(hashtable.is_padded());
+        assert_eq!(hashtable.capacity(), 1 << 5);
+
+        // Every slot is still usable
+        for x in 1..=32 {
+            assert_eq!(hashtable.add_to(x, x), Ok(0));
+        }
+        assert_eq!(hashtable.insert(33, 33), Err(AtomicHashMapError::Full));
+
+        for x in 1..=28 {
+            hashtable.remove(x);
+        }
+
+        let mut hashtable = hashtable;
+        hashtable.compact();
+        assert!(hashtable.is_padded());
+        assert_eq!(hashtable.capacity(), 8);
+
+        let mut entries = hashtable.to_vec();
+        entries.sort();
+        assert_eq!(entries, vec![(29, 29), (30, 30), (31, 31), (32, 32)]);
     }
 }
@@ -0,0 +1,171 @@
+//! Exponential backoff for compare-exchange loops and threads waiting on each other
+//!
+//! A loop that loses a compare-exchange to another thread, or finds another thread
+//! in its way, does better to wait a little before trying again than to hammer the
+//! cache line everybody is fighting over. `Backoff` doubles the wait on every step:
+//!
+//! * `spin` is for retrying after a lost race. The other thread made progress, so the
+//!   retry is likely to succeed soon, and the wait stays a short spin of at most
+//!   `2^SPIN_LIMIT` spin-loop hints.
+//! * `snooze` is for waiting on another thread to finish something, like releasing a
+//!   lock. Once spinning has gone on for `SPIN_LIMIT` steps, it yields the time slice
+//!   with `std` instead, in case the thread being waited on isn't running.
+//!
+//! After `YIELD_LIMIT` steps `is_completed` returns true, telling a caller that has a
+//! way to block, like parking the thread, that it is time to use it.
+//!
+//! When the crate is built with `--cfg loom`, every wait is a single yield to the
+//! model's scheduler, which otherwise never runs the thread being waited on.
+
+/// Log2 of the most spin-loop hints of one step
+pub const SPIN_LIMIT: u32 = 6;
+
+/// Number of steps after which the backoff is completed
+pub const YIELD_LIMIT: u32 = 10;
+
+/// Exponentially growing wait of a thread retrying an operation
+///
+/// ```
+/// use atomics_rs::Backoff;
+/// use core::sync::atomic::{AtomicU64, Ordering};
+///
+/// // Double a counter, backing off whenever another thread changed it first
+/// let counter = AtomicU64::new(3);
+/// let mut backoff = Backoff::new();
+/// let mut curr = counter.load(Ordering::Relaxed);
+/// loop {
+///     match counter.compare_exchange_weak(curr, curr * 2, Ordering::AcqRel,
+///                                         Ordering::Relaxed) {
+///         Ok(_) => break,
+///         Err(new) => curr = new
+///     }
+///     backoff.spin();
+/// }
+/// assert_eq!(counter.load(Ordering::Relaxed), 6);
+/// ```
+#[derive(Debug, Default)]
+pub struct Backoff {
+    step: u32
+}
+
+impl Backoff {
+    /// Construct a backoff that starts with the shortest wait
+    pub const fn new() -> Backoff {
+        Backoff { step: 0 }
+    }
+
+    /// Start over with the shortest wait, such as after the operation succeeded
+    pub fn reset(&mut self) {
+        self.step = 0;
+    }
+
+    /// Wait after losing a race to another thread, twice as long as last time up to
+    /// `2^SPIN_LIMIT` spin-loop hints. Never yields the time slice.
+    pub fn spin(&mut self) {
+        pause(self.step.min(SPIN_LIMIT));
+
+        if self.step <= SPIN_LIMIT {
+            self.step += 1;
+        }
+    }
+
+    /// Wait for another thread to make progress, twice as long as last time. Past
+    /// `SPIN_LIMIT` steps, yields the time slice with `std` and keeps spinning the
+    /// longest wait without it.
+    pub fn snooze(&mut self) {
+        if self.step <= SPIN_LIMIT {
+            pause(self.step);
+        } else {
+            // Tests link `std` even without the feature, and fair locks crawl on a
+            // busy core if waiters never let the holder run
+            #[cfg(all(any(feature = "std", test), not(loom)))]
+            std::thread::yield_now();
+
+            #[cfg(any(all(not(feature = "std"), not(test)), loom))]
+            pause(SPIN_LIMIT);
+        }
+
+        if self.step < YIELD_LIMIT {
+            self.step += 1;
+        }
+    }
+
+    /// Returns true once the backoff went through `YIELD_LIMIT` steps, and a caller
+    /// able to block should do so rather than keep snoozing
+    pub fn is_completed(&self) -> bool {
+        self.step >= YIELD_LIMIT
+    }
+}
+
+/// Spin for `2^step` spin-loop hints, or yield once to loom
+#[inline]
+fn pause(step: u32) {
+    #[cfg(loom)]
+    let step = {
+        let _ = step;
+        0
+    };
+
+    for _ in 0..1u32 << step {
+        crate::sync::spin_loop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    #[test]
+    fn test_backoff_steps() {
+        let mut backoff = Backoff::new();
+
+        // Spinning alone never completes the backoff
+        for _ in 0..2 * YIELD_LIMIT {
+            backoff.spin();
+        }
+        assert!(!backoff.is_completed());
+
+        backoff.reset();
+        for _ in 1..YIELD_LIMIT {
+            backoff.snooze();
+            assert!(!backoff.is_completed());
+        }
+        backoff.snooze();
+        assert!(backoff.is_completed());
+
+        // A completed backoff keeps snoozing at the longest wait
+        backoff.snooze();
+        assert!(backoff.is_completed());
+        backoff.reset();
+        assert!(!backoff.is_completed());
+    }
+
+    #[test]
+    fn test_backoff_threads() {
+        let counter = AtomicU64::new(0);
+
+        // Every increment is a compare-exchange loop backing off on lost races
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                let counter = &counter;
+                scope.spawn(move || {
+                    for _ in 0..10_000 {
+                        let mut backoff = Backoff::new();
+                        let mut curr = counter.load(Ordering::Relaxed);
+                        while let Err(new) = counter.compare_exchange_weak(
+                                curr, curr + 1, Ordering::Relaxed, Ordering::Relaxed) {
+                            curr = new;
+                            backoff.spin();
+                        }
+                    }
+                });
+            }
+        });
+
+        assert_eq!(counter.load(Ordering::Relaxed), 40_000);
+    }
+}
@@ -0,0 +1,172 @@
+//! Lock-free bitset over an array of `AtomicU64` words
+//!
+//! Each bit is changed with a single `fetch_or` or `fetch_and` on its word, so bits
+//! sharing a word never overwrite each other. Bit operations use `AcqRel` and reads
+//! `Acquire`, so a thread that sees a bit set also sees what the setting thread did
+//! before setting it, which makes bits usable as ready or claimed flags.
+
+use alloc::boxed::Box;
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Number of bits per word
+const WORD_BITS: usize = 64;
+
+/// Lock-free set of `len` bits, all clear at first
+///
+/// Indices past `len` panic, the same as out of bounds slice indices.
+pub struct AtomicBitmap {
+    words: Box<[AtomicU64]>,
+    len: usize
+}
+
+impl AtomicBitmap {
+    /// Construct a new bitmap of `len` clear bits
+    pub fn new(len: usize) -> AtomicBitmap {
+        AtomicBitmap {
+            words: (0..len.div_ceil(WORD_BITS)).map(|_| AtomicU64::new(0)).collect(),
+            len
+        }
+    }
+
+    /// Get the word holding bit `index` and the mask of the bit within it
+    fn word(&self, index: usize) -> (&AtomicU64, u64) {
+        assert!(index < self.len, "bit index {} out of range for AtomicBitmap of {} bits",
+                index, self.len);
+
+        (&self.words[index / WORD_BITS], 1 << (index % WORD_BITS))
+    }
+
+    /// Atomically set bit `index`
+    pub fn set(&self, index: usize) {
+        self.set_and_test(index);
+    }
+
+    /// Atomically clear bit `index`
+    pub fn clear(&self, index: usize) {
+        let (word, mask) = self.word(index);
+        word.fetch_and(!mask, Ordering::AcqRel);
+    }
+
+    /// Check if bit `index` is set
+    pub fn test(&self, index: usize) -> bool {
+        let (word, mask) = self.word(index);
+        word.load(Ordering::Acquire) & mask != 0
+    }
+
+    /// Atomically set bit `index`, returning whether it was already set
+    ///
+    /// Of many threads setting the same clear bit, exactly one gets back false.
+    pub fn set_and_test(&self, index: usize) -> bool {
+        let (word, mask) = self.word(index);
+        word.fetch_or(mask, Ordering::AcqRel) & mask != 0
+    }
+
+    /// Find the lowest clear bit, or `None` if every bit is set
+    ///
+    /// Words are read one at a time, so under concurrent updates the bit returned was
+    /// clear when its word was read but may have been set since. Follow up with
+    /// `set_and_test` to claim it.
+    pub fn find_first_zero(&self) -> Option<usize> {
+        self.words.iter().enumerate()
+            .find_map(|(index, word)| {
+                let zeros = !word.load(Ordering::Acquire);
+                (zeros != 0).then(|| index * WORD_BITS + zeros.trailing_zeros() as usize)
+            })
+            .filter(|&index| index < self.len)
+    }
+
+    /// Get the number of set bits
+    pub fn count_ones(&self) -> usize {
+        self.words.iter()
+            .map(|word| word.load(Ordering::Acquire).count_ones() as usize)
+            .sum()
+    }
+
+    /// Clear every bit with exclusive access
+    pub fn clear_all(&mut self) {
+        for word in self.words.iter() {
+            word.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Get the number of bits
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if the bitmap has no bits
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    #[test]
+    fn test_bitmap() {
+        let mut bitmap = AtomicBitmap::new(100);
+        assert_eq!(bitmap.len(), 100);
+        assert_eq!(bitmap.find_first_zero(), Some(0));
+
+        bitmap.set(0);
+        bitmap.set(64);
+        assert!(bitmap.test(0));
+        assert!(!bitmap.test(1));
+        assert!(bitmap.test(64));
+        assert!(!bitmap.set_and_test(1));
+        assert!(bitmap.set_and_test(1));
+        assert_eq!(bitmap.find_first_zero(), Some(2));
+        assert_eq!(bitmap.count_ones(), 3);
+
+        bitmap.clear(0);
+        assert!(!bitmap.test(0));
+        assert_eq!(bitmap.find_first_zero(), Some(0));
+
+        // Bits past the end of the last word are never reported
+        for index in 0..100 {
+            bitmap.set(index);
+        }
+        assert_eq!(bitmap.find_first_zero(), None);
+        assert_eq!(bitmap.count_ones(), 100);
+
+        bitmap.clear_all();
+        assert_eq!(bitmap.count_ones(), 0);
+        assert!(AtomicBitmap::new(0).is_empty());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_out_of_range() {
+        AtomicBitmap::new(64).test(64);
+    }
+
+    #[test]
+    fn test_bitmap_threads() {
+        let bitmap = AtomicBitmap::new(1000);
+        let claimed = AtomicUsize::new(0);
+
+        // Threads claim the lowest clear bit until none is left
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                let bitmap = &bitmap;
+                let claimed = &claimed;
+                scope.spawn(move || {
+                    while let Some(index) = bitmap.find_first_zero() {
+                        if !bitmap.set_and_test(index) {
+                            claimed.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                });
+            }
+        });
+
+        assert_eq!(claimed.load(Ordering::Relaxed), 1000);
+        assert_eq!(bitmap.count_ones(), 1000);
+    }
+}
@@ -0,0 +1,149 @@
+//! Lock-free Bloom filter over an `AtomicBitmap`
+//!
+//! A key sets `k` bits of the filter, picked by double hashing: the `i`th bit is
+//! `h1 + i * h2` modulo the number of bits, with `h1` the `hash_key` of the key and
+//! `h2` the `hash_key` of `h1`. Inserting is one `fetch_or` per bit and never
+//! waits on another thread. A key that was inserted is always reported as present,
+//! while a key that wasn't is reported as present with about the false positive
+//! rate the filter was sized for, once it holds the expected number of keys.
+
+use core::marker::PhantomData;
+
+use crate::bitmap::AtomicBitmap;
+use crate::hasher::hash_key;
+use crate::pod::PodU64;
+
+/// Lock-free Bloom filter of keys of type `K`
+pub struct AtomicBloomFilter<K: PodU64 = u64> {
+    bits: AtomicBitmap,
+
+    /// Number of bits set by each key
+    hashes: u32,
+
+    _keys: PhantomData<K>
+}
+
+impl<K: PodU64> AtomicBloomFilter<K> {
+    /// Construct a filter sized to answer with a false positive rate of about
+    /// `false_positive_rate` once it holds `expected_keys` keys
+    ///
+    /// # Panics
+    ///
+    /// Panics if `false_positive_rate` isn't between 0 and 1, exclusive.
+    pub fn new(expected_keys: usize, false_positive_rate: f64) -> AtomicBloomFilter<K> {
+        assert!(false_positive_rate > 0.0 && false_positive_rate < 1.0,
+                "false positive rate of an AtomicBloomFilter must be between 0 and 1");
+
+        // Optimal sizes from the standard analysis: m = -n ln p / ln(2)^2 bits and
+        // k = m / n ln 2 hashes
+        let ln2 = core::f64::consts::LN_2;
+        let keys = expected_keys.max(1) as f64;
+        let bits = (-keys * false_positive_rate.ln() / (ln2 * ln2)).ceil();
+        let hashes = (bits / keys * ln2).round();
+
+        AtomicBloomFilter::with_params(bits as usize, hashes as u32)
+    }
+
+    /// Construct a filter of `bits` bits in which each key sets `hashes` bits. Both
+    /// are raised to at least 1.
+    pub fn with_params(bits: usize, hashes: u32) -> AtomicBloomFilter<K> {
+        AtomicBloomFilter {
+            bits: AtomicBitmap::new(bits.max(1)),
+            hashes: hashes.max(1),
+            _keys: PhantomData
+        }
+    }
+
+    /// Iterate over the indices of the bits of `key`
+    fn indices(&self, key: &K) -> impl Iterator<Item = usize> {
+        let len = self.bits.len() as u64;
+        let h1 = hash_key(key.to_u64());
+        let h2 = hash_key(h1);
+
+        (0..u64::from(self.hashes))
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+
+    /// Atomically add `key` to the filter
+    ///
+    /// Returns true if the key was definitely not in the filter before, or false if
+    /// it probably was. Threads racing to insert the same new key may all get back
+    /// true, as each can be the first to set a different one of its bits.
+    pub fn insert(&self, key: K) -> bool {
+        // Set every bit, even after finding one already set
+        self.indices(&key)
+            .fold(false, |new, index| !self.bits.set_and_test(index) | new)
+    }
+
+    /// Check if `key` is probably in the filter. Never false for an inserted key.
+    pub fn contains(&self, key: &K) -> bool {
+        self.indices(key).all(|index| self.bits.test(index))
+    }
+
+    /// Remove every key from the filter with exclusive access
+    pub fn clear(&mut self) {
+        self.bits.clear_all();
+    }
+
+    /// Get the number of bits of the filter
+    pub fn bits(&self) -> usize {
+        self.bits.len()
+    }
+
+    /// Get the number of bits set by each key
+    pub fn hashes(&self) -> u32 {
+        self.hashes
+    }
+
+    /// Get the fraction of bits set, whose `hashes`th power is the false positive
+    /// rate of the filter as it currently stands
+    pub fn fill_ratio(&self) -> f64 {
+        self.bits.count_ones() as f64 / self.bits.len() as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    #[test]
+    fn test_bloom() {
+        let mut filter: AtomicBloomFilter = AtomicBloomFilter::new(1000, 0.01);
+        assert_eq!(filter.bits(), 9586);
+        assert_eq!(filter.hashes(), 7);
+
+        // Only the odd false positive hides a new key
+        let new = (0..1000).filter(|&key| filter.insert(key)).count();
+        assert!(new > 980, "{} new keys", new);
+        assert!(!filter.insert(5));
+        assert!((0..1000).all(|key| filter.contains(&key)));
+
+        let false_positives = (1000..101_000).filter(|key| filter.contains(key)).count();
+        assert!(false_positives < 1500, "{} false positives", false_positives);
+
+        filter.clear();
+        assert_eq!(filter.fill_ratio(), 0.0);
+        assert!(!filter.contains(&5));
+    }
+
+    #[test]
+    fn test_bloom_threads() {
+        let filter: AtomicBloomFilter = AtomicBloomFilter::new(10_000, 0.001);
+
+        // Threads insert interleaved keys, setting bits in the same words
+        thread::scope(|scope| {
+            for thread in 0..4 {
+                let filter = &filter;
+                scope.spawn(move || {
+                    for key in (thread..10_000).step_by(4) {
+                        filter.insert(key);
+                    }
+                });
+            }
+        });
+
+        assert!((0..10_000).all(|key| filter.contains(&key)));
+    }
+}
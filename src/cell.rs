@@ -0,0 +1,411 @@
+//! Atomic cell holding any `Copy` value without uninitialized bytes
+//!
+//! A value whose size matches a native atomic of 1, 2, 4 or 8 bytes is stored in the
+//! cell as that atomic, so that every operation is a single lock-free atomic
+//! instruction. Any other value, like a 24-byte record of counters, is guarded by a
+//! sequence lock of its own:
+//!
+//! * Writers take the lock by moving the sequence from even to odd with a
+//!   compare-exchange, write the value, and release it by storing the next even
+//!   sequence with `Release`. Writers of the same cell therefore take turns.
+//! * Readers never write to the cell. They load the sequence with `Acquire`, copy
+//!   the value and load the sequence again after an `Acquire` fence, retrying if a
+//!   writer held the lock in between, the same way as the `seqlock` module.
+//!
+//! A reader racing a writer may copy a torn value, so the copy is kept as
+//! `MaybeUninit` until the second load of the sequence proves it whole.
+//!
+//! Values are only moved in and out of a native atomic as integers, which is only
+//! sound if every byte of the value is initialized. The cell therefore holds
+//! [`NoUninit`] types, which rules out values with padding such as `(u8, u32)`.
+
+use core::cell::UnsafeCell;
+use core::mem::{self, MaybeUninit};
+use core::ptr;
+
+use core::sync::atomic::{self, AtomicU16, AtomicU32, AtomicU64, AtomicU8, AtomicUsize,
+                         Ordering};
+
+/// Run `$native` with `$atomic` bound to the value of `$cell` as the native atomic of
+/// its size, or `$locked` if there is none
+macro_rules! with_atomic {
+    ($cell:expr, $atomic:ident, $native:expr, $locked:expr) => {
+        match mem::size_of::<T>() {
+            1 => {
+                // SAFETY: The value is at the start of the cell, which is aligned for
+                // every native atomic, and is only accessed atomically
+                let $atomic = unsafe { &*($cell.value.get() as *const AtomicU8) };
+                $native
+            }
+            2 => {
+                // SAFETY: As above
+                let $atomic = unsafe { &*($cell.value.get() as *const AtomicU16) };
+                $native
+            }
+            4 => {
+                // SAFETY: As above
+                let $atomic = unsafe { &*($cell.value.get() as *const AtomicU32) };
+                $native
+            }
+            8 => {
+                // SAFETY: As above
+                let $atomic = unsafe { &*($cell.value.get() as *const AtomicU64) };
+                $native
+            }
+            _ => $locked
+        }
+    };
+}
+
+/// Get the bits of `value` as an integer of the same size
+fn to_bits<T: NoUninit, B>(value: T) -> B {
+    debug_assert_eq!(mem::size_of::<T>(), mem::size_of::<B>());
+    // SAFETY: Only called with an integer of the size of `T`, and every byte of a
+    // `NoUninit` value is initialized
+    unsafe { mem::transmute_copy(&value) }
+}
+
+/// Get the value whose bits are `bits`, an integer of the size of `T`
+fn from_bits<T, B>(bits: B) -> T {
+    debug_assert_eq!(mem::size_of::<T>(), mem::size_of::<B>());
+    // SAFETY: Only called with bits taken from a `T` by `to_bits`
+    unsafe { mem::transmute_copy(&bits) }
+}
+
+/// Types whose values have every byte initialized, so their bits can be read as an
+/// integer of the same size
+///
+/// # Safety
+///
+/// The type must have no padding and no fields that may be uninitialized, like a
+/// `MaybeUninit` or a union. Padding of a `#[repr(C)]` struct can be made explicit
+/// with extra fields to satisfy this.
+pub unsafe trait NoUninit: Copy {}
+
+/// Releases the sequence lock taken at the even sequence `.1` when dropped, also when
+/// a comparison of the value panics
+struct Unlock<'a>(&'a AtomicUsize, usize);
+
+impl Drop for Unlock<'_> {
+    fn drop(&mut self) {
+        self.0.store(self.1.wrapping_add(2), Ordering::Release);
+    }
+}
+
+macro_rules! impl_no_uninit {
+    ($($ty:ty)*) => {
+        $(
+            // SAFETY: Primitives have no padding
+            unsafe impl NoUninit for $ty {}
+        )*
+    }
+}
+
+impl_no_uninit!(u8 u16 u32 u64 u128 usize i8 i16 i32 i64 i128 isize f32 f64 bool char);
+
+// SAFETY: Elements of an array are laid out without padding between them
+unsafe impl<T: NoUninit, const N: usize> NoUninit for [T; N] {}
+
+/// Cell of a `Copy` value that can be shared and updated between threads
+#[repr(C, align(8))]
+pub struct AtomicCell<T: NoUninit> {
+    /// Kept first so that it is aligned for the native atomic of its size
+    value: UnsafeCell<T>,
+
+    /// Sequence lock of values without a native atomic, odd while a writer holds it
+    seq: AtomicUsize
+}
+
+// SAFETY: The value is only accessed through atomics or under the sequence lock
+unsafe impl<T: NoUninit + Send> Sync for AtomicCell<T> {}
+
+impl<T: NoUninit> AtomicCell<T> {
+    /// Construct a cell holding `value`
+    pub const fn new(value: T) -> AtomicCell<T> {
+        AtomicCell { value: UnsafeCell::new(value), seq: AtomicUsize::new(0) }
+    }
+
+    /// Returns true if operations on cells of `T` use a native atomic rather than
+    /// the sequence lock
+    pub const fn is_lock_free() -> bool {
+        matches!(mem::size_of::<T>(), 1 | 2 | 4 | 8)
+    }
+
+    /// Get the value in the cell
+    pub fn load(&self) -> T {
+        with_atomic!(self, atomic, from_bits(atomic.load(Ordering::Acquire)), {
+            loop {
+                let seq = self.seq.load(Ordering::Acquire);
+                if seq & 1 == 1 {
+                    // A writer holds the lock, wait for it to finish
+                    core::hint::spin_loop();
+                    continue;
+                }
+
+                // SAFETY: The copy may be torn, so it is only used once the sequence
+                // shows no writer ran
+                let value = unsafe {
+                    ptr::read_volatile(self.value.get() as *const MaybeUninit<T>)
+                };
+
+                atomic::fence(Ordering::Acquire);
+                if self.seq.load(Ordering::Relaxed) == seq {
+                    // SAFETY: No writer ran while the value was copied
+                    return unsafe { value.assume_init() };
+                }
+            }
+        })
+    }
+
+    /// Take the sequence lock, which is held until the returned guard is dropped
+    fn lock(&self) -> Unlock<'_> {
+        loop {
+            let seq = self.seq.load(Ordering::Relaxed);
+            if seq & 1 == 0 && self.seq.compare_exchange_weak(seq, seq + 1,
+                                                              Ordering::Acquire,
+                                                              Ordering::Relaxed).is_ok() {
+                // Order the odd sequence before the writes of the value
+                atomic::fence(Ordering::Release);
+                return Unlock(&self.seq, seq);
+            }
+
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Store `value` in the cell
+    pub fn store(&self, value: T) {
+        with_atomic!(self, atomic, atomic.store(to_bits(value), Ordering::Release), {
+            let _unlock = self.lock();
+            // SAFETY: Writers are excluded by the lock and readers check the sequence
+            unsafe { ptr::write_volatile(self.value.get(), value); }
+        })
+    }
+
+    /// Store `value` in the cell, returning the value it replaced
+    pub fn swap(&self, value: T) -> T {
+        with_atomic!(self, atomic, {
+            from_bits(atomic.swap(to_bits(value), Ordering::AcqRel))
+        }, {
+            let _unlock = self.lock();
+            // SAFETY: Writers are excluded by the lock and readers check the sequence
+            unsafe { ptr::replace(self.value.get(), value) }
+        })
+    }
+
+    /// Get a mutable reference to the value, which no other thread can access
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    /// Take the value out of the cell
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: NoUninit + Eq> AtomicCell<T> {
+    /// Store `new` in the cell if it holds a value equal to `current`. Returns the
+    /// value the cell held, as `Ok` if it was replaced and `Err` otherwise.
+    pub fn compare_exchange(&self, current: T, new: T) -> Result<T, T> {
+        with_atomic!(self, atomic, {
+            let mut bits = atomic.load(Ordering::Acquire);
+            loop {
+                // Values can be equal without having the same bits, so compare the
+                // values and swap the exact bits that were read
+                let old = from_bits(bits);
+                if old != current {
+                    return Err(old);
+                }
+
+                match atomic.compare_exchange_weak(bits, to_bits(new), Ordering::AcqRel,
+                                                   Ordering::Acquire) {
+                    Ok(_) => return Ok(old),
+                    Err(curr) => bits = curr
+                }
+            }
+        }, {
+            // The comparison runs user code, which may panic while the lock is held
+            let _unlock = self.lock();
+            // SAFETY: Writers are excluded by the lock and readers check the sequence
+            let old = unsafe { ptr::read(self.value.get()) };
+            if old != current {
+                return Err(old);
+            }
+
+            // SAFETY: As above
+            unsafe { ptr::write_volatile(self.value.get(), new); }
+            Ok(old)
+        })
+    }
+
+    /// Replace the value in the cell with `f` of it, retrying if another thread
+    /// changes it in between. Returns the value replaced, or `Err` with the current
+    /// value if `f` returns `None`.
+    pub fn fetch_update<F>(&self, mut f: F) -> Result<T, T>
+            where F: FnMut(T) -> Option<T> {
+        let mut current = self.load();
+        while let Some(new) = f(current) {
+            match self.compare_exchange(current, new) {
+                Ok(old) => return Ok(old),
+                Err(curr) => current = curr
+            }
+        }
+
+        Err(current)
+    }
+}
+
+impl<T: NoUninit + Default> Default for AtomicCell<T> {
+    fn default() -> AtomicCell<T> {
+        AtomicCell::new(T::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    /// Record too large for a native atomic
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    struct Stats {
+        count: u64,
+        sum: u64,
+        max: u64
+    }
+
+    // SAFETY: Three `u64`s leave no padding
+    unsafe impl NoUninit for Stats {}
+
+    #[test]
+    fn test_cell_native() {
+        assert!(AtomicCell::<u8>::is_lock_free());
+        assert!(AtomicCell::<[u16; 2]>::is_lock_free());
+        assert!(AtomicCell::<f64>::is_lock_free());
+
+        let cell = AtomicCell::new([1i16, -1]);
+        assert_eq!(cell.load(), [1, -1]);
+        cell.store([2, -2]);
+        assert_eq!(cell.swap([3, -3]), [2, -2]);
+        assert_eq!(cell.compare_exchange([2, -2], [4, -4]), Err([3, -3]));
+        assert_eq!(cell.compare_exchange([3, -3], [4, -4]), Ok([3, -3]));
+        assert_eq!(cell.fetch_update(|[a, b]| Some([a + 1, b - 1])), Ok([4, -4]));
+        assert_eq!(cell.into_inner(), [5, -5]);
+
+        let cell = AtomicCell::new(true);
+        assert!(cell.swap(false));
+        assert!(!cell.load());
+    }
+
+    #[test]
+    fn test_cell_explicit_padding() {
+        /// `(u8, u32)` with its padding spelled out as a field
+        #[repr(C)]
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+        struct Tagged {
+            tag: u8,
+            pad: [u8; 3],
+            value: u32
+        }
+
+        // SAFETY: The padding is an initialized field
+        unsafe impl NoUninit for Tagged {}
+
+        assert!(AtomicCell::<Tagged>::is_lock_free());
+        let cell = AtomicCell::new(Tagged { tag: 1, pad: [0; 3], value: 7 });
+        let new = Tagged { tag: 2, pad: [0; 3], value: 9 };
+        assert_eq!(cell.swap(new).value, 7);
+        assert_eq!(cell.load(), new);
+    }
+
+    #[test]
+    fn test_cell_locked() {
+        assert!(!AtomicCell::<Stats>::is_lock_free());
+        assert!(!AtomicCell::<[u8; 3]>::is_lock_free());
+
+        let mut cell = AtomicCell::<Stats>::default();
+        let stats = Stats { count: 1, sum: 5, max: 5 };
+        cell.store(stats);
+        assert_eq!(cell.load(), stats);
+        assert_eq!(cell.compare_exchange(Stats::default(), stats), Err(stats));
+        assert_eq!(cell.fetch_update(|_| None), Err(stats));
+
+        let new = Stats { count: 2, sum: 12, max: 7 };
+        assert_eq!(cell.compare_exchange(stats, new), Ok(stats));
+        assert_eq!(cell.swap(stats), new);
+        cell.get_mut().count = 3;
+        assert_eq!(cell.load().count, 3);
+
+        let cell = AtomicCell::new([1u8, 2, 3]);
+        cell.store([4, 5, 6]);
+        assert_eq!(cell.load(), [4, 5, 6]);
+    }
+
+    #[test]
+    fn test_cell_compare_panics() {
+        /// Record whose comparison panics on a zero count
+        #[derive(Clone, Copy, Debug)]
+        struct Fragile([u64; 3]);
+
+        // SAFETY: Three `u64`s leave no padding
+        unsafe impl NoUninit for Fragile {}
+
+        impl PartialEq for Fragile {
+            fn eq(&self, other: &Fragile) -> bool {
+                assert!(self.0[0] != 0 && other.0[0] != 0, "Compared a zero count");
+                self.0 == other.0
+            }
+        }
+
+        impl Eq for Fragile {}
+
+        let cell = AtomicCell::new(Fragile([0, 1, 2]));
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            cell.compare_exchange(Fragile([1, 1, 2]), Fragile([2, 1, 2]))
+        }));
+        assert!(res.is_err());
+
+        // The value is untouched and the cell isn't locked
+        assert_eq!(cell.load().0, [0, 1, 2]);
+        cell.store(Fragile([1, 1, 2]));
+        let res = cell.compare_exchange(Fragile([1, 1, 2]), Fragile([2, 1, 2]));
+        assert_eq!(res.map(|old| old.0), Ok([1, 1, 2]));
+        assert_eq!(cell.load().0, [2, 1, 2]);
+    }
+
+    #[test]
+    fn test_cell_threads() {
+        let cell = AtomicCell::<Stats>::default();
+
+        // Writers record samples while readers check every record they see is whole
+        thread::scope(|scope| {
+            for _ in 0..2 {
+                let cell = &cell;
+                scope.spawn(move || {
+                    for sample in 1..=10_000 {
+                        cell.fetch_update(|stats| Some(Stats {
+                            count: stats.count + 1,
+                            sum: stats.sum + sample,
+                            max: stats.max.max(sample)
+                        })).unwrap();
+                    }
+                });
+            }
+
+            for _ in 0..2 {
+                let cell = &cell;
+                scope.spawn(move || {
+                    for _ in 0..10_000 {
+                        let stats = cell.load();
+                        assert!(stats.sum >= stats.count && stats.max <= stats.sum);
+                        assert_eq!(stats.max == 0, stats.count == 0);
+                    }
+                });
+            }
+        });
+
+        assert_eq!(cell.load(), Stats { count: 20_000, sum: 100_010_000, max: 10_000 });
+    }
+}
@@ -0,0 +1,430 @@
+//! Control bytes of an `AtomicHashMap`, one per slot, probed a group at a time
+//!
+//! Each control byte is either a 7-bit tag taken from the hash of the key in its slot,
+//! or has its high bit set to mark a slot that must be checked against its key word:
+//! one that is empty, tombstoned, or claimed but not tagged yet. A probe only skips
+//! slots whose tag differs from the tag of the key it is looking for, so it visits
+//! the same slots in the same order as a probe of every key word, minus slots that
+//! are known to hold some other key.
+//!
+//! Control bytes are packed into `AtomicU64` words, two words to a group of 16 slots.
+//! A group is loaded with two atomic loads and then matched against a tag with SSE2
+//! or NEON where available, falling back to SWAR on the two words. The order in which
+//! groups are visited is given by the `ProbeStrategy` of the map.
+
+use alloc::boxed::Box;
+use crate::sync::atomic::{Ordering, AtomicU64};
+
+use crate::atomichashmap::ProbeStrategy;
+use crate::placement::Placement;
+
+/// Number of slots matched at once
+pub(crate) const GROUP_WIDTH: usize = 16;
+
+/// Control byte of a slot whose key word has to be checked
+const FREE: u8 = 0x80;
+
+/// Control word of eight free slots
+const FREE_WORD: u64 = 0x8080_8080_8080_8080;
+
+/// Get the tag of a key from its full hash. The low bits of the hash pick the start of
+/// the probe, so the tag is taken from the top bits.
+pub(crate) fn tag(hash: u64) -> u8 {
+    (hash >> 57) as u8
+}
+
+/// Control bytes of a table of `size` slots
+pub(crate) struct ControlBytes {
+    words: Box<[AtomicU64]>,
+    size: usize
+}
+
+impl ControlBytes {
+    /// Allocate control bytes for `size` slots, all free, placed as asked
+    pub(crate) fn new(size: usize, placement: Placement) -> ControlBytes {
+        let groups = size.div_ceil(GROUP_WIDTH);
+        let mut words = placement.alloc_vec(groups * 2);
+        words.extend((0..groups * 2).map(|_| AtomicU64::new(FREE_WORD)));
+        ControlBytes { words: words.into_boxed_slice(), size }
+    }
+
+    /// Tag the slot at `index` as holding a key with tag `tag`. Only the thread that
+    /// claimed the slot may tag it, and only before publishing it.
+    pub(crate) fn set(&self, index: usize, tag: u8) {
+        self.store(index, tag);
+    }
+
+    /// Mark the slot at `index` as needing its key checked again. Must be called by the
+    /// owner of the slot before its key is released.
+    pub(crate) fn free(&self, index: usize) {
+        self.store(index, FREE);
+    }
+
+    /// Replace the control byte of the slot at `index`. Other bytes of the same word
+    /// belong to other slots and may change underneath us, hence the CAS loop.
+    fn store(&self, index: usize, byte: u8) {
+        let shift = (index % 8) * 8;
+        let mask = 0xff << shift;
+        let _ = self.words[index / 8].fetch_update(Ordering::AcqRel, Ordering::Acquire,
+                                                   |word| {
+            Some((word & !mask) | (u64::from(byte) << shift))
+        });
+    }
+
+    /// Tag the slot at `index` with exclusive access
+    pub(crate) fn set_mut(&mut self, index: usize, tag: u8) {
+        let shift = (index % 8) * 8;
+        let word = &self.words[index / 8];
+        let prev = word.load(Ordering::Relaxed);
+        word.store((prev & !(0xff << shift)) | (u64::from(tag) << shift), Ordering::Relaxed);
+    }
+
+    /// Mark every slot as free with exclusive access
+    pub(crate) fn clear_mut(&mut self) {
+        for word in self.words.iter() {
+            word.store(FREE_WORD, Ordering::Relaxed);
+        }
+    }
+
+    /// Iterate over the slots a probe for a key with hash `hash` and tag `tag` has to
+    /// check, in probe order. The low bits of the hash pick the first slot. At most
+    /// `limit` groups are visited after the one holding the first slot.
+    pub(crate) fn probe(&self, hash: u64, tag: u8, strategy: ProbeStrategy, limit: usize)
+            -> Probe<'_> {
+        self.walk(hash, Some(tag), strategy, limit)
+    }
+
+    /// Iterate over every slot along the probe for a key with hash `hash`, whatever
+    /// its control byte, in the same order as `probe`
+    pub(crate) fn probe_all(&self, hash: u64, strategy: ProbeStrategy, limit: usize)
+            -> Probe<'_> {
+        self.walk(hash, None, strategy, limit)
+    }
+
+    /// Start a probe for `hash`, only visiting slots matching `tag` if there is one
+    fn walk(&self, hash: u64, tag: Option<u8>, strategy: ProbeStrategy, limit: usize)
+            -> Probe<'_> {
+        let start_index = hash as usize & (self.size - 1);
+        let group = start_index / GROUP_WIDTH;
+        let offset = start_index % GROUP_WIDTH;
+
+        Probe {
+            ctrl: self,
+            tag,
+            strategy,
+            // Odd, so that it is coprime with the power-of-two number of groups. Taken
+            // from bits used neither by the start index nor by the tag.
+            stride: (hash >> 32) as usize | 1,
+            start: group,
+            step: 0,
+            limit: limit.min(self.groups()),
+            group,
+            mask: self.match_group(group, tag) & (!0 << offset),
+            tail: !(!0 << offset)
+        }
+    }
+
+    /// Hint that the group holding the first slot of a probe for `hash` is about to be
+    /// matched
+    #[inline]
+    pub(crate) fn prefetch(&self, hash: u64) {
+        let start_index = hash as usize & (self.size - 1);
+        prefetch(&self.words[start_index / GROUP_WIDTH * 2]);
+    }
+
+    /// Get the number of groups in the table, always a power of two
+    fn groups(&self) -> usize {
+        self.words.len() / 2
+    }
+
+    /// Get the bitmask of slots in group `group` that either match `tag` or are free,
+    /// or of every slot in the group if there is no tag
+    fn match_group(&self, group: usize, tag: Option<u8>) -> u16 {
+        // Slots past the end of a table smaller than a group never match
+        let valid = match self.size - group * GROUP_WIDTH {
+            n if n >= GROUP_WIDTH => !0,
+            n => (1 << n) - 1
+        };
+
+        let tag = match tag {
+            Some(tag) => tag,
+            None => return valid
+        };
+
+        let lo = self.words[group * 2].load(Ordering::Acquire);
+        let hi = self.words[group * 2 + 1].load(Ordering::Acquire);
+        match_words(lo, hi, tag) & valid
+    }
+}
+
+/// Slots to check along a probe, created by `ControlBytes::probe`
+///
+/// The group holding the start of the probe is visited twice: first from the start
+/// onwards, and once the probe wraps around, up to the start.
+pub(crate) struct Probe<'a> {
+    ctrl: &'a ControlBytes,
+
+    /// Tag of the key probed for, `None` to visit every slot
+    tag: Option<u8>,
+    strategy: ProbeStrategy,
+
+    /// Distance between groups for `ProbeStrategy::DoubleHash`
+    stride: usize,
+
+    /// Group holding the start of the probe
+    start: usize,
+
+    /// Number of groups visited after the start one
+    step: usize,
+
+    /// Number of groups to visit after the start one before giving up
+    limit: usize,
+
+    /// Group currently being walked
+    group: usize,
+
+    /// Slots of the current group still to be visited
+    mask: u16,
+
+    /// Slots of the first group before the start of the probe
+    tail: u16
+}
+
+impl<'a> Iterator for Probe<'a> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        loop {
+            if self.mask != 0 {
+                let slot = self.mask.trailing_zeros() as usize;
+                self.mask &= self.mask - 1;
+                return Some(self.group * GROUP_WIDTH + slot);
+            }
+
+            let groups = self.ctrl.groups();
+            if self.step == self.limit {
+                return None;
+            }
+
+            self.step += 1;
+            if self.step == groups {
+                // Every other group has been visited, finish off the start group
+                self.group = self.start;
+                self.mask = self.ctrl.match_group(self.group, self.tag) & self.tail;
+                continue;
+            }
+
+            // Each sequence visits every group once in its first `groups` steps since
+            // the number of groups is a power of two
+            let offset = match self.strategy {
+                ProbeStrategy::Linear     => self.step,
+                ProbeStrategy::Quadratic  => self.step * (self.step + 1) / 2,
+                ProbeStrategy::DoubleHash => self.step.wrapping_mul(self.stride)
+            };
+            self.group = self.start.wrapping_add(offset) & (groups - 1);
+            self.mask = self.ctrl.match_group(self.group, self.tag);
+        }
+    }
+}
+
+/// Hint that the cache line holding `ptr` is about to be read, so that it is loaded
+/// while other work goes on
+#[cfg(all(target_arch = "x86_64", target_feature = "sse"))]
+#[inline]
+pub(crate) fn prefetch<T>(ptr: *const T) {
+    use core::arch::x86_64::*;
+
+    // SAFETY: SSE is enabled for this target, and prefetching never faults
+    unsafe { _mm_prefetch(ptr as *const i8, _MM_HINT_T0) }
+}
+
+/// Hint that the cache line holding `ptr` is about to be read, so that it is loaded
+/// while other work goes on
+#[cfg(not(all(target_arch = "x86_64", target_feature = "sse")))]
+#[inline]
+pub(crate) fn prefetch<T>(_ptr: *const T) {}
+
+/// Get the bitmask of the 16 control bytes in `lo` and `hi` that equal `tag` or have
+/// their high bit set
+#[cfg(all(target_arch = "x86_64", target_feature = "sse2"))]
+fn match_words(lo: u64, hi: u64, tag: u8) -> u16 {
+    use core::arch::x86_64::*;
+
+    // SAFETY: SSE2 is enabled for this target
+    unsafe {
+        let group = _mm_set_epi64x(hi as i64, lo as i64);
+        let eq = _mm_cmpeq_epi8(group, _mm_set1_epi8(tag as i8));
+
+        // Free bytes already have their high bit set, so or-ing them in is enough
+        _mm_movemask_epi8(_mm_or_si128(eq, group)) as u16
+    }
+}
+
+/// Get the bitmask of the 16 control bytes in `lo` and `hi` that equal `tag` or have
+/// their high bit set
+#[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+fn match_words(lo: u64, hi: u64, tag: u8) -> u16 {
+    use core::arch::aarch64::*;
+
+    const BITS: [u8; 16] = [1, 2, 4, 8, 16, 32, 64, 128, 1, 2, 4, 8, 16, 32, 64, 128];
+
+    // SAFETY: NEON is enabled for this target
+    unsafe {
+        let group = vcombine_u8(vcreate_u8(lo), vcreate_u8(hi));
+        let eq = vceqq_u8(group, vdupq_n_u8(tag));
+        let free = vtstq_u8(group, vdupq_n_u8(FREE));
+
+        // One bit per matching byte, summed into a byte per half
+        let bits = vandq_u8(vorrq_u8(eq, free), vld1q_u8(BITS.as_ptr()));
+        let lo = vaddv_u8(vget_low_u8(bits)) as u16;
+        let hi = vaddv_u8(vget_high_u8(bits)) as u16;
+        lo | (hi << 8)
+    }
+}
+
+/// Get the bitmask of the 16 control bytes in `lo` and `hi` that equal `tag` or have
+/// their high bit set
+#[cfg(not(any(all(target_arch = "x86_64", target_feature = "sse2"),
+              all(target_arch = "aarch64", target_feature = "neon"))))]
+fn match_words(lo: u64, hi: u64, tag: u8) -> u16 {
+    match_words_scalar(lo, hi, tag)
+}
+
+/// Portable version of `match_words`, matching each word as eight packed bytes
+#[cfg_attr(any(all(target_arch = "x86_64", target_feature = "sse2"),
+               all(target_arch = "aarch64", target_feature = "neon")),
+           allow(dead_code))]
+fn match_words_scalar(lo: u64, hi: u64, tag: u8) -> u16 {
+    match_word(lo, tag) | (match_word(hi, tag) << 8)
+}
+
+/// Get the bitmask of the 8 control bytes in `word` that equal `tag` or have their
+/// high bit set
+fn match_word(word: u64, tag: u8) -> u16 {
+    const LOW: u64 = 0x7f7f_7f7f_7f7f_7f7f;
+
+    // Bytes equal to the tag become zero. Adding 0x7f to the low 7 bits of a byte
+    // carries into its high bit unless they are all zero, so this leaves the high bit
+    // set exactly for the nonzero bytes, without carries crossing into other bytes.
+    let diff = word ^ (u64::from(tag) * 0x0101_0101_0101_0101);
+    let eq = !(((diff & LOW) + LOW) | diff) & !LOW;
+    let hits = (eq | word) & !LOW;
+
+    // Gather the high bit of every byte into the top byte
+    ((hits >> 7).wrapping_mul(0x0102_0408_1020_4080) >> 56) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::strategy::Just;
+
+    #[test]
+    fn test_match_words() {
+        // Simple xorshift so that the SIMD and scalar versions see the same bytes
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        for _ in 0..10_000 {
+            let lo = next();
+            let hi = next();
+            let tag = (next() & 0x7f) as u8;
+
+            let mut expected = 0u16;
+            for (i, byte) in lo.to_le_bytes().iter().chain(&hi.to_le_bytes()).enumerate() {
+                if *byte == tag || *byte & FREE != 0 {
+                    expected |= 1 << i;
+                }
+            }
+
+            assert_eq!(match_words(lo, hi, tag), expected);
+            assert_eq!(match_words_scalar(lo, hi, tag), expected);
+        }
+    }
+
+    #[test]
+    fn test_probe_order() {
+        let ctrl = ControlBytes::new(64, Placement::default());
+        for index in 0..64 {
+            ctrl.set(index, (index % 4) as u8);
+        }
+        ctrl.free(40);
+
+        // Tag 1 lives in every fourth slot, plus the free one, starting from 37 and
+        // wrapping around through the start group
+        let slots: Vec<usize> = ctrl.probe(37, 1, ProbeStrategy::Linear, usize::MAX)
+            .collect();
+        let expected: Vec<usize> = (37..64).chain(0..37)
+            .filter(|index| index % 4 == 1 || *index == 40)
+            .collect();
+        assert_eq!(slots, expected);
+
+        // A limited probe stops after the given number of groups past the first one
+        let slots: Vec<usize> = ctrl.probe(37, 1, ProbeStrategy::Linear, 1).collect();
+        assert_eq!(slots, vec![37, 40, 41, 45, 49, 53, 57, 61]);
+
+        // Probing every slot ignores the tags
+        let slots: Vec<usize> = ctrl.probe_all(37, ProbeStrategy::Linear, 1).collect();
+        assert_eq!(slots, (37..64).collect::<Vec<_>>());
+
+        // Tables smaller than a group only visit their own slots
+        let small = ControlBytes::new(4, Placement::default());
+        let slots: Vec<usize> = small.probe(2, 0x7f, ProbeStrategy::DoubleHash, usize::MAX)
+            .collect();
+        assert_eq!(slots, vec![2, 3, 0, 1]);
+    }
+
+    #[test]
+    fn test_probe_covers_table() {
+        let ctrl = ControlBytes::new(1 << 10, Placement::default());
+
+        for strategy in [ProbeStrategy::Linear, ProbeStrategy::Quadratic, 
+                         ProbeStrategy::DoubleHash].iter() {
+            for hash in [0, 37, 0xdead_beef_1234_5678, u64::MAX].iter() {
+                // Every slot is free, so the probe visits every slot exactly once
+                let mut slots: Vec<usize> = ctrl.probe(*hash, 0, *strategy, usize::MAX)
+                    .collect();
+                assert_eq!(slots[0], *hash as usize & ((1 << 10) - 1));
+
+                slots.sort();
+                assert_eq!(slots, (0..1 << 10).collect::<Vec<_>>());
+            }
+        }
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn test_probe_in_bounds(
+                size_log2 in 1..=12u32,
+                tags in proptest::collection::vec(proptest::option::of(0..0x80u8),
+                                                  1 << 12),
+                strategy in proptest::prop_oneof![Just(ProbeStrategy::Linear),
+                                                  Just(ProbeStrategy::Quadratic),
+                                                  Just(ProbeStrategy::DoubleHash)],
+                hash in proptest::num::u64::ANY,
+                tag in 0..0x80u8,
+                limit in proptest::prop_oneof![Just(usize::MAX), 0..8usize]) {
+            let size = 1 << size_log2;
+            let ctrl = ControlBytes::new(size, Placement::default());
+
+            // Tag some of the slots, so probes skip them
+            for (index, tag) in tags[..size].iter().enumerate() {
+                if let Some(tag) = *tag {
+                    ctrl.set(index, tag);
+                }
+            }
+
+            let mut seen = vec![false; size];
+            for index in ctrl.probe(hash, tag, strategy, limit) {
+                proptest::prop_assert!(index < size, "Slot {} of {} probed", index, size);
+                proptest::prop_assert!(!seen[index], "Slot {} probed twice", index);
+                seen[index] = true;
+            }
+        }
+    }
+}
@@ -0,0 +1,141 @@
+//! Lock-free map of per-key counters on top of `AtomicHashMap`
+//!
+//! Counting with `get` followed by `insert` loses increments whenever two threads
+//! race on a key. Here every increment is a single `fetch_add` on the value of the
+//! key, after claiming its slot if the key is new, so no increment is ever lost.
+
+use alloc::vec::Vec;
+use core::hash::BuildHasher;
+
+use crate::atomichashmap::{AtomicHashMap, AtomicHashMapError, BuildMurmurHasher, Iter};
+use crate::pod::PodU64;
+
+/// Lock-free map from keys of type `K` to `u64` counts
+pub struct AtomicCounterMap<K: PodU64 = u64, S = BuildMurmurHasher> {
+    map: AtomicHashMap<K, u64, S>
+}
+
+impl<K: PodU64> AtomicCounterMap<K> {
+    /// Construct a new counter map with room for `size` keys.
+    /// NOTE: Size must be a power of two, otherwise `InvalidCapacity` is returned.
+    pub fn new(size: usize) -> Result<AtomicCounterMap<K>, AtomicHashMapError> {
+        AtomicCounterMap::with_hasher(size, BuildMurmurHasher::default())
+    }
+}
+
+impl<K: PodU64, S: BuildHasher> AtomicCounterMap<K, S> {
+    /// Construct a new counter map with room for `size` keys, hashing keys with
+    /// `hasher`.
+    /// NOTE: Size must be a power of two, otherwise `InvalidCapacity` is returned.
+    pub fn with_hasher(size: usize, hasher: S)
+            -> Result<AtomicCounterMap<K, S>, AtomicHashMapError> {
+        Ok(AtomicCounterMap { map: AtomicHashMap::with_hasher(size, hasher)? })
+    }
+
+    /// Atomically add 1 to the count of `key`, starting it from 0 if the key is new
+    ///
+    /// Returns the count before the increment. The count wraps around on overflow.
+    pub fn increment(&self, key: K) -> Result<u64, AtomicHashMapError> {
+        self.add(key, 1)
+    }
+
+    /// Atomically add `delta` to the count of `key`, starting it from 0 if the key is
+    /// new
+    ///
+    /// Returns the count before the addition. The count wraps around on overflow.
+    pub fn add(&self, key: K, delta: u64) -> Result<u64, AtomicHashMapError> {
+        self.map.add_to(key, delta)
+    }
+
+    /// Get the count of `key`, which is 0 if it was never counted
+    pub fn count(&self, key: &K) -> u64 {
+        self.map.get(key).unwrap_or(0)
+    }
+
+    /// Atomically remove `key`, returning its count if it was counted
+    pub fn remove(&self, key: K) -> Option<u64> {
+        self.map.remove(key)
+    }
+
+    /// Iterate over the `(key, count)` pairs, with the same concurrency semantics as
+    /// `AtomicHashMap::iter`
+    pub fn iter(&self) -> Iter<'_, K, u64, S> {
+        self.map.iter()
+    }
+
+    /// Collect the `(key, count)` pairs, in table order
+    pub fn to_vec(&self) -> Vec<(K, u64)> {
+        self.map.to_vec()
+    }
+
+    /// Get the number of keys counted
+    pub fn len(&self) -> u64 {
+        self.map.len()
+    }
+
+    /// Returns true if no key was counted
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Get the total number of slots in the map
+    pub fn capacity(&self) -> usize {
+        self.map.capacity()
+    }
+}
+
+impl<'a, K: PodU64, S: BuildHasher> IntoIterator for &'a AtomicCounterMap<K, S> {
+    type Item = (K, u64);
+    type IntoIter = Iter<'a, K, u64, S>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    #[test]
+    fn test_counter() {
+        let counters: AtomicCounterMap = AtomicCounterMap::new(1 << 4).unwrap();
+        assert_eq!(counters.increment(1), Ok(0));
+        assert_eq!(counters.increment(1), Ok(1));
+        assert_eq!(counters.add(2, 5), Ok(0));
+        assert_eq!(counters.count(&1), 2);
+        assert_eq!(counters.count(&2), 5);
+        assert_eq!(counters.count(&3), 0);
+        assert_eq!(counters.increment(0), Err(AtomicHashMapError::InvalidKey));
+
+        let mut counts = counters.iter().collect::<Vec<_>>();
+        counts.sort();
+        assert_eq!(counts, vec![(1, 2), (2, 5)]);
+
+        assert_eq!(counters.remove(2), Some(5));
+        assert_eq!(counters.count(&2), 0);
+        assert_eq!(counters.len(), 1);
+    }
+
+    #[test]
+    fn test_counter_threads() {
+        let counters: AtomicCounterMap = AtomicCounterMap::new(1 << 8).unwrap();
+
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                let counters = &counters;
+                scope.spawn(move || {
+                    for x in 0..10_000u64 {
+                        counters.increment(x % 100 + 1).unwrap();
+                    }
+                });
+            }
+        });
+
+        // No increment was lost to a race
+        assert_eq!(counters.len(), 100);
+        assert!((&counters).into_iter().all(|(_, count)| count == 400));
+    }
+}
@@ -0,0 +1,174 @@
+//! Lock-free count-min sketch of approximate key frequencies
+//!
+//! The sketch is `depth` rows of `width` counters. A key maps to one counter per row,
+//! picked by double hashing as in `AtomicBloomFilter`, and adding it is a `Relaxed`
+//! `fetch_add` on each of them. Since other keys share those counters, each one
+//! over-counts the key, and the estimate of a key is the smallest of its counters.
+//! Estimates are never below the true count and exceed it by at most `e / width`
+//! of the total count with probability `1 - e^-depth`.
+
+use alloc::boxed::Box;
+use core::marker::PhantomData;
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::hasher::hash_key;
+use crate::pod::PodU64;
+
+/// Lock-free count-min sketch of keys of type `K`
+pub struct CountMinSketch<K: PodU64 = u64> {
+    /// Counters of every row, one row after the other
+    counters: Box<[AtomicU64]>,
+
+    /// Number of counters per row
+    width: usize,
+
+    /// Number of rows
+    depth: usize,
+
+    _keys: PhantomData<K>
+}
+
+impl<K: PodU64> CountMinSketch<K> {
+    /// Construct a sketch of `depth` rows of `width` counters. Both are raised to at
+    /// least 1.
+    pub fn new(width: usize, depth: usize) -> CountMinSketch<K> {
+        let (width, depth) = (width.max(1), depth.max(1));
+
+        CountMinSketch {
+            counters: (0..width * depth).map(|_| AtomicU64::new(0)).collect(),
+            width,
+            depth,
+            _keys: PhantomData
+        }
+    }
+
+    /// Construct a sketch whose estimates exceed the true count by at most
+    /// `epsilon` times the total count, with probability at least `1 - delta`
+    ///
+    /// # Panics
+    ///
+    /// Panics if `epsilon` or `delta` isn't between 0 and 1, exclusive.
+    #[cfg(feature = "std")]
+    pub fn with_error(epsilon: f64, delta: f64) -> CountMinSketch<K> {
+        assert!(epsilon > 0.0 && epsilon < 1.0 && delta > 0.0 && delta < 1.0,
+                "error bounds of a CountMinSketch must be between 0 and 1");
+
+        let width = (core::f64::consts::E / epsilon).ceil();
+        let depth = (1.0 / delta).ln().ceil();
+        CountMinSketch::new(width as usize, depth as usize)
+    }
+
+    /// Iterate over the counters of `key`, one per row
+    fn counters(&self, key: &K) -> impl Iterator<Item = &AtomicU64> {
+        let h1 = hash_key(key.to_u64());
+        let h2 = hash_key(h1);
+
+        (0..self.depth).map(move |row| {
+            let column = h1.wrapping_add((row as u64).wrapping_mul(h2)) as usize;
+            &self.counters[row * self.width + column % self.width]
+        })
+    }
+
+    /// Count one more occurrence of `key`
+    pub fn add(&self, key: K) {
+        self.add_n(key, 1);
+    }
+
+    /// Count `count` more occurrences of `key`
+    pub fn add_n(&self, key: K, count: u64) {
+        for counter in self.counters(&key) {
+            counter.fetch_add(count, Ordering::Relaxed);
+        }
+    }
+
+    /// Estimate the number of occurrences of `key`, never below the true count
+    pub fn estimate(&self, key: &K) -> u64 {
+        self.counters(key)
+            .map(|counter| counter.load(Ordering::Relaxed))
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// Get the total count added, over all keys
+    pub fn total(&self) -> u64 {
+        self.counters[..self.width].iter()
+            .map(|counter| counter.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Reset every counter to 0 with exclusive access
+    pub fn clear(&mut self) {
+        for counter in self.counters.iter() {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Get the number of counters per row
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Get the number of rows
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    #[test]
+    fn test_sketch() {
+        #[cfg(feature = "std")]
+        {
+            let sketch: CountMinSketch = CountMinSketch::with_error(0.001, 0.01);
+            assert_eq!((sketch.width(), sketch.depth()), (2719, 5));
+        }
+
+        let mut sketch: CountMinSketch = CountMinSketch::new(2719, 5);
+
+        // A few heavy hitters among many rare keys
+        for key in 1..=10_000 {
+            sketch.add(key);
+        }
+        for key in 1..=5 {
+            sketch.add_n(key, 1000 * key);
+        }
+        assert_eq!(sketch.total(), 25_000);
+
+        for key in 1..=5 {
+            let estimate = sketch.estimate(&key);
+            assert!(estimate > 1000 * key && estimate <= 1000 * key + 26,
+                    "{} estimated as {}", key, estimate);
+        }
+        let rare = (6..=10_000).filter(|key| sketch.estimate(key) > 26).count();
+        assert!(rare < 100, "{} rare keys over-estimated", rare);
+
+        sketch.clear();
+        assert_eq!(sketch.estimate(&1), 0);
+    }
+
+    #[test]
+    fn test_sketch_threads() {
+        let sketch: CountMinSketch = CountMinSketch::new(1024, 4);
+
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                let sketch = &sketch;
+                scope.spawn(move || {
+                    for x in 0..10_000u64 {
+                        sketch.add(x % 10);
+                    }
+                });
+            }
+        });
+
+        // No increment was lost
+        assert_eq!(sketch.total(), 40_000);
+        assert!((0..10).all(|key| sketch.estimate(&key) >= 4000));
+    }
+}
@@ -0,0 +1,938 @@
+//! Bucketized cuckoo hashing variant of `AtomicHashMap` for latency-sensitive readers
+//!
+//! Every key has two candidate buckets of four slots each, picked by the two halves of
+//! its hash, and is always stored in one of them. A lookup reads both buckets and
+//! nothing else, so it costs the same at 90% load as in an empty table.
+//!
+//! A key whose buckets are both full makes room by moving a key out of one of them to
+//! that key's other bucket, possibly several keys deep. The shortest such path is
+//! found with a breadth-first search and then carried out from its far end, so that
+//! every key stays in the table throughout.
+//!
+//! # Slots and entries
+//!
+//! Keys and values live in entries taken from a pool allocated with the table. A
+//! slot holds the handle of its entry in a single word, together with the state of
+//! the slot and a counter bumped by every change to it. An entry is never written
+//! while a slot refers to it, so every change to the table is a compare-exchange of
+//! one slot word: an insert links a new entry, an overwrite swaps in an entry holding
+//! the new value, and a removal empties the slot. The entry given up goes back to the
+//! pool.
+//!
+//! A key is moved in three steps. The slot it moves to is reserved for its entry, the
+//! slot it moves from is marked as moving to that slot, and the reserved slot is
+//! published before the marked one is emptied, so the key stays visible throughout.
+//! Any thread that finds a marked slot can carry out the rest of the move, so a
+//! thread that stops halfway through a move doesn't hold anybody up.
+//!
+//! A new key is linked as pending, which lookups don't see, and published once no
+//! other copy of it is found in its buckets. Of two inserts of the same key that see
+//! each other's pending entries, the one with the lower entry handle empties the slot
+//! of the other and the other backs out, so a key is never published twice.
+//!
+//! # Progress
+//!
+//! The map is lock-free. Lookups never write to the table and never wait on a
+//! writer: they read the slot words of both buckets and the entries they refer to,
+//! then read the slot words again, and only retry if one of them changed in between,
+//! which means another thread made progress. Writers only wait on each other where
+//! `AtomicHashMap` does, when an insert finds a pending insert of the same key, which
+//! it waits out until that is published or backs out.
+//!
+//! # Memory ordering
+//!
+//! Slot words are loaded and changed with `SeqCst`, so that of two inserts of the
+//! same key at least one sees the pending entry of the other. An entry is filled in
+//! after a `Release` fence and before the compare-exchange linking it. Readers load
+//! the entries of a slot between two loads of its word separated by an `Acquire`
+//! fence, so an entry refilled while it is read shows up as a changed slot word.
+
+use std::collections::{HashSet, VecDeque};
+use std::hash::{BuildHasher, Hasher};
+use std::marker::PhantomData;
+
+use core::sync::atomic::{self, Ordering, AtomicU32, AtomicU64};
+
+use crate::atomichashmap::{AtomicHashMapError, BuildMurmurHasher};
+use crate::backoff::Backoff;
+use crate::pod::PodU64;
+use crate::stack::{pack, unpack, NIL};
+
+/// Key marker reserved for slots that hold no key
+const EMPTY_KEY: u64 = 0;
+
+/// Number of slots in each bucket
+const BUCKET_SLOTS: usize = 4;
+
+/// Longest chain of keys moved to make room for a new key. With four slots per bucket
+/// this finds room until the table is well past 90% full.
+const MAX_PATH: usize = 5;
+
+/// State of a slot that holds no entry
+const EMPTY: u64 = 0;
+
+/// State of a slot holding the entry of an insert that isn't published yet
+const PENDING: u64 = 1;
+
+/// State of a slot holding a published entry
+const PUBLISHED: u64 = 2;
+
+/// State of a slot holding a published entry that is moving to the slot of its other
+/// bucket given by the target of the slot word
+const MOVING: u64 = 3;
+
+/// State of a slot reserved for an entry moving into it
+const RESERVED: u64 = 4;
+
+/// Position of the state in a slot word, above the handle of its entry
+const STATE_SHIFT: u32 = 32;
+
+/// Position of the target of a moving entry in a slot word, above its state
+const TARGET_SHIFT: u32 = 35;
+
+/// Lowest bit of the counter of a slot word, above its target
+const COUNTER_ONE: u64 = 1 << 37;
+
+/// Get the handle of the entry a slot word refers to
+fn handle(word: u64) -> u32 {
+    word as u32
+}
+
+/// Get the state of a slot word
+fn state(word: u64) -> u64 {
+    (word >> STATE_SHIFT) & 0b111
+}
+
+/// Get the index of the slot a moving entry moves to within its other bucket
+fn target(word: u64) -> usize {
+    ((word >> TARGET_SHIFT) & 0b11) as usize
+}
+
+/// Returns true if the slot word holds an entry visible to lookups
+fn is_visible(word: u64) -> bool {
+    matches!(state(word), PUBLISHED | MOVING)
+}
+
+/// Get the word replacing `word` in its slot, holding `handle` in `state`
+fn successor(word: u64, handle: u32, state: u64, target: usize) -> u64 {
+    (word & !(COUNTER_ONE - 1)).wrapping_add(COUNTER_ONE) |
+        (target as u64) << TARGET_SHIFT | state << STATE_SHIFT | u64::from(handle)
+}
+
+/// Key and value stored in the table, shared by every slot referring to it
+struct Entry {
+    key: AtomicU64,
+    value: AtomicU64,
+
+    /// Handle of the next entry of the pool while this one is in it
+    next: AtomicU32
+}
+
+/// Slots of both buckets of a key, all read at one point in time
+struct View {
+    /// Index in the table of each slot, those of the first bucket first
+    indices: [usize; 2 * BUCKET_SLOTS],
+
+    words: [u64; 2 * BUCKET_SLOTS],
+
+    /// Key and value of the entry each slot refers to, if it refers to one
+    entries: [(u64, u64); 2 * BUCKET_SLOTS]
+}
+
+impl View {
+    /// Find the slot holding an entry of `key` whose word passes `accept`
+    fn find(&self, key: u64, accept: fn(u64) -> bool) -> Option<usize> {
+        (0..self.words.len()).find(|&slot| {
+            accept(self.words[slot]) && self.entries[slot].0 == key
+        })
+    }
+}
+
+/// Bucket reached by the search for a free slot
+#[derive(Debug, Clone, Copy)]
+struct PathNode {
+    bucket: usize,
+
+    /// Node whose bucket the key moving into this bucket comes from, and the slot it
+    /// occupies there. `None` for the two buckets of the new key.
+    from: Option<(usize, usize)>,
+
+    /// Number of keys that have to move for the new key to reach this bucket
+    depth: usize
+}
+
+/// Hashmap from keys of type `K` to values of type `V` using bucketized cuckoo hashing
+///
+/// Lookups read exactly two buckets and never wait on a writer, and writers never
+/// lock each other out, see the module docs on progress. A key of 0 is reserved to
+/// mark empty slots.
+pub struct AtomicCuckooMap<K: PodU64 = u64, V: PodU64 = u64, S = BuildMurmurHasher> {
+    /// Slot words, `BUCKET_SLOTS` per bucket
+    slots: Box<[AtomicU64]>,
+
+    /// Pool of entries, twice the number of slots so that inserts in progress and
+    /// entries given up by overwrites don't run it out before the table fills up
+    entries: Box<[Entry]>,
+
+    /// First entry of the pool of free entries, tagged like the lists of
+    /// `AtomicStack`
+    free: AtomicU64,
+
+    /// Number of buckets, a power of two
+    buckets: usize,
+
+    /// Number of keys currently in the table
+    count: AtomicU64,
+
+    /// Builds the hasher picking the two buckets of each key
+    hasher: S,
+
+    _types: PhantomData<(K, V)>
+}
+
+unsafe impl<K: PodU64, V: PodU64, S: Send> Send for AtomicCuckooMap<K, V, S> {}
+unsafe impl<K: PodU64, V: PodU64, S: Sync> Sync for AtomicCuckooMap<K, V, S> {}
+
+impl<K: PodU64, V: PodU64> AtomicCuckooMap<K, V> {
+    /// Construct a new AtomicCuckooMap with a given number of slots.
+    /// NOTE: Size must be a power of two of at least 8, otherwise `InvalidCapacity` is
+    /// returned.
+    pub fn new(size: usize) -> Result<AtomicCuckooMap<K, V>, AtomicHashMapError> {
+        AtomicCuckooMap::with_hasher(size, BuildMurmurHasher::default())
+    }
+}
+
+impl<K: PodU64, V: PodU64, S: BuildHasher> AtomicCuckooMap<K, V, S> {
+    /// Construct a new AtomicCuckooMap with a given number of slots, hashing keys with
+    /// `hasher`.
+    /// NOTE: Size must be a power of two of at least 8, otherwise `InvalidCapacity` is
+    /// returned.
+    pub fn with_hasher(size: usize, hasher: S)
+            -> Result<AtomicCuckooMap<K, V, S>, AtomicHashMapError> {
+        // At least two buckets, so that keys have two distinct buckets to pick from
+        if size < BUCKET_SLOTS * 2 || !size.is_power_of_two() {
+            return Err(AtomicHashMapError::InvalidCapacity);
+        }
+
+        // Entry handles are 32 bits wide
+        let pool = size.checked_mul(2).filter(|&pool| pool < NIL as usize)
+            .ok_or(AtomicHashMapError::InvalidCapacity)?;
+
+        // Every entry starts out in the pool, in order
+        let entries = (0..pool).map(|index| {
+            let next = if index + 1 < pool { index as u32 + 1 } else { NIL };
+            Entry {
+                key: AtomicU64::new(EMPTY_KEY),
+                value: AtomicU64::new(0),
+                next: AtomicU32::new(next)
+            }
+        }).collect();
+
+        Ok(AtomicCuckooMap {
+            slots: (0..size).map(|_| AtomicU64::new(EMPTY)).collect(),
+            entries,
+            free: AtomicU64::new(pack(0, 0)),
+            buckets: size / BUCKET_SLOTS,
+            count: AtomicU64::new(0),
+            hasher,
+            _types: PhantomData
+        })
+    }
+
+    /// Get the raw slot representation of `key`, which must not be the empty marker
+    fn raw_key(&self, key: K) -> Result<u64, AtomicHashMapError> {
+        let key = key.to_u64();
+        if key == EMPTY_KEY {
+            return Err(AtomicHashMapError::InvalidKey);
+        }
+
+        Ok(key)
+    }
+
+    /// Get the two buckets of the raw `key`, from the low and high halves of its hash.
+    /// The two are distinct so that every key can be moved.
+    fn buckets_of(&self, key: u64) -> (usize, usize) {
+        let mut hasher = self.hasher.build_hasher();
+        hasher.write_u64(key);
+        let hash = hasher.finish();
+
+        let mask = self.buckets - 1;
+        let first = hash as usize & mask;
+        let second = (hash >> 32) as usize & mask;
+        if first == second {
+            return (first, first ^ 1);
+        }
+
+        (first, second)
+    }
+
+    /// Get the bucket of `key` other than `bucket`
+    fn other_bucket(&self, key: u64, bucket: usize) -> usize {
+        let (first, second) = self.buckets_of(key);
+        if bucket == first { second } else { first }
+    }
+
+    /// Get the word of the slot at `index` of `bucket`
+    fn slot(&self, bucket: usize, index: usize) -> &AtomicU64 {
+        &self.slots[bucket * BUCKET_SLOTS + index]
+    }
+
+    /// Replace the word of the slot at `index` with `new` if it is still `current`
+    fn replace(&self, index: usize, current: u64, new: u64) -> Result<u64, u64> {
+        self.slots[index]
+            .compare_exchange(current, new, Ordering::SeqCst, Ordering::SeqCst)
+    }
+
+    /// Take an entry out of the pool and fill it in with `key` and `value`. Returns
+    /// `None` if the pool has run out.
+    fn alloc(&self, key: u64, value: u64) -> Option<u32> {
+        let mut head = self.free.load(Ordering::Acquire);
+        let handle = loop {
+            let (handle, tag) = unpack(head);
+            if handle == NIL {
+                return None;
+            }
+
+            let next = self.entries[handle as usize].next.load(Ordering::Relaxed);
+            match self.free.compare_exchange_weak(head, pack(next, tag.wrapping_add(1)),
+                                                  Ordering::Acquire, Ordering::Acquire) {
+                Ok(_) => break handle,
+                Err(curr) => head = curr
+            }
+        };
+
+        // Readers may still be reading the entry from before it was given up. Order
+        // the new key and value after that, so that a reader seeing them also sees
+        // the slot word that referred to the entry change.
+        atomic::fence(Ordering::Release);
+        let entry = &self.entries[handle as usize];
+        entry.key.store(key, Ordering::Relaxed);
+        entry.value.store(value, Ordering::Relaxed);
+        Some(handle)
+    }
+
+    /// Give the entry `handle`, which no slot refers to anymore, back to the pool
+    fn free(&self, handle: u32) {
+        let mut head = self.free.load(Ordering::Relaxed);
+        loop {
+            let (next, tag) = unpack(head);
+            self.entries[handle as usize].next.store(next, Ordering::Relaxed);
+            match self.free.compare_exchange_weak(head, pack(handle, tag.wrapping_add(1)),
+                                                  Ordering::Release, Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(curr) => head = curr
+            }
+        }
+    }
+
+    /// Read the slots of the buckets `first` and `second` and their entries, retrying
+    /// until no slot word changed while they were read
+    fn view(&self, first: usize, second: usize) -> View {
+        let mut indices = [0; 2 * BUCKET_SLOTS];
+        for (slot, index) in indices.iter_mut().enumerate() {
+            let bucket = if slot < BUCKET_SLOTS { first } else { second };
+            *index = bucket * BUCKET_SLOTS + slot % BUCKET_SLOTS;
+        }
+
+        let mut view = View {
+            indices,
+            words: [0; 2 * BUCKET_SLOTS],
+            entries: [(EMPTY_KEY, 0); 2 * BUCKET_SLOTS]
+        };
+
+        loop {
+            for slot in 0..view.words.len() {
+                let word = self.slots[view.indices[slot]].load(Ordering::SeqCst);
+                view.words[slot] = word;
+
+                if state(word) != EMPTY {
+                    let entry = &self.entries[handle(word) as usize];
+                    view.entries[slot] = (entry.key.load(Ordering::Relaxed),
+                                          entry.value.load(Ordering::Relaxed));
+                }
+            }
+
+            atomic::fence(Ordering::Acquire);
+            let unchanged = view.indices.iter().zip(view.words.iter())
+                .all(|(&index, &word)| self.slots[index].load(Ordering::SeqCst) == word);
+            if unchanged {
+                return view;
+            }
+        }
+    }
+
+    /// Atomically get a value from the hashmap
+    pub fn get(&self, key: &K) -> Option<V> {
+        let key = self.raw_key(*key).ok()?;
+        let (first, second) = self.buckets_of(key);
+
+        let view = self.view(first, second);
+        let slot = view.find(key, is_visible)?;
+        Some(V::from_u64(view.entries[slot].1))
+    }
+
+    /// Check if a key is in the hashmap
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Set a key:value in the hashmap, moving other keys to their other bucket to make
+    /// room if both buckets of `key` are full
+    ///
+    /// Returns the value previously stored for this key, swapped out atomically with
+    /// the new value, or `None` if the key was newly inserted. Returns
+    /// `AtomicHashMapError::Full` if no room can be made within a few moves.
+    pub fn insert(&self, key: K, value: V) -> Result<Option<V>, AtomicHashMapError> {
+        let key = self.raw_key(key)?;
+        let (first, second) = self.buckets_of(key);
+
+        // Linked either as a new key or in place of the entry already holding the key
+        let entry = self.alloc(key, value.to_u64()).ok_or(AtomicHashMapError::Full)?;
+
+        let mut backoff = Backoff::new();
+        loop {
+            let view = self.view(first, second);
+            if self.finish_moves(&view, key) {
+                continue;
+            }
+
+            if let Some(slot) = view.find(key, is_visible) {
+                let word = view.words[slot];
+                let new = successor(word, entry, PUBLISHED, 0);
+                if self.replace(view.indices[slot], word, new).is_ok() {
+                    self.free(handle(word));
+                    return Ok(Some(V::from_u64(view.entries[slot].1)));
+                }
+                continue;
+            }
+
+            if view.find(key, |word| state(word) == PENDING).is_some() {
+                // Another insert of the key is settling, wait for it to be published or
+                // to back out
+                backoff.snooze();
+                continue;
+            }
+
+            let free = (0..view.words.len())
+                .find(|&slot| state(view.words[slot]) == EMPTY);
+            let slot = match free {
+                Some(slot) => slot,
+                None => {
+                    if let Err(err) = self.make_room(first, second) {
+                        self.free(entry);
+                        return Err(err);
+                    }
+                    continue;
+                }
+            };
+
+            let (index, word) = (view.indices[slot], view.words[slot]);
+            let pending = successor(word, entry, PENDING, 0);
+            if self.replace(index, word, pending).is_err() {
+                continue;
+            }
+
+            if self.settle(first, second, key, index, pending) {
+                self.count.fetch_add(1, Ordering::Relaxed);
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Publish the pending entry of `key` linked into the slot at `index` as `word`,
+    /// unless another copy of the key turns up in its buckets `first` and `second`
+    ///
+    /// Returns false if the entry was backed out, or taken out by another insert of
+    /// the key, in which case the insert starts over with the same entry.
+    fn settle(&self, first: usize, second: usize, key: u64, index: usize, word: u64)
+            -> bool {
+        let entry = handle(word);
+        'settle: loop {
+            let view = self.view(first, second);
+            let own = view.indices.iter().position(|&slot| slot == index)
+                .expect("The slot is in one of the buckets of the key");
+            if view.words[own] != word {
+                return false;
+            }
+
+            for slot in (0..view.words.len()).filter(|&slot| slot != own) {
+                let other = view.words[slot];
+                if state(other) == EMPTY || view.entries[slot].0 != key {
+                    continue;
+                }
+
+                match state(other) {
+                    // The key is already in the table, or wins over ours
+                    PUBLISHED | MOVING => {}
+                    PENDING if handle(other) < entry => {}
+                    PENDING => {
+                        // Ours wins, take the other one out, whether or not it saw ours
+                        let _ = self.replace(view.indices[slot], other,
+                                             successor(other, 0, EMPTY, 0));
+                        continue 'settle;
+                    }
+                    _ => continue
+                }
+
+                let _ = self.replace(index, word, successor(word, 0, EMPTY, 0));
+                return false;
+            }
+
+            let published = successor(word, entry, PUBLISHED, 0);
+            return self.replace(index, word, published).is_ok();
+        }
+    }
+
+    /// Finish the moves of the entries of `key` marked as moving in `view`. Returns
+    /// true if there were any, which leaves the view out of date.
+    fn finish_moves(&self, view: &View, key: u64) -> bool {
+        let mut moved = false;
+        for slot in 0..view.words.len() {
+            if state(view.words[slot]) == MOVING && view.entries[slot].0 == key {
+                self.finish_move(view.indices[slot], view.words[slot]);
+                moved = true;
+            }
+        }
+
+        moved
+    }
+
+    /// Carry out the rest of the move marked by `word` in the slot at `src`, unless
+    /// the slot no longer holds `word`
+    fn finish_move(&self, src: usize, word: u64) {
+        let entry = handle(word);
+        let key = self.entries[entry as usize].key.load(Ordering::Relaxed);
+        let dst = self.slot(self.other_bucket(key, src / BUCKET_SLOTS), target(word));
+
+        // An entry isn't given up while a slot is marked as moving it, so the key and
+        // the slot it led to are right if the mark is still there after reading them
+        atomic::fence(Ordering::Acquire);
+        let mut dst_word = dst.load(Ordering::SeqCst);
+        if self.slots[src].load(Ordering::SeqCst) != word {
+            return;
+        }
+
+        // Nothing else is linked into the reserved slot while the mark is there
+        if state(dst_word) == RESERVED && handle(dst_word) == entry {
+            let published = successor(dst_word, entry, PUBLISHED, 0);
+            dst_word = match dst.compare_exchange(dst_word, published, Ordering::SeqCst,
+                                                  Ordering::SeqCst) {
+                Ok(_) => published,
+                Err(curr) => curr
+            };
+        }
+
+        if is_visible(dst_word) && handle(dst_word) == entry {
+            let _ = self.replace(src, word, successor(word, 0, EMPTY, 0));
+        }
+    }
+
+    /// Move keys out of the buckets `first` and `second` until one of them has a free
+    /// slot, stopping early if another thread gets in the way. Returns
+    /// `AtomicHashMapError::Full` if no room can be made within `MAX_PATH` moves.
+    fn make_room(&self, first: usize, second: usize) -> Result<(), AtomicHashMapError> {
+        let path = self.find_path(first, second).ok_or(AtomicHashMapError::Full)?;
+
+        // Walk the path back from the bucket with a free slot, moving each key into
+        // the slot freed by the one after it
+        for pair in path.windows(2).rev() {
+            let index = pair[1].from.expect("Only the first node has no parent").1;
+            if !self.move_entry(pair[0].bucket, index, pair[1].bucket) {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Move the entry in the slot at `index` of bucket `from` to a free slot of `to`,
+    /// the other bucket of its key. Returns false if the slot changed since the path
+    /// was found or `to` has no free slot left.
+    fn move_entry(&self, from: usize, index: usize, to: usize) -> bool {
+        let view = self.view(from, to);
+        let (src, word) = (view.indices[index], view.words[index]);
+        if state(word) != PUBLISHED ||
+                self.other_bucket(view.entries[index].0, from) != to {
+            return false;
+        }
+
+        // The slot may be the end of a move that isn't finished yet
+        let entry = handle(word);
+        for slot in 0..view.words.len() {
+            if slot != index && state(view.words[slot]) == MOVING &&
+                    handle(view.words[slot]) == entry {
+                self.finish_move(view.indices[slot], view.words[slot]);
+                return false;
+            }
+        }
+
+        let target = match (BUCKET_SLOTS..2 * BUCKET_SLOTS)
+                .find(|&slot| state(view.words[slot]) == EMPTY) {
+            Some(slot) => slot,
+            None => return false
+        };
+
+        let (dst, dst_word) = (view.indices[target], view.words[target]);
+        let reserved = successor(dst_word, entry, RESERVED, 0);
+        if self.replace(dst, dst_word, reserved).is_err() {
+            return false;
+        }
+
+        let moving = successor(word, entry, MOVING, target - BUCKET_SLOTS);
+        if self.replace(src, word, moving).is_err() {
+            // The entry was overwritten, removed or moved first, give the slot back
+            let _ = self.replace(dst, reserved, successor(reserved, 0, EMPTY, 0));
+            return false;
+        }
+
+        self.finish_move(src, moving);
+        true
+    }
+
+    /// Search breadth-first for the shortest chain of moves that frees a slot in one
+    /// of the buckets `first` and `second`. Returns the chain from one of those buckets
+    /// to a bucket with a free slot.
+    fn find_path(&self, first: usize, second: usize) -> Option<Vec<PathNode>> {
+        let mut nodes = vec![
+            PathNode { bucket: first, from: None, depth: 0 },
+            PathNode { bucket: second, from: None, depth: 0 }
+        ];
+        let mut queue: VecDeque<usize> = (0..nodes.len()).collect();
+
+        while let Some(node_index) = queue.pop_front() {
+            let node = nodes[node_index];
+
+            let mut words = [EMPTY; BUCKET_SLOTS];
+            for (index, word) in words.iter_mut().enumerate() {
+                *word = self.slot(node.bucket, index).load(Ordering::SeqCst);
+            }
+
+            if words.iter().any(|&word| state(word) == EMPTY) {
+                // Unwind the chain back to one of the buckets of the new key
+                let mut path = vec![node];
+                let mut curr = node;
+                while let Some((from_node, _)) = curr.from {
+                    curr = nodes[from_node];
+                    path.push(curr);
+                }
+                path.reverse();
+                return Some(path);
+            }
+
+            if node.depth == MAX_PATH {
+                continue;
+            }
+
+            for (index, &word) in words.iter().enumerate() {
+                // Only published entries move. The key is checked again by the move.
+                if state(word) != PUBLISHED {
+                    continue;
+                }
+
+                let key = self.entries[handle(word) as usize].key.load(Ordering::Relaxed);
+                let bucket = self.other_bucket(key, node.bucket);
+
+                // Moving a key back into a bucket already on the path would have it
+                // reuse a slot the path still depends on
+                let mut curr = Some(node_index);
+                let mut on_path = false;
+                while let Some(curr_index) = curr {
+                    on_path |= nodes[curr_index].bucket == bucket;
+                    curr = nodes[curr_index].from.map(|(from_node, _)| from_node);
+                }
+
+                if on_path {
+                    continue;
+                }
+
+                nodes.push(PathNode {
+                    bucket,
+                    from: Some((node_index, index)),
+                    depth: node.depth + 1
+                });
+                queue.push_back(nodes.len() - 1);
+            }
+        }
+
+        None
+    }
+
+    /// Remove a key from the hashmap, returning its value if it was present
+    pub fn remove(&self, key: K) -> Option<V> {
+        let key = self.raw_key(key).ok()?;
+        let (first, second) = self.buckets_of(key);
+
+        loop {
+            let view = self.view(first, second);
+            if self.finish_moves(&view, key) {
+                continue;
+            }
+
+            let slot = view.find(key, is_visible)?;
+            let word = view.words[slot];
+            let empty = successor(word, 0, EMPTY, 0);
+            if self.replace(view.indices[slot], word, empty).is_ok() {
+                self.count.fetch_sub(1, Ordering::Relaxed);
+                self.free(handle(word));
+                return Some(V::from_u64(view.entries[slot].1));
+            }
+        }
+    }
+
+    /// Collect the `(key, value)` pairs currently in the hashmap, in table order
+    ///
+    /// The table is read again until no slot changed while it was read, so the pairs
+    /// were all in the table at the same time, but this may keep retrying while other
+    /// threads write to the table nonstop.
+    pub fn to_vec(&self) -> Vec<(K, V)> {
+        let mut words = vec![EMPTY; self.slots.len()];
+        let mut entries = vec![(EMPTY_KEY, 0); self.slots.len()];
+        loop {
+            for (index, slot) in self.slots.iter().enumerate() {
+                words[index] = slot.load(Ordering::SeqCst);
+                if state(words[index]) != EMPTY {
+                    let entry = &self.entries[handle(words[index]) as usize];
+                    entries[index] = (entry.key.load(Ordering::Relaxed),
+                                      entry.value.load(Ordering::Relaxed));
+                }
+            }
+
+            atomic::fence(Ordering::Acquire);
+            if self.slots.iter().zip(words.iter())
+                    .all(|(slot, &word)| slot.load(Ordering::SeqCst) == word) {
+                break;
+            }
+        }
+
+        // A moving entry is also published in the slot it moves to until its move is
+        // finished, and only counted there
+        let published: HashSet<u32> = words.iter()
+            .filter(|&&word| state(word) == PUBLISHED)
+            .map(|&word| handle(word))
+            .collect();
+
+        words.iter().zip(entries).filter(|&(&word, _)| {
+            state(word) == PUBLISHED ||
+                state(word) == MOVING && !published.contains(&handle(word))
+        }).map(|(_, (key, value))| (K::from_u64(key), V::from_u64(value))).collect()
+    }
+
+    /// Get the number of elements currently in the hashtable
+    pub fn len(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Returns true if the hashtable has no elements
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the total number of slots in the hashtable
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Get the fraction of slots in use
+    pub fn load_factor(&self) -> f64 {
+        self.len() as f64 / self.capacity() as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_insert_get_remove() {
+        let hashtable: AtomicCuckooMap = AtomicCuckooMap::new(1 << 4).unwrap();
+        assert_eq!(hashtable.insert(1, 10), Ok(None));
+        assert_eq!(hashtable.insert(1, 11), Ok(Some(10)));
+        assert_eq!(hashtable.get(&1), Some(11));
+        assert!(hashtable.contains_key(&1));
+
+        assert_eq!(hashtable.insert(0, 1), Err(AtomicHashMapError::InvalidKey));
+        assert_eq!(hashtable.get(&0), None);
+
+        assert_eq!(hashtable.remove(1), Some(11));
+        assert_eq!(hashtable.remove(1), None);
+        assert!(hashtable.is_empty());
+
+        assert!(AtomicCuckooMap::<u64, u64>::new(4).is_err());
+        assert!(AtomicCuckooMap::<u64, u64>::new(24).is_err());
+    }
+
+    #[test]
+    fn test_high_load() {
+        let size = 1 << 12;
+        let hashtable: AtomicCuckooMap = AtomicCuckooMap::new(size).unwrap();
+
+        let mut inserted = Vec::new();
+        for x in 1..=size as u64 {
+            match hashtable.insert(x, x * 2) {
+                Ok(None) => inserted.push(x),
+                Err(AtomicHashMapError::Full) => break,
+                res => panic!("Unexpected insert result {:?}", res)
+            }
+        }
+
+        assert!(hashtable.load_factor() > 0.9, "Only reached {}", hashtable.load_factor());
+        for x in inserted {
+            assert_eq!(hashtable.get(&x), Some(x * 2));
+        }
+    }
+
+    #[test]
+    fn test_matches_hashmap() {
+        let hashtable: AtomicCuckooMap = AtomicCuckooMap::new(1 << 10).unwrap();
+        let mut expected = HashMap::new();
+
+        let mut state = 0x9e37_79b9_7f4a_7c15_u64;
+        for _ in 0..20_000 {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+
+            let key = state % 900 + 1;
+            if state & (1 << 40) == 0 {
+                assert_eq!(hashtable.insert(key, state), Ok(expected.insert(key, state)));
+            } else {
+                assert_eq!(hashtable.remove(key), expected.remove(&key));
+            }
+        }
+
+        assert_eq!(hashtable.len() as usize, expected.len());
+        for key in 1..=900 {
+            assert_eq!(hashtable.get(&key), expected.get(&key).copied());
+        }
+    }
+
+    #[test]
+    fn test_readers_during_writes() {
+        use std::sync::Arc;
+        use std::thread;
+
+        let hashtable: Arc<AtomicCuckooMap> = Arc::new(AtomicCuckooMap::new(1 << 10).unwrap());
+
+        // Keys that are never removed must always be found, even while other inserts
+        // move them between their buckets
+        for x in 1..=500 {
+            hashtable.insert(x, x).unwrap();
+        }
+
+        let writer = {
+            let hashtable = hashtable.clone();
+            thread::spawn(move || {
+                for round in 0..20 {
+                    for x in 501..=900 {
+                        hashtable.insert(x, round).unwrap();
+                    }
+                    for x in 501..=900 {
+                        hashtable.remove(x);
+                    }
+                }
+            })
+        };
+
+        let readers: Vec<_> = (0..4).map(|_| {
+            let hashtable = hashtable.clone();
+            thread::spawn(move || {
+                for _ in 0..20 {
+                    for x in 1..=500 {
+                        assert_eq!(hashtable.get(&x), Some(x));
+                    }
+                }
+            })
+        }).collect();
+
+        writer.join().unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        assert_eq!(hashtable.len(), 500);
+    }
+
+    #[test]
+    fn test_writers_threads() {
+        use std::sync::Arc;
+        use std::thread;
+
+        let hashtable: Arc<AtomicCuckooMap> =
+            Arc::new(AtomicCuckooMap::new(1 << 10).unwrap());
+
+        // Writers on disjoint keys fill the table far enough that most inserts have to
+        // move keys written by the other threads
+        let writers: Vec<_> = (0..4u64).map(|thread| {
+            let hashtable = hashtable.clone();
+            thread::spawn(move || {
+                let keys = thread * 200 + 1..=thread * 200 + 200;
+                for round in 0..10 {
+                    for x in keys.clone() {
+                        let old = if round == 0 { None } else { Some(x + round - 1) };
+                        assert_eq!(hashtable.insert(x, x + round), Ok(old));
+                    }
+                    for x in keys.clone().step_by(2) {
+                        assert_eq!(hashtable.remove(x), Some(x + round));
+                    }
+                    for x in keys.clone().step_by(2) {
+                        assert_eq!(hashtable.insert(x, x + round), Ok(None));
+                    }
+                }
+            })
+        }).collect();
+
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        assert_eq!(hashtable.len(), 800);
+        let mut pairs = hashtable.to_vec();
+        pairs.sort_unstable();
+        assert_eq!(pairs, (1..=800).map(|x| (x, x + 9)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_threads_no_duplicates() {
+        use std::sync::Arc;
+        use std::thread;
+
+        let hashtable: Arc<AtomicCuckooMap> =
+            Arc::new(AtomicCuckooMap::new(1 << 8).unwrap());
+
+        // Every thread inserts and removes the same keys, so concurrent inserts of a
+        // key race to publish it
+        let threads: Vec<_> = (0..4).map(|_| {
+            let hashtable = hashtable.clone();
+            thread::spawn(move || {
+                for round in 0..200 {
+                    for x in 1..=100 {
+                        hashtable.insert(x, round).unwrap();
+                    }
+                    for x in (1..=100).step_by(3) {
+                        hashtable.remove(x);
+                    }
+                }
+            })
+        }).collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let mut keys: Vec<u64> =
+            hashtable.to_vec().into_iter().map(|(key, _)| key).collect();
+        keys.sort_unstable();
+        let len = keys.len();
+        keys.dedup();
+        assert_eq!(keys.len(), len, "A key was published twice");
+        assert_eq!(hashtable.len() as usize, len);
+
+        for x in 1..=100 {
+            hashtable.remove(x);
+            assert_eq!(hashtable.get(&x), None);
+        }
+        assert!(hashtable.is_empty());
+    }
+}
@@ -0,0 +1,258 @@
+//! Fixed-size Chase-Lev work-stealing deque
+//!
+//! One owner pushes and pops work at the bottom of the deque while any number of
+//! thieves steal from the top. The owner works on its end with plain loads and
+//! stores and only races the thieves for the very last item, which both sides claim
+//! with a compare-exchange of the top. Orderings and fences follow "Correct and
+//! Efficient Work-Stealing for Weak Memory Models" by Lê, Pop, Cohen and Zappa
+//! Nardelli:
+//!
+//! * `push` writes the item, then a `Release` fence orders it before the store of
+//!   the new bottom, so a thief that reads that bottom with `Acquire` sees the item.
+//! * `pop` publishes the decremented bottom before reading the top, and `steal`
+//!   reads the top before the bottom, each with a `SeqCst` fence in between. Without
+//!   them the owner and a thief could both read the other's old cursor and take the
+//!   same last item.
+//! * The last item and every steal are claimed with a `SeqCst` compare-exchange of
+//!   the top, so exactly one of the threads racing for an item gets it.
+//!
+//! The buffer doesn't grow, so no memory ever has to be reclaimed while thieves may
+//! still be reading it: `push` returns `AtomicHashMapError::Full` instead. Items are
+//! stored in `AtomicU64` slots, so a thief reading a slot the owner is overwriting
+//! only gets a stale item, which its failing compare-exchange then throws away.
+
+use alloc::boxed::Box;
+use core::marker::PhantomData;
+
+use crate::sync::atomic::{self, AtomicBool, AtomicIsize, AtomicU64, Ordering};
+
+use crate::error::AtomicHashMapError;
+use crate::pod::PodU64;
+
+/// Work-stealing deque of up to `capacity` items of type `T`
+///
+/// Thieves call `steal` through a shared reference. The owner pushes and pops through
+/// the single `DequeWorker` handed out by `worker`.
+pub struct WorkStealingDeque<T: PodU64 = u64> {
+    slots: Box<[AtomicU64]>,
+
+    /// Position of the next item to steal
+    top: AtomicIsize,
+
+    /// Position of the next item to push, only moved by the owner
+    bottom: AtomicIsize,
+
+    /// Set while a `DequeWorker` exists
+    worker: AtomicBool,
+
+    _items: PhantomData<T>
+}
+
+impl<T: PodU64> WorkStealingDeque<T> {
+    /// Construct an empty deque holding up to `capacity` items.
+    /// NOTE: Capacity must be a power of two, otherwise `InvalidCapacity` is returned.
+    pub fn new(capacity: usize) -> Result<WorkStealingDeque<T>, AtomicHashMapError> {
+        if capacity < 2 || !capacity.is_power_of_two() || capacity > isize::MAX as usize {
+            return Err(AtomicHashMapError::InvalidCapacity);
+        }
+
+        Ok(WorkStealingDeque {
+            slots: (0..capacity).map(|_| AtomicU64::new(0)).collect(),
+            top: AtomicIsize::new(0),
+            bottom: AtomicIsize::new(0),
+            worker: AtomicBool::new(false),
+            _items: PhantomData
+        })
+    }
+
+    /// Get the slot of position `pos`
+    fn slot(&self, pos: isize) -> &AtomicU64 {
+        &self.slots[pos as usize & (self.slots.len() - 1)]
+    }
+
+    /// Get the owner's end of the deque, or `None` if it is already taken
+    pub fn worker(&self) -> Option<DequeWorker<'_, T>> {
+        self.worker.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+
+        Some(DequeWorker { deque: self })
+    }
+
+    /// Steal the item at the top of the deque, the oldest one pushed, or `None` if it
+    /// is empty. Retries when another thread takes the item first.
+    pub fn steal(&self) -> Option<T> {
+        loop {
+            let top = self.top.load(Ordering::Acquire);
+            atomic::fence(Ordering::SeqCst);
+            let bottom = self.bottom.load(Ordering::Acquire);
+            if top >= bottom {
+                return None;
+            }
+
+            // Read before claiming, since the slot may be reused once the top moves
+            let item = self.slot(top).load(Ordering::Relaxed);
+            if self.top.compare_exchange(top, top + 1, Ordering::SeqCst,
+                                         Ordering::Relaxed).is_ok() {
+                return Some(T::from_u64(item));
+            }
+        }
+    }
+
+    /// Get the number of items in the deque. Only a hint while other threads push,
+    /// pop or steal.
+    pub fn len(&self) -> usize {
+        let bottom = self.bottom.load(Ordering::Relaxed);
+        let top = self.top.load(Ordering::Relaxed);
+        (bottom - top).max(0) as usize
+    }
+
+    /// Returns true if the deque holds no items
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the number of items the deque holds at most
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+}
+
+/// Owner's end of a `WorkStealingDeque`, of which there is at most one at a time
+pub struct DequeWorker<'a, T: PodU64> {
+    deque: &'a WorkStealingDeque<T>
+}
+
+impl<'a, T: PodU64> DequeWorker<'a, T> {
+    /// Push `item` at the bottom of the deque, or return `AtomicHashMapError::Full`
+    /// if it holds `capacity` items
+    pub fn push(&mut self, item: T) -> Result<(), AtomicHashMapError> {
+        let deque = self.deque;
+        let bottom = deque.bottom.load(Ordering::Relaxed);
+        let top = deque.top.load(Ordering::Acquire);
+        if bottom - top >= deque.slots.len() as isize {
+            return Err(AtomicHashMapError::Full);
+        }
+
+        deque.slot(bottom).store(item.to_u64(), Ordering::Relaxed);
+        atomic::fence(Ordering::Release);
+        deque.bottom.store(bottom + 1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Pop the item at the bottom of the deque, the newest one pushed, or `None` if
+    /// it is empty
+    pub fn pop(&mut self) -> Option<T> {
+        let deque = self.deque;
+        let bottom = deque.bottom.load(Ordering::Relaxed) - 1;
+        deque.bottom.store(bottom, Ordering::Relaxed);
+        atomic::fence(Ordering::SeqCst);
+        let top = deque.top.load(Ordering::Relaxed);
+
+        if top > bottom {
+            // Empty, put the bottom back
+            deque.bottom.store(bottom + 1, Ordering::Relaxed);
+            return None;
+        }
+
+        let item = deque.slot(bottom).load(Ordering::Relaxed);
+        if top < bottom {
+            // Thieves can't reach this item while the bottom is below it
+            return Some(T::from_u64(item));
+        }
+
+        // Last item, race the thieves for it
+        let won = deque.top.compare_exchange(top, top + 1, Ordering::SeqCst,
+                                             Ordering::Relaxed).is_ok();
+        deque.bottom.store(bottom + 1, Ordering::Relaxed);
+        won.then(|| T::from_u64(item))
+    }
+}
+
+impl<'a, T: PodU64> Drop for DequeWorker<'a, T> {
+    fn drop(&mut self) {
+        self.deque.worker.store(false, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    #[test]
+    fn test_deque() {
+        let deque: WorkStealingDeque<i32> = WorkStealingDeque::new(4).unwrap();
+        let mut worker = deque.worker().unwrap();
+        assert!(deque.worker().is_none());
+        assert_eq!(worker.pop(), None);
+        assert_eq!(deque.steal(), None);
+
+        for item in [1, -2, 3, -4] {
+            worker.push(item).unwrap();
+        }
+        assert_eq!(worker.push(5), Err(AtomicHashMapError::Full));
+        assert_eq!(deque.len(), 4);
+
+        // The owner works newest first, thieves take the oldest
+        assert_eq!(worker.pop(), Some(-4));
+        assert_eq!(deque.steal(), Some(1));
+        worker.push(5).unwrap();
+        worker.push(6).unwrap();
+        assert_eq!(deque.steal(), Some(-2));
+        assert_eq!(worker.pop(), Some(6));
+        assert_eq!(worker.pop(), Some(5));
+        assert_eq!(worker.pop(), Some(3));
+        assert_eq!(worker.pop(), None);
+        assert!(deque.is_empty());
+
+        drop(worker);
+        assert!(deque.worker().is_some());
+        assert!(WorkStealingDeque::<u64>::new(3).is_err());
+    }
+
+    #[test]
+    fn test_deque_threads() {
+        let deque: WorkStealingDeque = WorkStealingDeque::new(256).unwrap();
+        let mut worker = deque.worker().unwrap();
+
+        let stolen = thread::scope(|scope| {
+            let thieves = (0..3).map(|_| {
+                let deque = &deque;
+                scope.spawn(move || {
+                    let mut stolen = Vec::new();
+                    let mut misses = 0;
+                    while misses < 10_000 {
+                        match deque.steal() {
+                            Some(item) => stolen.push(item),
+                            None => misses += 1
+                        }
+                    }
+                    stolen
+                })
+            }).collect::<Vec<_>>();
+
+            // The owner pushes every item and pops some of them back
+            let mut items = Vec::new();
+            for item in 0..20_000 {
+                while worker.push(item).is_err() {
+                    items.extend(worker.pop());
+                }
+                if item % 3 == 0 {
+                    items.extend(worker.pop());
+                }
+            }
+            items.extend(core::iter::from_fn(|| worker.pop()));
+
+            for thief in thieves {
+                items.extend(thief.join().unwrap());
+            }
+            items
+        });
+
+        // Every item was taken exactly once
+        let mut items = stolen;
+        items.sort();
+        assert_eq!(items, (0..20_000).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,464 @@
+//! Epoch-based reclamation for structures that free memory other threads may still
+//! be reading
+//!
+//! A thread registers with a `Collector` once, getting a `LocalHandle`, and pins the
+//! handle for the duration of every operation that reads shared nodes. Memory that
+//! has been unlinked from a structure is handed to `Guard::defer_destroy` instead of
+//! being freed straight away, and is freed by a later `collect` once no thread can
+//! still hold a reference to it.
+//!
+//! The collector keeps a global epoch. Pinning records the current global epoch in
+//! the handle's participant, and the epoch only moves forward once every pinned
+//! participant has caught up with it. Garbage is tagged with the epoch at the time
+//! it was deferred. A thread pinned at that point can only still be pinned in that
+//! epoch or the next one, so once the global epoch is two past the tag nobody can
+//! hold a reference to the garbage and it is freed.
+//!
+//! Each handle keeps its garbage in a bag of its own and collects it every
+//! `COLLECT_EVERY` deferrals, or when `collect` is called. Garbage left in the bag of
+//! a dropped handle is adopted by the next handle that collects, or freed with the
+//! collector.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cell::{Cell, RefCell};
+use core::ptr;
+
+use crate::sync::atomic::{self, AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+
+/// Number of deferrals between collections of a handle's bag
+const COLLECT_EVERY: usize = 64;
+
+/// Participant epoch bit set while its handle is pinned
+const PINNED: usize = 1;
+
+/// Deferred function along with the epoch it was deferred in
+type Deferred = (usize, Box<dyn FnOnce() + Send>);
+
+/// Registration slot of one handle, reused once the handle is dropped
+struct Participant {
+    /// Epoch the handle was pinned in, shifted up past `PINNED`, or 0 if unpinned
+    epoch: AtomicUsize,
+
+    /// Set while a `LocalHandle` owns this participant
+    in_use: AtomicBool,
+
+    /// Next participant of the collector, fixed once this one is published
+    next: *mut Participant
+}
+
+/// Garbage of a dropped handle, waiting to be adopted
+struct Orphan {
+    bag: Vec<Deferred>,
+    next: *mut Orphan
+}
+
+/// Shared state of the epoch-based reclamation of one structure or set of structures
+pub struct Collector {
+    /// Global epoch
+    epoch: AtomicUsize,
+
+    /// List of every participant ever registered. Only pushed to until the collector
+    /// is dropped.
+    participants: AtomicPtr<Participant>,
+
+    /// Bags of dropped handles, taken all at once by the handle adopting them
+    orphans: AtomicPtr<Orphan>
+}
+
+// SAFETY: Participants and orphans are only reached through atomics and are freed by
+// `Drop`, which takes the collector by value
+unsafe impl Send for Collector {}
+unsafe impl Sync for Collector {}
+
+impl Collector {
+    /// Construct a collector with no registered handles
+    pub fn new() -> Collector {
+        Collector {
+            epoch: AtomicUsize::new(0),
+            participants: AtomicPtr::new(ptr::null_mut()),
+            orphans: AtomicPtr::new(ptr::null_mut())
+        }
+    }
+
+    /// Get the participants registered with the collector
+    fn participants(&self) -> impl Iterator<Item = &Participant> {
+        let mut curr = self.participants.load(Ordering::Acquire);
+        core::iter::from_fn(move || {
+            // SAFETY: Participants are only freed when the collector is dropped
+            let participant = unsafe { curr.as_ref()? };
+            curr = participant.next;
+            Some(participant)
+        })
+    }
+
+    /// Register a new handle, to be kept by the calling thread and used for every
+    /// operation it makes on the structures reclaimed by this collector
+    pub fn register(&self) -> LocalHandle<'_> {
+        // Reuse the participant of a dropped handle if there is one
+        let participant = self.participants().find(|participant| {
+            participant.in_use.compare_exchange(false, true, Ordering::Acquire,
+                                                Ordering::Relaxed).is_ok()
+        });
+
+        let participant = participant.unwrap_or_else(|| {
+            let new = Box::into_raw(Box::new(Participant {
+                epoch: AtomicUsize::new(0),
+                in_use: AtomicBool::new(true),
+                next: ptr::null_mut()
+            }));
+
+            let mut head = self.participants.load(Ordering::Relaxed);
+            loop {
+                // SAFETY: `new` isn't shared until the compare-exchange succeeds
+                unsafe { (*new).next = head; }
+                let res = self.participants.compare_exchange_weak(head, new,
+                                                                  Ordering::Release,
+                                                                  Ordering::Relaxed);
+                match res {
+                    Ok(_) => break,
+                    Err(curr) => head = curr
+                }
+            }
+
+            // SAFETY: Participants are only freed when the collector is dropped
+            unsafe { &*new }
+        });
+
+        LocalHandle {
+            collector: self,
+            participant,
+            guards: Cell::new(0),
+            deferred: Cell::new(0),
+            bag: RefCell::new(Vec::new())
+        }
+    }
+
+    /// Move the global epoch one forward if every pinned participant is in it,
+    /// returning the global epoch
+    fn try_advance(&self) -> usize {
+        let global = self.epoch.load(Ordering::Relaxed);
+        atomic::fence(Ordering::SeqCst);
+
+        for participant in self.participants() {
+            let epoch = participant.epoch.load(Ordering::Relaxed);
+            if epoch & PINNED != 0 && epoch >> 1 != global {
+                return global;
+            }
+        }
+
+        // Order the reads of nodes by the threads unpinned above before the garbage
+        // freed in the new epoch
+        atomic::fence(Ordering::Acquire);
+        let next = global.wrapping_add(1);
+        match self.epoch.compare_exchange(global, next, Ordering::Release,
+                                          Ordering::Relaxed) {
+            Ok(_) => next,
+            Err(curr) => curr
+        }
+    }
+}
+
+impl Default for Collector {
+    fn default() -> Collector {
+        Collector::new()
+    }
+}
+
+impl Drop for Collector {
+    fn drop(&mut self) {
+        // No handle is left, so every piece of garbage can go
+        let mut orphan = self.orphans.load(Ordering::Relaxed);
+        while !orphan.is_null() {
+            // SAFETY: Orphans were allocated by `LocalHandle::drop` and are only
+            // reachable from this list
+            let boxed = unsafe { Box::from_raw(orphan) };
+            orphan = boxed.next;
+            for (_, func) in boxed.bag {
+                func();
+            }
+        }
+
+        let mut participant = self.participants.load(Ordering::Relaxed);
+        while !participant.is_null() {
+            // SAFETY: Participants were allocated by `register` and are only
+            // reachable from this list
+            let boxed = unsafe { Box::from_raw(participant) };
+            participant = boxed.next;
+        }
+    }
+}
+
+/// Registration of one thread with a `Collector`
+///
+/// A handle is only used by the thread that holds it, while the collector is shared.
+pub struct LocalHandle<'a> {
+    collector: &'a Collector,
+    participant: &'a Participant,
+
+    /// Number of live guards, the handle is pinned while it isn't 0
+    guards: Cell<usize>,
+
+    /// Number of deferrals since the last collection
+    deferred: Cell<usize>,
+
+    /// Garbage deferred through this handle and not freed yet
+    bag: RefCell<Vec<Deferred>>
+}
+
+impl<'a> LocalHandle<'a> {
+    /// Pin the handle until the returned guard is dropped. Nodes read from a
+    /// structure while pinned aren't freed before the guard is dropped. Pinning an
+    /// already pinned handle is cheap.
+    pub fn pin(&self) -> Guard<'_> {
+        let guards = self.guards.get();
+        self.guards.set(guards + 1);
+
+        if guards == 0 {
+            let global = self.collector.epoch.load(Ordering::Relaxed);
+            self.participant.epoch.store(global << 1 | PINNED, Ordering::Relaxed);
+
+            // Publish the pin before any read of a shared node. Pairs with the fence
+            // of `try_advance`.
+            atomic::fence(Ordering::SeqCst);
+        }
+
+        Guard { handle: self }
+    }
+
+    /// Returns true if a guard of this handle is live
+    pub fn is_pinned(&self) -> bool {
+        self.guards.get() != 0
+    }
+
+    /// Try to move the global epoch forward and free the garbage of this handle, and
+    /// of dropped handles, that no thread can reach any more
+    pub fn collect(&self) {
+        self.deferred.set(0);
+        let global = self.collector.try_advance();
+
+        let mut bag = self.bag.borrow_mut();
+
+        // Adopt the garbage of dropped handles
+        let mut orphan = self.collector.orphans.swap(ptr::null_mut(), Ordering::Acquire);
+        while !orphan.is_null() {
+            // SAFETY: The swap took the whole list, so no other thread can reach it
+            let boxed = unsafe { Box::from_raw(orphan) };
+            orphan = boxed.next;
+            bag.extend(boxed.bag);
+        }
+
+        let (ready, waiting) = core::mem::take(&mut *bag).into_iter()
+            .partition::<Vec<_>, _>(|(epoch, _)| global.wrapping_sub(*epoch) >= 2);
+        *bag = waiting;
+
+        // Don't hold the bag while running the functions, which may defer more
+        drop(bag);
+        for (_, func) in ready {
+            func();
+        }
+    }
+
+    /// Defer `func` until no thread can hold a reference read while pinned now
+    fn defer_boxed(&self, func: Box<dyn FnOnce() + Send>) {
+        atomic::fence(Ordering::SeqCst);
+        let epoch = self.collector.epoch.load(Ordering::Relaxed);
+        self.bag.borrow_mut().push((epoch, func));
+
+        self.deferred.set(self.deferred.get() + 1);
+        if self.deferred.get() >= COLLECT_EVERY {
+            self.collect();
+        }
+    }
+}
+
+impl<'a> Drop for LocalHandle<'a> {
+    fn drop(&mut self) {
+        let bag = core::mem::take(self.bag.get_mut());
+        if !bag.is_empty() {
+            let orphan = Box::into_raw(Box::new(Orphan { bag, next: ptr::null_mut() }));
+            let mut head = self.collector.orphans.load(Ordering::Relaxed);
+            loop {
+                // SAFETY: `orphan` isn't shared until the compare-exchange succeeds
+                unsafe { (*orphan).next = head; }
+                match self.collector.orphans.compare_exchange_weak(head, orphan,
+                                                                   Ordering::Release,
+                                                                   Ordering::Relaxed) {
+                    Ok(_) => break,
+                    Err(curr) => head = curr
+                }
+            }
+        }
+
+        self.participant.in_use.store(false, Ordering::Release);
+    }
+}
+
+/// Pointer that is only dereferenced once no other thread can reach it
+pub(crate) struct SendPtr<T>(pub(crate) *mut T);
+
+// SAFETY: The pointee is `Send` and only used by the thread freeing it
+unsafe impl<T: Send> Send for SendPtr<T> {}
+
+/// Proof that a `LocalHandle` is pinned, unpinning it when dropped
+pub struct Guard<'a> {
+    handle: &'a LocalHandle<'a>
+}
+
+impl<'a> Guard<'a> {
+    /// Get the collector the pinned handle is registered with
+    pub(crate) fn collector(&self) -> &Collector {
+        self.handle.collector
+    }
+
+    /// Run `func` once no thread can hold a reference read while pinned now
+    pub fn defer<F: FnOnce() + Send + 'static>(&self, func: F) {
+        self.handle.defer_boxed(Box::new(func));
+    }
+
+    /// Free `ptr` once no thread can hold a reference to it read while pinned now
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must come from `Box::into_raw`, must already be unreachable for threads
+    /// pinning from now on, and must not be freed in any other way.
+    pub unsafe fn defer_destroy<T: Send + 'static>(&self, ptr: *mut T) {
+        let ptr = SendPtr(ptr);
+        self.defer(move || {
+            let ptr = ptr;
+            // SAFETY: Guaranteed by the caller
+            unsafe { drop(Box::from_raw(ptr.0)); }
+        });
+    }
+}
+
+impl<'a> Drop for Guard<'a> {
+    fn drop(&mut self) {
+        let guards = self.handle.guards.get() - 1;
+        self.handle.guards.set(guards);
+
+        if guards == 0 {
+            // Release the reads made while pinned to the thread freeing their nodes
+            self.handle.participant.epoch.store(0, Ordering::Release);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_epoch() {
+        let collector = Collector::new();
+        let freed = Arc::new(AtomicUsize::new(0));
+
+        let reader = collector.register();
+        let writer = collector.register();
+
+        let read_guard = reader.pin();
+        {
+            let guard = writer.pin();
+            let freed = freed.clone();
+            guard.defer(move || { freed.fetch_add(1, Ordering::Relaxed); });
+        }
+
+        // The reader pinned before the deferral holds the garbage back
+        for _ in 0..10 {
+            writer.collect();
+        }
+        assert_eq!(freed.load(Ordering::Relaxed), 0);
+
+        drop(read_guard);
+        for _ in 0..3 {
+            writer.collect();
+        }
+        assert_eq!(freed.load(Ordering::Relaxed), 1);
+
+        // Nested pins only unpin with the outer guard
+        let outer = reader.pin();
+        let inner = reader.pin();
+        drop(inner);
+        assert!(reader.is_pinned());
+        drop(outer);
+        assert!(!reader.is_pinned());
+    }
+
+    #[test]
+    fn test_epoch_orphans() {
+        let freed = Arc::new(AtomicUsize::new(0));
+        let collector = Collector::new();
+        {
+            let handle = collector.register();
+            let guard = handle.pin();
+            for _ in 0..3 {
+                let freed = freed.clone();
+                guard.defer(move || { freed.fetch_add(1, Ordering::Relaxed); });
+            }
+        }
+
+        // A new handle reuses the participant and adopts the garbage
+        let handle = collector.register();
+        assert_eq!(collector.participants().count(), 1);
+        for _ in 0..3 {
+            handle.collect();
+        }
+        assert_eq!(freed.load(Ordering::Relaxed), 3);
+
+        // Whatever is left goes with the collector
+        let guard = handle.pin();
+        let freed_t = freed.clone();
+        guard.defer(move || { freed_t.fetch_add(1, Ordering::Relaxed); });
+        drop(guard);
+        drop(handle);
+        drop(collector);
+        assert_eq!(freed.load(Ordering::Relaxed), 4);
+    }
+
+    #[test]
+    fn test_epoch_threads() {
+        /// Value that counts its drops
+        struct Tracked(u64, Arc<AtomicUsize>);
+
+        impl Drop for Tracked {
+            fn drop(&mut self) {
+                self.1.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let collector = Collector::new();
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let shared = AtomicPtr::new(Box::into_raw(Box::new(Tracked(0, dropped.clone()))));
+
+        // Threads replace the shared value and read it, freeing replaced values
+        // through the collector while others may still be reading them
+        thread::scope(|scope| {
+            for thread in 0..4u64 {
+                let (collector, shared, dropped) = (&collector, &shared, &dropped);
+                scope.spawn(move || {
+                    let handle = collector.register();
+                    for i in 0..10_000 {
+                        let guard = handle.pin();
+                        if i % 4 == 0 {
+                            let new = Tracked(thread << 32 | i, dropped.clone());
+                            let new = Box::into_raw(Box::new(new));
+                            let old = shared.swap(new, Ordering::AcqRel);
+                            unsafe { guard.defer_destroy(old); }
+                        } else {
+                            let value = unsafe { &*shared.load(Ordering::Acquire) };
+                            assert!(value.0 & 0xffff_ffff < 10_000);
+                        }
+                    }
+                });
+            }
+        });
+
+        drop(collector);
+        assert_eq!(dropped.load(Ordering::Relaxed), 10_000);
+        drop(unsafe { Box::from_raw(shared.load(Ordering::Relaxed)) });
+        assert_eq!(dropped.load(Ordering::Relaxed), 10_001);
+    }
+}
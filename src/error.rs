@@ -0,0 +1,65 @@
+//! Errors returned by the maps, queues, stacks and allocators of the crate
+
+use core::fmt;
+
+/// Error returned by the operations of every container in the crate
+///
+/// It is named after the first map of the crate, but shared by every container, so
+/// its messages don't name any of them.
+#[derive(Debug, PartialEq, Eq)]
+pub enum AtomicHashMapError {
+    /// No free slot is left for a new key or value
+    Full,
+
+    /// The key is one of the sentinels marking empty or removed slots and can't be
+    /// stored. Also returned when constructing a map with identical sentinels.
+    InvalidKey,
+
+    /// The requested capacity isn't supported by the container, like a map size that
+    /// is not a power of two or an arena too large to allocate
+    InvalidCapacity,
+
+    /// The memory region given for a shared map is misaligned, too small, or doesn't
+    /// hold an initialized map
+    InvalidRegion,
+
+    /// A `try_` operation gave up rather than wait on another thread or keep losing
+    /// races to other threads
+    Contended
+}
+
+impl fmt::Display for AtomicHashMapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AtomicHashMapError::Full => write!(f, "container is full"),
+            AtomicHashMapError::InvalidKey => 
+                write!(f, "key is reserved as a sentinel"),
+            AtomicHashMapError::InvalidCapacity => 
+                write!(f, "capacity is not supported by the container"),
+            AtomicHashMapError::InvalidRegion => 
+                write!(f, "memory region doesn't hold a valid shared map"),
+            AtomicHashMapError::Contended =>
+                write!(f, "operation gave up under contention")
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for AtomicHashMapError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display_is_generic() {
+        // Every container reports these
+        for err in [AtomicHashMapError::Full, AtomicHashMapError::InvalidKey,
+                    AtomicHashMapError::InvalidCapacity,
+                    AtomicHashMapError::InvalidRegion, AtomicHashMapError::Contended] {
+            let msg = std::format!("{}", err);
+            assert!(!msg.contains("AtomicHashMap"), "{}", msg);
+            assert!(!msg.contains("power of two"), "{}", msg);
+        }
+    }
+}
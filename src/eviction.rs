@@ -0,0 +1,111 @@
+//! Policies picking which entry of a full `AtomicHashMap` to evict for a new key
+//!
+//! A map built with `AtomicHashMapBuilder::eviction` doesn't return
+//! `AtomicHashMapError::Full` when a new key finds no free slot along its probe.
+//! Instead, the first live entries along that probe are handed to its
+//! `EvictionPolicy`, the chosen entry is removed unless its value changed in the
+//! meantime, and the insert tries again. Since every candidate sits on the probe of
+//! the new key, the freed slot is always within its reach, even with `max_probe`.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Picks the entry to evict when a new key doesn't fit in a full map
+///
+/// Policies are shared by every thread inserting into the map, so any state they keep
+/// has to be updated atomically.
+pub trait EvictionPolicy<K, V>: Send + Sync {
+    /// Choose which of the non-empty `candidates` to evict, returning its index in
+    /// the slice. Out of range indices evict the last candidate.
+    fn choose(&self, candidates: &[(K, V)]) -> usize;
+}
+
+/// Evict a pseudo random candidate
+///
+/// Candidates are taken from the probe of the new key, which already starts at a
+/// pseudo random slot, so this mostly spreads evictions across the candidates of
+/// keys that probe from the same slot.
+#[derive(Debug, Default)]
+pub struct RandomEviction {
+    /// State of the SplitMix64 generator
+    state: AtomicU64
+}
+
+impl RandomEviction {
+    /// Construct a policy whose generator starts from `seed`
+    pub fn new(seed: u64) -> RandomEviction {
+        RandomEviction { state: AtomicU64::new(seed) }
+    }
+}
+
+impl<K, V> EvictionPolicy<K, V> for RandomEviction {
+    fn choose(&self, candidates: &[(K, V)]) -> usize {
+        let mut x = self.state.fetch_add(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed)
+            .wrapping_add(0x9e37_79b9_7f4a_7c15);
+        x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        x ^= x >> 31;
+
+        (x % candidates.len() as u64) as usize
+    }
+}
+
+/// Evict the candidate with the lowest value, e.g. the least hit counter
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LowestValueEviction;
+
+impl<K, V: Ord> EvictionPolicy<K, V> for LowestValueEviction {
+    fn choose(&self, candidates: &[(K, V)]) -> usize {
+        candidates.iter().enumerate()
+            .min_by(|(_, (_, a)), (_, (_, b))| a.cmp(b))
+            .map_or(0, |(index, _)| index)
+    }
+}
+
+/// Evict the candidate with the oldest timestamp, as extracted from its value by the
+/// wrapped function
+///
+/// ```
+/// use atomics_rs::{AtomicHashMap, OldestEviction};
+///
+/// // Values pack a payload in the low 32 bits and a timestamp in the high ones
+/// let map: AtomicHashMap = AtomicHashMap::builder(16)
+///     .eviction(OldestEviction(|value: u64| value >> 32))
+///     .build()
+///     .unwrap();
+///
+/// for key in 1..=17 {
+///     map.insert(key, key << 32).unwrap();
+/// }
+/// assert_eq!(map.len(), 16);
+/// ```
+#[derive(Debug, Default, Clone, Copy)]
+pub struct OldestEviction<F>(pub F);
+
+impl<K, V, F> EvictionPolicy<K, V> for OldestEviction<F>
+        where V: Copy, F: Fn(V) -> u64 + Send + Sync {
+    fn choose(&self, candidates: &[(K, V)]) -> usize {
+        candidates.iter().enumerate()
+            .min_by_key(|(_, (_, value))| (self.0)(*value))
+            .map_or(0, |(index, _)| index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policies() {
+        let candidates = [(1u64, 30u64), (2, 10), (3, 20), (4, 10)];
+
+        assert_eq!(LowestValueEviction.choose(&candidates), 1);
+        assert_eq!(OldestEviction(|value: u64| u64::MAX - value).choose(&candidates), 0);
+
+        let random = RandomEviction::new(7);
+        let mut seen = [false; 4];
+        for _ in 0..64 {
+            seen[EvictionPolicy::<u64, u64>::choose(&random, &candidates)] = true;
+        }
+        assert_eq!(seen, [true; 4]);
+    }
+}
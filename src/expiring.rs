@@ -0,0 +1,296 @@
+//! `AtomicHashMap` whose entries expire a fixed time after they were last inserted
+//!
+//! Each value word packs a `u32` value in its low half and the deadline of the entry
+//! in its high half, counted in whole seconds since the map was built. A value and
+//! its deadline are therefore always read and replaced together by the single atomic
+//! operations of the inner map, and an entry refreshed by one thread can never be
+//! purged by another that saw its old deadline.
+//!
+//! Expired entries are treated as absent by every read. They keep their slots until
+//! `purge_expired` turns them into tombstones, which `insert` does by itself before
+//! giving up on a full table, so that new keys reuse the slots of expired ones.
+//!
+//! Deadlines are rounded up to the next whole second, so an entry lives for at least
+//! the TTL of the map and expires less than a second after it.
+
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::hash::BuildHasher;
+use std::time::{Duration, Instant};
+
+use crate::atomichashmap::{AtomicHashMap, AtomicHashMapError, BuildMurmurHasher};
+use crate::pod::PodU64;
+
+/// Returns true if an entry with `deadline` has expired at `now`
+fn is_expired(deadline: u32, now: Duration) -> bool {
+    now.as_secs() >= u64::from(deadline)
+}
+
+/// Get the value of a `(value, deadline)` entry if it hasn't expired at `now`
+fn live(entry: (u32, u32), now: Duration) -> Option<u32> {
+    let (value, deadline) = entry;
+    if is_expired(deadline, now) {
+        return None;
+    }
+
+    Some(value)
+}
+
+/// Lock-free hashmap from keys of type `K` to `u32` values that expire `ttl` after
+/// they were last inserted
+///
+/// Built for concurrent deduplication: `insert_if_absent` tells exactly one of many
+/// threads racing on a key that it is the first to see the key within the window.
+pub struct ExpiringAtomicHashMap<K: PodU64 = u64, S = BuildMurmurHasher> {
+    /// Entries as `(value, deadline)` pairs
+    map: AtomicHashMap<K, (u32, u32), S>,
+
+    /// Time an entry lives after being inserted
+    ttl: Duration,
+
+    /// Start of the clock deadlines are counted on
+    epoch: Instant
+}
+
+impl<K: PodU64> ExpiringAtomicHashMap<K> {
+    /// Construct a new map with a given size whose entries expire `ttl` after they
+    /// were last inserted.
+    /// NOTE: Size must be a power of two, otherwise `InvalidCapacity` is returned.
+    pub fn new(size: usize, ttl: Duration)
+            -> Result<ExpiringAtomicHashMap<K>, AtomicHashMapError> {
+        ExpiringAtomicHashMap::with_hasher(size, ttl, BuildMurmurHasher::default())
+    }
+}
+
+impl<K: PodU64, S: BuildHasher> ExpiringAtomicHashMap<K, S> {
+    /// Construct a new map with a given size, hashing keys with `hasher`, whose
+    /// entries expire `ttl` after they were last inserted.
+    /// NOTE: Size must be a power of two, otherwise `InvalidCapacity` is returned.
+    pub fn with_hasher(size: usize, ttl: Duration, hasher: S)
+            -> Result<ExpiringAtomicHashMap<K, S>, AtomicHashMapError> {
+        Ok(ExpiringAtomicHashMap {
+            map: AtomicHashMap::with_hasher(size, hasher)?,
+            ttl,
+            epoch: Instant::now()
+        })
+    }
+
+    /// Get the time an entry lives after being inserted
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Get the time elapsed on the clock of the map
+    fn now(&self) -> Duration {
+        self.epoch.elapsed()
+    }
+
+    /// Get the deadline of an entry inserted at `now`, rounded up to a whole second
+    fn deadline(&self, now: Duration) -> u32 {
+        let deadline = now + self.ttl;
+        let secs = deadline.as_secs() + u64::from(deadline.subsec_nanos() > 0);
+        u32::try_from(secs).unwrap_or(u32::MAX)
+    }
+
+    /// Atomically set a key:value in the hashmap, restarting the TTL of the key
+    ///
+    /// If the table is full, expired entries are purged and the insert is retried
+    /// once before returning `AtomicHashMapError::Full`.
+    ///
+    /// Returns the previous value of the key, or `None` if the key was newly inserted
+    /// or had expired.
+    pub fn insert(&self, key: K, value: u32) -> Result<Option<u32>, AtomicHashMapError> {
+        self.insert_at(key, value, self.now())
+    }
+
+    fn insert_at(&self, key: K, value: u32, now: Duration)
+            -> Result<Option<u32>, AtomicHashMapError> {
+        let entry = (value, self.deadline(now));
+        let prev = self.retry_full(now, || self.map.insert(key, entry))?;
+        Ok(prev.and_then(|prev| live(prev, now)))
+    }
+
+    /// Atomically insert `key` with `value` unless it is already in the hashmap and
+    /// hasn't expired
+    ///
+    /// Of many threads racing to insert the same absent or expired key, exactly one
+    /// gets back `None`. A key that is already live keeps its value and its deadline.
+    ///
+    /// Returns the live value of the key, or `None` if `value` was inserted.
+    pub fn insert_if_absent(&self, key: K, value: u32)
+            -> Result<Option<u32>, AtomicHashMapError> {
+        self.insert_if_absent_at(key, value, self.now())
+    }
+
+    fn insert_if_absent_at(&self, key: K, value: u32, now: Duration)
+            -> Result<Option<u32>, AtomicHashMapError> {
+        let entry = (value, self.deadline(now));
+        let prev = self.retry_full(now, || {
+            self.map.upsert(key, entry, |old, new| {
+                if is_expired(old.1, now) { new } else { old }
+            })
+        })?;
+        Ok(prev.and_then(|prev| live(prev, now)))
+    }
+
+    /// Run `insert`, purging expired entries and running it once more if the table
+    /// is full
+    fn retry_full<T, F>(&self, now: Duration, mut insert: F)
+            -> Result<T, AtomicHashMapError>
+            where F: FnMut() -> Result<T, AtomicHashMapError> {
+        match insert() {
+            Err(AtomicHashMapError::Full) => {
+                self.purge_expired_at(now);
+                insert()
+            }
+            res => res
+        }
+    }
+
+    /// Atomically get the value of a key from the hashmap, if it hasn't expired
+    pub fn get(&self, key: &K) -> Option<u32> {
+        self.get_at(key, self.now())
+    }
+
+    fn get_at(&self, key: &K, now: Duration) -> Option<u32> {
+        live(self.map.get(key)?, now)
+    }
+
+    /// Check if a key is in the hashmap and hasn't expired
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Atomically remove a key from the hashmap, returning its value if it was present
+    /// and hadn't expired
+    pub fn remove(&self, key: K) -> Option<u32> {
+        let now = self.now();
+        live(self.map.remove(key)?, now)
+    }
+
+    /// Replace every expired entry with a tombstone so that its slot can be reused.
+    /// An entry refreshed concurrently is kept.
+    pub fn purge_expired(&self) {
+        self.purge_expired_at(self.now())
+    }
+
+    fn purge_expired_at(&self, now: Duration) {
+        self.map.retain(|_, (_, deadline)| !is_expired(deadline, now));
+    }
+
+    /// Get a `Vec` of the key:value pairs that haven't expired
+    pub fn to_vec(&self) -> Vec<(K, u32)> {
+        let now = self.now();
+        self.map.iter()
+            .filter_map(|(key, entry)| Some((key, live(entry, now)?)))
+            .collect()
+    }
+
+    /// Get the number of keys holding a slot, including expired ones that haven't
+    /// been purged yet
+    pub fn len(&self) -> u64 {
+        self.map.len()
+    }
+
+    /// Returns true if no key holds a slot
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Get the number of slots in the table
+    pub fn capacity(&self) -> usize {
+        self.map.capacity()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    #[test]
+    fn test_expiry() {
+        let hashtable: ExpiringAtomicHashMap =
+            ExpiringAtomicHashMap::new(1 << 4, Duration::from_secs(60)).unwrap();
+        let at = Duration::from_millis;
+
+        assert_eq!(hashtable.insert_at(1, 10, at(500)), Ok(None));
+        assert_eq!(hashtable.get_at(&1, at(500)), Some(10));
+        assert_eq!(hashtable.get_at(&1, at(60_500)), Some(10));
+        assert_eq!(hashtable.get_at(&1, at(61_000)), None);
+
+        // Inserting restarts the TTL and an expired value isn't handed back
+        assert_eq!(hashtable.insert_at(1, 11, at(30_000)), Ok(Some(10)));
+        assert_eq!(hashtable.get_at(&1, at(89_999)), Some(11));
+        assert_eq!(hashtable.insert_at(1, 12, at(90_000)), Ok(None));
+        assert_eq!(hashtable.get_at(&1, at(90_000)), Some(12));
+
+        // A live key keeps its value and deadline
+        assert_eq!(hashtable.insert_if_absent_at(2, 20, at(0)), Ok(None));
+        assert_eq!(hashtable.insert_if_absent_at(2, 21, at(59_000)), Ok(Some(20)));
+        assert_eq!(hashtable.get_at(&2, at(59_999)), Some(20));
+        assert_eq!(hashtable.insert_if_absent_at(2, 22, at(60_000)), Ok(None));
+        assert_eq!(hashtable.get_at(&2, at(60_000)), Some(22));
+
+        assert_eq!(hashtable.ttl(), Duration::from_secs(60));
+        assert_eq!(hashtable.get(&3), None);
+        assert_eq!(hashtable.insert(3, 30), Ok(None));
+        assert!(hashtable.contains_key(&3));
+        assert_eq!(hashtable.remove(3), Some(30));
+        assert!(!hashtable.contains_key(&3));
+    }
+
+    #[test]
+    fn test_reuse_expired() {
+        let hashtable: ExpiringAtomicHashMap =
+            ExpiringAtomicHashMap::new(1 << 4, Duration::from_secs(1)).unwrap();
+        let at = Duration::from_secs;
+
+        for key in 1..=16 {
+            assert_eq!(hashtable.insert_at(key, 0, at(0)), Ok(None));
+        }
+        assert_eq!(hashtable.insert_at(17, 0, at(0)), Err(AtomicHashMapError::Full));
+
+        // Only the refreshed keys survive the purge of a full table
+        for key in 1..=4 {
+            hashtable.insert_at(key, 1, at(1)).unwrap();
+        }
+        for key in 17..=28 {
+            assert_eq!(hashtable.insert_if_absent_at(key, 2, at(1)), Ok(None));
+        }
+        assert_eq!(hashtable.len(), 16);
+        for key in 1..=4 {
+            assert_eq!(hashtable.get_at(&key, at(1)), Some(1));
+        }
+        for key in 5..=16 {
+            assert_eq!(hashtable.get_at(&key, at(1)), None);
+        }
+    }
+
+    #[test]
+    fn test_insert_if_absent_threads() {
+        let hashtable: ExpiringAtomicHashMap =
+            ExpiringAtomicHashMap::new(1 << 10, Duration::from_secs(60)).unwrap();
+        let first = AtomicUsize::new(0);
+
+        thread::scope(|scope| {
+            for thread in 0..4 {
+                let hashtable = &hashtable;
+                let first = &first;
+                scope.spawn(move || {
+                    for key in 1..=500 {
+                        if hashtable.insert_if_absent(key, thread).unwrap().is_none() {
+                            first.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                });
+            }
+        });
+
+        // Each key was first seen by exactly one thread
+        assert_eq!(first.load(Ordering::Relaxed), 500);
+        assert_eq!(hashtable.to_vec().len(), 500);
+    }
+}
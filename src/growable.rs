@@ -0,0 +1,451 @@
+//! Growable wrapper around `AtomicHashMap`
+//!
+//! A `GrowableAtomicHashMap` never returns `AtomicHashMapError::Full`. It is a chain
+//! of tables, each twice the size of the one before it, that keys probe one after
+//! the other as if they were a single longer table. Every table limits how far an
+//! insert probes into it, so a key that finds no room within reach in any table
+//! makes the thread inserting it append a new table to the chain, and whichever
+//! thread gets its table in first wins.
+//!
+//! Entries never move once inserted, so growing never copies a table and never waits
+//! on other threads. Claims, removals and lookups go through the same protocol as
+//! `AtomicHashMap` across the whole chain, which keeps two threads from inserting the
+//! same key in two different tables: of two copies claimed at the same time, the one
+//! nearest the start of the chain wins.
+//!
+//! Tables are only freed when the map is dropped. Lookups probe every table in turn
+//! until they reach an empty slot, which the earlier, fuller tables rarely have, so
+//! a map that grew a long way from a small first table pays for it on every lookup.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::hash::BuildHasher;
+use core::ptr;
+
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use crate::atomichashmap::{AtomicHashMap, AtomicHashMapBuilder, AtomicHashMapError,
+                           BuildMurmurHasher, TableProbe};
+use crate::pod::PodU64;
+use crate::probe::{self, Publish, Slot, Table};
+
+/// Most slots an insert probes in each table before moving on to the next one, unless
+/// the first table of the map sets its own limit
+const TABLE_PROBE: usize = 64;
+
+/// Most tables in the chain, enough to keep doubling until the address space runs out
+const MAX_TABLES: usize = usize::BITS as usize;
+
+/// Lock-free hashmap that grows instead of returning `AtomicHashMapError::Full`
+///
+/// Supports the same core operations as `AtomicHashMap`, with the same progress
+/// guarantees: growing the map is a single compare-exchange by the first thread that
+/// runs out of room, and never waits on other threads.
+pub struct GrowableAtomicHashMap<K: PodU64 = u64, V: PodU64 = u64, S = BuildMurmurHasher> {
+    /// Tables in the order keys probe them, each twice the size of the one before.
+    /// Only a prefix of them is allocated, and none is freed before the map.
+    tables: [AtomicPtr<AtomicHashMap<K, V, S>>; MAX_TABLES],
+
+    /// Number of slots of the first table
+    first_capacity: usize
+}
+
+unsafe impl<K: PodU64, V: PodU64, S: Send> Send for GrowableAtomicHashMap<K, V, S> {}
+unsafe impl<K: PodU64, V: PodU64, S: Sync> Sync for GrowableAtomicHashMap<K, V, S> {}
+
+impl<K: PodU64, V: PodU64> GrowableAtomicHashMap<K, V> {
+    /// Construct a new GrowableAtomicHashMap starting out with a given size.
+    /// NOTE: Size must be a power of two, otherwise `InvalidCapacity` is returned.
+    pub fn new(size: usize) -> Result<GrowableAtomicHashMap<K, V>, AtomicHashMapError> {
+        Ok(GrowableAtomicHashMap::from_map(AtomicHashMap::new(size)?))
+    }
+}
+
+impl<K: PodU64, V: PodU64, S> GrowableAtomicHashMap<K, V, S> {
+    /// Get table `table` of the chain, if it was allocated
+    fn table(&self, table: usize) -> Option<&AtomicHashMap<K, V, S>> {
+        let map = self.tables.get(table)?.load(Ordering::SeqCst);
+
+        // SAFETY: Tables are never freed before the map, which borrows them to us
+        unsafe { map.as_ref() }
+    }
+
+    /// Get the first table of the chain, which is always allocated
+    fn first(&self) -> &AtomicHashMap<K, V, S> {
+        self.table(0).expect("First table is allocated with the map")
+    }
+
+    /// Iterate over the tables allocated so far, in chain order
+    fn iter_tables(&self) -> impl Iterator<Item = &AtomicHashMap<K, V, S>> + '_ {
+        (0..MAX_TABLES).map_while(move |table| self.table(table))
+    }
+
+    /// Get the table holding the slot at `index` of the chain, and the index of the
+    /// slot within it
+    fn locate(&self, index: usize) -> (&AtomicHashMap<K, V, S>, usize) {
+        // Table `n` holds the `first_capacity << n` slots from
+        // `first_capacity * (2^n - 1)` on
+        let table = (index / self.first_capacity + 1).ilog2() as usize;
+        let offset = self.first_capacity * ((1 << table) - 1);
+        (self.table(table).expect("Slot of an allocated table"), index - offset)
+    }
+}
+
+impl<K: PodU64, V: PodU64, S: BuildHasher + Clone> GrowableAtomicHashMap<K, V, S> {
+    /// Construct a GrowableAtomicHashMap starting out with `map`. Larger tables use the
+    /// same hasher, sentinels, ordering profile, probe strategy, probe limit and
+    /// padding as `map`. If `map` doesn't limit its probes, every table is limited to
+    /// 64 slots so that the map grows before probes get long.
+    pub fn from_map(mut map: AtomicHashMap<K, V, S>) -> GrowableAtomicHashMap<K, V, S> {
+        if map.max_probe().is_none() {
+            map.limit_probe(TABLE_PROBE);
+        }
+
+        let first_capacity = map.capacity();
+        let tables: [AtomicPtr<AtomicHashMap<K, V, S>>; MAX_TABLES] =
+            core::array::from_fn(|_| AtomicPtr::new(ptr::null_mut()));
+        tables[0].store(Box::into_raw(Box::new(map)), Ordering::Relaxed);
+
+        GrowableAtomicHashMap { tables, first_capacity }
+    }
+
+    /// Append a table twice the size of table `last` to the chain, unless another
+    /// thread already did
+    fn grow(&self, last: usize) {
+        if self.table(last + 1).is_some() {
+            return;
+        }
+
+        let table = self.table(last).expect("Only the last table grows");
+        let mut builder = AtomicHashMapBuilder::new(table.capacity() * 2)
+            .raw_sentinels(table.raw_sentinels())
+            .hasher(table.hasher().clone())
+            .ordering(table.ordering_profile())
+            .probe(table.probe_strategy())
+            .padded(table.is_padded());
+        if let Some(slots) = table.max_probe() {
+            builder = builder.max_probe(slots);
+        }
+
+        let map = builder.build().expect("Sentinels and size already validated");
+        let next = Box::into_raw(Box::new(map));
+        if self.tables[last + 1].compare_exchange(ptr::null_mut(), next, Ordering::SeqCst,
+                                                  Ordering::SeqCst).is_err() {
+            // SAFETY: Another thread appended its table first, so ours was never shared
+            unsafe { drop(Box::from_raw(next)); }
+        }
+    }
+
+    /// Find the published slot holding `key` in the chain, claiming one for it if it
+    /// isn't in the map yet and growing the map until there is room for it
+    fn claim_slot(&self, key: u64) -> Slot {
+        let hash = self.first().hash(key);
+        loop {
+            let last = self.iter_tables().count() - 1;
+
+            // A probe only comes back without a slot if there is no room for the key in
+            // any table allocated when it checked them
+            match probe::claim_slot(self, key, hash) {
+                Ok(slot) => return slot,
+                Err(_) => self.grow(last)
+            }
+        }
+    }
+
+    /// Find the slot currently holding `key` in the chain
+    fn find_slot(&self, key: u64) -> Option<usize> {
+        probe::find_slot(self, key, self.first().hash(key))
+    }
+
+    /// Atomically set a key:value in the hashmap, growing it if needed. Returns the
+    /// previous value for this key, or `None` if the key was newly inserted.
+    pub fn insert(&self, key: K, value: V) -> Result<Option<V>, AtomicHashMapError> {
+        let key = self.first().raw_key(key)?;
+
+        match self.claim_slot(key) {
+            Slot::Found(index) => {
+                let (table, index) = self.locate(index);
+                let prev_value = table.value_word(index)
+                    .swap(value.to_u64(), table.ordering_profile().rmw());
+                Ok(Some(V::from_u64(prev_value)))
+            }
+            Slot::Claimed(index) => {
+                let (table, index) = self.locate(index);
+                table.value_word(index).store(value.to_u64(),
+                                              table.ordering_profile().store());
+                probe::publish(table, index);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Get the value for `key`, inserting the result of `init` if the key isn't in the
+    /// hashmap. `init` is only called by the thread that inserts the key, and may use
+    /// the map itself, as long as it doesn't look up `key`.
+    pub fn get_or_insert_with<F>(&self, key: K, init: F) -> Result<V, AtomicHashMapError>
+            where F: FnOnce() -> V {
+        let key = self.first().raw_key(key)?;
+
+        loop {
+            match self.claim_slot(key) {
+                Slot::Found(index) => {
+                    let (table, index) = self.locate(index);
+                    if let Some(value) = table.read_value(index, key) {
+                        return Ok(value);
+                    }
+
+                    // Removed since it was found, look for the key again
+                }
+                Slot::Claimed(index) => {
+                    let (table, index) = self.locate(index);
+
+                    // Published even if `init` panics, so threads waiting on the key
+                    // don't wait forever
+                    let _publish = Publish(table, index);
+                    let value = init();
+                    table.value_word(index).store(value.to_u64(),
+                                                  table.ordering_profile().store());
+                    return Ok(value);
+                }
+            }
+        }
+    }
+
+    /// Atomically remove a key from the hashmap, returning its value if it was present
+    pub fn remove(&self, key: K) -> Option<V> {
+        let key = self.first().raw_key(key).ok()?;
+
+        let index = self.find_slot(key)?;
+
+        // Only the thread that unpublishes the slot may release it
+        if !probe::unpublish(self, index, key) {
+            return None;
+        }
+
+        let (table, local) = self.locate(index);
+        let value = table.value_word(local).swap(0, table.ordering_profile().rmw());
+        probe::release(self, index, key);
+        Some(V::from_u64(value))
+    }
+
+    /// Atomically get a value from the hashmap
+    pub fn get(&self, key: &K) -> Option<V> {
+        let key = self.first().raw_key(*key).ok()?;
+
+        let (table, index) = self.locate(self.find_slot(key)?);
+        table.read_value(index, key)
+    }
+
+    /// Check if a key is in the hashmap
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Get the number of elements currently in the hashtable
+    pub fn len(&self) -> u64 {
+        self.iter_tables().map(|table| table.len()).sum()
+    }
+
+    /// Returns true if the hashtable has no elements
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the total number of slots of all the tables allocated so far
+    pub fn capacity(&self) -> usize {
+        self.iter_tables().map(|table| table.capacity()).sum()
+    }
+
+    /// Collect the `(key, value)` pairs currently in the hashmap
+    pub fn to_vec(&self) -> Vec<(K, V)> {
+        self.iter_tables().flat_map(|table| table.to_vec()).collect()
+    }
+}
+
+impl<K: PodU64, S: BuildHasher + Clone> GrowableAtomicHashMap<K, u64, S> {
+    /// Atomically add `delta` to the value for `key`, inserting the key with a value of
+    /// `delta` if it isn't in the hashmap yet. Returns the value before the addition.
+    pub fn add_to(&self, key: K, delta: u64) -> Result<u64, AtomicHashMapError> {
+        let key = self.first().raw_key(key)?;
+
+        let (index, claimed) = match self.claim_slot(key) {
+            Slot::Found(index) => (index, false),
+            Slot::Claimed(index) => (index, true)
+        };
+
+        let (table, index) = self.locate(index);
+        let prev_value = table.value_word(index)
+            .fetch_add(delta, table.ordering_profile().rmw());
+        if claimed {
+            probe::publish(table, index);
+        }
+
+        Ok(prev_value)
+    }
+}
+
+impl<K: PodU64, V: PodU64, S> Table for GrowableAtomicHashMap<K, V, S> {
+    type Key = <AtomicHashMap<K, V, S> as Table>::Key;
+    type State = <AtomicHashMap<K, V, S> as Table>::State;
+    type Probe<'a> = ChainProbe<'a, K, V, S> where Self: 'a;
+
+    fn key_word(&self, index: usize) -> &Self::Key {
+        let (table, index) = self.locate(index);
+        table.key_word(index)
+    }
+
+    fn state_word(&self, index: usize) -> &Self::State {
+        let (table, index) = self.locate(index);
+        table.state_word(index)
+    }
+
+    fn raw_sentinels(&self) -> (u64, u64) {
+        self.first().raw_sentinels()
+    }
+
+    fn probe_slots(&self, hash: u64, all: bool) -> ChainProbe<'_, K, V, S> {
+        ChainProbe {
+            map: self,
+            hash,
+            all,
+            table: 0,
+            offset: 0,
+            slots: Some(self.first().probe_slots(hash, all))
+        }
+    }
+
+    fn claimed(&self, index: usize, hash: u64) {
+        let (table, index) = self.locate(index);
+        table.claimed(index, hash);
+    }
+
+    fn released(&self, index: usize) {
+        let (table, index) = self.locate(index);
+        table.released(index);
+    }
+}
+
+/// Slots visited by a probe of a `GrowableAtomicHashMap`: the probe of the key in
+/// each table of the chain in turn, numbered across the whole chain
+pub(crate) struct ChainProbe<'a, K: PodU64, V: PodU64, S> {
+    map: &'a GrowableAtomicHashMap<K, V, S>,
+    hash: u64,
+    all: bool,
+
+    /// Table currently probed, and the chain index of its first slot
+    table: usize,
+    offset: usize,
+
+    /// Probe of the current table, `None` once past the last allocated table
+    slots: Option<TableProbe<'a>>
+}
+
+impl<'a, K: PodU64, V: PodU64, S> Iterator for ChainProbe<'a, K, V, S> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        loop {
+            if let Some(index) = self.slots.as_mut()?.next() {
+                return Some(self.offset + index);
+            }
+
+            // Done with this table. A table appended since the probe started is
+            // probed as well, so that a claim in it is seen by `settle`.
+            self.offset += self.map.first_capacity << self.table;
+            self.table += 1;
+            self.slots = self.map.table(self.table)
+                .map(|table| table.probe_slots(self.hash, self.all));
+        }
+    }
+}
+
+impl<K: PodU64, V: PodU64, S> Drop for GrowableAtomicHashMap<K, V, S> {
+    fn drop(&mut self) {
+        for table in self.tables.iter() {
+            let table = table.load(Ordering::Relaxed);
+            if !table.is_null() {
+                // SAFETY: Every table was allocated with `Box::new` and is only freed
+                // here, and the map is no longer borrowed
+                unsafe { drop(Box::from_raw(table)); }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grows() {
+        let hashtable: GrowableAtomicHashMap = GrowableAtomicHashMap::new(1 << 2).unwrap();
+
+        for x in 1..=10_000 {
+            assert_eq!(hashtable.insert(x, x * 2), Ok(None));
+        }
+
+        assert_eq!(hashtable.len(), 10_000);
+        assert!(hashtable.capacity() >= 10_000);
+        for x in 1..=10_000 {
+            assert_eq!(hashtable.get(&x), Some(x * 2));
+        }
+
+        assert_eq!(hashtable.remove(5), Some(10));
+        assert!(!hashtable.contains_key(&5));
+        assert_eq!(hashtable.insert(5, 1), Ok(None));
+        assert_eq!(hashtable.get(&5), Some(1));
+        assert_eq!(hashtable.to_vec().len(), 10_000);
+    }
+
+    #[test]
+    fn test_grows_threads() {
+        use std::thread;
+        use std::sync::Arc;
+
+        let size: u64 = 1 << 14;
+        let hashtable: Arc<GrowableAtomicHashMap> =
+            Arc::new(GrowableAtomicHashMap::new(1 << 4).unwrap());
+
+        let mut threads = Vec::new();
+        for _ in 0..8 {
+            let hashtable_i = hashtable.clone();
+            let t = thread::spawn(move || {
+                for x in 1..=size {
+                    hashtable_i.add_to(x, 1).unwrap();
+                    assert!(hashtable_i.get(&x).is_some());
+                }
+            });
+            threads.push(t);
+        }
+
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        // No counts were lost and no key was inserted twice across the tables
+        assert_eq!(hashtable.len(), size);
+        assert_eq!(hashtable.to_vec().len() as u64, size);
+        for x in 1..=size {
+            assert_eq!(hashtable.get(&x), Some(8));
+        }
+    }
+
+    #[test]
+    fn test_init_grows() {
+        let hashtable: GrowableAtomicHashMap =
+            GrowableAtomicHashMap::new(1 << 2).unwrap();
+
+        // `init` fills the map well past its first table while the key is claimed
+        let value = hashtable.get_or_insert_with(1, || {
+            for x in 2..=1000 {
+                hashtable.insert(x, x).unwrap();
+            }
+            7
+        });
+
+        assert_eq!(value, Ok(7));
+        assert_eq!(hashtable.get(&1), Some(7));
+        assert_eq!(hashtable.len(), 1000);
+        assert_eq!(hashtable.get_or_insert_with(1, || unreachable!()), Ok(7));
+    }
+}
@@ -0,0 +1,82 @@
+//! Default hasher shared by every map in the crate
+
+use core::hash::{BuildHasherDefault, Hasher};
+
+/// Integer Hash function from MurmurHash3's integer finalizer
+pub fn hash_key(val: u64) -> u64 {
+    let mut res = val;
+    res ^= res >> 33;
+    res = res.wrapping_mul(0xff51afd7ed558ccd);
+    res ^= res >> 33;
+    res = res.wrapping_mul(0xc4ceb9fe1a85ec53);
+    res ^= res >> 33;
+    res
+}
+
+/// Inverse of `hash_key`, giving back the key that hashes to `hash`
+///
+/// `hash_key` is a bijection on `u64`, so every hash has exactly one key. Useful to
+/// work out which key landed in a given slot while debugging.
+pub fn unhash_key(hash: u64) -> u64 {
+    // Shifting by at least half the width makes each xorshift its own inverse, and
+    // each multiplier is odd so has an inverse modulo 2^64
+    let mut res = hash;
+    res ^= res >> 33;
+    res = res.wrapping_mul(0x9cb4b2f8129337db);
+    res ^= res >> 33;
+    res = res.wrapping_mul(0x4f74430c22a54005);
+    res ^= res >> 33;
+    res
+}
+
+/// `Hasher` built on `hash_key`, the default hasher of `AtomicHashMap`
+///
+/// Hashing a single `u64` gives exactly `hash_key` of it. Longer inputs are mixed in
+/// one word at a time.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MurmurHasher {
+    state: u64
+}
+
+impl Hasher for MurmurHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(8) {
+            let mut word = [0u8; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            self.write_u64(u64::from_le_bytes(word));
+        }
+    }
+
+    fn write_u64(&mut self, val: u64) {
+        // hash_key(0) == 0, so the first word is taken as is
+        self.state = hash_key(self.state) ^ val;
+    }
+
+    fn finish(&self) -> u64 {
+        hash_key(self.state)
+    }
+}
+
+/// Default `BuildHasher` of `AtomicHashMap`
+pub type BuildMurmurHasher = BuildHasherDefault<MurmurHasher>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unhash_key() {
+        for &key in [0, 1, 2, u64::MAX, u64::MAX - 1, 1 << 63, 0xdead_beef].iter() {
+            assert_eq!(unhash_key(hash_key(key)), key);
+            assert_eq!(hash_key(unhash_key(key)), key);
+        }
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn test_hash_key_bijection(key in proptest::num::u64::ANY) {
+            proptest::prop_assert_eq!(unhash_key(hash_key(key)), key);
+            proptest::prop_assert_eq!(hash_key(unhash_key(key)), key);
+        }
+    }
+}
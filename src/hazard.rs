@@ -0,0 +1,440 @@
+//! Hazard pointers for structures that free memory other threads may still be
+//! reading
+//!
+//! A thread registers with a `HazardDomain` once, getting a `HazardHandle` with
+//! `SLOTS` hazard slots of its own. Before dereferencing a pointer loaded from a
+//! structure, the thread publishes it in one of its slots with
+//! `HazardPointer::protect`, which reloads the source until the published pointer
+//! is still current. Memory unlinked from a structure is handed to `retire` and only
+//! freed once no slot of any thread holds it.
+//!
+//! Unlike epochs, a reader only holds back the few nodes it has published rather
+//! than all garbage retired while it is pinned, so a reader that sits on a node for
+//! a long time doesn't keep the memory of the whole structure from being reclaimed.
+//! The price is a `SeqCst` store and a reload for every pointer protected.
+//!
+//! Each handle keeps a list of the pointers it retired and scans the slots of every
+//! thread once the list reaches `RECLAIM_EVERY` entries, or when `reclaim` is
+//! called. Pointers left in the list of a dropped handle are adopted by the next
+//! handle that reclaims, or freed with the domain.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cell::{Cell, RefCell};
+use core::ptr;
+
+use crate::sync::atomic::{self, AtomicBool, AtomicPtr, Ordering};
+
+use crate::epoch::SendPtr;
+
+/// Number of hazard slots of each handle
+pub const SLOTS: usize = 4;
+
+/// Number of retired pointers that triggers a scan of the hazard slots
+const RECLAIM_EVERY: usize = 64;
+
+/// Retired pointer along with the function freeing it
+type Retired = (usize, Box<dyn FnOnce() + Send>);
+
+/// Hazard slots of one handle, reused once the handle is dropped
+struct Record {
+    /// Pointers published by the handle, null when a slot is free
+    hazards: [AtomicPtr<u8>; SLOTS],
+
+    /// Set while a `HazardHandle` owns this record
+    in_use: AtomicBool,
+
+    /// Next record of the domain, fixed once this one is published
+    next: *mut Record
+}
+
+/// Retired pointers of a dropped handle, waiting to be adopted
+struct Orphan {
+    retired: Vec<Retired>,
+    next: *mut Orphan
+}
+
+/// Shared state of the hazard pointers of one structure or set of structures
+pub struct HazardDomain {
+    /// List of every record ever registered. Only pushed to until the domain is
+    /// dropped.
+    records: AtomicPtr<Record>,
+
+    /// Retired lists of dropped handles, taken all at once by the handle adopting
+    /// them
+    orphans: AtomicPtr<Orphan>
+}
+
+// SAFETY: Records and orphans are only reached through atomics and are freed by
+// `Drop`, which takes the domain by value
+unsafe impl Send for HazardDomain {}
+unsafe impl Sync for HazardDomain {}
+
+impl HazardDomain {
+    /// Construct a domain with no registered handles
+    pub fn new() -> HazardDomain {
+        HazardDomain {
+            records: AtomicPtr::new(ptr::null_mut()),
+            orphans: AtomicPtr::new(ptr::null_mut())
+        }
+    }
+
+    /// Get the records registered with the domain
+    fn records(&self) -> impl Iterator<Item = &Record> {
+        let mut curr = self.records.load(Ordering::Acquire);
+        core::iter::from_fn(move || {
+            // SAFETY: Records are only freed when the domain is dropped
+            let record = unsafe { curr.as_ref()? };
+            curr = record.next;
+            Some(record)
+        })
+    }
+
+    /// Register a new handle, to be kept by the calling thread and used for every
+    /// operation it makes on the structures protected by this domain
+    pub fn register(&self) -> HazardHandle<'_> {
+        // Reuse the record of a dropped handle if there is one
+        let record = self.records().find(|record| {
+            record.in_use.compare_exchange(false, true, Ordering::Acquire,
+                                           Ordering::Relaxed).is_ok()
+        });
+
+        let record = record.unwrap_or_else(|| {
+            let new = Box::into_raw(Box::new(Record {
+                hazards: core::array::from_fn(|_| AtomicPtr::new(ptr::null_mut())),
+                in_use: AtomicBool::new(true),
+                next: ptr::null_mut()
+            }));
+
+            let mut head = self.records.load(Ordering::Relaxed);
+            loop {
+                // SAFETY: `new` isn't shared until the compare-exchange succeeds
+                unsafe { (*new).next = head; }
+                match self.records.compare_exchange_weak(head, new, Ordering::Release,
+                                                         Ordering::Relaxed) {
+                    Ok(_) => break,
+                    Err(curr) => head = curr
+                }
+            }
+
+            // SAFETY: Records are only freed when the domain is dropped
+            unsafe { &*new }
+        });
+
+        HazardHandle {
+            domain: self,
+            record,
+            free_slots: Cell::new((1 << SLOTS) - 1),
+            retired: RefCell::new(Vec::new())
+        }
+    }
+}
+
+impl Default for HazardDomain {
+    fn default() -> HazardDomain {
+        HazardDomain::new()
+    }
+}
+
+impl Drop for HazardDomain {
+    fn drop(&mut self) {
+        // No handle is left, so every retired pointer can go
+        let mut orphan = self.orphans.load(Ordering::Relaxed);
+        while !orphan.is_null() {
+            // SAFETY: Orphans were allocated by `HazardHandle::drop` and are only
+            // reachable from this list
+            let boxed = unsafe { Box::from_raw(orphan) };
+            orphan = boxed.next;
+            for (_, free) in boxed.retired {
+                free();
+            }
+        }
+
+        let mut record = self.records.load(Ordering::Relaxed);
+        while !record.is_null() {
+            // SAFETY: Records were allocated by `register` and are only reachable
+            // from this list
+            let boxed = unsafe { Box::from_raw(record) };
+            record = boxed.next;
+        }
+    }
+}
+
+/// Registration of one thread with a `HazardDomain`
+///
+/// A handle is only used by the thread that holds it, while the domain is shared.
+pub struct HazardHandle<'a> {
+    domain: &'a HazardDomain,
+    record: &'a Record,
+
+    /// Bit for each slot of `record` not held by a `HazardPointer`
+    free_slots: Cell<usize>,
+
+    /// Pointers retired through this handle and not freed yet
+    retired: RefCell<Vec<Retired>>
+}
+
+impl<'a> HazardHandle<'a> {
+    /// Take one of the hazard slots of this handle, or `None` if all `SLOTS` of them
+    /// are in use
+    pub fn hazard(&self) -> Option<HazardPointer<'_>> {
+        let free = self.free_slots.get();
+        if free == 0 {
+            return None;
+        }
+
+        let slot = free.trailing_zeros() as usize;
+        self.free_slots.set(free & !(1 << slot));
+        Some(HazardPointer { handle: self, slot })
+    }
+
+    /// Free `ptr` once no hazard slot holds it
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must come from `Box::into_raw`, must already be unreachable for threads
+    /// protecting pointers from now on, and must not be freed in any other way.
+    pub unsafe fn retire<T: Send + 'static>(&self, ptr: *mut T) {
+        let addr = ptr as usize;
+        let ptr = SendPtr(ptr);
+        let free = Box::new(move || {
+            let ptr = ptr;
+            // SAFETY: Guaranteed by the caller
+            unsafe { drop(Box::from_raw(ptr.0)); }
+        });
+
+        let len = {
+            let mut retired = self.retired.borrow_mut();
+            retired.push((addr, free));
+            retired.len()
+        };
+
+        if len >= RECLAIM_EVERY {
+            self.reclaim();
+        }
+    }
+
+    /// Free the pointers retired through this handle, and through dropped handles,
+    /// that no hazard slot holds
+    pub fn reclaim(&self) {
+        let mut retired = self.retired.borrow_mut();
+
+        // Adopt the pointers of dropped handles
+        let mut orphan = self.domain.orphans.swap(ptr::null_mut(), Ordering::Acquire);
+        while !orphan.is_null() {
+            // SAFETY: The swap took the whole list, so no other thread can reach it
+            let boxed = unsafe { Box::from_raw(orphan) };
+            orphan = boxed.next;
+            retired.extend(boxed.retired);
+        }
+
+        // Pairs with the fence of `protect`: a reader whose hazard isn't seen here
+        // reloads its source after publishing it and finds the pointer unlinked
+        atomic::fence(Ordering::SeqCst);
+        let mut hazards = self.domain.records()
+            .flat_map(|record| record.hazards.iter())
+            .map(|hazard| hazard.load(Ordering::Relaxed) as usize)
+            .filter(|&addr| addr != 0)
+            .collect::<Vec<_>>();
+        hazards.sort_unstable();
+
+        let (ready, waiting) = core::mem::take(&mut *retired).into_iter()
+            .partition::<Vec<_>, _>(|(addr, _)| hazards.binary_search(addr).is_err());
+        *retired = waiting;
+
+        // Don't hold the list while freeing, drops may retire more
+        drop(retired);
+        for (_, free) in ready {
+            free();
+        }
+    }
+}
+
+impl<'a> Drop for HazardHandle<'a> {
+    fn drop(&mut self) {
+        self.reclaim();
+
+        let retired = core::mem::take(self.retired.get_mut());
+        if !retired.is_empty() {
+            let orphan = Orphan { retired, next: ptr::null_mut() };
+            let orphan = Box::into_raw(Box::new(orphan));
+            let mut head = self.domain.orphans.load(Ordering::Relaxed);
+            loop {
+                // SAFETY: `orphan` isn't shared until the compare-exchange succeeds
+                unsafe { (*orphan).next = head; }
+                match self.domain.orphans.compare_exchange_weak(head, orphan,
+                                                                Ordering::Release,
+                                                                Ordering::Relaxed) {
+                    Ok(_) => break,
+                    Err(curr) => head = curr
+                }
+            }
+        }
+
+        self.record.in_use.store(false, Ordering::Release);
+    }
+}
+
+/// One hazard slot of a `HazardHandle`, cleared and given back when dropped
+pub struct HazardPointer<'a> {
+    handle: &'a HazardHandle<'a>,
+    slot: usize
+}
+
+impl<'a> HazardPointer<'a> {
+    /// Get the hazard slot of this pointer
+    fn hazard(&self) -> &AtomicPtr<u8> {
+        &self.handle.record.hazards[self.slot]
+    }
+
+    /// Load the pointer in `src` and protect it, replacing the pointer protected
+    /// until now. The returned pointer isn't freed while it stays protected, up to
+    /// the next `protect` or `reset` of this hazard pointer or its drop.
+    pub fn protect<T>(&self, src: &AtomicPtr<T>) -> *mut T {
+        let mut ptr = src.load(Ordering::Relaxed);
+        loop {
+            self.hazard().store(ptr.cast(), Ordering::Relaxed);
+
+            // Publish the hazard before checking it is still current. Pairs with the
+            // fence of `reclaim`.
+            atomic::fence(Ordering::SeqCst);
+            let curr = src.load(Ordering::Acquire);
+            if curr == ptr {
+                return ptr;
+            }
+
+            ptr = curr;
+        }
+    }
+
+    /// Stop protecting the pointer protected until now
+    pub fn reset(&self) {
+        self.hazard().store(ptr::null_mut(), Ordering::Release);
+    }
+}
+
+impl<'a> Drop for HazardPointer<'a> {
+    fn drop(&mut self) {
+        self.reset();
+        let free = self.handle.free_slots.get();
+        self.handle.free_slots.set(free | 1 << self.slot);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    /// Value that counts its drops
+    struct Tracked(u64, Arc<AtomicUsize>);
+
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            self.1.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_hazard() {
+        let domain = HazardDomain::new();
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let shared = AtomicPtr::new(Box::into_raw(Box::new(Tracked(1, dropped.clone()))));
+
+        let reader = domain.register();
+        let writer = domain.register();
+
+        let hazard = reader.hazard().unwrap();
+        let protected = hazard.protect(&shared);
+
+        let new = Box::into_raw(Box::new(Tracked(2, dropped.clone())));
+        let old = shared.swap(new, Ordering::AcqRel);
+        assert_eq!(old, protected);
+        unsafe { writer.retire(old); }
+
+        // The reader holds the old value back however often the writer reclaims
+        writer.reclaim();
+        writer.reclaim();
+        assert_eq!(dropped.load(Ordering::Relaxed), 0);
+        assert_eq!(unsafe { (*protected).0 }, 1);
+
+        hazard.reset();
+        writer.reclaim();
+        assert_eq!(dropped.load(Ordering::Relaxed), 1);
+
+        // Slots run out and come back
+        let hazards = (0..SLOTS - 1).map(|_| reader.hazard().unwrap())
+            .collect::<Vec<_>>();
+        assert!(reader.hazard().is_none());
+        drop(hazards);
+        assert!(reader.hazard().is_some());
+
+        drop(hazard);
+        drop((reader, writer));
+        drop(domain);
+        drop(unsafe { Box::from_raw(shared.load(Ordering::Relaxed)) });
+        assert_eq!(dropped.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_hazard_orphans() {
+        let domain = HazardDomain::new();
+        let dropped = Arc::new(AtomicUsize::new(0));
+
+        let reader = domain.register();
+        let hazard = reader.hazard().unwrap();
+        let shared = AtomicPtr::new(Box::into_raw(Box::new(Tracked(1, dropped.clone()))));
+        let protected = hazard.protect(&shared);
+        {
+            // Retired while protected, so left behind by the dropped handle
+            let writer = domain.register();
+            unsafe { writer.retire(protected); }
+        }
+        assert_eq!(dropped.load(Ordering::Relaxed), 0);
+
+        // A new handle reuses the record and adopts the pointer
+        let writer = domain.register();
+        assert_eq!(domain.records().count(), 2);
+        drop(hazard);
+        writer.reclaim();
+        assert_eq!(dropped.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_hazard_threads() {
+        let domain = HazardDomain::new();
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let shared = AtomicPtr::new(Box::into_raw(Box::new(Tracked(0, dropped.clone()))));
+
+        // Threads replace the shared value and read it, retiring replaced values
+        // while others may still be reading them
+        thread::scope(|scope| {
+            for thread in 0..4u64 {
+                let (domain, shared, dropped) = (&domain, &shared, &dropped);
+                scope.spawn(move || {
+                    let handle = domain.register();
+                    let hazard = handle.hazard().unwrap();
+                    for i in 0..10_000 {
+                        if i % 4 == 0 {
+                            let new = Tracked(thread << 32 | i, dropped.clone());
+                            let new = Box::into_raw(Box::new(new));
+                            let old = shared.swap(new, Ordering::AcqRel);
+                            unsafe { handle.retire(old); }
+                        } else {
+                            let value = unsafe { &*hazard.protect(shared) };
+                            assert!(value.0 & 0xffff_ffff < 10_000);
+                        }
+                    }
+                });
+            }
+        });
+
+        drop(domain);
+        assert_eq!(dropped.load(Ordering::Relaxed), 10_000);
+        drop(unsafe { Box::from_raw(shared.load(Ordering::Relaxed)) });
+        assert_eq!(dropped.load(Ordering::Relaxed), 10_001);
+    }
+}
@@ -0,0 +1,217 @@
+//! Lock-free histogram of `u64` values over fixed buckets
+//!
+//! Recording a value is a single `Relaxed` `fetch_add` on the count of its bucket, so
+//! threads can record latencies next to their map operations without contending on
+//! anything but the bucket they land in. Reads sum the bucket counts one at a time
+//! and are only exact once recording has stopped.
+//!
+//! Buckets are either the powers of two, which cover every `u64` in 65 buckets and
+//! find the bucket of a value with a single `leading_zeros`, or given by the user as
+//! inclusive upper bounds, whose bucket is found with a binary search.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// How the bucket of a value is found
+enum Bounds {
+    /// Bucket `i` holds the values of `i` significant bits
+    PowerOfTwo,
+
+    /// Bucket `i` holds the values up to `bounds[i]` above the previous bound, and a
+    /// last bucket holds the values above every bound
+    Custom(Box<[u64]>)
+}
+
+/// Lock-free histogram of `u64` values, e.g. latencies in nanoseconds
+pub struct AtomicHistogram {
+    bounds: Bounds,
+
+    /// Number of values recorded in each bucket
+    counts: Box<[AtomicU64]>
+}
+
+impl AtomicHistogram {
+    /// Construct a histogram whose buckets are the powers of two: 0, 1, 2..=3,
+    /// 4..=7, and so on up to `1 << 63..=u64::MAX`
+    pub fn power_of_two() -> AtomicHistogram {
+        AtomicHistogram::with_counts(Bounds::PowerOfTwo, 65)
+    }
+
+    /// Construct a histogram with a bucket for the values up to each of `bounds`,
+    /// above the previous bound, plus one bucket for the values above every bound
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bounds` isn't strictly increasing.
+    pub fn with_bounds(bounds: &[u64]) -> AtomicHistogram {
+        assert!(bounds.windows(2).all(|pair| pair[0] < pair[1]),
+                "bounds of an AtomicHistogram must be strictly increasing");
+
+        AtomicHistogram::with_counts(Bounds::Custom(bounds.into()), bounds.len() + 1)
+    }
+
+    fn with_counts(bounds: Bounds, buckets: usize) -> AtomicHistogram {
+        AtomicHistogram {
+            bounds,
+            counts: (0..buckets).map(|_| AtomicU64::new(0)).collect()
+        }
+    }
+
+    /// Get the index of the bucket holding `value`
+    fn bucket(&self, value: u64) -> usize {
+        match &self.bounds {
+            Bounds::PowerOfTwo => (64 - value.leading_zeros()) as usize,
+            Bounds::Custom(bounds) => bounds.partition_point(|&bound| bound < value)
+        }
+    }
+
+    /// Get the largest value held by the bucket at `index`
+    fn upper_bound(&self, index: usize) -> u64 {
+        match &self.bounds {
+            Bounds::PowerOfTwo if index == 64 => u64::MAX,
+            Bounds::PowerOfTwo => (1 << index) - 1,
+            Bounds::Custom(bounds) => bounds.get(index).copied().unwrap_or(u64::MAX)
+        }
+    }
+
+    /// Record one occurrence of `value`
+    pub fn record(&self, value: u64) {
+        self.record_n(value, 1);
+    }
+
+    /// Record `count` occurrences of `value`
+    pub fn record_n(&self, value: u64, count: u64) {
+        self.counts[self.bucket(value)].fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Get the number of values recorded
+    pub fn count(&self) -> u64 {
+        self.counts.iter().map(|count| count.load(Ordering::Relaxed)).sum()
+    }
+
+    /// Estimate the `percentile`th percentile of the recorded values, between 0 and
+    /// 100, or `None` if nothing was recorded
+    ///
+    /// The estimate is the upper bound of the bucket holding the value of that rank,
+    /// so it is never below the exact percentile and is off by at most the width of
+    /// that bucket. A percentile landing in the last bucket is estimated as
+    /// `u64::MAX`.
+    pub fn percentile(&self, percentile: f64) -> Option<u64> {
+        let counts = self.counts.iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .collect::<Vec<_>>();
+
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return None;
+        }
+
+        // Rank of the value within the sorted recorded values, starting at 1 and
+        // rounded up by hand as `f64::ceil` needs `std`
+        let exact = percentile.clamp(0.0, 100.0) / 100.0 * total as f64;
+        let mut rank = exact as u64;
+        if (rank as f64) < exact {
+            rank += 1;
+        }
+        let rank = rank.max(1);
+
+        let mut seen = 0;
+        for (index, count) in counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(self.upper_bound(index));
+            }
+        }
+
+        Some(u64::MAX)
+    }
+
+    /// Get the upper bound and count of every bucket, in increasing order of bounds
+    pub fn buckets(&self) -> Vec<(u64, u64)> {
+        self.counts.iter().enumerate()
+            .map(|(index, count)| {
+                (self.upper_bound(index), count.load(Ordering::Relaxed))
+            })
+            .collect()
+    }
+
+    /// Reset the count of every bucket to 0
+    pub fn reset(&self) {
+        for count in self.counts.iter() {
+            count.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    #[test]
+    fn test_power_of_two() {
+        let histogram = AtomicHistogram::power_of_two();
+        assert_eq!(histogram.percentile(50.0), None);
+
+        for value in [0, 1, 2, 3, 4, 7, 8, 1000, u64::MAX] {
+            histogram.record(value);
+        }
+        let buckets = histogram.buckets();
+        assert_eq!(buckets.len(), 65);
+        assert_eq!(&buckets[..5], &[(0, 1), (1, 1), (3, 2), (7, 2), (15, 1)]);
+        assert_eq!(buckets[10], (1023, 1));
+        assert_eq!(buckets[64], (u64::MAX, 1));
+
+        assert_eq!(histogram.count(), 9);
+        assert_eq!(histogram.percentile(0.0), Some(0));
+        assert_eq!(histogram.percentile(50.0), Some(7));
+        assert_eq!(histogram.percentile(80.0), Some(1023));
+        assert_eq!(histogram.percentile(100.0), Some(u64::MAX));
+
+        histogram.reset();
+        assert_eq!(histogram.count(), 0);
+    }
+
+    #[test]
+    fn test_bounds() {
+        let histogram = AtomicHistogram::with_bounds(&[10, 100, 1000]);
+        histogram.record_n(5, 90);
+        histogram.record(10);
+        histogram.record_n(50, 8);
+        histogram.record(5000);
+
+        assert_eq!(histogram.buckets(),
+                   vec![(10, 91), (100, 8), (1000, 0), (u64::MAX, 1)]);
+        assert_eq!(histogram.percentile(50.0), Some(10));
+        assert_eq!(histogram.percentile(99.0), Some(100));
+        assert_eq!(histogram.percentile(99.5), Some(u64::MAX));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_unsorted_bounds() {
+        AtomicHistogram::with_bounds(&[10, 10]);
+    }
+
+    #[test]
+    fn test_histogram_threads() {
+        let histogram = AtomicHistogram::power_of_two();
+
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                let histogram = &histogram;
+                scope.spawn(move || {
+                    for value in 0..10_000 {
+                        histogram.record(value);
+                    }
+                });
+            }
+        });
+
+        assert_eq!(histogram.count(), 40_000);
+        assert_eq!(histogram.buckets()[14], (16_383, 4 * (10_000 - 8192)));
+    }
+}
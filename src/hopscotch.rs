@@ -0,0 +1,452 @@
+//! Hopscotch hashing variant of `AtomicHashMap` with a hard bound on lookups
+//!
+//! Every key is stored within `NEIGHBORHOOD` slots of its home slot, and each home
+//! slot keeps a bitmap of which slots of its neighborhood hold its keys. A lookup
+//! loads that bitmap and checks only the slots it names, so it never reads more than
+//! `NEIGHBORHOOD` keys no matter how full the table is.
+//!
+//! When the nearest free slot is too far from the home of a new key, keys between the
+//! two are hopped forward into the free slot, as long as that keeps them inside their
+//! own neighborhoods, until the free slot is close enough. If no key can hop, the
+//! insert fails with `AtomicHashMapError::Full` even if the table has free slots
+//! elsewhere. Moving keys can't be done with a single compare-exchange, so writers
+//! take a lock while readers never do, and a lookup that overlapped a move or a
+//! removal is retried, see the `seqlock` module.
+//!
+//! # Memory ordering
+//!
+//! A new key is stored with `Release` after its value and its bit is then set in the
+//! bitmap of its home with `Release`. Lookups load the bitmap and keys with `Acquire`,
+//! so a thread that sees the bit of a key sees the key and its value.
+
+use std::hash::{BuildHasher, Hasher};
+use std::marker::PhantomData;
+use std::sync::{Mutex, PoisonError};
+
+use core::sync::atomic::{Ordering, AtomicU32, AtomicU64};
+
+use crate::atomichashmap::{AtomicHashMapError, BuildMurmurHasher};
+use crate::pod::PodU64;
+use crate::seqlock::MoveSeq;
+
+/// Key marker for a slot that holds no key
+const EMPTY_KEY: u64 = 0;
+
+/// Maximum distance of a key from its home slot, plus one. A lookup checks at most
+/// this many slots.
+pub const NEIGHBORHOOD: usize = 32;
+
+/// One slot of the table
+struct Slot {
+    key: AtomicU64,
+    value: AtomicU64,
+
+    /// Bit `d` is set if the slot `d` past this one holds a key whose home is this
+    /// slot
+    hop: AtomicU32
+}
+
+/// Hashmap from keys of type `K` to values of type `V` using hopscotch hashing
+///
+/// Lookups check at most `NEIGHBORHOOD` slots and never take a lock. Writes are
+/// serialized. A key of 0 is reserved to mark empty slots.
+pub struct AtomicHopscotchMap<K: PodU64 = u64, V: PodU64 = u64, S = BuildMurmurHasher> {
+    slots: Box<[Slot]>,
+    size: usize,
+
+    /// Number of keys currently in the table
+    count: AtomicU64,
+
+    /// Lets readers detect a writer moving entries between slots
+    seq: MoveSeq,
+
+    /// Held by the thread currently writing to the table
+    writer: Mutex<()>,
+
+    /// Builds the hasher used to find the home slot of each key
+    hasher: S,
+
+    _types: PhantomData<(K, V)>
+}
+
+unsafe impl<K: PodU64, V: PodU64, S: Send> Send for AtomicHopscotchMap<K, V, S> {}
+unsafe impl<K: PodU64, V: PodU64, S: Sync> Sync for AtomicHopscotchMap<K, V, S> {}
+
+impl<K: PodU64, V: PodU64> AtomicHopscotchMap<K, V> {
+    /// Construct a new AtomicHopscotchMap with a given size.
+    /// NOTE: Size must be a power of two, otherwise `InvalidCapacity` is returned.
+    pub fn new(size: usize) -> Result<AtomicHopscotchMap<K, V>, AtomicHashMapError> {
+        AtomicHopscotchMap::with_hasher(size, BuildMurmurHasher::default())
+    }
+}
+
+impl<K: PodU64, V: PodU64, S: BuildHasher> AtomicHopscotchMap<K, V, S> {
+    /// Construct a new AtomicHopscotchMap with a given size, hashing keys with
+    /// `hasher`.
+    /// NOTE: Size must be a power of two, otherwise `InvalidCapacity` is returned.
+    pub fn with_hasher(size: usize, hasher: S)
+            -> Result<AtomicHopscotchMap<K, V, S>, AtomicHashMapError> {
+        if size < 2 || !size.is_power_of_two() {
+            return Err(AtomicHashMapError::InvalidCapacity);
+        }
+
+        let slots = (0..size).map(|_| Slot {
+            key: AtomicU64::new(EMPTY_KEY),
+            value: AtomicU64::new(0),
+            hop: AtomicU32::new(0)
+        }).collect();
+
+        Ok(AtomicHopscotchMap {
+            slots,
+            size,
+            count: AtomicU64::new(0),
+            seq: MoveSeq::new(),
+            writer: Mutex::new(()),
+            hasher,
+            _types: PhantomData
+        })
+    }
+
+    /// Get the raw slot representation of `key`, which must not be the empty marker
+    fn raw_key(&self, key: K) -> Result<u64, AtomicHashMapError> {
+        let key = key.to_u64();
+        if key == EMPTY_KEY {
+            return Err(AtomicHashMapError::InvalidKey);
+        }
+
+        Ok(key)
+    }
+
+    /// Get the index of the home slot of the raw `key`
+    fn home(&self, key: u64) -> usize {
+        let mut hasher = self.hasher.build_hasher();
+        hasher.write_u64(key);
+        hasher.finish() as usize & (self.size - 1)
+    }
+
+    /// Get the index of the slot `dist` past `index`
+    fn offset(&self, index: usize, dist: usize) -> usize {
+        index.wrapping_add(dist) & (self.size - 1)
+    }
+
+    /// Get how far the slot at `to` is past the slot at `from`
+    fn distance(&self, from: usize, to: usize) -> usize {
+        to.wrapping_sub(from) & (self.size - 1)
+    }
+
+    /// Find the slot holding `key`. Only reliable while no writer moves entries.
+    fn find_slot(&self, key: u64) -> Option<usize> {
+        let home = self.home(key);
+
+        let mut hop = self.slots[home].hop.load(Ordering::Acquire);
+        while hop != 0 {
+            let index = self.offset(home, hop.trailing_zeros() as usize);
+            if self.slots[index].key.load(Ordering::Acquire) == key {
+                return Some(index);
+            }
+
+            hop &= hop - 1;
+        }
+
+        None
+    }
+
+    /// Atomically get a value from the hashmap
+    pub fn get(&self, key: &K) -> Option<V> {
+        let key = self.raw_key(*key).ok()?;
+
+        self.seq.read(|| {
+            let index = self.find_slot(key)?;
+            Some(V::from_u64(self.slots[index].value.load(Ordering::Acquire)))
+        })
+    }
+
+    /// Check if a key is in the hashmap
+    pub fn contains_key(&self, key: &K) -> bool {
+        match self.raw_key(*key) {
+            Ok(key) => self.seq.read(|| self.find_slot(key).is_some()),
+            Err(_) => false
+        }
+    }
+
+    /// Set a key:value in the hashmap, hopping other keys forward to make room within
+    /// the neighborhood of `key` if needed
+    ///
+    /// Returns the value previously stored for this key, swapped out atomically with
+    /// the new value, or `None` if the key was newly inserted. Returns
+    /// `AtomicHashMapError::Full` if no free slot can be brought into the neighborhood
+    /// of `key`.
+    pub fn insert(&self, key: K, value: V) -> Result<Option<V>, AtomicHashMapError> {
+        let key = self.raw_key(key)?;
+        let value = value.to_u64();
+
+        let _writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+
+        // Entries only move under the lock, so no retry is needed here
+        if let Some(index) = self.find_slot(key) {
+            let prev_value = self.slots[index].value.swap(value, Ordering::AcqRel);
+            return Ok(Some(V::from_u64(prev_value)));
+        }
+
+        let home = self.home(key);
+        let mut dist = (0..self.size)
+            .find(|&dist| {
+                self.slots[self.offset(home, dist)].key.load(Ordering::Relaxed) == EMPTY_KEY
+            })
+            .ok_or(AtomicHashMapError::Full)?;
+
+        let moving = dist >= NEIGHBORHOOD;
+        if moving {
+            self.seq.begin_move();
+        }
+
+        while dist >= NEIGHBORHOOD {
+            match self.hop_into(self.offset(home, dist)) {
+                Some(hopped) => dist -= hopped,
+                None => break
+            }
+        }
+
+        let res = if dist < NEIGHBORHOOD {
+            let slot = &self.slots[self.offset(home, dist)];
+            slot.value.store(value, Ordering::Relaxed);
+            slot.key.store(key, Ordering::Release);
+            self.slots[home].hop.fetch_or(1 << dist, Ordering::Release);
+
+            self.count.fetch_add(1, Ordering::Relaxed);
+            Ok(None)
+        } else {
+            // Keys that already hopped are still within their neighborhoods
+            Err(AtomicHashMapError::Full)
+        };
+
+        if moving {
+            self.seq.end_move();
+        }
+
+        res
+    }
+
+    /// Move the furthest key before the empty slot `free` that stays within its
+    /// neighborhood into `free`. Returns how far back the empty slot moved, or `None`
+    /// if no key can move. Must be called between `begin_move` and `end_move`.
+    fn hop_into(&self, free: usize) -> Option<usize> {
+        for back in (1..NEIGHBORHOOD).rev() {
+            let index = free.wrapping_sub(back) & (self.size - 1);
+            let key = self.slots[index].key.load(Ordering::Relaxed);
+            if key == EMPTY_KEY {
+                continue;
+            }
+
+            let home = self.home(key);
+            let new_dist = self.distance(home, free);
+            if new_dist >= NEIGHBORHOOD {
+                continue;
+            }
+
+            let old_dist = self.distance(home, index);
+            let from = &self.slots[index];
+            let to = &self.slots[free];
+            to.value.store(from.value.load(Ordering::Relaxed), Ordering::Relaxed);
+            to.key.store(key, Ordering::Release);
+            self.slots[home].hop.fetch_xor((1 << new_dist) | (1 << old_dist),
+                                           Ordering::Release);
+
+            from.key.store(EMPTY_KEY, Ordering::Relaxed);
+            from.value.store(0, Ordering::Relaxed);
+            return Some(back);
+        }
+
+        None
+    }
+
+    /// Remove a key from the hashmap, returning its value if it was present
+    pub fn remove(&self, key: K) -> Option<V> {
+        let key = self.raw_key(key).ok()?;
+
+        let _writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let index = self.find_slot(key)?;
+        let home = self.home(key);
+        let dist = self.distance(home, index);
+        let slot = &self.slots[index];
+        let value = slot.value.load(Ordering::Relaxed);
+
+        // The slot may be reused right away, so a reader that found the key here must
+        // not go on to read the value of the next one
+        self.seq.begin_move();
+        self.slots[home].hop.fetch_and(!(1 << dist), Ordering::Release);
+        slot.key.store(EMPTY_KEY, Ordering::Relaxed);
+        slot.value.store(0, Ordering::Relaxed);
+        self.seq.end_move();
+
+        self.count.fetch_sub(1, Ordering::Relaxed);
+        Some(V::from_u64(value))
+    }
+
+    /// Collect the `(key, value)` pairs currently in the hashmap, in table order
+    pub fn to_vec(&self) -> Vec<(K, V)> {
+        self.seq.read(|| {
+            self.slots.iter().filter_map(|slot| {
+                let key = slot.key.load(Ordering::Acquire);
+                if key == EMPTY_KEY {
+                    return None;
+                }
+
+                let value = slot.value.load(Ordering::Acquire);
+                Some((K::from_u64(key), V::from_u64(value)))
+            }).collect()
+        })
+    }
+
+    /// Get the number of elements currently in the hashtable
+    pub fn len(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Returns true if the hashtable has no elements
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the total number of slots in the hashtable
+    pub fn capacity(&self) -> usize {
+        self.size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    impl<K: PodU64, V: PodU64, S: BuildHasher> AtomicHopscotchMap<K, V, S> {
+        /// Check that every key is within its neighborhood and marked in its bitmap
+        fn check_neighborhoods(&self) {
+            for (index, slot) in self.slots.iter().enumerate() {
+                let key = slot.key.load(Ordering::Relaxed);
+                if key == EMPTY_KEY {
+                    continue;
+                }
+
+                let home = self.home(key);
+                let dist = self.distance(home, index);
+                assert!(dist < NEIGHBORHOOD);
+                assert!(self.slots[home].hop.load(Ordering::Relaxed) & (1 << dist) != 0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_insert_get_remove() {
+        let hashtable: AtomicHopscotchMap = AtomicHopscotchMap::new(1 << 4).unwrap();
+        assert_eq!(hashtable.insert(1, 10), Ok(None));
+        assert_eq!(hashtable.insert(1, 11), Ok(Some(10)));
+        assert_eq!(hashtable.get(&1), Some(11));
+        assert!(hashtable.contains_key(&1));
+
+        assert_eq!(hashtable.insert(0, 1), Err(AtomicHashMapError::InvalidKey));
+        assert_eq!(hashtable.get(&0), None);
+
+        assert_eq!(hashtable.remove(1), Some(11));
+        assert_eq!(hashtable.remove(1), None);
+        assert!(hashtable.is_empty());
+
+        assert!(AtomicHopscotchMap::<u64, u64>::new(12).is_err());
+    }
+
+    #[test]
+    fn test_high_load() {
+        let size = 1 << 12;
+        let hashtable: AtomicHopscotchMap = AtomicHopscotchMap::new(size).unwrap();
+
+        let mut inserted = Vec::new();
+        for x in 1..=size as u64 {
+            match hashtable.insert(x, x * 2) {
+                Ok(None) => inserted.push(x),
+                Err(AtomicHashMapError::Full) => break,
+                res => panic!("Unexpected insert result {:?}", res)
+            }
+        }
+
+        assert!(inserted.len() > size * 9 / 10, "Only inserted {}", inserted.len());
+        hashtable.check_neighborhoods();
+        for x in inserted {
+            assert_eq!(hashtable.get(&x), Some(x * 2));
+        }
+    }
+
+    #[test]
+    fn test_matches_hashmap() {
+        let hashtable: AtomicHopscotchMap = AtomicHopscotchMap::new(1 << 10).unwrap();
+        let mut expected = HashMap::new();
+
+        let mut state = 0x9e37_79b9_7f4a_7c15_u64;
+        for _ in 0..20_000 {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+
+            let key = state % 900 + 1;
+            if state & (1 << 40) == 0 {
+                assert_eq!(hashtable.insert(key, state), Ok(expected.insert(key, state)));
+            } else {
+                assert_eq!(hashtable.remove(key), expected.remove(&key));
+            }
+        }
+
+        hashtable.check_neighborhoods();
+        assert_eq!(hashtable.len() as usize, expected.len());
+        for key in 1..=900 {
+            assert_eq!(hashtable.get(&key), expected.get(&key).copied());
+        }
+    }
+
+    #[test]
+    fn test_readers_during_writes() {
+        use std::sync::Arc;
+        use std::thread;
+
+        let hashtable: Arc<AtomicHopscotchMap> =
+            Arc::new(AtomicHopscotchMap::new(1 << 10).unwrap());
+
+        // Keys that are never removed must always be found, even while other inserts
+        // hop them forward
+        for x in 1..=500 {
+            hashtable.insert(x, x).unwrap();
+        }
+
+        let writer = {
+            let hashtable = hashtable.clone();
+            thread::spawn(move || {
+                for round in 0..20 {
+                    for x in 501..=900 {
+                        hashtable.insert(x, round).unwrap();
+                    }
+                    for x in 501..=900 {
+                        hashtable.remove(x);
+                    }
+                }
+            })
+        };
+
+        let readers: Vec<_> = (0..4).map(|_| {
+            let hashtable = hashtable.clone();
+            thread::spawn(move || {
+                for _ in 0..20 {
+                    for x in 1..=500 {
+                        assert_eq!(hashtable.get(&x), Some(x));
+                    }
+                }
+            })
+        }).collect();
+
+        writer.join().unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        assert_eq!(hashtable.len(), 500);
+    }
+}
@@ -0,0 +1,227 @@
+//! Lock-free HyperLogLog estimator of the number of distinct keys
+//!
+//! The sketch keeps `2^precision` registers of 6 bits, ten to an `AtomicU64` word. A
+//! key picks a register with the top `precision` bits of its `hash_key` and raises it
+//! to the rank of the first set bit among the remaining bits. Raising a register is
+//! a CAS loop on its word that only ever increases it, so concurrent inserts commute
+//! and the sketch ends up the same whatever order keys arrive in. `merge` raises
+//! every register to the one of another sketch, giving the sketch of the union.
+//!
+//! With `m` registers the standard error of the estimate is about `1.04 / sqrt(m)`,
+//! so 1.6% for the default precision of 12, whose 4096 registers fit in 410 words.
+
+use alloc::boxed::Box;
+use core::marker::PhantomData;
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::hasher::hash_key;
+use crate::pod::PodU64;
+
+/// Bits per register
+const REGISTER_BITS: u32 = 6;
+
+/// Registers per word
+const REGISTERS_PER_WORD: usize = 10;
+
+/// Mask of a register in the low bits of a word
+const REGISTER_MASK: u64 = (1 << REGISTER_BITS) - 1;
+
+/// Lock-free HyperLogLog sketch of keys of type `K`
+pub struct HyperLogLog<K: PodU64 = u64> {
+    words: Box<[AtomicU64]>,
+
+    /// Number of hash bits picking the register of a key
+    precision: u32,
+
+    _keys: PhantomData<K>
+}
+
+impl<K: PodU64> HyperLogLog<K> {
+    /// Default number of hash bits picking the register of a key
+    pub const DEFAULT_PRECISION: u32 = 12;
+
+    /// Construct an empty sketch of `2^DEFAULT_PRECISION` registers
+    pub fn new() -> HyperLogLog<K> {
+        HyperLogLog::with_precision(HyperLogLog::<K>::DEFAULT_PRECISION)
+    }
+
+    /// Construct an empty sketch of `2^precision` registers
+    ///
+    /// # Panics
+    ///
+    /// Panics if `precision` isn't between 4 and 18.
+    pub fn with_precision(precision: u32) -> HyperLogLog<K> {
+        assert!((4..=18).contains(&precision),
+                "precision of a HyperLogLog must be between 4 and 18");
+
+        let words = (1usize << precision).div_ceil(REGISTERS_PER_WORD);
+        HyperLogLog {
+            words: (0..words).map(|_| AtomicU64::new(0)).collect(),
+            precision,
+            _keys: PhantomData
+        }
+    }
+
+    /// Get the number of registers
+    pub fn registers(&self) -> usize {
+        1 << self.precision
+    }
+
+    /// Get the word holding register `index` and the shift of the register within it
+    fn register(&self, index: usize) -> (&AtomicU64, u32) {
+        let shift = (index % REGISTERS_PER_WORD) as u32 * REGISTER_BITS;
+        (&self.words[index / REGISTERS_PER_WORD], shift)
+    }
+
+    /// Atomically raise register `index` to `rank` if it is lower
+    fn raise(&self, index: usize, rank: u64) {
+        let (word, shift) = self.register(index);
+        let _ = word.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |word| {
+            let curr = (word >> shift) & REGISTER_MASK;
+            (rank > curr).then(|| (word & !(REGISTER_MASK << shift)) | (rank << shift))
+        });
+    }
+
+    /// Read register `index`
+    fn load(&self, index: usize) -> u64 {
+        let (word, shift) = self.register(index);
+        (word.load(Ordering::Relaxed) >> shift) & REGISTER_MASK
+    }
+
+    /// Add `key` to the sketch
+    pub fn insert(&self, key: K) {
+        let hash = hash_key(key.to_u64());
+        let index = (hash >> (64 - self.precision)) as usize;
+
+        // Rank of the first set bit after the index bits, capped by a sentinel bit
+        // for a hash whose remaining bits are all clear
+        let rest = (hash << self.precision) | (1 << (self.precision - 1));
+        self.raise(index, u64::from(rest.leading_zeros()) + 1);
+    }
+
+    /// Raise every register to the one of `other`, so that the sketch estimates the
+    /// number of distinct keys inserted into either
+    ///
+    /// # Panics
+    ///
+    /// Panics if the sketches don't have the same precision.
+    pub fn merge(&self, other: &HyperLogLog<K>) {
+        assert_eq!(self.precision, other.precision,
+                   "only HyperLogLogs of the same precision can be merged");
+
+        for index in 0..self.registers() {
+            let rank = other.load(index);
+            if rank > 0 {
+                self.raise(index, rank);
+            }
+        }
+    }
+
+    /// Estimate the number of distinct keys inserted
+    pub fn estimate(&self) -> f64 {
+        let registers = self.registers() as f64;
+        let alpha = match self.precision {
+            4 => 0.673,
+            5 => 0.697,
+            6 => 0.709,
+            _ => 0.7213 / (1.0 + 1.079 / registers)
+        };
+
+        let mut sum = 0.0;
+        let mut zeros = 0;
+        for index in 0..self.registers() {
+            let rank = self.load(index);
+            sum += 1.0 / (1u64 << rank) as f64;
+            zeros += usize::from(rank == 0);
+        }
+
+        let estimate = alpha * registers * registers / sum;
+
+        // Small cardinalities leave registers empty, which linear counting handles
+        // better than the harmonic mean
+        if estimate <= 2.5 * registers && zeros > 0 {
+            return registers * (registers / zeros as f64).ln();
+        }
+
+        estimate
+    }
+
+    /// Reset every register with exclusive access
+    pub fn clear(&mut self) {
+        for word in self.words.iter() {
+            word.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<K: PodU64> Default for HyperLogLog<K> {
+    fn default() -> HyperLogLog<K> {
+        HyperLogLog::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    /// Check that `estimate` is within `error` of `expected`, relatively
+    fn assert_close(estimate: f64, expected: f64, error: f64) {
+        assert!((estimate - expected).abs() <= expected * error,
+                "estimated {} instead of {}", estimate, expected);
+    }
+
+    #[test]
+    fn test_hyperloglog() {
+        let mut hll: HyperLogLog = HyperLogLog::new();
+        assert_eq!(hll.registers(), 4096);
+        assert_eq!(hll.estimate(), 0.0);
+
+        for key in 0..100_000 {
+            hll.insert(key);
+            hll.insert(key);
+        }
+        assert_close(hll.estimate(), 100_000.0, 0.05);
+
+        hll.clear();
+        for key in 0..100 {
+            hll.insert(key);
+        }
+        assert_close(hll.estimate(), 100.0, 0.05);
+    }
+
+    #[test]
+    fn test_merge() {
+        let a: HyperLogLog = HyperLogLog::with_precision(14);
+        let b: HyperLogLog = HyperLogLog::with_precision(14);
+        for key in 0..60_000 {
+            a.insert(key);
+        }
+        for key in 40_000..100_000 {
+            b.insert(key);
+        }
+
+        a.merge(&b);
+        assert_close(a.estimate(), 100_000.0, 0.03);
+    }
+
+    #[test]
+    fn test_hyperloglog_threads() {
+        let hll: HyperLogLog = HyperLogLog::new();
+
+        thread::scope(|scope| {
+            for thread in 0..4 {
+                let hll = &hll;
+                scope.spawn(move || {
+                    for key in 0..50_000 {
+                        hll.insert(thread * 25_000 + key);
+                    }
+                });
+            }
+        });
+
+        assert_close(hll.estimate(), 125_000.0, 0.05);
+    }
+}
@@ -1,5 +1,198 @@
-#![feature(new_uninit)]
-#![feature(core_intrinsics)]
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 
+extern crate alloc;
+
+#[cfg(target_has_atomic = "64")]
+pub mod arc;
+pub mod arena;
+pub mod atomic128;
+#[cfg(target_has_atomic = "64")]
 pub mod atomichashmap;
-pub use atomichashmap::AtomicHashMap;
+pub mod backoff;
+#[cfg(target_has_atomic = "64")]
+pub mod bitmap;
+#[cfg(all(feature = "std", target_has_atomic = "64"))]
+pub mod bloom;
+#[cfg(target_has_atomic = "64")]
+pub mod cell;
+#[cfg(target_has_atomic = "64")]
+mod control;
+#[cfg(target_has_atomic = "64")]
+pub mod counter;
+#[cfg(target_has_atomic = "64")]
+pub mod countmin;
+#[cfg(all(feature = "std", target_has_atomic = "64"))]
+pub mod cuckoo;
+#[cfg(target_has_atomic = "64")]
+pub mod deque;
+#[cfg(target_has_atomic = "64")]
+pub mod epoch;
+pub mod error;
+#[cfg(target_has_atomic = "64")]
+pub mod eviction;
+#[cfg(all(feature = "std", target_has_atomic = "64"))]
+pub mod expiring;
+#[cfg(target_has_atomic = "64")]
+pub mod growable;
+pub mod hasher;
+#[cfg(target_has_atomic = "64")]
+pub mod hazard;
+#[cfg(target_has_atomic = "64")]
+pub mod histogram;
+#[cfg(all(feature = "std", target_has_atomic = "64"))]
+pub mod hopscotch;
+#[cfg(all(feature = "std", target_has_atomic = "64"))]
+pub mod hyperloglog;
+#[cfg(target_has_atomic = "64")]
+pub mod lru;
+#[cfg(target_has_atomic = "64")]
+pub mod map128;
+#[cfg(target_has_atomic = "32")]
+pub mod map32;
+pub mod mcslock;
+#[cfg(target_has_atomic = "64")]
+pub mod metrics;
+#[cfg(target_has_atomic = "64")]
+pub mod mpsc;
+#[cfg(target_has_atomic = "64")]
+pub mod multimap;
+pub mod optionbox;
+pub mod ordering;
+#[cfg(all(feature = "rayon", target_has_atomic = "64"))]
+pub mod parallel;
+#[cfg(all(feature = "persist", unix, target_has_atomic = "64"))]
+pub mod persist;
+#[cfg(target_has_atomic = "64")]
+mod placement;
+pub mod pod;
+#[cfg(target_has_atomic = "32")]
+mod probe;
+#[cfg(target_has_atomic = "64")]
+pub mod queue;
+#[cfg(target_has_atomic = "64")]
+pub mod radix;
+#[cfg(target_has_atomic = "64")]
+pub mod rcu;
+#[cfg(test)]
+mod rng;
+#[cfg(all(feature = "std", target_has_atomic = "64"))]
+pub mod robinhood;
+#[cfg(target_has_atomic = "64")]
+pub mod rwspinlock;
+#[cfg(target_has_atomic = "64")]
+pub mod seqlock;
+#[cfg(all(feature = "serde", target_has_atomic = "64"))]
+mod serialize;
+#[cfg(target_has_atomic = "64")]
+pub mod set;
+#[cfg(target_has_atomic = "64")]
+pub mod sharded;
+#[cfg(target_has_atomic = "64")]
+pub mod shared;
+#[cfg(target_has_atomic = "64")]
+pub mod skiplist;
+#[cfg(target_has_atomic = "64")]
+pub mod slab;
+pub mod spinlock;
+#[cfg(target_has_atomic = "64")]
+pub mod spsc;
+#[cfg(target_has_atomic = "64")]
+pub mod stack;
+#[cfg(target_has_atomic = "64")]
+pub mod staticmap;
+pub mod ticketlock;
+#[cfg(all(test, feature = "stress"))]
+mod stress;
+mod sync;
+#[cfg(target_has_atomic = "64")]
+pub mod tagged;
+#[cfg(all(test, loom))]
+mod test;
+#[cfg(target_has_atomic = "64")]
+pub use arc::AtomicArc;
+pub use arena::AtomicArena;
+pub use atomic128::AtomicU128;
+#[cfg(target_has_atomic = "64")]
+pub use atomichashmap::{AtomicHashMap, AtomicHashMapBuilder, MapStats, ProbeStrategy};
+pub use backoff::Backoff;
+#[cfg(target_has_atomic = "64")]
+pub use bitmap::AtomicBitmap;
+#[cfg(all(feature = "std", target_has_atomic = "64"))]
+pub use bloom::AtomicBloomFilter;
+#[cfg(target_has_atomic = "64")]
+pub use cell::{AtomicCell, NoUninit};
+#[cfg(target_has_atomic = "64")]
+pub use counter::AtomicCounterMap;
+#[cfg(target_has_atomic = "64")]
+pub use countmin::CountMinSketch;
+#[cfg(all(feature = "std", target_has_atomic = "64"))]
+pub use cuckoo::AtomicCuckooMap;
+#[cfg(target_has_atomic = "64")]
+pub use deque::WorkStealingDeque;
+#[cfg(target_has_atomic = "64")]
+pub use epoch::Collector;
+pub use error::AtomicHashMapError;
+#[cfg(target_has_atomic = "64")]
+pub use eviction::{EvictionPolicy, LowestValueEviction, OldestEviction, RandomEviction};
+#[cfg(all(feature = "std", target_has_atomic = "64"))]
+pub use expiring::ExpiringAtomicHashMap;
+#[cfg(target_has_atomic = "64")]
+pub use growable::GrowableAtomicHashMap;
+#[cfg(target_has_atomic = "64")]
+pub use hazard::HazardDomain;
+#[cfg(target_has_atomic = "64")]
+pub use histogram::AtomicHistogram;
+#[cfg(all(feature = "std", target_has_atomic = "64"))]
+pub use hopscotch::AtomicHopscotchMap;
+#[cfg(all(feature = "std", target_has_atomic = "64"))]
+pub use hyperloglog::HyperLogLog;
+#[cfg(target_has_atomic = "64")]
+pub use lru::AtomicLruCache;
+#[cfg(target_has_atomic = "64")]
+pub use map128::AtomicHashMap128;
+#[cfg(target_has_atomic = "32")]
+pub use map32::AtomicHashMap32;
+pub use mcslock::McsLock;
+#[cfg(all(feature = "metrics", target_has_atomic = "64"))]
+pub use metrics::Metrics;
+#[cfg(target_has_atomic = "64")]
+pub use mpsc::MpscQueue;
+#[cfg(target_has_atomic = "64")]
+pub use multimap::AtomicHashMultiMap;
+pub use optionbox::AtomicOptionBox;
+pub use ordering::OrderingProfile;
+#[cfg(all(feature = "persist", unix, target_has_atomic = "64"))]
+pub use persist::AtomicFileHashMap;
+pub use pod::PodU64;
+#[cfg(target_has_atomic = "64")]
+pub use queue::AtomicQueue;
+#[cfg(target_has_atomic = "64")]
+pub use radix::AtomicRadixTree;
+#[cfg(target_has_atomic = "64")]
+pub use rcu::Rcu;
+#[cfg(all(feature = "std", target_has_atomic = "64"))]
+pub use robinhood::AtomicRobinHoodMap;
+#[cfg(target_has_atomic = "64")]
+pub use rwspinlock::RwSpinLock;
+#[cfg(target_has_atomic = "64")]
+pub use seqlock::SeqLock;
+#[cfg(target_has_atomic = "64")]
+pub use set::AtomicHashSet;
+#[cfg(target_has_atomic = "64")]
+pub use sharded::ShardedAtomicHashMap;
+#[cfg(target_has_atomic = "64")]
+pub use shared::AtomicSharedHashMap;
+#[cfg(target_has_atomic = "64")]
+pub use skiplist::AtomicSkipListMap;
+#[cfg(target_has_atomic = "64")]
+pub use slab::AtomicSlab;
+pub use spinlock::SpinLock;
+#[cfg(target_has_atomic = "64")]
+pub use spsc::SpscRing;
+#[cfg(target_has_atomic = "64")]
+pub use stack::AtomicStack;
+#[cfg(target_has_atomic = "64")]
+pub use staticmap::AtomicStaticHashMap;
+#[cfg(target_has_atomic = "64")]
+pub use tagged::{TaggedAtomicPtr, VersionedAtomicPtr};
+pub use ticketlock::TicketLock;
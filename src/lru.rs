@@ -0,0 +1,170 @@
+//! Fixed-size lock-free cache on top of `AtomicHashMap`, evicting with CLOCK
+//!
+//! Every slot has a CLOCK bit next to its key, set by `get`. A `put` that finds no
+//! room for a new key sweeps the first live entries along the probe of that key and
+//! evicts the first one whose bit is clear. If they were all referenced, their bits
+//! are cleared to give each a second chance and the first of them is evicted. This
+//! approximates LRU without any shared list or global hand, so reads and writes never
+//! contend beyond the slots they touch.
+//!
+//! New entries start with a clear bit, so entries that are never read again are the
+//! first to go, but a new entry can also be evicted by the very next `put` probing
+//! past it. Only entries along the probe of the new key are swept, so the entry
+//! evicted is the least recently used of that neighbourhood rather than of the
+//! whole cache.
+
+use alloc::vec::Vec;
+use core::hash::BuildHasher;
+
+use crate::atomichashmap::{AtomicHashMap, AtomicHashMapError, BuildMurmurHasher};
+use crate::pod::PodU64;
+
+/// Lock-free cache of a fixed number of entries from keys of type `K` to values of
+/// type `V`, evicting approximately least recently used entries to make room
+pub struct AtomicLruCache<K: PodU64 = u64, V: PodU64 = u64, S = BuildMurmurHasher> {
+    map: AtomicHashMap<K, V, S>
+}
+
+impl<K: PodU64, V: PodU64> AtomicLruCache<K, V> {
+    /// Construct a new cache holding up to `size` entries.
+    /// NOTE: Size must be a power of two, otherwise `InvalidCapacity` is returned.
+    pub fn new(size: usize) -> Result<AtomicLruCache<K, V>, AtomicHashMapError> {
+        AtomicLruCache::with_hasher(size, BuildMurmurHasher::default())
+    }
+}
+
+impl<K: PodU64, V: PodU64, S: BuildHasher> AtomicLruCache<K, V, S> {
+    /// Construct a new cache holding up to `size` entries, hashing keys with `hasher`.
+    /// NOTE: Size must be a power of two, otherwise `InvalidCapacity` is returned.
+    pub fn with_hasher(size: usize, hasher: S)
+            -> Result<AtomicLruCache<K, V, S>, AtomicHashMapError> {
+        Ok(AtomicLruCache { map: AtomicHashMap::with_hasher(size, hasher)? })
+    }
+
+    /// Get the value of `key`, marking it as recently used
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.map.get_referenced(key)
+    }
+
+    /// Get the value of `key` without marking it as recently used
+    pub fn peek(&self, key: &K) -> Option<V> {
+        self.map.get(key)
+    }
+
+    /// Check if `key` is cached, without marking it as recently used
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.map.contains_key(key)
+    }
+
+    /// Atomically set a key:value in the cache, evicting another entry if there is no
+    /// room for a new key
+    ///
+    /// Returns the previous value of the key, or `None` if the key was newly cached.
+    /// Only fails with `AtomicHashMapError::InvalidKey` for a sentinel key, or with
+    /// `AtomicHashMapError::Full` if every slot within reach is still being written by
+    /// other threads.
+    pub fn put(&self, key: K, value: V) -> Result<Option<V>, AtomicHashMapError> {
+        loop {
+            match self.map.insert(key, value) {
+                // Room was made for the key, try again
+                Err(AtomicHashMapError::Full) if self.map.evict_unreferenced(key) => {}
+                res => return res
+            }
+        }
+    }
+
+    /// Atomically remove a key from the cache, returning its value if it was present
+    pub fn remove(&self, key: K) -> Option<V> {
+        self.map.remove(key)
+    }
+
+    /// Get a `Vec` of the cached key:value pairs
+    pub fn to_vec(&self) -> Vec<(K, V)> {
+        self.map.to_vec()
+    }
+
+    /// Get the number of cached entries
+    pub fn len(&self) -> u64 {
+        self.map.len()
+    }
+
+    /// Returns true if the cache is empty
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Get the most entries the cache holds
+    pub fn capacity(&self) -> usize {
+        self.map.capacity()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    #[test]
+    fn test_lru() {
+        let cache: AtomicLruCache = AtomicLruCache::new(1 << 4).unwrap();
+        for key in 1..=16 {
+            assert_eq!(cache.put(key, key * 10), Ok(None));
+        }
+        for key in 1..=8 {
+            assert_eq!(cache.get(&key), Some(key * 10));
+        }
+
+        // Entries that were read outlive the ones that weren't
+        for key in 17..=24 {
+            assert_eq!(cache.put(key, key * 10), Ok(None));
+            assert_eq!(cache.len(), 16);
+        }
+        for key in 1..=8 {
+            assert_eq!(cache.peek(&key), Some(key * 10));
+        }
+        assert_eq!((9..=24).filter(|key| cache.contains_key(key)).count(), 8);
+        assert_eq!(cache.peek(&24), Some(240));
+
+        // Once every entry was used, they all get a second chance
+        for key in 1..=16 {
+            cache.get(&key);
+        }
+        for key in 25..=32 {
+            assert_eq!(cache.put(key, key * 10), Ok(None));
+        }
+        assert_eq!(cache.len(), 16);
+
+        assert_eq!(cache.put(1, 11), Ok(Some(10)));
+        assert_eq!(cache.remove(1), Some(11));
+        assert_eq!(cache.put(0, 1), Err(AtomicHashMapError::InvalidKey));
+    }
+
+    #[test]
+    fn test_lru_threads() {
+        let cache: AtomicLruCache = AtomicLruCache::new(1 << 8).unwrap();
+
+        thread::scope(|scope| {
+            for thread in 0..4 {
+                let cache = &cache;
+                scope.spawn(move || {
+                    for x in 1..=5000u64 {
+                        // A hot set of 64 keys shared by every thread, and a stream of
+                        // keys each only read once
+                        let key = match x % 2 {
+                            0 => x / 2 % 64 + 1,
+                            _ => (thread + 1) * 10_000 + x
+                        };
+                        if cache.get(&key).is_none() {
+                            cache.put(key, key).unwrap();
+                        }
+                    }
+                });
+            }
+        });
+
+        assert!(cache.len() <= 1 << 8);
+        let hot = (1..=64).filter(|key| cache.contains_key(key)).count();
+        assert!(hot > 48, "Only {} hot keys cached", hot);
+    }
+}
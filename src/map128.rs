@@ -0,0 +1,677 @@
+//! Lock-free hashmap with `u128` keys stored across two atomic words
+//!
+//! Stable Rust has no 128-bit atomics, so each key is stored as two `AtomicU64`
+//! halves. A per-slot state word says whether the slot is empty, being claimed,
+//! keyed, published or removed, and a generation counter kept next to that state
+//! changes each time the slot is claimed. Readers only compare key halves in keyed
+//! slots and retry if the state word changed while they read them, so they never act
+//! on a key torn between two inserts. Since the state word tracks emptiness, every
+//! `u128` key can be stored; none is reserved as a sentinel.
+//!
+//! Keys can't be compared and swapped in one step, so this map can't run the claim
+//! protocol of the `probe` module over its key words, but it follows the same rules
+//! with its state words. A claim is settled against other copies of the key claimed
+//! at the same time, the copy nearest the start of the probe winning, and a removal
+//! unpublishes a slot with a single compare-exchange of the state word it found the
+//! key under, so it never touches a slot that has since been reused.
+//!
+//! # Memory ordering
+//!
+//! A writer claims a slot with a `SeqCst` compare-exchange of its state word, which
+//! moves it to a new generation. It then issues a `Release` fence, stores both key
+//! halves, and stores the keyed state with `Release`. Readers load the state with
+//! `Acquire` before reading the halves and again after an `Acquire` fence. A reader
+//! that sees the same keyed state twice therefore read both halves from the same
+//! claim. Claims are settled with `SeqCst` loads of the state words, so that of two
+//! claims of the same key at least one sees the other. Values are published by
+//! storing the published state with `Release` once they are written, the same as
+//! `AtomicHashMap` with `OrderingProfile::AcquireRelease`.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::hash::{BuildHasher, Hasher};
+
+use core::sync::atomic::{self, Ordering, AtomicU64, AtomicUsize};
+
+use crate::error::AtomicHashMapError;
+use crate::hasher::BuildMurmurHasher;
+
+/// State of a slot that has never been claimed
+const EMPTY: u64 = 0;
+
+/// State of a slot whose key halves are being written
+const BUSY: u64 = 1;
+
+/// State of a slot holding a key whose value isn't published, either because it is
+/// still being inserted or because it is being removed
+const KEYED: u64 = 2;
+
+/// State of a slot holding a key and its value
+const PUBLISHED: u64 = 3;
+
+/// State of a slot whose entry has been removed
+const TOMBSTONE: u64 = 4;
+
+/// Mask of the state in a state word. The remaining bits hold the generation.
+const STATE_MASK: u64 = 0b111;
+
+/// One slot of the table
+struct Bucket {
+    /// State in the low three bits, generation of the slot above them
+    state: AtomicU64,
+
+    key_lo: AtomicU64,
+    key_hi: AtomicU64,
+    value: AtomicU64
+}
+
+impl Bucket {
+    /// Read the key of the slot if it holds one, loading the state first with
+    /// `order`. Returns the key together with the state word it was read under, or
+    /// `None` if the slot holds no key.
+    fn key(&self, order: Ordering) -> Option<(u128, u64)> {
+        loop {
+            let state = self.state.load(order);
+            match state & STATE_MASK {
+                EMPTY | TOMBSTONE => return None,
+                BUSY => {
+                    // The claimer is writing the key halves
+                    core::hint::spin_loop();
+                    continue;
+                }
+                _ => {}
+            }
+
+            let lo = self.key_lo.load(Ordering::Relaxed);
+            let hi = self.key_hi.load(Ordering::Relaxed);
+
+            atomic::fence(Ordering::Acquire);
+            if self.state.load(Ordering::Relaxed) == state {
+                return Some(((u128::from(hi) << 64) | u128::from(lo), state));
+            }
+        }
+    }
+}
+
+/// Get `state` with its state bits replaced by `kind`, keeping its generation
+fn with_kind(state: u64, kind: u64) -> u64 {
+    (state & !STATE_MASK) | kind
+}
+
+/// Outcome of probing for the slot belonging to a key
+enum Slot {
+    /// The key was already stored at this index, under this state word
+    Found(usize, u64),
+
+    /// The key wasn't in the table and this call claimed this index for it, under
+    /// this state word
+    Claimed(usize, u64)
+}
+
+/// Lock-free hashmap from `u128` keys to `u64` values
+///
+/// Supports the core operations of `AtomicHashMap`. Keys don't need to be spread
+/// over both halves; they are hashed as a whole.
+pub struct AtomicHashMap128<S = BuildMurmurHasher> {
+    buckets: Box<[Bucket]>,
+    size: usize,
+
+    /// Number of keys currently in the table
+    count: AtomicUsize,
+
+    /// Builds the hasher used to find the start of the probe for each key
+    hasher: S
+}
+
+impl AtomicHashMap128 {
+    /// Construct a new AtomicHashMap128 with a given size.
+    /// NOTE: Size must be a power of two, otherwise `InvalidCapacity` is returned.
+    pub fn new(size: usize) -> Result<AtomicHashMap128, AtomicHashMapError> {
+        AtomicHashMap128::with_hasher(size, BuildMurmurHasher::default())
+    }
+}
+
+impl<S: BuildHasher> AtomicHashMap128<S> {
+    /// Construct a new AtomicHashMap128 with a given size, hashing keys with `hasher`.
+    /// NOTE: Size must be a power of two, otherwise `InvalidCapacity` is returned.
+    pub fn with_hasher(size: usize, hasher: S)
+            -> Result<AtomicHashMap128<S>, AtomicHashMapError> {
+        if size < 2 || !size.is_power_of_two() {
+            return Err(AtomicHashMapError::InvalidCapacity);
+        }
+
+        let buckets = (0..size).map(|_| Bucket {
+            state: AtomicU64::new(EMPTY),
+            key_lo: AtomicU64::new(0),
+            key_hi: AtomicU64::new(0),
+            value: AtomicU64::new(0)
+        }).collect();
+
+        Ok(AtomicHashMap128 {
+            buckets,
+            size,
+            count: AtomicUsize::new(0),
+            hasher
+        })
+    }
+
+    /// Get the index of the first slot to probe for `key`
+    fn start_index(&self, key: u128) -> usize {
+        let mut hasher = self.hasher.build_hasher();
+        hasher.write_u128(key);
+        hasher.finish() as usize
+    }
+
+    /// Iterate over the slots `key` may be stored in, in probe order
+    fn probe(&self, key: u128) -> impl Iterator<Item = usize> {
+        let start_index = self.start_index(key);
+        let mask = self.size - 1;
+        (0..self.size).map(move |probe| start_index.wrapping_add(probe) & mask)
+    }
+
+    /// Publish the slot at `index`, claimed under `state` and holding its first value
+    fn publish(&self, index: usize, state: u64) {
+        self.buckets[index].state.store(with_kind(state, PUBLISHED), Ordering::Release);
+    }
+
+    /// Atomically set a key:value in the hashmap
+    ///
+    /// Returns the value previously stored for this key, or `None` if the key was
+    /// newly inserted. A new key becomes visible to other threads together with its
+    /// value.
+    pub fn insert(&self, key: u128, value: u64) -> Result<Option<u64>, AtomicHashMapError> {
+        match self.claim_slot(key)? {
+            Slot::Found(index, _) => {
+                Ok(Some(self.buckets[index].value.swap(value, Ordering::AcqRel)))
+            }
+            Slot::Claimed(index, state) => {
+                self.buckets[index].value.store(value, Ordering::Release);
+                self.publish(index, state);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Get the value for `key`, inserting `default` if the key isn't in the hashmap
+    pub fn get_or_insert(&self, key: u128, default: u64) -> Result<u64, AtomicHashMapError> {
+        loop {
+            match self.claim_slot(key)? {
+                Slot::Found(index, state) => {
+                    let value = self.buckets[index].value.load(Ordering::Acquire);
+                    if self.buckets[index].state.load(Ordering::Acquire) == state {
+                        return Ok(value);
+                    }
+
+                    // The key was removed while reading its value, insert it again
+                }
+                Slot::Claimed(index, state) => {
+                    self.buckets[index].value.store(default, Ordering::Release);
+                    self.publish(index, state);
+                    return Ok(default);
+                }
+            }
+        }
+    }
+
+    /// Atomically add `delta` to the value for `key`, inserting the key with a value of
+    /// `delta` if it isn't in the hashmap yet. The addition wraps around on overflow.
+    ///
+    /// Returns the value before the addition, which is 0 for a newly inserted key.
+    pub fn add_to(&self, key: u128, delta: u64) -> Result<u64, AtomicHashMapError> {
+        match self.claim_slot(key)? {
+            Slot::Found(index, _) => {
+                Ok(self.buckets[index].value.fetch_add(delta, Ordering::AcqRel))
+            }
+            Slot::Claimed(index, state) => {
+                let prev_value = self.buckets[index].value.fetch_add(delta, Ordering::AcqRel);
+                self.publish(index, state);
+                Ok(prev_value)
+            }
+        }
+    }
+
+    /// Find the published slot holding `key`, claiming a tombstone or an empty slot
+    /// for it if it isn't in the table yet. A claimed slot must be published by the
+    /// caller once its value is written.
+    fn claim_slot(&self, key: u128) -> Result<Slot, AtomicHashMapError> {
+        loop {
+            match self.probe_slot(key)? {
+                Slot::Found(index, state) => match self.wait_published(index, state) {
+                    Some(state) => return Ok(Slot::Found(index, state)),
+                    // The key was removed while we waited for it, look for it again
+                    None => continue
+                },
+                slot => return Ok(slot)
+            }
+        }
+    }
+
+    /// Wait for the thread that claimed `index` under the generation of `state` to
+    /// publish it. Returns the published state, or `None` if the key left the slot
+    /// instead.
+    fn wait_published(&self, index: usize, state: u64) -> Option<u64> {
+        let published = with_kind(state, PUBLISHED);
+        let keyed = with_kind(state, KEYED);
+        loop {
+            match self.buckets[index].state.load(Ordering::Acquire) {
+                curr if curr == published => return Some(published),
+                curr if curr == keyed => core::hint::spin_loop(),
+                _ => return None
+            }
+        }
+    }
+
+    /// Find the slot holding `key` or claim one for it, without waiting for a found
+    /// slot to be published
+    fn probe_slot(&self, key: u128) -> Result<Slot, AtomicHashMapError> {
+        'retry: loop {
+            // First tombstone seen along the probe, reused if the key isn't found
+            let mut reuse = None;
+
+            for index in self.probe(key) {
+                let bucket = &self.buckets[index];
+
+                if let Some((curr_key, state)) = bucket.key(Ordering::Acquire) {
+                    if curr_key == key {
+                        return Ok(Slot::Found(index, state));
+                    }
+                    continue;
+                }
+
+                let state = bucket.state.load(Ordering::Acquire);
+                match state & STATE_MASK {
+                    TOMBSTONE => {
+                        if reuse.is_none() {
+                            reuse = Some((index, state));
+                        }
+                        continue;
+                    }
+                    EMPTY => {}
+                    // Claimed since `key()` looked at it, look at it again
+                    _ => continue 'retry
+                }
+
+                // Hit the end of the probe without finding the key. Prefer the earlier
+                // tombstone over this empty slot. If either changed under us, another
+                // thread may have stored this key there, so probe again.
+                let (claim_index, expected) = reuse.unwrap_or((index, state));
+                match self.claim_index(claim_index, expected, key) {
+                    Some(slot) => return Ok(slot),
+                    None => continue 'retry
+                }
+            }
+
+            // No empty slot left, but a tombstone along the way can still be reused
+            match reuse {
+                Some((tomb_index, expected)) => {
+                    if let Some(slot) = self.claim_index(tomb_index, expected, key) {
+                        return Ok(slot);
+                    }
+                }
+                None => return Err(AtomicHashMapError::Full)
+            }
+        }
+    }
+
+    /// Attempt to claim `index` for `key` if its state word is still `expected`,
+    /// writing the key halves under a new generation, and settle the claim against
+    /// copies of the key claimed at the same time
+    fn claim_index(&self, index: usize, expected: u64, key: u128) -> Option<Slot> {
+        let bucket = &self.buckets[index];
+        let generation = (expected & !STATE_MASK).wrapping_add(STATE_MASK + 1);
+
+        // `SeqCst` so that of two claims of the same key, at least one sees the other
+        // in `settle`
+        bucket.state.compare_exchange(expected, generation | BUSY, Ordering::SeqCst,
+                                      Ordering::Acquire).ok()?;
+
+        // Order the key stores after the busy state for readers validating the state
+        atomic::fence(Ordering::Release);
+        bucket.key_lo.store(key as u64, Ordering::Relaxed);
+        bucket.key_hi.store((key >> 64) as u64, Ordering::Relaxed);
+        bucket.state.store(generation | KEYED, Ordering::Release);
+
+        self.count.fetch_add(1, Ordering::Relaxed);
+        Some(self.settle(index, generation | KEYED, key))
+    }
+
+    /// Check the probe of `key`, just claimed at `index` under `state`, for another
+    /// copy of it
+    ///
+    /// A thread that skipped a slot while it held another key may claim a later slot
+    /// for the same key as a thread that claimed the tombstone the other key left.
+    /// The copy nearest the start of the probe wins. A copy before `index` makes us
+    /// back out right away. A copy after it may not have seen ours, so we wait for it
+    /// to either back out or be published, backing out in the latter case. Returns
+    /// the slot `key` ends up in.
+    fn settle(&self, index: usize, state: u64, key: u128) -> Slot {
+        let mut before = true;
+        for other in self.probe(key) {
+            if other == index {
+                before = false;
+                continue;
+            }
+
+            let bucket = &self.buckets[other];
+            let other_state = match bucket.key(Ordering::SeqCst) {
+                Some((curr_key, other_state)) if curr_key == key => other_state,
+                Some(_) => continue,
+                None => {
+                    // Keys are never claimed past an empty slot
+                    let state = bucket.state.load(Ordering::SeqCst);
+                    if !before && state & STATE_MASK == EMPTY {
+                        break;
+                    }
+                    continue;
+                }
+            };
+
+            if before || self.wait_published(other, other_state).is_some() {
+                // Our slot was never published, so it can be released as is
+                self.count.fetch_sub(1, Ordering::Relaxed);
+                self.buckets[index].state.store(with_kind(state, TOMBSTONE),
+                                                Ordering::Release);
+                return Slot::Found(other, other_state);
+            }
+
+            // The other copy backed out
+        }
+
+        Slot::Claimed(index, state)
+    }
+
+    /// Find the slot currently holding `key`, along with the state word it was found
+    /// under
+    fn find_slot(&self, key: u128) -> Option<(usize, u64)> {
+        for index in self.probe(key) {
+            let bucket = &self.buckets[index];
+
+            if let Some((curr_key, state)) = bucket.key(Ordering::Acquire) {
+                if curr_key == key {
+                    return Some((index, state));
+                }
+                continue;
+            }
+
+            if bucket.state.load(Ordering::Acquire) & STATE_MASK == EMPTY {
+                // Keys are never stored past an empty slot, so the key isn't here
+                return None;
+            }
+        }
+
+        None
+    }
+
+    /// Atomically get a value from the hashmap
+    pub fn get(&self, key: &u128) -> Option<u64> {
+        let (index, state) = self.find_slot(*key)?;
+        if state & STATE_MASK != PUBLISHED {
+            // Still being inserted, or being removed
+            return None;
+        }
+
+        let bucket = &self.buckets[index];
+        let value = bucket.value.load(Ordering::Acquire);
+
+        // The slot was removed and possibly reused while reading the value
+        if bucket.state.load(Ordering::Acquire) != state {
+            return None;
+        }
+
+        Some(value)
+    }
+
+    /// Check if a key is in the hashmap
+    pub fn contains_key(&self, key: &u128) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Atomically remove a key from the hashmap, returning its value if it was present
+    pub fn remove(&self, key: u128) -> Option<u64> {
+        let (index, state) = self.find_slot(key)?;
+        if state & STATE_MASK != PUBLISHED {
+            return None;
+        }
+
+        // Only the thread that unpublishes the slot may release it. The generation
+        // changes whenever the slot is claimed, so the state still being the one the
+        // key was found under means the key still owns the slot.
+        let bucket = &self.buckets[index];
+        bucket.state.compare_exchange(state, with_kind(state, KEYED), Ordering::AcqRel,
+                                      Ordering::Acquire).ok()?;
+
+        // Take the value before releasing the slot, same as `AtomicHashMap::remove`
+        let value = bucket.value.swap(0, Ordering::AcqRel);
+        self.count.fetch_sub(1, Ordering::Relaxed);
+        bucket.state.store(with_kind(state, TOMBSTONE), Ordering::Release);
+        Some(value)
+    }
+
+    /// Collect the `(key, value)` pairs currently in the hashmap, in table order
+    pub fn to_vec(&self) -> Vec<(u128, u64)> {
+        self.buckets.iter().filter_map(|bucket| {
+            let (key, state) = bucket.key(Ordering::Acquire)?;
+            if state & STATE_MASK != PUBLISHED {
+                return None;
+            }
+
+            let value = bucket.value.load(Ordering::Acquire);
+
+            // The slot was removed while reading the value
+            if bucket.state.load(Ordering::Acquire) != state {
+                return None;
+            }
+
+            Some((key, value))
+        }).collect()
+    }
+
+    /// Get the number of elements currently in the hashtable
+    pub fn len(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Returns true if the hashtable has no elements
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the total number of slots in the hashtable
+    pub fn capacity(&self) -> usize {
+        self.size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::hash::BuildHasherDefault;
+
+    /// Sends every key down the same probe, so that removing one key opens a tombstone
+    /// in front of inserts of all the others
+    #[derive(Default)]
+    struct Collide;
+
+    impl Hasher for Collide {
+        fn finish(&self) -> u64 {
+            0
+        }
+
+        fn write(&mut self, _bytes: &[u8]) {}
+    }
+
+    #[test]
+    fn test_insert_get_remove() {
+        let hashtable = AtomicHashMap128::new(1 << 4).unwrap();
+        let key = 0xdead_beef_0000_0001_u128 << 64 | 0x1234;
+
+        assert_eq!(hashtable.insert(key, 10), Ok(None));
+        assert_eq!(hashtable.insert(key, 11), Ok(Some(10)));
+        assert_eq!(hashtable.get(&key), Some(11));
+
+        // Keys sharing either half are distinct
+        assert_eq!(hashtable.get(&0x1234), None);
+        assert_eq!(hashtable.get(&(key ^ 1)), None);
+
+        // No key is reserved
+        assert_eq!(hashtable.insert(0, 1), Ok(None));
+        assert_eq!(hashtable.insert(u128::MAX, 2), Ok(None));
+        assert_eq!(hashtable.get_or_insert(0, 5), Ok(1));
+        assert_eq!(hashtable.len(), 3);
+
+        assert_eq!(hashtable.remove(key), Some(11));
+        assert_eq!(hashtable.remove(key), None);
+        assert!(!hashtable.contains_key(&key));
+
+        let mut entries = hashtable.to_vec();
+        entries.sort();
+        assert_eq!(entries, vec![(0, 1), (u128::MAX, 2)]);
+
+        assert!(AtomicHashMap128::new(12).is_err());
+    }
+
+    #[test]
+    fn test_full() {
+        let hashtable = AtomicHashMap128::new(1 << 4).unwrap();
+        for x in 0..16 {
+            assert_eq!(hashtable.insert(x << 64, x as u64), Ok(None));
+        }
+        assert_eq!(hashtable.insert(16 << 64, 16), Err(AtomicHashMapError::Full));
+
+        // Removed slots are reused
+        assert_eq!(hashtable.remove(3 << 64), Some(3));
+        assert_eq!(hashtable.insert(16 << 64, 16), Ok(None));
+        assert_eq!(hashtable.get(&(16 << 64)), Some(16));
+    }
+
+    #[test]
+    fn test_no_torn_keys() {
+        use std::sync::Arc;
+        use std::sync::atomic::AtomicBool;
+        use std::thread;
+
+        // Keys whose halves are equal. A torn read would surface a key whose halves
+        // differ.
+        let hashtable = Arc::new(AtomicHashMap128::new(1 << 4).unwrap());
+        let done = Arc::new(AtomicBool::new(false));
+
+        let writers: Vec<_> = (0..4u64).map(|t| {
+            let hashtable = hashtable.clone();
+            thread::spawn(move || {
+                for i in 0..20_000u64 {
+                    let half = u128::from(t * 4 + i % 4);
+                    let key = half << 64 | half;
+                    hashtable.insert(key, 1).unwrap();
+                    hashtable.remove(key);
+                }
+            })
+        }).collect();
+
+        let reader = {
+            let hashtable = hashtable.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                while !done.load(Ordering::Relaxed) {
+                    for (key, _) in hashtable.to_vec() {
+                        assert_eq!(key >> 64, key & u128::from(u64::MAX));
+                    }
+                }
+            })
+        };
+
+        for t in writers {
+            t.join().unwrap();
+        }
+        done.store(true, Ordering::Relaxed);
+        reader.join().unwrap();
+
+        assert!(hashtable.is_empty());
+    }
+
+    #[test]
+    fn test_threads() {
+        use std::sync::Arc;
+        use std::thread;
+
+        let hashtable = Arc::new(AtomicHashMap128::new(1 << 10).unwrap());
+
+        let threads: Vec<_> = (0..8).map(|_| {
+            let hashtable = hashtable.clone();
+            thread::spawn(move || {
+                for x in 0..512u128 {
+                    hashtable.add_to(x << 64 | x, 1).unwrap();
+                }
+            })
+        }).collect();
+
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        assert_eq!(hashtable.len(), 512);
+        for x in 0..512u128 {
+            assert_eq!(hashtable.get(&(x << 64 | x)), Some(8));
+        }
+    }
+
+    #[test]
+    fn test_threads_no_duplicates() {
+        use std::thread;
+
+        let hashtable = AtomicHashMap128::with_hasher(1 << 6,
+            BuildHasherDefault::<Collide>::default()).unwrap();
+
+        for round in 0..200 {
+            for x in 100..116u128 {
+                hashtable.insert(x << 64, round).unwrap();
+            }
+
+            // Two threads insert the same keys while a third removes the keys in front
+            // of them, and each key still ends up in a single slot
+            thread::scope(|scope| {
+                for _ in 0..2 {
+                    scope.spawn(|| {
+                        for x in 1..=8u128 {
+                            hashtable.insert(x << 64, round).unwrap();
+                        }
+                    });
+                }
+
+                scope.spawn(|| {
+                    for x in 100..116u128 {
+                        assert_eq!(hashtable.remove(x << 64), Some(round));
+                    }
+                });
+            });
+
+            let entries = hashtable.to_vec();
+            assert_eq!(entries.len(), 8);
+            assert_eq!(hashtable.len(), 8);
+            for x in 1..=8u128 {
+                assert_eq!(entries.iter().filter(|&&(key, _)| key == x << 64).count(), 1);
+                assert_eq!(hashtable.remove(x << 64), Some(round));
+                assert_eq!(hashtable.remove(x << 64), None);
+            }
+        }
+    }
+
+    #[test]
+    fn test_remove_reused_slot() {
+        let hashtable = AtomicHashMap128::with_hasher(1 << 4,
+            BuildHasherDefault::<Collide>::default()).unwrap();
+        assert_eq!(hashtable.insert(1, 10), Ok(None));
+        let (index, state) = hashtable.find_slot(1).unwrap();
+        assert_eq!(hashtable.remove(1), Some(10));
+        assert_eq!(hashtable.insert(2, 20), Ok(None));
+
+        // A removal of the old key that found the slot before it changed hands can't
+        // unpublish the new key, since the slot moved to a new generation
+        let (reused, reused_state) = hashtable.find_slot(2).unwrap();
+        assert_eq!(reused, index);
+        assert_eq!(reused_state & STATE_MASK, PUBLISHED);
+        assert_ne!(reused_state, state);
+        assert!(hashtable.buckets[index].state.compare_exchange(state,
+            with_kind(state, KEYED), Ordering::AcqRel, Ordering::Acquire).is_err());
+        assert_eq!(hashtable.get(&2), Some(20));
+    }
+}
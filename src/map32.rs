@@ -0,0 +1,462 @@
+//! 32-bit variant of `AtomicHashMap` for targets without 64-bit atomics
+//!
+//! Keys and values are `u32` and every slot is made of 32-bit atomics, so the map is
+//! available wherever `AtomicU32` is, e.g. ARMv7 and other 32-bit embedded targets.
+//! Entries are claimed, published and removed with the same protocol as
+//! `AtomicHashMap`, and keys are hashed with the same hasher, but slots are probed one
+//! at a time rather than in groups.
+//!
+//! # Memory ordering
+//!
+//! Same as `AtomicHashMap` with the default `OrderingProfile::AcquireRelease`: keys
+//! are claimed, published and removed through the state word of their slot exactly
+//! as in `AtomicHashMap`, values are written with `Release` and read with
+//! `Acquire`, and a key is only considered in the map once its slot is published.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::hash::{BuildHasher, Hasher};
+
+use core::sync::atomic::{Ordering, AtomicU32};
+
+use crate::error::AtomicHashMapError;
+use crate::hasher::BuildMurmurHasher;
+use crate::probe::{self, Linear, Slot, Table};
+
+/// Default key marker for a slot that has never been claimed
+const EMPTY_KEY: u32 = 0;
+
+/// Default key marker for a slot whose entry has been removed
+const TOMBSTONE_KEY: u32 = u32::MAX;
+
+/// One slot of the table
+struct Bucket {
+    key: AtomicU32,
+    value: AtomicU32,
+
+    /// `PUBLISHED` once the first value of a claimed slot has been written, and the
+    /// number of times the slot was unpublished above its state bits
+    state: AtomicU32
+}
+
+/// Lock-free hashmap from `u32` keys to `u32` values built on 32-bit atomics
+///
+/// Supports the core operations of `AtomicHashMap`. Keys of 0 and `u32::MAX` mark
+/// empty and removed slots unless other sentinels are given.
+pub struct AtomicHashMap32<S = BuildMurmurHasher> {
+    buckets: Box<[Bucket]>,
+    size: usize,
+
+    /// Number of keys currently in the table
+    count: AtomicU32,
+
+    /// Key marking a slot that has never been claimed
+    empty_key: u32,
+
+    /// Key marking a slot whose entry has been removed
+    tombstone_key: u32,
+
+    /// Builds the hasher used to find the start of the probe for each key
+    hasher: S
+}
+
+impl AtomicHashMap32 {
+    /// Construct a new AtomicHashMap32 with a given size.
+    /// NOTE: Size must be a power of two, otherwise `InvalidCapacity` is returned.
+    pub fn new(size: usize) -> Result<AtomicHashMap32, AtomicHashMapError> {
+        AtomicHashMap32::with_hasher(size, BuildMurmurHasher::default())
+    }
+
+    /// Construct a new AtomicHashMap32 with a given size, using `empty_key` and
+    /// `tombstone_key` to mark empty and removed slots instead of 0 and `u32::MAX`.
+    /// NOTE: Size must be a power of two, otherwise `InvalidCapacity` is returned.
+    pub fn with_sentinels(size: usize, empty_key: u32, tombstone_key: u32)
+            -> Result<AtomicHashMap32, AtomicHashMapError> {
+        AtomicHashMap32::with_hasher_and_sentinels(size, BuildMurmurHasher::default(),
+                                                   empty_key, tombstone_key)
+    }
+}
+
+impl<S: BuildHasher> AtomicHashMap32<S> {
+    /// Construct a new AtomicHashMap32 with a given size, hashing keys with `hasher`.
+    /// NOTE: Size must be a power of two, otherwise `InvalidCapacity` is returned.
+    pub fn with_hasher(size: usize, hasher: S)
+            -> Result<AtomicHashMap32<S>, AtomicHashMapError> {
+        AtomicHashMap32::with_hasher_and_sentinels(size, hasher, EMPTY_KEY, TOMBSTONE_KEY)
+    }
+
+    /// Construct a new AtomicHashMap32 with a given size, hashing keys with `hasher`
+    /// and marking empty and removed slots with `empty_key` and `tombstone_key`.
+    /// NOTE: Size must be a power of two, otherwise `InvalidCapacity` is returned.
+    pub fn with_hasher_and_sentinels(size: usize, hasher: S, empty_key: u32,
+                                     tombstone_key: u32)
+            -> Result<AtomicHashMap32<S>, AtomicHashMapError> {
+        if empty_key == tombstone_key {
+            return Err(AtomicHashMapError::InvalidKey);
+        }
+
+        if size < 2 || !size.is_power_of_two() {
+            return Err(AtomicHashMapError::InvalidCapacity);
+        }
+
+        let buckets = (0..size).map(|_| Bucket {
+            key: AtomicU32::new(empty_key),
+            value: AtomicU32::new(0),
+            state: AtomicU32::new(0)
+        }).collect();
+
+        Ok(AtomicHashMap32 {
+            buckets,
+            size,
+            count: AtomicU32::new(0),
+            empty_key,
+            tombstone_key,
+            hasher
+        })
+    }
+
+    /// Returns true if the key read from a slot is a stored key rather than one of the
+    /// sentinels
+    fn is_live(&self, key: u32) -> bool {
+        key != self.empty_key && key != self.tombstone_key
+    }
+
+    /// Check that `key` isn't a sentinel
+    fn check_key(&self, key: u32) -> Result<u32, AtomicHashMapError> {
+        if !self.is_live(key) {
+            return Err(AtomicHashMapError::InvalidKey);
+        }
+
+        Ok(key)
+    }
+
+    /// Get the hash picking the first slot to probe for `key`
+    fn hash(&self, key: u32) -> u64 {
+        let mut hasher = self.hasher.build_hasher();
+        hasher.write_u64(u64::from(key));
+        hasher.finish()
+    }
+
+    /// Atomically set a key:value in the hashmap
+    ///
+    /// Returns the value previously stored for this key, or `None` if the key was
+    /// newly inserted. A new key becomes visible to other threads together with its
+    /// value.
+    pub fn insert(&self, key: u32, value: u32) -> Result<Option<u32>, AtomicHashMapError> {
+        let key = self.check_key(key)?;
+
+        match self.claim_slot(key)? {
+            Slot::Found(index) => {
+                Ok(Some(self.buckets[index].value.swap(value, Ordering::AcqRel)))
+            }
+            Slot::Claimed(index) => {
+                self.buckets[index].value.store(value, Ordering::Release);
+                probe::publish(self, index);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Get the value for `key`, inserting `default` if the key isn't in the hashmap
+    pub fn get_or_insert(&self, key: u32, default: u32) -> Result<u32, AtomicHashMapError> {
+        let key = self.check_key(key)?;
+
+        match self.claim_slot(key)? {
+            Slot::Found(index) => {
+                match self.read_value(index, key) {
+                    Some(value) => Ok(value),
+                    // Removed since it was found, look for the key again
+                    None => self.get_or_insert(key, default)
+                }
+            }
+            Slot::Claimed(index) => {
+                self.buckets[index].value.store(default, Ordering::Release);
+                probe::publish(self, index);
+                Ok(default)
+            }
+        }
+    }
+
+    /// Atomically add `delta` to the value for `key`, inserting the key with a value of
+    /// `delta` if it isn't in the hashmap yet. The addition wraps around on overflow.
+    ///
+    /// Returns the value before the addition, which is 0 for a newly inserted key.
+    pub fn add_to(&self, key: u32, delta: u32) -> Result<u32, AtomicHashMapError> {
+        let key = self.check_key(key)?;
+
+        match self.claim_slot(key)? {
+            Slot::Found(index) => {
+                Ok(self.buckets[index].value.fetch_add(delta, Ordering::AcqRel))
+            }
+            Slot::Claimed(index) => {
+                let prev_value = self.buckets[index].value.fetch_add(delta, Ordering::AcqRel);
+                probe::publish(self, index);
+                Ok(prev_value)
+            }
+        }
+    }
+
+    /// Find the published slot holding `key`, claiming a tombstone or an empty slot
+    /// for it if it isn't in the table yet. A claimed slot must be published by the
+    /// caller once its value is written.
+    fn claim_slot(&self, key: u32) -> Result<Slot, AtomicHashMapError> {
+        probe::claim_slot(self, u64::from(key), self.hash(key))
+    }
+
+    /// Find the slot currently holding `key`
+    fn find_slot(&self, key: u32) -> Option<usize> {
+        probe::find_slot(self, u64::from(key), self.hash(key))
+    }
+
+    /// Read the value of the published slot at `index` holding `key`. Returns `None`
+    /// if the entry was removed while reading it, since the value read may then be the
+    /// reset done by the removal.
+    fn read_value(&self, index: usize, key: u32) -> Option<u32> {
+        let value = self.buckets[index].value.load(Ordering::Acquire);
+        if !probe::is_published(self, index)
+                || self.buckets[index].key.load(Ordering::Acquire) != key {
+            return None;
+        }
+
+        Some(value)
+    }
+
+    /// Atomically get a value from the hashmap
+    pub fn get(&self, key: &u32) -> Option<u32> {
+        let key = self.check_key(*key).ok()?;
+
+        let index = self.find_slot(key)?;
+        self.read_value(index, key)
+    }
+
+    /// Check if a key is in the hashmap
+    pub fn contains_key(&self, key: &u32) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Atomically remove a key from the hashmap, returning its value if it was present
+    pub fn remove(&self, key: u32) -> Option<u32> {
+        let key = self.check_key(key).ok()?;
+
+        let index = self.find_slot(key)?;
+
+        // Only the thread that unpublishes the slot may release it
+        if !probe::unpublish(self, index, u64::from(key)) {
+            return None;
+        }
+
+        // Take the value before releasing the key, same as `AtomicHashMap::remove`
+        let value = self.buckets[index].value.swap(0, Ordering::AcqRel);
+        probe::release(self, index, u64::from(key));
+        Some(value)
+    }
+
+    /// Collect the `(key, value)` pairs currently in the hashmap, in table order
+    pub fn to_vec(&self) -> Vec<(u32, u32)> {
+        (0..self.size).filter_map(|index| {
+            let key = self.buckets[index].key.load(Ordering::Acquire);
+            if !self.is_live(key) || !probe::is_published(self, index) {
+                return None;
+            }
+
+            Some((key, self.read_value(index, key)?))
+        }).collect()
+    }
+
+    /// Get the number of elements currently in the hashtable
+    pub fn len(&self) -> u32 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Returns true if the hashtable has no elements
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the total number of slots in the hashtable
+    pub fn capacity(&self) -> usize {
+        self.size
+    }
+}
+
+impl<S> Table for AtomicHashMap32<S> {
+    type Key = AtomicU32;
+    type State = AtomicU32;
+    type Probe<'a> = Linear where Self: 'a;
+
+    #[inline]
+    fn key_word(&self, index: usize) -> &AtomicU32 {
+        &self.buckets[index].key
+    }
+
+    #[inline]
+    fn state_word(&self, index: usize) -> &AtomicU32 {
+        &self.buckets[index].state
+    }
+
+    fn raw_sentinels(&self) -> (u64, u64) {
+        (u64::from(self.empty_key), u64::from(self.tombstone_key))
+    }
+
+    fn probe_slots(&self, hash: u64, _all: bool) -> Linear {
+        Linear::new(hash, self.size)
+    }
+
+    fn claimed(&self, _index: usize, _hash: u64) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn released(&self, _index: usize) {
+        self.count.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::hash::BuildHasherDefault;
+
+    /// Sends every key down the same probe, so that removing one key opens a tombstone
+    /// in front of inserts of all the others
+    #[derive(Default)]
+    struct Collide;
+
+    impl Hasher for Collide {
+        fn finish(&self) -> u64 {
+            0
+        }
+
+        fn write(&mut self, _bytes: &[u8]) {}
+    }
+
+    #[test]
+    fn test_insert_get_remove() {
+        let hashtable = AtomicHashMap32::new(1 << 4).unwrap();
+        assert_eq!(hashtable.insert(1, 10), Ok(None));
+        assert_eq!(hashtable.insert(1, 11), Ok(Some(10)));
+        assert_eq!(hashtable.get(&1), Some(11));
+        assert_eq!(hashtable.get_or_insert(1, 5), Ok(11));
+        assert_eq!(hashtable.get_or_insert(2, 5), Ok(5));
+        assert_eq!(hashtable.len(), 2);
+
+        assert_eq!(hashtable.insert(0, 1), Err(AtomicHashMapError::InvalidKey));
+        assert_eq!(hashtable.insert(u32::MAX, 1), Err(AtomicHashMapError::InvalidKey));
+
+        assert_eq!(hashtable.remove(1), Some(11));
+        assert_eq!(hashtable.remove(1), None);
+        assert!(!hashtable.contains_key(&1));
+
+        let mut entries = hashtable.to_vec();
+        entries.sort();
+        assert_eq!(entries, vec![(2, 5)]);
+
+        assert!(AtomicHashMap32::new(12).is_err());
+        assert!(AtomicHashMap32::with_sentinels(1 << 4, 7, 7).is_err());
+    }
+
+    #[test]
+    fn test_sentinels() {
+        let hashtable = AtomicHashMap32::with_sentinels(1 << 4, 7, 8).unwrap();
+        assert_eq!(hashtable.insert(0, 1), Ok(None));
+        assert_eq!(hashtable.insert(7, 1), Err(AtomicHashMapError::InvalidKey));
+        assert_eq!(hashtable.get(&0), Some(1));
+    }
+
+    #[test]
+    fn test_full() {
+        let hashtable = AtomicHashMap32::new(1 << 4).unwrap();
+        for x in 1..=16 {
+            assert_eq!(hashtable.insert(x, x), Ok(None));
+        }
+        assert_eq!(hashtable.insert(17, 17), Err(AtomicHashMapError::Full));
+
+        // Removed slots are reused
+        assert_eq!(hashtable.remove(3), Some(3));
+        assert_eq!(hashtable.insert(17, 17), Ok(None));
+        assert_eq!(hashtable.get(&17), Some(17));
+    }
+
+    #[test]
+    fn test_threads() {
+        use std::sync::Arc;
+        use std::thread;
+
+        let hashtable = Arc::new(AtomicHashMap32::new(1 << 10).unwrap());
+
+        let threads: Vec<_> = (0..8).map(|_| {
+            let hashtable = hashtable.clone();
+            thread::spawn(move || {
+                for x in 1..=512 {
+                    hashtable.add_to(x, 1).unwrap();
+                }
+            })
+        }).collect();
+
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        assert_eq!(hashtable.len(), 512);
+        for x in 1..=512 {
+            assert_eq!(hashtable.get(&x), Some(8));
+        }
+    }
+
+    #[test]
+    fn test_threads_no_duplicates() {
+        use std::thread;
+
+        let hashtable = AtomicHashMap32::with_hasher(1 << 6,
+            BuildHasherDefault::<Collide>::default()).unwrap();
+
+        for round in 0..200 {
+            for x in 100..116 {
+                hashtable.insert(x, round).unwrap();
+            }
+
+            // Two threads insert the same keys while a third removes the keys in front
+            // of them, and each key still ends up in a single slot
+            thread::scope(|scope| {
+                for _ in 0..2 {
+                    scope.spawn(|| {
+                        for x in 1..=8 {
+                            hashtable.insert(x, round).unwrap();
+                        }
+                    });
+                }
+
+                scope.spawn(|| {
+                    for x in 100..116 {
+                        assert_eq!(hashtable.remove(x), Some(round));
+                    }
+                });
+            });
+
+            let entries = hashtable.to_vec();
+            assert_eq!(entries.len(), 8);
+            assert_eq!(hashtable.len(), 8);
+            for x in 1..=8 {
+                assert_eq!(entries.iter().filter(|&&(key, _)| key == x).count(), 1);
+                assert_eq!(hashtable.remove(x), Some(round));
+                assert_eq!(hashtable.remove(x), None);
+            }
+        }
+    }
+
+    #[test]
+    fn test_remove_reused_slot() {
+        let hashtable = AtomicHashMap32::with_hasher(1 << 4,
+            BuildHasherDefault::<Collide>::default()).unwrap();
+        assert_eq!(hashtable.insert(1, 10), Ok(None));
+        let index = hashtable.find_slot(1).unwrap();
+        assert_eq!(hashtable.remove(1), Some(10));
+        assert_eq!(hashtable.insert(2, 20), Ok(None));
+
+        // A removal of the old key that found the slot before it changed hands leaves
+        // the new key alone
+        assert_eq!(hashtable.find_slot(2), Some(index));
+        assert!(!probe::unpublish(&hashtable, index, 1));
+        assert!(probe::is_published(&hashtable, index));
+        assert_eq!(hashtable.get(&2), Some(20));
+    }
+}
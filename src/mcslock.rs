@@ -0,0 +1,260 @@
+//! Fair queue lock of Mellor-Crummey and Scott
+//!
+//! Waiters for an MCS lock form a linked queue of nodes, one per waiter, kept on the
+//! stack of the waiting thread. A thread appends its node by swapping it into the tail of
+//! the queue, links it behind the node it replaced, and then spins on a flag in its own
+//! node until the thread ahead of it hands the lock over by clearing that flag. The lock
+//! is handed out in arrival order like a `TicketLock`, but each waiter spins on a cache
+//! line nobody else writes to until its turn, so a release costs one cache miss whatever
+//! the number of waiters. That matters on machines with many cores, where a `SpinLock`
+//! can starve threads and a `TicketLock` has every waiter reload the shared counter on
+//! every release.
+//!
+//! A holder with no successor releases the lock by swinging the tail from its own
+//! node back to null. If that fails, a thread has swapped itself in but not linked
+//! its node yet, and the holder waits for the link before handing the lock over.
+//!
+//! The handover is a `Release` store of the successor's flag and the successor sees
+//! it with an `Acquire` load, and an uncontended release and lock go through the tail
+//! with `Release` and `Acquire`, so each holder sees the writes of the previous one.
+//!
+//! Waiting threads back off like `SpinLock` waiters, yielding their time slice with
+//! `std` once they have backed off for a while.
+//!
+//! Other threads write to a node for as long as it is queued, so the lock is only
+//! held for the duration of a closure, and the node lives in the frame of the call
+//! running it. A guard that could be leaked would let the node be freed while the
+//! tail still points at it.
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::ptr;
+
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+use crate::backoff::Backoff;
+
+/// Place in the queue of an `McsLock`, owned by the thread waiting on or holding it
+struct McsNode {
+    /// Node of the thread that queued up next, null until it links itself
+    next: AtomicPtr<McsNode>,
+
+    /// Set while the thread owning the node has to wait
+    waiting: AtomicBool
+}
+
+impl McsNode {
+    /// Construct a node for a thread to queue up with
+    const fn new() -> McsNode {
+        McsNode { next: AtomicPtr::new(ptr::null_mut()), waiting: AtomicBool::new(false) }
+    }
+}
+
+/// Fair queue lock protecting a value of type `T`
+pub struct McsLock<T> {
+    /// Node of the last thread in the queue, null when the lock is free
+    tail: AtomicPtr<McsNode>,
+
+    value: UnsafeCell<T>
+}
+
+// SAFETY: The value is only reached through a guard, of which there is at most one
+unsafe impl<T: Send> Send for McsLock<T> {}
+unsafe impl<T: Send> Sync for McsLock<T> {}
+
+impl<T> McsLock<T> {
+    /// Construct an unlocked lock holding `value`
+    pub const fn new(value: T) -> McsLock<T> {
+        McsLock { tail: AtomicPtr::new(ptr::null_mut()), value: UnsafeCell::new(value) }
+    }
+
+    /// Acquire the lock behind the threads that asked for it first and run `f` on the
+    /// value, releasing the lock when `f` returns or panics
+    pub fn lock_with<R, F: FnOnce(&mut T) -> R>(&self, f: F) -> R {
+        let node = McsNode::new();
+        let mut guard = self.lock(&node);
+        f(&mut guard)
+    }
+
+    /// Run `f` on the value if the lock is free and nobody is waiting for it, without
+    /// waiting. Returns `None` if the lock is held.
+    pub fn try_lock_with<R, F: FnOnce(&mut T) -> R>(&self, f: F) -> Option<R> {
+        let node = McsNode::new();
+        let mut guard = self.try_lock(&node)?;
+        Some(f(&mut guard))
+    }
+
+    /// Acquire the lock, queueing up with `node` behind the threads that asked for it
+    /// first. The node must stay in place until the guard is dropped.
+    fn lock<'a>(&'a self, node: &'a McsNode) -> McsLockGuard<'a, T> {
+        // Other threads only touch the node through its atomics from now on
+        let ptr = node as *const McsNode as *mut McsNode;
+        node.waiting.store(true, Ordering::Relaxed);
+
+        // Release the new node to the thread ahead, acquire the value from the
+        // previous holder if there is none
+        let pred = self.tail.swap(ptr, Ordering::AcqRel);
+        if !pred.is_null() {
+            // SAFETY: The thread ahead keeps its node in place until it has handed the
+            // lock over, which it can't do before we link ourselves
+            unsafe { (*pred).next.store(ptr, Ordering::Release); }
+
+            let mut backoff = Backoff::new();
+            while node.waiting.load(Ordering::Acquire) {
+                backoff.snooze();
+            }
+        }
+
+        McsLockGuard { lock: self, node }
+    }
+
+    /// Acquire the lock with `node` if it is free and nobody is waiting for it,
+    /// without waiting
+    fn try_lock<'a>(&'a self, node: &'a McsNode) -> Option<McsLockGuard<'a, T>> {
+        let ptr = node as *const McsNode as *mut McsNode;
+        self.tail.compare_exchange(ptr::null_mut(), ptr, Ordering::AcqRel,
+                                   Ordering::Relaxed).ok()?;
+
+        Some(McsLockGuard { lock: self, node })
+    }
+
+    /// Returns true if the lock is held. Only a hint while other threads lock and
+    /// unlock it.
+    pub fn is_locked(&self) -> bool {
+        !self.tail.load(Ordering::Relaxed).is_null()
+    }
+
+    /// Get a mutable reference to the value, which no other thread can access
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    /// Take the value out of the lock
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: Default> Default for McsLock<T> {
+    fn default() -> McsLock<T> {
+        McsLock::new(T::default())
+    }
+}
+
+/// Proof that an `McsLock` is held, giving access to its value and handing the lock
+/// to the next thread in the queue when dropped. Never leaves the call that created
+/// its node.
+struct McsLockGuard<'a, T> {
+    lock: &'a McsLock<T>,
+
+    /// Node the lock was acquired with
+    node: &'a McsNode
+}
+
+impl<'a, T> Deref for McsLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: The lock is held by this guard
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<'a, T> DerefMut for McsLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: The lock is held by this guard
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<'a, T> Drop for McsLockGuard<'a, T> {
+    fn drop(&mut self) {
+        let node = self.node as *const McsNode as *mut McsNode;
+        let mut next = self.node.next.load(Ordering::Acquire);
+        if next.is_null() {
+            // Nobody queued up behind us, free the lock
+            let res = self.lock.tail.compare_exchange(node, ptr::null_mut(),
+                                                      Ordering::Release,
+                                                      Ordering::Relaxed);
+            if res.is_ok() {
+                return;
+            }
+
+            // A thread swapped itself in, wait for it to link its node
+            let mut backoff = Backoff::new();
+            loop {
+                next = self.node.next.load(Ordering::Acquire);
+                if !next.is_null() {
+                    break;
+                }
+
+                backoff.snooze();
+            }
+        }
+
+        // SAFETY: The next thread keeps its node in place while it waits
+        unsafe { (*next).waiting.store(false, Ordering::Release); }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    #[test]
+    fn test_mcslock() {
+        let mut lock = McsLock::new(vec![1]);
+        lock.lock_with(|value| {
+            assert!(lock.is_locked());
+            assert_eq!(lock.try_lock_with(|_| ()), None);
+            value.push(2);
+        });
+
+        assert!(!lock.is_locked());
+        assert_eq!(lock.try_lock_with(|value| value.push(3)), Some(()));
+        assert_eq!(lock.lock_with(|value| value.len()), 3);
+        lock.get_mut().push(4);
+        assert_eq!(lock.into_inner(), vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_mcslock_panic() {
+        let lock = McsLock::new(0);
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            lock.lock_with(|value| {
+                *value += 1;
+                panic!("Critical section failed");
+            })
+        }));
+        assert!(res.is_err());
+
+        // The lock was handed back while unwinding
+        assert!(!lock.is_locked());
+        assert_eq!(lock.lock_with(|value| *value), 1);
+    }
+
+    #[test]
+    fn test_mcslock_threads() {
+        let lock = McsLock::new((0u64, 0u64));
+
+        // Both halves are updated under the lock, so they always match
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                let lock = &lock;
+                scope.spawn(move || {
+                    for _ in 0..10_000 {
+                        lock.lock_with(|value| {
+                            assert_eq!(value.0, value.1);
+                            value.0 += 1;
+                            value.1 += 1;
+                        });
+                    }
+                });
+            }
+        });
+
+        assert_eq!(lock.into_inner(), (40_000, 40_000));
+    }
+}
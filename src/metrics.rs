@@ -0,0 +1,137 @@
+//! Counters of probe lengths and lost races kept by every `AtomicHashMap` built with
+//! the `metrics` feature
+//!
+//! Without the feature the counters are zero-sized and every update compiles away.
+//! With it, each map has its own counters, updated with `Relaxed` read-modify-writes
+//! of a handful of shared words. That contends between threads, so an instrumented
+//! map is slower than a plain one, but the counts show where the time goes: a mean
+//! probe length climbing with the load factor, or CAS failures piling up on a few
+//! hot keys.
+
+#[cfg(feature = "metrics")]
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters of an instrumented `AtomicHashMap`, read with `AtomicHashMap::metrics`
+#[cfg(feature = "metrics")]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Metrics {
+    /// Number of probes for a key, by lookups and inserts alike
+    pub probes: u64,
+
+    /// Number of slots checked against a key over all probes. Slots whose tag rules
+    /// the key out are skipped without being checked and don't count.
+    pub probe_steps: u64,
+
+    /// Number of compare-exchanges of a key or a value lost to another thread
+    pub cas_failures: u64,
+
+    /// Number of inserts that returned `AtomicHashMapError::Full`
+    pub full_errors: u64
+}
+
+#[cfg(feature = "metrics")]
+impl Metrics {
+    /// Get the mean number of slots checked per probe
+    pub fn mean_probe_length(&self) -> f64 {
+        if self.probes == 0 {
+            return 0.0;
+        }
+
+        self.probe_steps as f64 / self.probes as f64
+    }
+}
+
+/// Live counters of a map
+#[cfg(feature = "metrics")]
+#[derive(Default)]
+pub(crate) struct Counters {
+    probes: AtomicU64,
+    probe_steps: AtomicU64,
+    cas_failures: AtomicU64,
+    full_errors: AtomicU64
+}
+
+/// Live counters of a map, all no-ops without the `metrics` feature
+#[cfg(not(feature = "metrics"))]
+#[derive(Default)]
+pub(crate) struct Counters {}
+
+/// Slots checked by one probe, added to the counters of its map when dropped
+pub(crate) struct Probe<'a> {
+    #[cfg(feature = "metrics")]
+    counters: &'a Counters,
+
+    #[cfg(feature = "metrics")]
+    steps: u64,
+
+    #[cfg(not(feature = "metrics"))]
+    _counters: core::marker::PhantomData<&'a Counters>
+}
+
+impl Counters {
+    /// Start counting the slots checked by a probe
+    #[inline]
+    pub(crate) fn probe(&self) -> Probe<'_> {
+        Probe {
+            #[cfg(feature = "metrics")]
+            counters: self,
+            #[cfg(feature = "metrics")]
+            steps: 0,
+            #[cfg(not(feature = "metrics"))]
+            _counters: core::marker::PhantomData
+        }
+    }
+
+    /// Count a compare-exchange lost to another thread
+    #[inline]
+    pub(crate) fn cas_failure(&self) {
+        #[cfg(feature = "metrics")]
+        self.cas_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count an insert that found no room for its key
+    #[inline]
+    pub(crate) fn full_error(&self) {
+        #[cfg(feature = "metrics")]
+        self.full_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Read the counters
+    #[cfg(feature = "metrics")]
+    pub(crate) fn snapshot(&self) -> Metrics {
+        Metrics {
+            probes: self.probes.load(Ordering::Relaxed),
+            probe_steps: self.probe_steps.load(Ordering::Relaxed),
+            cas_failures: self.cas_failures.load(Ordering::Relaxed),
+            full_errors: self.full_errors.load(Ordering::Relaxed)
+        }
+    }
+
+    /// Reset every counter to 0
+    #[cfg(feature = "metrics")]
+    pub(crate) fn reset(&self) {
+        self.probes.store(0, Ordering::Relaxed);
+        self.probe_steps.store(0, Ordering::Relaxed);
+        self.cas_failures.store(0, Ordering::Relaxed);
+        self.full_errors.store(0, Ordering::Relaxed);
+    }
+}
+
+impl<'a> Probe<'a> {
+    /// Count one more slot checked
+    #[inline]
+    pub(crate) fn step(&mut self) {
+        #[cfg(feature = "metrics")]
+        {
+            self.steps += 1;
+        }
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl<'a> Drop for Probe<'a> {
+    fn drop(&mut self) {
+        self.counters.probes.fetch_add(1, Ordering::Relaxed);
+        self.counters.probe_steps.fetch_add(self.steps, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,230 @@
+//! Bounded multi-producer single-consumer queue with wait-free pushes
+//!
+//! A push reserves room with a `fetch_add` on the number of values in the queue,
+//! backing out if that makes too many, then takes a ticket with a `fetch_add` on the
+//! tail, writes its value into the slot of the ticket and flags the slot as ready
+//! with `Release`. Neither step retries, so a push finishes in a bounded number of
+//! steps whatever other threads do.
+//!
+//! The slot of a ticket is always free by the time the ticket is taken. The consumer
+//! pops tickets in order and only gives a value's room back once its slot is
+//! cleared, so a ticket one lap after a slot that is still in use would make one
+//! more reservation than the queue has room for.
+//!
+//! Pops take values in ticket order. A push that took its ticket but hasn't flagged
+//! its slot yet holds up the values pushed after it, which `pop` reports as empty
+//! until that push completes.
+
+use alloc::boxed::Box;
+use core::marker::PhantomData;
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use crate::error::AtomicHashMapError;
+use crate::pod::PodU64;
+
+/// Counter on a cache line of its own
+#[repr(align(64))]
+struct Padded(AtomicUsize);
+
+/// One slot of the ring
+struct Slot {
+    value: AtomicU64,
+
+    /// Set once `value` is written, cleared by the consumer once it is read
+    ready: AtomicBool
+}
+
+/// Bounded MPSC queue of values of type `T`
+///
+/// Any number of threads can `push` through a shared reference, while values are
+/// popped through the single `MpscConsumer` handed out by `consumer`.
+pub struct MpscQueue<T: PodU64 = u64> {
+    slots: Box<[Slot]>,
+
+    /// Number of values pushed or being pushed and not popped yet
+    len: Padded,
+
+    /// Ticket of the next push
+    tail: Padded,
+
+    /// Ticket of the next pop, only moved by the consumer
+    head: Padded,
+
+    /// Set while an `MpscConsumer` exists
+    consumer: AtomicBool,
+
+    _values: PhantomData<T>
+}
+
+impl<T: PodU64> MpscQueue<T> {
+    /// Construct an empty queue holding up to `capacity` values.
+    /// NOTE: Capacity must be a power of two, otherwise `InvalidCapacity` is returned.
+    pub fn new(capacity: usize) -> Result<MpscQueue<T>, AtomicHashMapError> {
+        if capacity < 2 || !capacity.is_power_of_two() {
+            return Err(AtomicHashMapError::InvalidCapacity);
+        }
+
+        Ok(MpscQueue {
+            slots: (0..capacity).map(|_| Slot {
+                value: AtomicU64::new(0),
+                ready: AtomicBool::new(false)
+            }).collect(),
+            len: Padded(AtomicUsize::new(0)),
+            tail: Padded(AtomicUsize::new(0)),
+            head: Padded(AtomicUsize::new(0)),
+            consumer: AtomicBool::new(false),
+            _values: PhantomData
+        })
+    }
+
+    /// Get the slot of ticket `ticket`
+    fn slot(&self, ticket: usize) -> &Slot {
+        &self.slots[ticket & (self.slots.len() - 1)]
+    }
+
+    /// Push `value` to the back of the queue, or return `AtomicHashMapError::Full`
+    /// if it holds `capacity` values. Wait-free.
+    pub fn push(&self, value: T) -> Result<(), AtomicHashMapError> {
+        // Acquire the room given back by the consumer along with its cleared slot
+        if self.len.0.fetch_add(1, Ordering::AcqRel) >= self.slots.len() {
+            self.len.0.fetch_sub(1, Ordering::Relaxed);
+            return Err(AtomicHashMapError::Full);
+        }
+
+        let slot = self.slot(self.tail.0.fetch_add(1, Ordering::Relaxed));
+        slot.value.store(value.to_u64(), Ordering::Relaxed);
+        slot.ready.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Get the consumer of the queue, or `None` if it is already taken
+    pub fn consumer(&self) -> Option<MpscConsumer<'_, T>> {
+        self.consumer.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+
+        Some(MpscConsumer { queue: self, head: self.head.0.load(Ordering::Relaxed) })
+    }
+
+    /// Get the number of values in the queue, including pushes still in progress.
+    /// Only a hint while other threads push or pop.
+    pub fn len(&self) -> usize {
+        self.len.0.load(Ordering::Relaxed).min(self.slots.len())
+    }
+
+    /// Returns true if the queue holds no values
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the number of values the queue holds at most
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+}
+
+/// Popping side of an `MpscQueue`, of which there is at most one at a time
+pub struct MpscConsumer<'a, T: PodU64> {
+    queue: &'a MpscQueue<T>,
+
+    /// Ticket of the next pop, owned by this consumer
+    head: usize
+}
+
+impl<'a, T: PodU64> MpscConsumer<'a, T> {
+    /// Pop the value at the front of the queue, or `None` if it is empty or its push
+    /// is still in progress
+    pub fn pop(&mut self) -> Option<T> {
+        let slot = self.queue.slot(self.head);
+        if !slot.ready.load(Ordering::Acquire) {
+            return None;
+        }
+
+        let value = slot.value.load(Ordering::Relaxed);
+        slot.ready.store(false, Ordering::Relaxed);
+        self.head = self.head.wrapping_add(1);
+
+        // Hand the cleared slot over to the push that reserves the room
+        self.queue.len.0.fetch_sub(1, Ordering::Release);
+        Some(T::from_u64(value))
+    }
+}
+
+impl<'a, T: PodU64> Drop for MpscConsumer<'a, T> {
+    fn drop(&mut self) {
+        self.queue.head.0.store(self.head, Ordering::Relaxed);
+        self.queue.consumer.store(false, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    #[test]
+    fn test_mpsc() {
+        let queue: MpscQueue<i32> = MpscQueue::new(4).unwrap();
+        {
+            let mut consumer = queue.consumer().unwrap();
+            assert!(queue.consumer().is_none());
+            assert_eq!(consumer.pop(), None);
+
+            for value in [1, -2, 3, -4] {
+                queue.push(value).unwrap();
+            }
+            assert_eq!(queue.push(5), Err(AtomicHashMapError::Full));
+            assert_eq!(queue.len(), 4);
+            assert_eq!(consumer.pop(), Some(1));
+            queue.push(5).unwrap();
+            assert_eq!(consumer.pop(), Some(-2));
+        }
+
+        // A new consumer picks up where the old one left off
+        let mut consumer = queue.consumer().unwrap();
+        assert_eq!(consumer.pop(), Some(3));
+        assert_eq!(consumer.pop(), Some(-4));
+        assert_eq!(consumer.pop(), Some(5));
+        assert_eq!(consumer.pop(), None);
+        assert!(queue.is_empty());
+
+        assert!(MpscQueue::<u64>::new(5).is_err());
+    }
+
+    #[test]
+    fn test_mpsc_threads() {
+        let queue: MpscQueue = MpscQueue::new(64).unwrap();
+        let mut consumer = queue.consumer().unwrap();
+
+        thread::scope(|scope| {
+            for thread in 0..4u64 {
+                let queue = &queue;
+                scope.spawn(move || {
+                    for value in 0..10_000 {
+                        while queue.push(thread << 32 | value).is_err() {
+                            thread::yield_now();
+                        }
+                    }
+                });
+            }
+
+            // Each producer's values arrive in the order it pushed them
+            let mut next = [0; 4];
+            for _ in 0..40_000 {
+                let value = loop {
+                    match consumer.pop() {
+                        Some(value) => break value,
+                        None => thread::yield_now()
+                    }
+                };
+                let thread = (value >> 32) as usize;
+                assert_eq!(value & 0xffff_ffff, next[thread]);
+                next[thread] += 1;
+            }
+            assert_eq!(next, [10_000; 4]);
+        });
+
+        assert_eq!(consumer.pop(), None);
+    }
+}
@@ -0,0 +1,264 @@
+//! Lock-free hashmap keeping every value inserted for each key
+//!
+//! Values live in a fixed pool of nodes allocated up front, each holding one value and
+//! the index of the node inserted before it for the same key. An `AtomicHashMap` maps
+//! each key to the index of its newest node, so inserting a value takes a node from
+//! the pool and swings the head of its key over to it with a compare-exchange.
+//!
+//! Nodes are handed out by bumping a counter and are never reused while the map is
+//! shared, so readers can walk a list while it is appended to, or after it was
+//! removed, without ever landing on a node that now belongs to another key. Removing
+//! a key therefore doesn't give its nodes back to the pool; `clear` does, since it
+//! takes the map by `&mut`.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::hash::BuildHasher;
+use core::marker::PhantomData;
+
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::atomichashmap::{AtomicHashMap, AtomicHashMapError, BuildMurmurHasher};
+use crate::pod::PodU64;
+
+/// Link marking the end of a list
+const NIL: u64 = 0;
+
+/// One value of a key, linked to the value inserted before it
+struct Node {
+    value: AtomicU64,
+
+    /// Index of the previous node of the key plus one, or `NIL` for the first one
+    next: AtomicU64
+}
+
+/// Lock-free hashmap from keys of type `K` to every value of type `V` inserted for
+/// them
+///
+/// Holds up to `size` keys and `values` values in total, whichever runs out first.
+pub struct AtomicHashMultiMap<K: PodU64 = u64, V: PodU64 = u64, S = BuildMurmurHasher> {
+    /// Link to the newest node of each key
+    heads: AtomicHashMap<K, u64, S>,
+
+    nodes: Box<[Node]>,
+
+    /// Index of the next node to hand out
+    next_node: AtomicUsize,
+
+    _values: PhantomData<V>
+}
+
+impl<K: PodU64, V: PodU64> AtomicHashMultiMap<K, V> {
+    /// Construct a new multimap with room for `size` keys and `values` values.
+    /// NOTE: Size must be a power of two, otherwise `InvalidCapacity` is returned.
+    pub fn new(size: usize, values: usize)
+            -> Result<AtomicHashMultiMap<K, V>, AtomicHashMapError> {
+        AtomicHashMultiMap::with_hasher(size, values, BuildMurmurHasher::default())
+    }
+}
+
+impl<K: PodU64, V: PodU64, S: BuildHasher> AtomicHashMultiMap<K, V, S> {
+    /// Construct a new multimap with room for `size` keys and `values` values, hashing
+    /// keys with `hasher`.
+    /// NOTE: Size must be a power of two, otherwise `InvalidCapacity` is returned.
+    pub fn with_hasher(size: usize, values: usize, hasher: S)
+            -> Result<AtomicHashMultiMap<K, V, S>, AtomicHashMapError> {
+        let nodes = (0..values)
+            .map(|_| Node { value: AtomicU64::new(0), next: AtomicU64::new(NIL) })
+            .collect::<Vec<_>>();
+
+        Ok(AtomicHashMultiMap {
+            heads: AtomicHashMap::with_hasher(size, hasher)?,
+            nodes: nodes.into_boxed_slice(),
+            next_node: AtomicUsize::new(0),
+            _values: PhantomData
+        })
+    }
+
+    /// Append `value` to the values of `key`
+    ///
+    /// Returns `AtomicHashMapError::Full` if either the table of keys or the pool of
+    /// values has run out.
+    pub fn insert(&self, key: K, value: V) -> Result<(), AtomicHashMapError> {
+        // Check the key first so that a sentinel doesn't use up a node
+        self.heads.raw_key(key)?;
+
+        let index = self.next_node.fetch_add(1, Ordering::Relaxed);
+        let node = match self.nodes.get(index) {
+            Some(node) => node,
+            None => {
+                // Keep the counter from wrapping around after many failed inserts
+                self.next_node.store(self.nodes.len(), Ordering::Relaxed);
+                return Err(AtomicHashMapError::Full);
+            }
+        };
+        node.value.store(value.to_u64(), Ordering::Relaxed);
+
+        // The node only becomes reachable through the compare-exchange of the head,
+        // which releases both of its stores. It is private to us until then, so
+        // relinking it on every retry is fine.
+        let link = index as u64 + 1;
+        self.heads.upsert(key, link, |head, link| {
+            node.next.store(head, Ordering::Relaxed);
+            link
+        })?;
+
+        Ok(())
+    }
+
+    /// Iterate over the values of `key`, newest first
+    ///
+    /// Values appended while iterating aren't visited.
+    pub fn get_all(&self, key: &K) -> GetAll<'_, V> {
+        GetAll {
+            nodes: &self.nodes,
+            next: self.heads.get(key).unwrap_or(NIL),
+            _values: PhantomData
+        }
+    }
+
+    /// Check if at least one value was inserted for `key`
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.heads.contains_key(key)
+    }
+
+    /// Atomically remove `key`, returning an iterator over the values it had, newest
+    /// first. The nodes of the values aren't reused until `clear`.
+    pub fn remove(&self, key: K) -> Option<GetAll<'_, V>> {
+        Some(GetAll {
+            nodes: &self.nodes,
+            next: self.heads.remove(key)?,
+            _values: PhantomData
+        })
+    }
+
+    /// Remove every key and give every node back to the pool
+    pub fn clear(&mut self) {
+        self.heads.clear_mut();
+        for node in self.nodes.iter() {
+            node.next.store(NIL, Ordering::Relaxed);
+        }
+        self.next_node.store(0, Ordering::Relaxed);
+    }
+
+    /// Get the number of keys in the multimap
+    pub fn len(&self) -> u64 {
+        self.heads.len()
+    }
+
+    /// Returns true if there are no keys in the multimap
+    pub fn is_empty(&self) -> bool {
+        self.heads.is_empty()
+    }
+
+    /// Get the number of values taken from the pool, including the values of removed
+    /// keys
+    pub fn values_used(&self) -> usize {
+        self.next_node.load(Ordering::Relaxed).min(self.nodes.len())
+    }
+
+    /// Get the number of keys the multimap has room for
+    pub fn capacity(&self) -> usize {
+        self.heads.capacity()
+    }
+
+    /// Get the number of values the multimap has room for
+    pub fn value_capacity(&self) -> usize {
+        self.nodes.len()
+    }
+}
+
+/// Iterator over the values of one key of an `AtomicHashMultiMap`, newest first,
+/// created by `AtomicHashMultiMap::get_all` and `AtomicHashMultiMap::remove`
+pub struct GetAll<'a, V: PodU64> {
+    nodes: &'a [Node],
+
+    /// Link to the next node to visit
+    next: u64,
+
+    _values: PhantomData<V>
+}
+
+impl<'a, V: PodU64> Iterator for GetAll<'a, V> {
+    type Item = V;
+
+    fn next(&mut self) -> Option<V> {
+        if self.next == NIL {
+            return None;
+        }
+
+        // Every node reachable from a head was fully written before it was linked in
+        let node = &self.nodes[self.next as usize - 1];
+        self.next = node.next.load(Ordering::Relaxed);
+        Some(V::from_u64(node.value.load(Ordering::Relaxed)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    #[test]
+    fn test_multimap() {
+        let mut multimap: AtomicHashMultiMap<u64, i32> =
+            AtomicHashMultiMap::new(1 << 4, 8).unwrap();
+
+        multimap.insert(1, 10).unwrap();
+        multimap.insert(2, 20).unwrap();
+        multimap.insert(1, 11).unwrap();
+        multimap.insert(1, -12).unwrap();
+        assert_eq!(multimap.get_all(&1).collect::<Vec<_>>(), vec![-12, 11, 10]);
+        assert_eq!(multimap.get_all(&2).collect::<Vec<_>>(), vec![20]);
+        assert_eq!(multimap.get_all(&3).count(), 0);
+        assert_eq!(multimap.len(), 2);
+        assert!(multimap.contains_key(&1));
+
+        // Removed values can still be read but their nodes stay taken
+        assert_eq!(multimap.remove(1).unwrap().collect::<Vec<_>>(), vec![-12, 11, 10]);
+        assert!(multimap.remove(1).is_none());
+        assert_eq!(multimap.get_all(&1).count(), 0);
+        assert_eq!(multimap.values_used(), 4);
+
+        for value in 0..4 {
+            multimap.insert(3, value).unwrap();
+        }
+        assert_eq!(multimap.insert(3, 4), Err(AtomicHashMapError::Full));
+        assert_eq!(multimap.insert(0, 4), Err(AtomicHashMapError::InvalidKey));
+        assert_eq!(multimap.values_used(), 8);
+
+        multimap.clear();
+        assert!(multimap.is_empty());
+        assert_eq!(multimap.values_used(), 0);
+        multimap.insert(3, 5).unwrap();
+        assert_eq!(multimap.get_all(&3).collect::<Vec<_>>(), vec![5]);
+    }
+
+    #[test]
+    fn test_multimap_threads() {
+        let multimap: AtomicHashMultiMap = AtomicHashMultiMap::new(1 << 8, 4000).unwrap();
+
+        // Every thread records itself against every address
+        thread::scope(|scope| {
+            for thread in 0..4 {
+                let multimap = &multimap;
+                scope.spawn(move || {
+                    for address in 1..=100 {
+                        for _ in 0..10 {
+                            multimap.insert(address * 0x1000, thread).unwrap();
+                        }
+                    }
+                });
+            }
+        });
+
+        for address in 1..=100 {
+            let mut threads: Vec<u64> = multimap.get_all(&(address * 0x1000)).collect();
+            threads.sort();
+            let expected = (0..4).flat_map(|thread| vec![thread; 10]).collect::<Vec<_>>();
+            assert_eq!(threads, expected);
+        }
+        assert_eq!(multimap.values_used(), 4000);
+    }
+}
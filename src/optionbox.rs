@@ -0,0 +1,196 @@
+//! Atomic cell holding an optional `Box<T>`, for handing owned values between
+//! threads
+//!
+//! The cell stores the box as a raw pointer in an `AtomicPtr`, null when it is
+//! empty. Ownership moves in and out with whole-pointer swaps and compare-exchanges
+//! and the cell never dereferences the pointer, so no thread can be reading a box
+//! while another one takes and drops it, and nothing has to be reclaimed later. A
+//! box is published with `Release` and taken with `Acquire`, so the thread taking it
+//! sees everything written to it before it was put in the cell.
+
+use alloc::boxed::Box;
+use core::marker::PhantomData;
+use core::ptr;
+
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+/// Turn an optional box into the pointer stored in the cell
+fn into_ptr<T>(value: Option<Box<T>>) -> *mut T {
+    value.map_or(ptr::null_mut(), Box::into_raw)
+}
+
+/// Take ownership of a pointer taken out of the cell
+fn from_ptr<T>(ptr: *mut T) -> Option<Box<T>> {
+    // SAFETY: Non-null pointers in the cell come from `Box::into_raw` and are owned by
+    // whoever takes them out of the cell
+    (!ptr.is_null()).then(|| unsafe { Box::from_raw(ptr) })
+}
+
+/// Atomic `Option<Box<T>>` whose box can be moved in and out by any thread
+pub struct AtomicOptionBox<T> {
+    ptr: AtomicPtr<T>,
+
+    /// The cell owns the box it holds
+    _owned: PhantomData<Box<T>>
+}
+
+// SAFETY: The box is only ever owned by one thread at a time, and the cell never
+// hands out references to it through a shared reference
+unsafe impl<T: Send> Send for AtomicOptionBox<T> {}
+unsafe impl<T: Send> Sync for AtomicOptionBox<T> {}
+
+impl<T> AtomicOptionBox<T> {
+    /// Construct a cell holding `value`
+    pub fn new(value: Option<Box<T>>) -> AtomicOptionBox<T> {
+        AtomicOptionBox { ptr: AtomicPtr::new(into_ptr(value)), _owned: PhantomData }
+    }
+
+    /// Construct an empty cell
+    pub const fn none() -> AtomicOptionBox<T> {
+        AtomicOptionBox { ptr: AtomicPtr::new(ptr::null_mut()), _owned: PhantomData }
+    }
+
+    /// Take the box out of the cell, leaving it empty
+    pub fn take(&self) -> Option<Box<T>> {
+        from_ptr(self.ptr.swap(ptr::null_mut(), Ordering::Acquire))
+    }
+
+    /// Put `value` in the cell, returning the box it replaced
+    pub fn swap(&self, value: Option<Box<T>>) -> Option<Box<T>> {
+        from_ptr(self.ptr.swap(into_ptr(value), Ordering::AcqRel))
+    }
+
+    /// Put `value` in the cell if it is empty, otherwise hand `value` back as `Err`
+    pub fn set_if_none(&self, value: Box<T>) -> Result<(), Box<T>> {
+        let new = Box::into_raw(value);
+        match self.ptr.compare_exchange(ptr::null_mut(), new, Ordering::Release,
+                                        Ordering::Relaxed) {
+            Ok(_) => Ok(()),
+            // SAFETY: `new` was never published, so it is still ours
+            Err(_) => Err(unsafe { Box::from_raw(new) })
+        }
+    }
+
+    /// Returns true if the cell holds no box. Only a hint while other threads move
+    /// boxes in and out.
+    pub fn is_none(&self) -> bool {
+        self.ptr.load(Ordering::Relaxed).is_null()
+    }
+
+    /// Get a mutable reference to the value in the cell, which no other thread can
+    /// access
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        // SAFETY: The cell owns the box and is borrowed mutably
+        unsafe { self.ptr.get_mut().as_mut() }
+    }
+
+    /// Take the box out of the cell
+    pub fn into_inner(mut self) -> Option<Box<T>> {
+        from_ptr(core::mem::replace(self.ptr.get_mut(), ptr::null_mut()))
+    }
+}
+
+impl<T> Default for AtomicOptionBox<T> {
+    fn default() -> AtomicOptionBox<T> {
+        AtomicOptionBox::none()
+    }
+}
+
+impl<T> Drop for AtomicOptionBox<T> {
+    fn drop(&mut self) {
+        drop(from_ptr(*self.ptr.get_mut()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    /// Value that counts its drops
+    struct Tracked(u64, Arc<AtomicUsize>);
+
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            self.1.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_option_box() {
+        let cell = AtomicOptionBox::none();
+        assert!(cell.is_none());
+        assert_eq!(cell.take(), None);
+
+        assert_eq!(cell.set_if_none(Box::new(1)), Ok(()));
+        assert_eq!(cell.set_if_none(Box::new(2)), Err(Box::new(2)));
+        assert_eq!(cell.swap(Some(Box::new(3))), Some(Box::new(1)));
+        assert_eq!(cell.take(), Some(Box::new(3)));
+        assert!(cell.is_none());
+
+        let mut cell = AtomicOptionBox::new(Some(Box::new(String::from("a"))));
+        cell.get_mut().unwrap().push('b');
+        assert_eq!(cell.into_inner().as_deref().map(String::as_str), Some("ab"));
+    }
+
+    #[test]
+    fn test_option_box_drop() {
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let cell = AtomicOptionBox::new(Some(Box::new(Tracked(1, dropped.clone()))));
+
+        // Boxes handed back by a failed set or a swap are dropped by their new owner
+        drop(cell.set_if_none(Box::new(Tracked(2, dropped.clone()))));
+        assert_eq!(dropped.load(Ordering::Relaxed), 1);
+        drop(cell.swap(Some(Box::new(Tracked(3, dropped.clone())))));
+        assert_eq!(dropped.load(Ordering::Relaxed), 2);
+
+        // The box left in the cell goes with it
+        drop(cell);
+        assert_eq!(dropped.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn test_option_box_threads() {
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let cell = AtomicOptionBox::none();
+        let received = AtomicUsize::new(0);
+
+        // Producers hand boxes to consumers through the cell, each box is taken once
+        thread::scope(|scope| {
+            for thread in 0..2u64 {
+                let (cell, dropped) = (&cell, &dropped);
+                scope.spawn(move || {
+                    for i in 0..5_000 {
+                        let mut value = Box::new(Tracked(thread << 32 | i,
+                                                         dropped.clone()));
+                        while let Err(back) = cell.set_if_none(value) {
+                            value = back;
+                            thread::yield_now();
+                        }
+                    }
+                });
+            }
+
+            for _ in 0..2 {
+                let (cell, received) = (&cell, &received);
+                scope.spawn(move || {
+                    while received.load(Ordering::Relaxed) < 10_000 {
+                        match cell.take() {
+                            Some(value) => {
+                                assert!(value.0 & 0xffff_ffff < 5_000);
+                                received.fetch_add(1, Ordering::Relaxed);
+                            }
+                            None => thread::yield_now()
+                        }
+                    }
+                });
+            }
+        });
+
+        assert!(cell.is_none());
+        assert_eq!(dropped.load(Ordering::Relaxed), 10_000);
+    }
+}
@@ -0,0 +1,48 @@
+//! Memory orderings selectable for the values of a map
+
+use core::sync::atomic::Ordering;
+
+/// Memory orderings used for the values of an `AtomicHashMap`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OrderingProfile {
+    /// Values are only atomic, with no ordering relative to other memory. Enough for
+    /// statistics such as counters that are only read once all writers are done.
+    Relaxed,
+
+    /// Value writes release and value reads acquire, so a thread reading a value sees
+    /// everything the writer did before writing it. The default.
+    #[default]
+    AcquireRelease,
+
+    /// Every value access is sequentially consistent
+    SeqCst
+}
+
+impl OrderingProfile {
+    /// Ordering for loading a value, also used when a compare-exchange fails
+    pub(crate) fn load(self) -> Ordering {
+        match self {
+            OrderingProfile::Relaxed        => Ordering::Relaxed,
+            OrderingProfile::AcquireRelease => Ordering::Acquire,
+            OrderingProfile::SeqCst         => Ordering::SeqCst
+        }
+    }
+
+    /// Ordering for storing a value
+    pub(crate) fn store(self) -> Ordering {
+        match self {
+            OrderingProfile::Relaxed        => Ordering::Relaxed,
+            OrderingProfile::AcquireRelease => Ordering::Release,
+            OrderingProfile::SeqCst         => Ordering::SeqCst
+        }
+    }
+
+    /// Ordering for a read-modify-write of a value
+    pub(crate) fn rmw(self) -> Ordering {
+        match self {
+            OrderingProfile::Relaxed        => Ordering::Relaxed,
+            OrderingProfile::AcquireRelease => Ordering::AcqRel,
+            OrderingProfile::SeqCst         => Ordering::SeqCst
+        }
+    }
+}
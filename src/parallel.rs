@@ -0,0 +1,89 @@
+//! Rayon parallel iterators for `AtomicHashMap`, enabled with the `rayon` feature
+//!
+//! `par_iter` splits the table into ranges of slots and reads each range like `iter`,
+//! so it sees the same snapshot semantics when the map is written to concurrently.
+//! `par_extend` inserts from every thread of the pool at once. Entries that don't
+//! fit are handed to `Extend` afterwards, which grows the table for them.
+
+use alloc::vec::Vec;
+use core::hash::BuildHasher;
+
+use rayon::iter::plumbing::UnindexedConsumer;
+use rayon::iter::{IntoParallelIterator, ParallelExtend, ParallelIterator};
+
+use crate::atomichashmap::AtomicHashMap;
+use crate::pod::PodU64;
+
+/// Parallel iterator over the live entries of an `AtomicHashMap`, created by
+/// `AtomicHashMap::par_iter`
+pub struct ParIter<'a, K: PodU64, V: PodU64, S> {
+    map: &'a AtomicHashMap<K, V, S>
+}
+
+impl<K: PodU64, V: PodU64, S: BuildHasher> AtomicHashMap<K, V, S> {
+    /// Iterate over the live entries of the map in parallel
+    pub fn par_iter(&self) -> ParIter<'_, K, V, S> {
+        ParIter { map: self }
+    }
+}
+
+impl<'a, K, V, S> ParallelIterator for ParIter<'a, K, V, S>
+        where K: PodU64 + Send, V: PodU64 + Send, S: BuildHasher + Sync {
+    type Item = (K, V);
+
+    fn drive_unindexed<C>(self, consumer: C) -> C::Result
+            where C: UnindexedConsumer<(K, V)> {
+        let map = self.map;
+        (0..map.capacity()).into_par_iter()
+            .filter_map(move |index| map.entry_at(index))
+            .drive_unindexed(consumer)
+    }
+}
+
+impl<'a, K, V, S> IntoParallelIterator for &'a AtomicHashMap<K, V, S>
+        where K: PodU64 + Send, V: PodU64 + Send, S: BuildHasher + Sync {
+    type Iter = ParIter<'a, K, V, S>;
+    type Item = (K, V);
+
+    fn into_par_iter(self) -> ParIter<'a, K, V, S> {
+        self.par_iter()
+    }
+}
+
+/// Insert every item from the threads of the pool. Panics on a sentinel key, like
+/// `Extend`.
+impl<K, V, S> ParallelExtend<(K, V)> for AtomicHashMap<K, V, S>
+        where K: PodU64 + Send, V: PodU64 + Send, S: BuildHasher + Sync {
+    fn par_extend<I>(&mut self, par_iter: I) where I: IntoParallelIterator<Item = (K, V)> {
+        let map = &*self;
+        let overflow: Vec<(K, V)> = par_iter.into_par_iter()
+            .filter(|&(key, value)| map.insert(key, value).is_err())
+            .collect();
+
+        self.extend(overflow);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_par_iter_extend() {
+        let mut hashtable: AtomicHashMap = AtomicHashMap::new(1 << 4).unwrap();
+        hashtable.par_extend((1..=10_000u64).into_par_iter().map(|x| (x, x * 2)));
+
+        assert_eq!(hashtable.len(), 10_000);
+        assert!(hashtable.capacity() >= 10_000);
+
+        let sum: u64 = hashtable.par_iter().map(|(_, value)| value).sum();
+        assert_eq!(sum, 10_000 * 10_001);
+        assert_eq!((&hashtable).into_par_iter().count(), 10_000);
+
+        let mut entries: Vec<(u64, u64)> = hashtable.par_iter().collect();
+        entries.sort();
+        let mut expected = hashtable.to_vec();
+        expected.sort();
+        assert_eq!(entries, expected);
+    }
+}
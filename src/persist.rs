@@ -0,0 +1,308 @@
+//! File-backed `AtomicSharedHashMap` that survives restarts, enabled with the
+//! `persist` feature on Unix
+//!
+//! `AtomicFileHashMap::open` maps a file with `MAP_SHARED` and lays an
+//! `AtomicSharedHashMap` over it, so every write lands in the page cache and is
+//! still there when the file is opened again, whether the process exited cleanly
+//! or crashed. `flush` forces the pages to disk for surviving a power loss as well.
+//!
+//! # Crash safety
+//!
+//! Only an empty file is ever formatted. It is formatted with the magic number
+//! cleared, synced, and only then given its magic number, which is synced again.
+//! Any non-empty file without the magic number and layout version is refused rather
+//! than overwritten, including one whose formatting was cut short by a crash, which
+//! has to be removed before the map can be created again.
+//!
+//! `open` holds an exclusive `flock` on the file from checking its contents until it
+//! is formatted, so processes opening a new file at the same time format it once and
+//! all attach to the same table.
+
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::ops::Deref;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr;
+
+use crate::error::AtomicHashMapError;
+use crate::pod::PodU64;
+use crate::shared::AtomicSharedHashMap;
+
+/// Shared, writable mapping of the whole of a file
+struct Mapping {
+    ptr: *mut u8,
+    len: usize
+}
+
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    /// Map the first `len` bytes of `file`
+    fn new(file: &File, len: usize) -> io::Result<Mapping> {
+        let ptr = unsafe {
+            libc::mmap(ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE,
+                       libc::MAP_SHARED, file.as_raw_fd(), 0)
+        };
+
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Mapping { ptr: ptr as *mut u8, len })
+    }
+
+    /// Write the dirty pages of the mapping back to the file, waiting for them to
+    /// reach the disk
+    fn flush(&self) -> io::Result<()> {
+        let res = unsafe {
+            libc::msync(self.ptr as *mut libc::c_void, self.len, libc::MS_SYNC)
+        };
+
+        if res != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
+/// Exclusive `flock` on a file, released when dropped
+struct FileLock<'a>(&'a File);
+
+impl<'a> FileLock<'a> {
+    /// Lock `file`, waiting for any other holder of the lock to release it
+    fn exclusive(file: &'a File) -> io::Result<FileLock<'a>> {
+        loop {
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } == 0 {
+                return Ok(FileLock(file));
+            }
+
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+}
+
+impl<'a> Drop for FileLock<'a> {
+    fn drop(&mut self) {
+        unsafe {
+            libc::flock(self.0.as_raw_fd(), libc::LOCK_UN);
+        }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+/// Convert an error of the map into an `io::Error`, blaming the file for a bad region
+/// and the caller for anything else
+fn map_error(err: AtomicHashMapError) -> io::Error {
+    let kind = match err {
+        AtomicHashMapError::InvalidRegion => io::ErrorKind::InvalidData,
+        _ => io::ErrorKind::InvalidInput
+    };
+
+    io::Error::new(kind, err)
+}
+
+/// Lock-free hashmap persisted in a memory-mapped file
+///
+/// Derefs to the `AtomicSharedHashMap` laid over the file, so it is used just like
+/// one. Other processes may open the same file concurrently and share the table.
+///
+/// ```no_run
+/// use atomics_rs::AtomicFileHashMap;
+///
+/// let counters: AtomicFileHashMap = AtomicFileHashMap::open("counters.map", 1 << 16)?;
+/// counters.add_to(7, 1).unwrap();
+/// counters.flush()?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub struct AtomicFileHashMap<K: PodU64 = u64, V: PodU64 = u64> {
+    /// View of the table, borrowing from `mapping` for as long as it is alive
+    map: AtomicSharedHashMap<'static, K, V>,
+
+    mapping: Mapping
+}
+
+impl<K: PodU64, V: PodU64> AtomicFileHashMap<K, V> {
+    /// Open the map stored in the file at `path`, creating the file with an empty map
+    /// of `capacity` slots if it doesn't exist or is empty
+    ///
+    /// Fails with `InvalidInput` if `capacity` isn't a power of two or differs from
+    /// the capacity of the map already in the file, and with `InvalidData` if the file
+    /// holds anything other than a map of this layout version.
+    pub fn open<P: AsRef<Path>>(path: P, capacity: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false)
+            .open(path)?;
+
+        // Another process may be formatting the file, check it once they are done
+        let _lock = FileLock::exclusive(&file)?;
+
+        let len = usize::try_from(file.metadata()?.len())
+            .map_err(|_| map_error(AtomicHashMapError::InvalidRegion))?;
+        if len == 0 {
+            return Self::create(&file, capacity);
+        }
+
+        let mapping = Mapping::new(&file, len)?;
+        let map = unsafe {
+            AtomicSharedHashMap::attach(mapping.ptr, mapping.len).map_err(map_error)?
+        };
+
+        if map.capacity() != capacity {
+            return Err(map_error(AtomicHashMapError::InvalidCapacity));
+        }
+
+        Ok(AtomicFileHashMap { map, mapping })
+    }
+
+    /// Size `file` for `capacity` slots and format an empty map into it
+    fn create(file: &File, capacity: usize) -> io::Result<Self> {
+        if capacity < 2 || !capacity.is_power_of_two() {
+            return Err(map_error(AtomicHashMapError::InvalidCapacity));
+        }
+
+        let len = AtomicSharedHashMap::<K, V>::region_size(capacity);
+        file.set_len(len as u64)?;
+
+        let mapping = Mapping::new(file, len)?;
+        let map = unsafe {
+            AtomicSharedHashMap::format(mapping.ptr, mapping.len, capacity, 0, u64::MAX)
+                .map_err(map_error)?
+        };
+
+        // The table must be on disk before the magic number claims it is valid
+        mapping.flush()?;
+        map.publish();
+        mapping.flush()?;
+
+        Ok(AtomicFileHashMap { map, mapping })
+    }
+
+    /// Write every change made so far back to the file, waiting for it to reach the
+    /// disk
+    pub fn flush(&self) -> io::Result<()> {
+        self.mapping.flush()
+    }
+}
+
+impl<K: PodU64, V: PodU64> Deref for AtomicFileHashMap<K, V> {
+    type Target = AtomicSharedHashMap<'static, K, V>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.map
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::os::unix::fs::FileExt;
+    use std::path::PathBuf;
+
+    /// Get a path in the temporary directory unique to this test run
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("atomics-rs-{}-{}.map", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_reopen() {
+        let path = temp_path("reopen");
+        let _ = fs::remove_file(&path);
+
+        {
+            let counters: AtomicFileHashMap = AtomicFileHashMap::open(&path, 64).unwrap();
+            for x in 1..=10 {
+                counters.add_to(x, x).unwrap();
+            }
+            counters.flush().unwrap();
+        }
+
+        let counters: AtomicFileHashMap = AtomicFileHashMap::open(&path, 64).unwrap();
+        assert_eq!(counters.len(), 10);
+        for x in 1..=10 {
+            assert_eq!(counters.add_to(x, 1), Ok(x));
+        }
+        drop(counters);
+
+        let err = AtomicFileHashMap::<u64>::open(&path, 128).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_file() {
+        let path = temp_path("invalid");
+
+        // A file without the magic number is left alone, even if it is all zeroes
+        fs::write(&path, [0u8; 100]).unwrap();
+        let err = AtomicFileHashMap::<u64>::open(&path, 16).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(fs::read(&path).unwrap(), [0u8; 100]);
+
+        // Only an empty file is formatted
+        fs::write(&path, []).unwrap();
+        let hashtable: AtomicFileHashMap = AtomicFileHashMap::open(&path, 16).unwrap();
+        assert_eq!(hashtable.capacity(), 16);
+        assert!(hashtable.is_empty());
+        drop(hashtable);
+
+        // A newer layout version is refused
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.write_all_at(&(crate::shared::VERSION + 1).to_ne_bytes(), 8).unwrap();
+        let err = AtomicFileHashMap::<u64>::open(&path, 16).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // So is a file that doesn't hold a map at all
+        fs::write(&path, b"not an AtomicHashMap").unwrap();
+        let err = AtomicFileHashMap::<u64>::open(&path, 16).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(fs::read(&path).unwrap(), b"not an AtomicHashMap");
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_open_concurrently() {
+        use std::thread;
+
+        let path = temp_path("concurrent");
+        let _ = fs::remove_file(&path);
+
+        // Every opener of the new file sees the keys of the others, so only one of
+        // them formatted it
+        thread::scope(|scope| {
+            for id in 0..4 {
+                let path = &path;
+                scope.spawn(move || {
+                    let counters: AtomicFileHashMap =
+                        AtomicFileHashMap::open(path, 64).unwrap();
+                    counters.add_to(id + 1, 1).unwrap();
+                });
+            }
+        });
+
+        let counters: AtomicFileHashMap = AtomicFileHashMap::open(&path, 64).unwrap();
+        assert_eq!(counters.len(), 4);
+        for id in 0..4 {
+            assert_eq!(counters.get(&(id + 1)), Some(1));
+        }
+        drop(counters);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
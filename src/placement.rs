@@ -0,0 +1,146 @@
+//! Placement of the tables of a map in memory: on transparent huge pages with the
+//! `hugepages` feature, and on a given NUMA node with the `numa` feature, both on
+//! Linux
+//!
+//! A table of 2^26 slots spans well over a gigabyte, and with 4 KiB pages nearly
+//! every probe misses the TLB. Tables of maps built with
+//! `AtomicHashMapBuilder::huge_pages` are advised with `MADV_HUGEPAGE` before they
+//! are filled, so the kernel backs them with 2 MiB pages where it can.
+//!
+//! On a machine with several NUMA nodes, pages are placed on the node of the thread
+//! that first touches them, so a table filled by one thread ends up on one node.
+//! Tables of maps built with `AtomicHashMapBuilder::numa_node` are bound to their
+//! node with `mbind` before they are filled, wherever the thread building them runs.
+//! The binding is only a preference, so a full node spills over to the others.
+//!
+//! Without the features, on other platforms, or when the kernel refuses, the tables
+//! simply stay wherever the allocator puts them.
+
+use alloc::vec::Vec;
+
+/// Where the tables of a map are allocated
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Placement {
+    /// Back the tables with transparent huge pages
+    pub(crate) huge_pages: bool,
+
+    /// Allocate the tables on this NUMA node
+    pub(crate) numa_node: Option<usize>
+}
+
+impl Placement {
+    /// Allocate an empty vector with room for `len` items, placed as asked before any
+    /// of its pages are touched
+    pub(crate) fn alloc_vec<T>(self, len: usize) -> Vec<T> {
+        let vec = Vec::with_capacity(len);
+        let (start, bytes) = (vec.as_ptr() as usize, len * core::mem::size_of::<T>());
+
+        if self.huge_pages {
+            advise_huge_pages(start, bytes);
+        }
+
+        if let Some(node) = self.numa_node {
+            bind(start, bytes, node);
+        }
+
+        vec
+    }
+}
+
+/// Get the range of whole, aligned `block`s lying inside the `len` bytes at `start`,
+/// which is all of an allocation the kernel can be told about
+#[cfg(all(any(feature = "hugepages", feature = "numa"), target_os = "linux"))]
+fn inner_blocks(start: usize, len: usize, block: usize) -> Option<(usize, usize)> {
+    let begin = start.checked_next_multiple_of(block)?;
+    let end = (start + len) / block * block;
+
+    if begin >= end {
+        return None;
+    }
+
+    Some((begin, end - begin))
+}
+
+/// Ask for the `len` bytes at `start` to be backed by transparent huge pages. Failure
+/// is ignored since the table works the same on normal pages.
+#[cfg(all(feature = "hugepages", target_os = "linux"))]
+fn advise_huge_pages(start: usize, len: usize) {
+    /// Size of the huge pages backing transparent huge page mappings
+    const HUGE_PAGE: usize = 2 << 20;
+
+    if let Some((begin, len)) = inner_blocks(start, len, HUGE_PAGE) {
+        unsafe {
+            libc::madvise(begin as *mut libc::c_void, len, libc::MADV_HUGEPAGE);
+        }
+    }
+}
+
+#[cfg(not(all(feature = "hugepages", target_os = "linux")))]
+fn advise_huge_pages(_start: usize, _len: usize) {}
+
+/// Ask for the pages of the `len` bytes at `start` to be allocated on `node`. Failure
+/// is ignored since the table works the same on any node.
+#[cfg(all(feature = "numa", target_os = "linux"))]
+fn bind(start: usize, len: usize, node: usize) {
+    /// `mbind` mode preferring the given node but falling back to the others
+    const MPOL_PREFERRED: libc::c_int = 1;
+
+    /// Highest node number plus one that fits in the node mask
+    const MAX_NODES: usize = 1024;
+
+    let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if page <= 0 || node >= MAX_NODES {
+        return;
+    }
+
+    if let Some((begin, len)) = inner_blocks(start, len, page as usize) {
+        let mut mask = [0 as libc::c_ulong; MAX_NODES / libc::c_ulong::BITS as usize];
+        let bits = libc::c_ulong::BITS as usize;
+        mask[node / bits] |= 1 << (node % bits);
+
+        unsafe {
+            libc::syscall(libc::SYS_mbind, begin, len, MPOL_PREFERRED, mask.as_ptr(),
+                          MAX_NODES + 1, 0);
+        }
+    }
+}
+
+#[cfg(not(all(feature = "numa", target_os = "linux")))]
+fn bind(_start: usize, _len: usize, _node: usize) {}
+
+/// Get the NUMA nodes currently online, or just node 0 if they can't be read
+#[cfg(all(feature = "numa", target_os = "linux"))]
+pub(crate) fn online_nodes() -> Vec<usize> {
+    std::fs::read_to_string("/sys/devices/system/node/online").ok()
+        .and_then(|online| parse_node_list(online.trim()))
+        .filter(|nodes| !nodes.is_empty())
+        .unwrap_or_else(|| alloc::vec![0])
+}
+
+/// Parse a kernel node list such as `0-1,4`
+#[cfg(all(feature = "numa", target_os = "linux"))]
+fn parse_node_list(list: &str) -> Option<Vec<usize>> {
+    let mut nodes = Vec::new();
+    for range in list.split(',') {
+        match range.split_once('-') {
+            Some((first, last)) => nodes.extend(first.parse::<usize>().ok()?..=
+                                                last.parse::<usize>().ok()?),
+            None => nodes.push(range.parse().ok()?)
+        }
+    }
+
+    Some(nodes)
+}
+
+#[cfg(all(test, feature = "numa", target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_node_list() {
+        assert_eq!(parse_node_list("0"), Some(vec![0]));
+        assert_eq!(parse_node_list("0-1,4,6-7"), Some(vec![0, 1, 4, 6, 7]));
+        assert_eq!(parse_node_list("0-x"), None);
+        assert!(!online_nodes().is_empty());
+    }
+}
@@ -0,0 +1,161 @@
+/// Types that can be stored in the single `u64` value slot of an `AtomicHashMap`
+///
+/// `from_u64(x.to_u64())` must give back `x`. Value slots start out zeroed, so
+/// `from_u64(0)` must also produce a valid value.
+pub trait PodU64: Copy {
+    /// Pack this value into a `u64`
+    fn to_u64(self) -> u64;
+
+    /// Unpack a value previously packed by `to_u64`
+    fn from_u64(val: u64) -> Self;
+}
+
+/// Integers are zero-extended through the unsigned type of the same width, so that
+/// only 64-bit integers can be packed into `u64::MAX`
+macro_rules! impl_pod_int {
+    ($($ty:ty => $uty:ty),*) => {
+        $(
+            impl PodU64 for $ty {
+                #[inline]
+                fn to_u64(self) -> u64 {
+                    self as $uty as u64
+                }
+
+                #[inline]
+                fn from_u64(val: u64) -> Self {
+                    val as $ty
+                }
+            }
+        )*
+    }
+}
+
+impl_pod_int!(u8 => u8, u16 => u16, u32 => u32, u64 => u64, usize => usize,
+              i8 => u8, i16 => u16, i32 => u32, i64 => u64, isize => usize);
+
+impl PodU64 for bool {
+    #[inline]
+    fn to_u64(self) -> u64 {
+        self as u64
+    }
+
+    #[inline]
+    fn from_u64(val: u64) -> Self {
+        val != 0
+    }
+}
+
+impl PodU64 for f32 {
+    #[inline]
+    fn to_u64(self) -> u64 {
+        self.to_bits() as u64
+    }
+
+    #[inline]
+    fn from_u64(val: u64) -> Self {
+        f32::from_bits(val as u32)
+    }
+}
+
+impl PodU64 for f64 {
+    #[inline]
+    fn to_u64(self) -> u64 {
+        self.to_bits()
+    }
+
+    #[inline]
+    fn from_u64(val: u64) -> Self {
+        f64::from_bits(val)
+    }
+}
+
+/// Pairs are packed with the first element in the low 32 bits
+impl PodU64 for (u32, u32) {
+    #[inline]
+    fn to_u64(self) -> u64 {
+        (self.0 as u64) | ((self.1 as u64) << 32)
+    }
+
+    #[inline]
+    fn from_u64(val: u64) -> Self {
+        (val as u32, (val >> 32) as u32)
+    }
+}
+
+impl PodU64 for [u8; 8] {
+    #[inline]
+    fn to_u64(self) -> u64 {
+        u64::from_ne_bytes(self)
+    }
+
+    #[inline]
+    fn from_u64(val: u64) -> Self {
+        val.to_ne_bytes()
+    }
+}
+
+impl PodU64 for [u16; 4] {
+    #[inline]
+    fn to_u64(self) -> u64 {
+        self.iter().rev().fold(0, |acc, &x| (acc << 16) | x as u64)
+    }
+
+    #[inline]
+    fn from_u64(val: u64) -> Self {
+        [val as u16, (val >> 16) as u16, (val >> 32) as u16, (val >> 48) as u16]
+    }
+}
+
+impl PodU64 for [u32; 2] {
+    #[inline]
+    fn to_u64(self) -> u64 {
+        (self[0], self[1]).to_u64()
+    }
+
+    #[inline]
+    fn from_u64(val: u64) -> Self {
+        let (a, b) = <(u32, u32)>::from_u64(val);
+        [a, b]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip<T: PodU64 + PartialEq + core::fmt::Debug>(val: T) {
+        assert_eq!(T::from_u64(val.to_u64()), val);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        roundtrip(0xdead_beef_u32);
+        roundtrip(-5_i64);
+        roundtrip(-5_i8);
+        roundtrip(true);
+        roundtrip(-1.5_f32);
+        roundtrip(core::f64::consts::PI);
+        roundtrip((1_u32, u32::MAX));
+        roundtrip([1_u8, 2, 3, 4, 5, 6, 7, 8]);
+        roundtrip([1_u16, 2, 3, u16::MAX]);
+        roundtrip([7_u32, 9]);
+    }
+
+    #[test]
+    fn test_signed_zero_extended() {
+        assert_eq!((-1_i8).to_u64(), 0xff);
+        assert_eq!((-1_i16).to_u64(), 0xffff);
+        assert_eq!((-1_i32).to_u64(), 0xffff_ffff);
+        assert_eq!(i32::MIN.to_u64(), 0x8000_0000);
+        assert_eq!((-1_i64).to_u64(), u64::MAX);
+        roundtrip(i16::MIN);
+        roundtrip(-1_i32);
+    }
+
+    #[test]
+    fn test_zero_is_valid() {
+        assert!(!bool::from_u64(0));
+        assert_eq!(f64::from_u64(0), 0.0);
+        assert_eq!(<(u32, u32)>::from_u64(0), (0, 0));
+    }
+}
@@ -0,0 +1,531 @@
+//! Claiming, publishing and releasing the slots of the open addressing maps
+//!
+//! `AtomicHashMap`, `AtomicHashMap32`, `AtomicStaticHashMap` and `AtomicSharedHashMap`
+//! lay their slots out differently, but each slot is a key word and a state word
+//! next to its value, and they all claim and release slots with the protocol of
+//! this module, written once over the `Table` trait. Key and state words are
+//! `AtomicU32` or `AtomicU64`, both seen as `u64` through `Word`.
+//! `GrowableAtomicHashMap` runs the same protocol over its chain of `AtomicHashMap`
+//! tables as if they were a single table.
+//!
+//! # Claims
+//!
+//! A key is claimed with a `SeqCst` compare-exchange of the first empty slot along
+//! its probe, or of the first tombstone before it. Reusing tombstones lets two
+//! threads claim the same key in different slots: a thread that read a slot as
+//! holding another key moves past it, and that key may be removed and its tombstone
+//! claimed for our key by another thread before we claim a slot further down. Every
+//! claim is therefore followed by `settle`, which scans the probe of the key again
+//! with `SeqCst` loads, so that of two such claims at least one sees the other. The
+//! copy nearest the start of the probe wins and the other backs out before it is
+//! ever published.
+//!
+//! # State
+//!
+//! The state word of a slot holds a `PUBLISHED` bit, set with `Release` by the
+//! claiming thread once the first value is written, and above it a generation
+//! bumped every time the slot is unpublished. Removals take a slot with a single
+//! compare-exchange of the state word they loaded before checking the key, so one
+//! that raced with the slot changing hands fails instead of unpublishing another key
+//! even briefly.
+//!
+//! A removal that only takes a key if its value is still one it read, like those of
+//! `retain` and eviction, can't check the value and unpublish in one step. It first
+//! sets the `PENDING` bit of the published slot, compares the value, and then either
+//! unpublishes the slot or clears the bit again. The slot stays published while the
+//! bit is set, and lookups and removals of the key wait for it to clear, so a key
+//! that is left in place is never seen missing and a removal racing with the check
+//! is never lost.
+
+use crate::sync::atomic::Ordering;
+
+use crate::backoff::Backoff;
+use crate::error::AtomicHashMapError;
+
+/// State bit of a published slot. The bits above `PENDING` count the times the slot
+/// was unpublished, so that a slot changing hands doesn't go back to a state it had
+/// before for another 2^30 removals, or 2^62 with a 64-bit state word.
+pub(crate) const PUBLISHED: u64 = 1;
+
+/// State bit of a published slot whose value a conditional removal is checking
+const PENDING: u64 = 2;
+
+/// Increment of the state of a slot each time it is unpublished
+const GENERATION: u64 = 4;
+
+/// Atomic word holding a key or a state, seen as a `u64` whatever its width
+pub(crate) trait Word {
+    fn load(&self, order: Ordering) -> u64;
+    fn store(&self, value: u64, order: Ordering);
+    fn swap(&self, value: u64, order: Ordering) -> u64;
+    fn compare_exchange(&self, current: u64, new: u64, success: Ordering,
+                        failure: Ordering) -> Result<u64, u64>;
+}
+
+/// Implement `Word` for an atomic of `$raw` integers. Values are truncated to the
+/// width of the atomic, which only ever holds values that came from it.
+macro_rules! impl_word {
+    ($atomic:ty, $raw:ty) => {
+        #[allow(clippy::unnecessary_cast)]
+        impl Word for $atomic {
+            #[inline]
+            fn load(&self, order: Ordering) -> u64 {
+                <$atomic>::load(self, order) as u64
+            }
+
+            #[inline]
+            fn store(&self, value: u64, order: Ordering) {
+                <$atomic>::store(self, value as $raw, order)
+            }
+
+            #[inline]
+            fn swap(&self, value: u64, order: Ordering) -> u64 {
+                <$atomic>::swap(self, value as $raw, order) as u64
+            }
+
+            #[inline]
+            fn compare_exchange(&self, current: u64, new: u64, success: Ordering,
+                                failure: Ordering) -> Result<u64, u64> {
+                <$atomic>::compare_exchange(self, current as $raw, new as $raw, success,
+                                            failure)
+                    .map(|prev| prev as u64)
+                    .map_err(|prev| prev as u64)
+            }
+        }
+    };
+}
+
+impl_word!(core::sync::atomic::AtomicU32, u32);
+#[cfg(target_has_atomic = "64")]
+impl_word!(core::sync::atomic::AtomicU64, u64);
+#[cfg(loom)]
+impl_word!(loom::sync::atomic::AtomicU32, u32);
+#[cfg(loom)]
+impl_word!(loom::sync::atomic::AtomicU64, u64);
+
+/// Slots of an open addressing map, as seen by the claim protocol
+pub(crate) trait Table {
+    type Key: Word;
+    type State: Word;
+    type Probe<'a>: Iterator<Item = usize> where Self: 'a;
+
+    /// Get the key word of the slot at `index`
+    fn key_word(&self, index: usize) -> &Self::Key;
+
+    /// Get the state word of the slot at `index`
+    fn state_word(&self, index: usize) -> &Self::State;
+
+    /// Get the raw `(empty, tombstone)` keys marking empty and removed slots
+    fn raw_sentinels(&self) -> (u64, u64);
+
+    /// Iterate over the slots a key with hash `hash` may be stored in, in probe order.
+    /// Unless `all` is set, slots known to hold some other key may be skipped.
+    fn probe_slots(&self, hash: u64, all: bool) -> Self::Probe<'_>;
+
+    /// Called right after the slot at `index` is claimed for a key with hash `hash`
+    fn claimed(&self, index: usize, hash: u64);
+
+    /// Called by the owner of the slot at `index` right before its key is released
+    fn released(&self, index: usize);
+
+    /// Count a compare-exchange lost to another thread
+    fn cas_failure(&self) {}
+}
+
+/// Outcome of probing for the slot belonging to a key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Slot {
+    /// The key was already stored at this index
+    Found(usize),
+
+    /// The key wasn't in the table and this call claimed this index for it
+    Claimed(usize)
+}
+
+/// Linear probe over a table of `size` slots, starting at the slot picked by the low
+/// bits of a hash and visiting every slot once
+pub(crate) struct Linear {
+    start: usize,
+    mask: usize,
+    step: usize
+}
+
+impl Linear {
+    /// Start a probe for `hash` over a table of `size` slots, a power of two
+    pub(crate) fn new(hash: u64, size: usize) -> Linear {
+        Linear { start: hash as usize, mask: size - 1, step: 0 }
+    }
+}
+
+impl Iterator for Linear {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        if self.step > self.mask {
+            return None;
+        }
+
+        let index = self.start.wrapping_add(self.step) & self.mask;
+        self.step += 1;
+        Some(index)
+    }
+}
+
+/// Count one more race lost by an operation that may only retry `retries` more times,
+/// or any number of times if `None`
+pub(crate) fn retry(retries: &mut Option<usize>) -> Result<(), AtomicHashMapError> {
+    match retries {
+        Some(0) => Err(AtomicHashMapError::Contended),
+        Some(left) => {
+            *left -= 1;
+            Ok(())
+        }
+        None => Ok(())
+    }
+}
+
+/// Returns true if the first value of the key in the slot at `index` has been written
+/// and the key hasn't been removed since. Waits for a conditional removal checking
+/// the value of the slot to decide.
+#[inline]
+pub(crate) fn is_published<T: Table + ?Sized>(table: &T, index: usize) -> bool {
+    let state = table.state_word(index).load(Ordering::Acquire);
+    if state & PENDING == 0 {
+        return state & PUBLISHED != 0;
+    }
+
+    wait_settled(table, index) & PUBLISHED != 0
+}
+
+/// Wait for the conditional removal checking the value of the slot at `index` to
+/// decide, returning the state it left the slot in
+#[cold]
+fn wait_settled<T: Table + ?Sized>(table: &T, index: usize) -> u64 {
+    let mut backoff = Backoff::new();
+    loop {
+        backoff.snooze();
+        let state = table.state_word(index).load(Ordering::Acquire);
+        if state & PENDING == 0 {
+            return state;
+        }
+    }
+}
+
+/// Publish the claimed or unpublished slot at `index`, which the caller owns and is
+/// then the only one writing the state of
+#[inline]
+pub(crate) fn publish<T: Table + ?Sized>(table: &T, index: usize) {
+    let state = table.state_word(index);
+    state.store(state.load(Ordering::Relaxed) | PUBLISHED, Ordering::Release);
+}
+
+/// Publishes a freshly claimed slot when dropped, so that a panic while producing
+/// its first value doesn't leave the key waited on forever
+pub(crate) struct Publish<'a, T: Table + ?Sized>(pub(crate) &'a T, pub(crate) usize);
+
+impl<'a, T: Table + ?Sized> Drop for Publish<'a, T> {
+    fn drop(&mut self) {
+        publish(self.0, self.1);
+    }
+}
+
+/// Take ownership of the published slot at `index` holding `key` so that it can be
+/// released. Returns false if the slot isn't published or holds another key.
+pub(crate) fn unpublish<T: Table + ?Sized>(table: &T, index: usize, key: u64) -> bool {
+    let mut state = table.state_word(index).load(Ordering::Acquire);
+    loop {
+        if state & PENDING != 0 {
+            state = wait_settled(table, index);
+        }
+
+        if state & PUBLISHED == 0 || table.key_word(index).load(Ordering::Acquire) != key
+        {
+            return false;
+        }
+
+        // Only the owner of an unpublished slot can change its key, and unpublishing
+        // bumps the generation, so the state still being the one we loaded means the
+        // key we checked still owns the slot
+        let unpublished = (state ^ PUBLISHED).wrapping_add(GENERATION);
+        match table.state_word(index).compare_exchange(state, unpublished,
+                                                       Ordering::AcqRel,
+                                                       Ordering::Acquire) {
+            Ok(_) => return true,
+            Err(curr) => {
+                table.cas_failure();
+
+                // Unless a conditional removal is checking the value, the slot was
+                // unpublished by someone else
+                if curr & PENDING == 0 {
+                    return false;
+                }
+
+                state = curr;
+            }
+        }
+    }
+}
+
+/// Take ownership of the published slot at `index` holding `key` like `unpublish`,
+/// but only if `take` succeeds. `take` runs with the slot still published and marked
+/// `PENDING`, so that other removals of the key wait for it and lookups never see
+/// the key missing if it fails.
+///
+/// Returns whether the slot was taken, false meaning it isn't published or holds
+/// another key, or the error of `take`.
+pub(crate) fn unpublish_if<T, E, F>(table: &T, index: usize, key: u64, take: F)
+        -> Result<bool, E>
+        where T: Table + ?Sized, F: FnOnce() -> Result<(), E> {
+    let state_word = table.state_word(index);
+    let mut state = state_word.load(Ordering::Acquire);
+    loop {
+        if state & PENDING != 0 {
+            state = wait_settled(table, index);
+        }
+
+        if state & PUBLISHED == 0 || table.key_word(index).load(Ordering::Acquire) != key
+        {
+            return Ok(false);
+        }
+
+        // As in `unpublish`, the key we checked owns the slot if the state is unchanged
+        match state_word.compare_exchange(state, state | PENDING, Ordering::AcqRel,
+                                          Ordering::Acquire) {
+            Ok(_) => break,
+            Err(curr) => {
+                table.cas_failure();
+                state = curr;
+            }
+        }
+    }
+
+    // Nobody else changes the state of the slot while it is pending
+    match take() {
+        Ok(()) => {
+            state_word.store((state ^ PUBLISHED).wrapping_add(GENERATION),
+                             Ordering::Release);
+            Ok(true)
+        }
+        Err(err) => {
+            state_word.store(state, Ordering::Release);
+            Err(err)
+        }
+    }
+}
+
+/// Replace `key` at `index` with a tombstone. The slot must be unpublished, and owned
+/// by the caller with its value already taken.
+pub(crate) fn release<T: Table + ?Sized>(table: &T, index: usize, key: u64) {
+    table.released(index);
+    let (_, tombstone_key) = table.raw_sentinels();
+    let prev_key = table.key_word(index).swap(tombstone_key, Ordering::AcqRel);
+    debug_assert_eq!(prev_key, key);
+}
+
+/// Wait for the thread that claimed `index` for `key` to publish it. Returns false if
+/// the key left the slot instead.
+pub(crate) fn wait_published<T: Table + ?Sized>(table: &T, index: usize, key: u64)
+        -> bool {
+    let mut backoff = Backoff::new();
+    loop {
+        let published = is_published(table, index);
+        if table.key_word(index).load(Ordering::Acquire) != key {
+            return false;
+        }
+
+        if published {
+            return true;
+        }
+
+        backoff.snooze();
+    }
+}
+
+/// Find the slot currently holding `key`, whose hash is `hash`
+pub(crate) fn find_slot<T: Table + ?Sized>(table: &T, key: u64, hash: u64)
+        -> Option<usize> {
+    let (empty_key, _) = table.raw_sentinels();
+    for index in table.probe_slots(hash, false) {
+        let curr_key = table.key_word(index).load(Ordering::Acquire);
+        if curr_key == key {
+            return Some(index);
+        }
+
+        if curr_key == empty_key {
+            // Keys are never stored past an empty slot, so the key isn't here
+            return None;
+        }
+
+        // Either another key or a tombstone at this index.. continue
+    }
+
+    None
+}
+
+/// Find the published slot holding `key`, claiming a tombstone or an empty slot for
+/// it if it isn't in the table yet. A claimed slot must be published by the caller
+/// once its value is written.
+pub(crate) fn claim_slot<T: Table + ?Sized>(table: &T, key: u64, hash: u64)
+        -> Result<Slot, AtomicHashMapError> {
+    loop {
+        match probe_slot(table, key, hash, &mut None)? {
+            // The key was removed while we waited for it, look for it again
+            Slot::Found(index) if !wait_published(table, index, key) => continue,
+            slot => return Ok(slot)
+        }
+    }
+}
+
+/// Find the slot holding `key` or claim one for it, without waiting for a found slot
+/// to be published. Each time a tombstone is taken out from under us counts against
+/// `retries`, and with a bound on them a claim never waits on another thread.
+pub(crate) fn probe_slot<T: Table + ?Sized>(table: &T, key: u64, hash: u64,
+                                            retries: &mut Option<usize>)
+        -> Result<Slot, AtomicHashMapError> {
+    let (empty_key, tombstone_key) = table.raw_sentinels();
+    let wait = retries.is_none();
+    let mut backoff = Backoff::new();
+
+    loop {
+        // First tombstone seen along the probe, reused if the key isn't found
+        let mut reuse = None;
+
+        for index in table.probe_slots(hash, false) {
+            let curr_key = table.key_word(index).load(Ordering::Acquire);
+            if curr_key == key {
+                return Ok(Slot::Found(index));
+            }
+
+            if curr_key == tombstone_key {
+                if reuse.is_none() {
+                    reuse = Some(index);
+                }
+                continue;
+            }
+
+            if curr_key != empty_key {
+                // This key is already taken.. continue
+                continue;
+            }
+
+            // Hit the end of the probe without finding the key, so it isn't in the
+            // table. Prefer the earlier tombstone over this empty slot.
+            if let Some(tomb_index) = reuse.take() {
+                if let Some(slot) = claim_index(table, tomb_index, tombstone_key, key,
+                                                hash, wait) {
+                    return Ok(slot);
+                }
+
+                // The tombstone was taken out from under us, try this empty slot
+                // without it
+            }
+
+            if let Some(slot) = claim_index(table, index, empty_key, key, hash, wait) {
+                return Ok(slot);
+            }
+
+            // This key was stored out from under us, can't store there now..
+        }
+
+        // No empty slot left, but a tombstone along the way can still be reused
+        match reuse {
+            Some(tomb_index) => {
+                if let Some(slot) = claim_index(table, tomb_index, tombstone_key, key,
+                                                hash, wait) {
+                    return Ok(slot);
+                }
+            }
+            // No free slot within reach of the key, either because the table is full
+            // or because the probe limit was hit
+            None => return Err(AtomicHashMapError::Full)
+        }
+
+        retry(retries)?;
+        backoff.spin();
+    }
+}
+
+/// Attempt to swap `key` into `index` if it currently holds `expected`. Returns the
+/// slot if `key` now owns it, whether we stored it or another thread raced us with
+/// the same key, and settles the claim against copies of the key claimed at the same
+/// time.
+fn claim_index<T: Table + ?Sized>(table: &T, index: usize, expected: u64, key: u64,
+                                  hash: u64, wait: bool) -> Option<Slot> {
+    // Strong CAS: a spurious failure here would read as the slot being taken and push
+    // the key further down the probe chain. `SeqCst` so that of two claims of the same
+    // key, at least one sees the other in `settle`.
+    match table.key_word(index).compare_exchange(expected, key, Ordering::SeqCst,
+                                                 Ordering::Acquire) {
+        Ok(_) => {
+            table.claimed(index, hash);
+            Some(settle(table, index, key, hash, wait))
+        }
+        Err(prev_key) => {
+            table.cas_failure();
+            (prev_key == key).then_some(Slot::Found(index))
+        }
+    }
+}
+
+/// Check the probe of `key`, just claimed at `index`, for another copy of it
+///
+/// The copy nearest the start of the probe wins. A copy before `index` makes us back
+/// out right away. A copy after it may not have seen ours, so we wait for it to
+/// either back out or be published, backing out in the latter case, or back out
+/// right away if we may not `wait`. Returns the slot `key` ends up in.
+fn settle<T: Table + ?Sized>(table: &T, index: usize, key: u64, hash: u64, wait: bool)
+        -> Slot {
+    let (empty_key, _) = table.raw_sentinels();
+    let mut before = true;
+    for other in table.probe_slots(hash, true) {
+        if other == index {
+            before = false;
+            continue;
+        }
+
+        let curr_key = table.key_word(other).load(Ordering::SeqCst);
+        if curr_key == empty_key && !before {
+            // Keys are never claimed past an empty slot
+            break;
+        }
+
+        if curr_key != key {
+            continue;
+        }
+
+        if before || !wait || wait_published(table, other, key) {
+            // The slot was never published, so it can be released as is
+            release(table, index, key);
+            return Slot::Found(other);
+        }
+
+        // The other copy backed out
+    }
+
+    Slot::Claimed(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_linear() {
+        let mut slots: Vec<usize> = Linear::new(13, 16).collect();
+        assert_eq!(slots[..4], [13, 14, 15, 0]);
+        slots.sort();
+        assert_eq!(slots, (0..16).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_retry() {
+        let mut retries = Some(2);
+        assert_eq!(retry(&mut retries), Ok(()));
+        assert_eq!(retry(&mut retries), Ok(()));
+        assert_eq!(retry(&mut retries), Err(AtomicHashMapError::Contended));
+        let mut retries = None;
+        for _ in 0..1000 {
+            assert_eq!(retry(&mut retries), Ok(()));
+        }
+    }
+}
@@ -0,0 +1,226 @@
+//! Bounded lock-free multi-producer multi-consumer queue
+//!
+//! A ring of slots, each with a sequence number next to its value, after Dmitry
+//! Vyukov's bounded MPMC queue. The sequence of a slot tells whose turn it is: a slot
+//! is free for the push of position `pos` when its sequence is `pos`, and holds the
+//! value of that push when its sequence is `pos + 1`. Producers and consumers claim
+//! positions with a compare-exchange on the tail and head counters, then hand the
+//! slot over by storing the next sequence with `Release`. A consumer therefore sees
+//! everything the producer did before pushing the value it pops.
+//!
+//! A thread that claimed a position but hasn't handed its slot over yet holds up the
+//! threads whose positions come one lap later, so the queue is lock-free only as
+//! long as no thread stalls in that window, like the inserts of `AtomicHashMap`.
+
+use alloc::boxed::Box;
+use core::marker::PhantomData;
+
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::error::AtomicHashMapError;
+use crate::pod::PodU64;
+
+/// Counter on a cache line of its own, so that producers and consumers don't
+/// contend on the same line
+#[repr(align(64))]
+struct Padded(AtomicUsize);
+
+/// One slot of the ring
+struct Slot {
+    /// Position of the next push or pop allowed on this slot, see the module docs
+    sequence: AtomicUsize,
+    value: AtomicU64
+}
+
+/// Bounded lock-free MPMC queue of values of type `T`
+pub struct AtomicQueue<T: PodU64 = u64> {
+    slots: Box<[Slot]>,
+
+    /// Position of the next pop
+    head: Padded,
+
+    /// Position of the next push
+    tail: Padded,
+
+    _values: PhantomData<T>
+}
+
+impl<T: PodU64> AtomicQueue<T> {
+    /// Construct an empty queue holding up to `capacity` values.
+    /// NOTE: Capacity must be a power of two, otherwise `InvalidCapacity` is returned.
+    pub fn new(capacity: usize) -> Result<AtomicQueue<T>, AtomicHashMapError> {
+        if capacity < 2 || !capacity.is_power_of_two() {
+            return Err(AtomicHashMapError::InvalidCapacity);
+        }
+
+        Ok(AtomicQueue {
+            slots: (0..capacity).map(|pos| Slot {
+                sequence: AtomicUsize::new(pos),
+                value: AtomicU64::new(0)
+            }).collect(),
+            head: Padded(AtomicUsize::new(0)),
+            tail: Padded(AtomicUsize::new(0)),
+            _values: PhantomData
+        })
+    }
+
+    /// Get the slot of position `pos`
+    fn slot(&self, pos: usize) -> &Slot {
+        &self.slots[pos & (self.slots.len() - 1)]
+    }
+
+    /// Push `value` to the back of the queue, or return `AtomicHashMapError::Full`
+    /// if it holds `capacity` values
+    pub fn push(&self, value: T) -> Result<(), AtomicHashMapError> {
+        let mut pos = self.tail.0.load(Ordering::Relaxed);
+        loop {
+            let slot = self.slot(pos);
+            let sequence = slot.sequence.load(Ordering::Acquire);
+
+            match sequence.wrapping_sub(pos) as isize {
+                // Free for this position, try to claim it
+                0 => match self.tail.0.compare_exchange_weak(pos, pos.wrapping_add(1),
+                                                             Ordering::Relaxed,
+                                                             Ordering::Relaxed) {
+                    Ok(_) => {
+                        slot.value.store(value.to_u64(), Ordering::Relaxed);
+                        slot.sequence.store(pos.wrapping_add(1), Ordering::Release);
+                        return Ok(());
+                    }
+                    Err(tail) => pos = tail
+                },
+
+                // Still holds the value pushed a lap ago
+                diff if diff < 0 => return Err(AtomicHashMapError::Full),
+
+                // Another producer claimed this position, catch up
+                _ => pos = self.tail.0.load(Ordering::Relaxed)
+            }
+        }
+    }
+
+    /// Pop the value at the front of the queue, or `None` if it is empty
+    pub fn pop(&self) -> Option<T> {
+        let mut pos = self.head.0.load(Ordering::Relaxed);
+        loop {
+            let slot = self.slot(pos);
+            let sequence = slot.sequence.load(Ordering::Acquire);
+
+            match sequence.wrapping_sub(pos.wrapping_add(1)) as isize {
+                // Holds the value of this position, try to claim it
+                0 => match self.head.0.compare_exchange_weak(pos, pos.wrapping_add(1),
+                                                             Ordering::Relaxed,
+                                                             Ordering::Relaxed) {
+                    Ok(_) => {
+                        let value = slot.value.load(Ordering::Relaxed);
+
+                        // Free the slot for the push one lap later
+                        slot.sequence.store(pos.wrapping_add(self.slots.len()),
+                                            Ordering::Release);
+                        return Some(T::from_u64(value));
+                    }
+                    Err(head) => pos = head
+                },
+
+                // Not pushed yet
+                diff if diff < 0 => return None,
+
+                // Another consumer claimed this position, catch up
+                _ => pos = self.head.0.load(Ordering::Relaxed)
+            }
+        }
+    }
+
+    /// Get the number of values in the queue. Only a hint while other threads push
+    /// or pop.
+    pub fn len(&self) -> usize {
+        loop {
+            let tail = self.tail.0.load(Ordering::Acquire);
+            let head = self.head.0.load(Ordering::Acquire);
+
+            // Retry if the tail moved, so that the two were read at the same time
+            if self.tail.0.load(Ordering::Acquire) == tail {
+                return tail.wrapping_sub(head).min(self.slots.len());
+            }
+        }
+    }
+
+    /// Returns true if the queue holds no values
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the number of values the queue holds at most
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    #[test]
+    fn test_queue() {
+        let queue: AtomicQueue<i32> = AtomicQueue::new(4).unwrap();
+        assert_eq!(queue.pop(), None);
+
+        for value in [1, -2, 3, -4] {
+            queue.push(value).unwrap();
+        }
+        assert_eq!(queue.push(5), Err(AtomicHashMapError::Full));
+        assert_eq!(queue.len(), 4);
+
+        // Values come out in order, and wrapping around reuses the slots
+        assert_eq!(queue.pop(), Some(1));
+        assert_eq!(queue.pop(), Some(-2));
+        queue.push(5).unwrap();
+        queue.push(6).unwrap();
+        assert_eq!((0..4).map(|_| queue.pop().unwrap()).collect::<Vec<_>>(),
+                   vec![3, -4, 5, 6]);
+        assert!(queue.is_empty());
+
+        assert!(AtomicQueue::<u64>::new(6).is_err());
+    }
+
+    #[test]
+    fn test_queue_threads() {
+        let queue: AtomicQueue = AtomicQueue::new(64).unwrap();
+        let sum = AtomicU64::new(0);
+        let popped = AtomicU64::new(0);
+
+        thread::scope(|scope| {
+            for thread in 0..4u64 {
+                let queue = &queue;
+                scope.spawn(move || {
+                    for value in 1..=10_000 {
+                        while queue.push(thread * 10_000 + value).is_err() {
+                            thread::yield_now();
+                        }
+                    }
+                });
+            }
+
+            for _ in 0..4 {
+                let (queue, sum, popped) = (&queue, &sum, &popped);
+                scope.spawn(move || {
+                    while popped.load(Ordering::Relaxed) < 40_000 {
+                        match queue.pop() {
+                            Some(value) => {
+                                sum.fetch_add(value, Ordering::Relaxed);
+                                popped.fetch_add(1, Ordering::Relaxed);
+                            }
+                            None => thread::yield_now()
+                        }
+                    }
+                });
+            }
+        });
+
+        // Every value was popped exactly once
+        assert_eq!(sum.load(Ordering::Relaxed), (1..=40_000).sum::<u64>());
+        assert!(queue.is_empty());
+    }
+}
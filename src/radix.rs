@@ -0,0 +1,541 @@
+//! Lock-free radix tree from `u64` keys to `u64` values
+//!
+//! Keys are split into bytes, most significant first. The first seven bytes pick a
+//! path through seven levels of branch nodes and the last byte picks the slot of the
+//! key in a leaf. Nodes are allocated the first time a key needs them and installed
+//! with a compare-exchange of the null child pointer, so the tree grows without
+//! bound and `insert` never returns `Full`. A thread that loses the race to install
+//! a node frees its own and carries on down the node that won. Nodes are only freed
+//! when the tree is dropped, so a reference to one stays valid for as long as the
+//! tree is borrowed.
+//!
+//! Fanout is 256 rather than the 65536 of a 16-bit radix: a node of 65536 pointers
+//! takes 512KB, which a sparse tree would pay for every key on a new path. With
+//! 8-bit fanout a branch node takes 2KB, at the cost of twice as many levels.
+//!
+//! Since keys sit in the tree in order, walking the children of each node in order
+//! visits keys in order, and `prefix_scan` only descends into the children that can
+//! hold keys with the prefix.
+//!
+//! Every leaf slot has a state word and two value cells, one of which holds the value
+//! of the key while it is present. The state word holds whether the key is present,
+//! which cell is active, whether a writer owns the slot, and a version bumped every
+//! time the key is inserted, overwritten or removed. Every operation on a key takes
+//! effect with a single change of its state word:
+//!
+//! * `insert` takes ownership of the slot, writes the new value to the inactive cell
+//!   and then publishes it by storing a state word with the cells swapped, the key
+//!   present and the version bumped. The previous value is read from the active cell
+//!   while the slot is owned, so it is the one the new value replaced.
+//! * `remove` reads the active cell and clears the presence bit with a compare-exchange
+//!   of the state word it read the cell under. The active cell is only written after
+//!   a version change, so the value it returns is the one it removed.
+//! * `get` reads the state word, the active cell and the state word again, and retries
+//!   if the version changed in between. It never waits on a writer, since the active
+//!   cell isn't written while a writer owns the slot.
+//!
+//! Writers of the same key take turns, so an insert or remove waits while another
+//! insert of the same key owns its slot, as with a pending insert in
+//! `AtomicHashMap`. Writers of different keys never wait on each other.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::ptr;
+
+use core::sync::atomic::{self, AtomicIsize, AtomicPtr, AtomicU64, Ordering};
+
+use crate::backoff::Backoff;
+
+/// Number of children of a branch node and slots of a leaf
+const FANOUT: usize = 256;
+
+/// Number of bits of the key consumed by each level
+const BITS: u32 = 8;
+
+/// Number of levels of branch nodes above the leaves
+const BRANCH_LEVELS: u32 = 64 / BITS - 1;
+
+/// Bit of a state word set while the key of the slot is in the tree
+const PRESENT: u64 = 1 << 0;
+
+/// Bit of a state word set while a writer owns the slot
+const BUSY: u64 = 1 << 1;
+
+/// Bit of a state word picking the cell holding the value of the key
+const ACTIVE: u64 = 1 << 2;
+
+/// Lowest bit of the version of a state word, above its flags
+const VERSION_ONE: u64 = 1 << 3;
+
+/// Entry of one key in a leaf
+struct Slot<'a> {
+    /// Presence, active cell, ownership and version of the slot, see the module docs
+    state: &'a AtomicU64,
+
+    /// Cells holding the value of the key, only the active one while it is present.
+    /// The inactive one is only written by the writer owning the slot.
+    values: &'a [AtomicU64; 2]
+}
+
+impl<'a> Slot<'a> {
+    /// Get the cell picked by the state word `state`
+    fn active(&self, state: u64) -> &'a AtomicU64 {
+        &self.values[(state & ACTIVE != 0) as usize]
+    }
+
+    /// Get the value of the key, if it is present
+    fn load(&self) -> Option<u64> {
+        let mut state = self.state.load(Ordering::Acquire);
+        loop {
+            if state & PRESENT == 0 {
+                return None;
+            }
+
+            let value = self.active(state).load(Ordering::Relaxed);
+
+            // Order the read of the cell before checking that it wasn't rewritten
+            atomic::fence(Ordering::Acquire);
+            let curr = self.state.load(Ordering::Relaxed);
+            if curr & !BUSY == state & !BUSY {
+                return Some(value);
+            }
+
+            state = curr;
+        }
+    }
+
+    /// Store `value` for the key, returning the value it replaced if the key was
+    /// present
+    fn store(&self, value: u64) -> Option<u64> {
+        let state = self.own();
+
+        let old = self.active(state).load(Ordering::Relaxed);
+        self.active(state ^ ACTIVE).store(value, Ordering::Relaxed);
+
+        // Publish the new value along with the cell holding it
+        let new = (state & !(VERSION_ONE - 1)).wrapping_add(VERSION_ONE) |
+            (state & ACTIVE) ^ ACTIVE | PRESENT;
+        self.state.store(new, Ordering::Release);
+
+        if state & PRESENT != 0 { Some(old) } else { None }
+    }
+
+    /// Take ownership of the slot, waiting for the current owner to publish if there
+    /// is one. Returns the state word from before.
+    fn own(&self) -> u64 {
+        let mut backoff = Backoff::new();
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            if state & BUSY != 0 {
+                backoff.snooze();
+                state = self.state.load(Ordering::Relaxed);
+                continue;
+            }
+
+            match self.state.compare_exchange_weak(state, state | BUSY, Ordering::Acquire,
+                                                   Ordering::Relaxed) {
+                Ok(_) => return state,
+                Err(curr) => state = curr
+            }
+        }
+    }
+
+    /// Take the key out, returning its value if it was present
+    fn take(&self) -> Option<u64> {
+        let mut backoff = Backoff::new();
+        let mut state = self.state.load(Ordering::Acquire);
+        loop {
+            if state & PRESENT == 0 {
+                return None;
+            }
+
+            if state & BUSY != 0 {
+                // The key is being overwritten, which has to happen before the remove
+                backoff.snooze();
+                state = self.state.load(Ordering::Acquire);
+                continue;
+            }
+
+            // The active cell isn't written again before the version changes, so the
+            // value is the one removed if the state word is still the same
+            let value = self.active(state).load(Ordering::Relaxed);
+            let new = (state & !(VERSION_ONE - 1)).wrapping_add(VERSION_ONE) |
+                state & ACTIVE;
+            match self.state.compare_exchange_weak(state, new, Ordering::AcqRel,
+                                                   Ordering::Acquire) {
+                Ok(_) => return Some(value),
+                Err(curr) => state = curr
+            }
+        }
+    }
+}
+
+/// One node of the tree
+enum Node {
+    /// Node on one of the upper levels, pointing to the nodes below it
+    Branch([AtomicPtr<Node>; FANOUT]),
+
+    /// Node on the bottom level, holding the entries of up to `FANOUT` keys
+    Leaf {
+        /// State word of each slot
+        states: [AtomicU64; FANOUT],
+
+        /// Value cells of each slot, boxed so that a leaf takes up no more than a
+        /// branch node
+        values: Box<[[AtomicU64; 2]; FANOUT]>
+    }
+}
+
+impl Node {
+    /// Allocate an empty node for level `level`
+    fn new(level: u32) -> *mut Node {
+        let node = if level < BRANCH_LEVELS {
+            Node::Branch(core::array::from_fn(|_| AtomicPtr::new(ptr::null_mut())))
+        } else {
+            Node::Leaf {
+                states: core::array::from_fn(|_| AtomicU64::new(0)),
+                values: Box::new(core::array::from_fn(|_| {
+                    [AtomicU64::new(0), AtomicU64::new(0)]
+                }))
+            }
+        };
+
+        Box::into_raw(Box::new(node))
+    }
+
+    /// Get the slot at `index` of a leaf
+    fn slot(&self, index: usize) -> Slot<'_> {
+        match self {
+            Node::Leaf { states, values } => Slot {
+                state: &states[index],
+                values: &values[index]
+            },
+            Node::Branch(_) => unreachable!("Branch on the bottom level")
+        }
+    }
+}
+
+/// Get the index of `key` in a node on level `level`
+fn index(key: u64, level: u32) -> usize {
+    (key >> (64 - BITS * (level + 1))) as usize & (FANOUT - 1)
+}
+
+/// Lock-free radix tree from `u64` keys to `u64` values, kept in key order
+pub struct AtomicRadixTree {
+    root: *mut Node,
+
+    /// Number of keys in the tree, briefly negative when a remove counts a key before
+    /// its insert does
+    count: AtomicIsize
+}
+
+// SAFETY: Nodes are only reached through atomics and only freed by `Drop`, which
+// takes the tree by value
+unsafe impl Send for AtomicRadixTree {}
+unsafe impl Sync for AtomicRadixTree {}
+
+impl AtomicRadixTree {
+    /// Construct an empty tree
+    pub fn new() -> AtomicRadixTree {
+        AtomicRadixTree {
+            root: Node::new(0),
+            count: AtomicIsize::new(0)
+        }
+    }
+
+    /// Get the leaf holding `key`, allocating the nodes on its path if `create` is
+    /// set. Returns `None` if the path is missing and `create` isn't set.
+    fn leaf(&self, key: u64, create: bool) -> Option<&Node> {
+        // SAFETY: Nodes are only freed when the tree is dropped
+        let mut node = unsafe { &*self.root };
+
+        for level in 0..BRANCH_LEVELS {
+            let children = match node {
+                Node::Branch(children) => children,
+                Node::Leaf { .. } => unreachable!("Leaf above the bottom level")
+            };
+
+            let child = &children[index(key, level)];
+            let mut next = child.load(Ordering::Acquire);
+            if next.is_null() {
+                if !create {
+                    return None;
+                }
+
+                let new = Node::new(level + 1);
+                let res = child.compare_exchange(ptr::null_mut(), new, Ordering::AcqRel,
+                                                 Ordering::Acquire);
+                next = match res {
+                    Ok(_) => new,
+                    Err(curr) => {
+                        // Another thread installed the node first
+                        // SAFETY: `new` was never shared
+                        unsafe { drop(Box::from_raw(new)); }
+                        curr
+                    }
+                };
+            }
+
+            // SAFETY: Nodes are only freed when the tree is dropped
+            node = unsafe { &*next };
+        }
+
+        Some(node)
+    }
+
+    /// Atomically set a key:value in the tree
+    ///
+    /// Returns the value previously stored for this key, or `None` if the key was
+    /// newly inserted.
+    pub fn insert(&self, key: u64, value: u64) -> Option<u64> {
+        let leaf = self.leaf(key, true).expect("Path to the leaf was just created");
+        let old = leaf.slot(index(key, BRANCH_LEVELS)).store(value);
+        if old.is_none() {
+            self.count.fetch_add(1, Ordering::Relaxed);
+        }
+
+        old
+    }
+
+    /// Atomically get the value of a key from the tree
+    pub fn get(&self, key: &u64) -> Option<u64> {
+        self.leaf(*key, false)?.slot(index(*key, BRANCH_LEVELS)).load()
+    }
+
+    /// Check if a key is in the tree
+    pub fn contains_key(&self, key: &u64) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Remove a key from the tree, returning its value if it was in the tree
+    ///
+    /// The nodes on the path of the key are kept for later inserts.
+    pub fn remove(&self, key: &u64) -> Option<u64> {
+        let value = self.leaf(*key, false)?.slot(index(*key, BRANCH_LEVELS)).take()?;
+        self.count.fetch_sub(1, Ordering::Relaxed);
+        Some(value)
+    }
+
+    /// Get the entries whose keys start with the highest `bits` bits of `prefix`, in
+    /// key order. Only a snapshot while other threads insert or remove.
+    /// NOTE: Panics if `bits` is over 64.
+    pub fn prefix_scan(&self, prefix: u64, bits: u32) -> Vec<(u64, u64)> {
+        assert!(bits <= 64, "Prefix longer than a key");
+
+        let mask = if bits == 0 { 0 } else { u64::MAX << (64 - bits) };
+        let low = prefix & mask;
+        let high = low | !mask;
+
+        let mut entries = Vec::new();
+        // SAFETY: Nodes are only freed when the tree is dropped
+        AtomicRadixTree::collect(unsafe { &*self.root }, 0, 0, low, high, &mut entries);
+        entries
+    }
+
+    /// Push the entries below `node`, on level `level` and reached through the key
+    /// bits in `base`, whose keys are in `low..=high`
+    fn collect(node: &Node, level: u32, base: u64, low: u64, high: u64,
+               entries: &mut Vec<(u64, u64)>) {
+        // Number of low key bits below this level
+        let shift = 64 - BITS * (level + 1);
+
+        match node {
+            Node::Branch(children) => {
+                for (i, child) in children.iter().enumerate() {
+                    let first = base | (i as u64) << shift;
+                    let last = first | ((1 << shift) - 1);
+                    if last < low || first > high {
+                        continue;
+                    }
+
+                    let next = child.load(Ordering::Acquire);
+                    if !next.is_null() {
+                        // SAFETY: Nodes are only freed when the tree is dropped
+                        let next = unsafe { &*next };
+                        AtomicRadixTree::collect(next, level + 1, first, low, high,
+                                                 entries);
+                    }
+                }
+            }
+            Node::Leaf { .. } => {
+                for i in 0..FANOUT {
+                    let key = base | i as u64;
+                    if key < low || key > high {
+                        continue;
+                    }
+
+                    if let Some(value) = node.slot(i).load() {
+                        entries.push((key, value));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Get every entry in the tree, in key order. Only a snapshot while other
+    /// threads insert or remove.
+    pub fn to_vec(&self) -> Vec<(u64, u64)> {
+        self.prefix_scan(0, 0)
+    }
+
+    /// Get the number of keys in the tree. Only a hint while other threads insert
+    /// or remove.
+    pub fn len(&self) -> usize {
+        self.count.load(Ordering::Relaxed).max(0) as usize
+    }
+
+    /// Returns true if the tree holds no keys
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for AtomicRadixTree {
+    fn default() -> AtomicRadixTree {
+        AtomicRadixTree::new()
+    }
+}
+
+impl Drop for AtomicRadixTree {
+    fn drop(&mut self) {
+        /// Free `node` and every node below it
+        fn free(node: *mut Node) {
+            // SAFETY: Every node was allocated by `Node::new` and is reachable from
+            // exactly one pointer
+            let node = unsafe { Box::from_raw(node) };
+            if let Node::Branch(children) = &*node {
+                for child in children {
+                    let child = child.load(Ordering::Relaxed);
+                    if !child.is_null() {
+                        free(child);
+                    }
+                }
+            }
+        }
+
+        free(self.root);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    #[test]
+    fn test_radix() {
+        let tree = AtomicRadixTree::new();
+        assert!(tree.is_empty());
+        assert_eq!(tree.get(&5), None);
+        assert_eq!(tree.remove(&5), None);
+
+        assert_eq!(tree.insert(5, 50), None);
+        assert_eq!(tree.insert(0, 1), None);
+        assert_eq!(tree.insert(u64::MAX, 2), None);
+        assert_eq!(tree.insert(0x1234_0000_0000_0000, 3), None);
+        assert_eq!(tree.insert(5, 51), Some(50));
+        assert_eq!(tree.len(), 4);
+
+        assert_eq!(tree.get(&5), Some(51));
+        assert_eq!(tree.get(&0), Some(1));
+        assert_eq!(tree.get(&u64::MAX), Some(2));
+        assert!(tree.contains_key(&0x1234_0000_0000_0000));
+        assert!(!tree.contains_key(&6));
+
+        assert_eq!(tree.to_vec(), vec![(0, 1), (5, 51), (0x1234_0000_0000_0000, 3),
+                                       (u64::MAX, 2)]);
+
+        assert_eq!(tree.remove(&0), Some(1));
+        assert_eq!(tree.remove(&0), None);
+        assert_eq!(tree.get(&0), None);
+        assert_eq!(tree.len(), 3);
+
+        // Keys can come back after being removed
+        assert_eq!(tree.insert(0, 4), None);
+        assert_eq!(tree.get(&0), Some(4));
+    }
+
+    #[test]
+    fn test_radix_prefix_scan() {
+        let tree = AtomicRadixTree::new();
+        for key in 0..1000u64 {
+            tree.insert(key << 20 | 0xabc, key);
+        }
+
+        // Keys 0x300..0x3ff shifted up by 20 bits
+        let entries = tree.prefix_scan(0x300 << 20, 64 - 28);
+        assert_eq!(entries, (0x300..0x3e8).map(|key| (key << 20 | 0xabc, key))
+                   .collect::<Vec<_>>());
+
+        assert_eq!(tree.prefix_scan(5 << 20 | 0xabc, 64), vec![(5 << 20 | 0xabc, 5)]);
+        assert_eq!(tree.prefix_scan(5 << 20, 64), vec![]);
+        assert_eq!(tree.prefix_scan(u64::MAX, 1), vec![]);
+        assert_eq!(tree.prefix_scan(0, 0).len(), 1000);
+    }
+
+    #[test]
+    fn test_radix_threads() {
+        let tree = AtomicRadixTree::new();
+
+        // Threads race to build the same paths
+        thread::scope(|scope| {
+            for thread in 0..4u64 {
+                let tree = &tree;
+                scope.spawn(move || {
+                    for key in 0..10_000u64 {
+                        let key = key.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 2;
+                        tree.insert(thread << 62 | key, thread);
+                    }
+                });
+            }
+        });
+
+        assert_eq!(tree.len(), 40_000);
+        let entries = tree.to_vec();
+        assert_eq!(entries.len(), 40_000);
+        assert!(entries.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        for (key, value) in entries {
+            assert_eq!(key >> 62, value);
+        }
+    }
+
+    #[test]
+    fn test_radix_same_keys() {
+        let tree = AtomicRadixTree::new();
+
+        // Threads overwrite and remove the same keys. Every value inserted has to come
+        // back exactly once, from the insert replacing it, the remove taking it out or
+        // the tree at the end.
+        let returned: Vec<Vec<u64>> = thread::scope(|scope| {
+            let threads: Vec<_> = (0..4u64).map(|thread| {
+                let tree = &tree;
+                scope.spawn(move || {
+                    let mut returned = Vec::new();
+                    for round in 0..2000u64 {
+                        let key = round % 8;
+                        let value = thread << 32 | round;
+                        returned.extend(tree.insert(key, value));
+                        if round % 3 == 0 {
+                            returned.extend(tree.remove(&((round + thread) % 8)));
+                        }
+                    }
+                    returned
+                })
+            }).collect();
+
+            threads.into_iter().map(|thread| thread.join().unwrap()).collect()
+        });
+
+        let mut values: Vec<u64> = returned.into_iter().flatten().collect();
+        values.extend(tree.to_vec().into_iter().map(|(_, value)| value));
+        values.sort_unstable();
+
+        let mut expected: Vec<u64> = (0..4u64)
+            .flat_map(|thread| (0..2000).map(move |round| thread << 32 | round))
+            .collect();
+        expected.sort_unstable();
+        assert_eq!(values, expected);
+        assert_eq!(tree.len(), tree.to_vec().len());
+    }
+}
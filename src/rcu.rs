@@ -0,0 +1,236 @@
+//! Read-copy-update cell for read-mostly data
+//!
+//! An `Rcu<T>` holds the current version of a value as a boxed pointer in an
+//! `AtomicPtr`. Readers pin a handle of the cell's `Collector` and get a plain
+//! reference to the current version, valid for as long as they stay pinned, without
+//! writing to any shared memory besides their own participant. Writers build a new
+//! version from a copy of the current one and swap it in with a compare-exchange,
+//! retrying on a version published in between, and defer freeing the old version
+//! until every reader pinned at the time has unpinned.
+//!
+//! Readers may keep reading the old version for a while after an update, and see
+//! each version whole.
+
+use alloc::boxed::Box;
+
+use crate::sync::atomic::{AtomicPtr, Ordering};
+
+use crate::epoch::{Collector, Guard, LocalHandle, SendPtr};
+
+/// Read-copy-update cell of a value of type `T`
+pub struct Rcu<T: Send + Sync + 'static> {
+    /// Current version, owned by the cell
+    ptr: AtomicPtr<T>,
+
+    /// Reclaims replaced versions
+    collector: Collector
+}
+
+impl<T: Send + Sync + 'static> Rcu<T> {
+    /// Construct a cell holding `value`
+    pub fn new(value: T) -> Rcu<T> {
+        Rcu {
+            ptr: AtomicPtr::new(Box::into_raw(Box::new(value))),
+            collector: Collector::new()
+        }
+    }
+
+    /// Register a handle for a reader of the cell, to be pinned around reads
+    pub fn register(&self) -> LocalHandle<'_> {
+        self.collector.register()
+    }
+
+    /// Get the current version, which stays alive for as long as `guard` is.
+    /// NOTE: Panics if `guard` doesn't come from a handle of this cell.
+    pub fn read<'g>(&self, guard: &'g Guard<'_>) -> &'g T {
+        assert!(core::ptr::eq(guard.collector(), &self.collector),
+                "Guard of another Rcu");
+
+        // SAFETY: Versions are only freed once no thread pinned before they were
+        // replaced is still pinned, and `guard` keeps this thread pinned
+        unsafe { &*self.ptr.load(Ordering::Acquire) }
+    }
+
+    /// Run `f` on the current version, registering and pinning a handle for it
+    pub fn read_with<R, F: FnOnce(&T) -> R>(&self, f: F) -> R {
+        let handle = self.register();
+        let guard = handle.pin();
+        f(self.read(&guard))
+    }
+
+    /// Replace the current version with `f` of it. `f` is run again on the newer
+    /// version whenever another writer replaces the version it was given first.
+    pub fn update<F: FnMut(&T) -> T>(&self, mut f: F) {
+        let handle = self.register();
+        let guard = handle.pin();
+
+        let mut curr = self.ptr.load(Ordering::Acquire);
+        loop {
+            // SAFETY: `curr` was read while pinned
+            let new = Box::into_raw(Box::new(f(unsafe { &*curr })));
+            match self.ptr.compare_exchange(curr, new, Ordering::AcqRel,
+                                            Ordering::Acquire) {
+                Ok(_) => break,
+                Err(actual) => {
+                    // SAFETY: `new` was never published
+                    drop(unsafe { Box::from_raw(new) });
+                    curr = actual;
+                }
+            }
+        }
+
+        self.retire(&guard, curr);
+    }
+
+    /// Replace the current version with `value`
+    pub fn store(&self, value: T) {
+        let handle = self.register();
+        let guard = handle.pin();
+        let old = self.ptr.swap(Box::into_raw(Box::new(value)), Ordering::AcqRel);
+        self.retire(&guard, old);
+    }
+
+    /// Free the replaced version `old` once no reader can be reading it
+    fn retire(&self, guard: &Guard<'_>, old: *mut T) {
+        let old = SendPtr(old);
+        guard.defer(move || {
+            let old = old;
+            // SAFETY: The version was taken out of the cell by the caller
+            drop(unsafe { Box::from_raw(old.0) });
+        });
+    }
+
+    /// Take the current version out of the cell
+    pub fn into_inner(self) -> T {
+        let mut rcu = core::mem::ManuallyDrop::new(self);
+        let ptr = rcu.ptr.load(Ordering::Relaxed);
+
+        // SAFETY: The cell is never used again, its collector is dropped here and its
+        // current version is handed to the caller
+        unsafe {
+            core::ptr::drop_in_place(&mut rcu.collector);
+            *Box::from_raw(ptr)
+        }
+    }
+}
+
+impl<T: Send + Sync + Default + 'static> Default for Rcu<T> {
+    fn default() -> Rcu<T> {
+        Rcu::new(T::default())
+    }
+}
+
+impl<T: Send + Sync + 'static> Drop for Rcu<T> {
+    fn drop(&mut self) {
+        // SAFETY: The cell owns its current version. Replaced versions are freed with
+        // the collector.
+        drop(unsafe { Box::from_raw(self.ptr.load(Ordering::Relaxed)) });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    #[test]
+    fn test_rcu() {
+        let rcu = Rcu::new(vec![1, 2]);
+        let handle = rcu.register();
+
+        let guard = handle.pin();
+        let old = rcu.read(&guard);
+        rcu.update(|routes| {
+            let mut routes = routes.clone();
+            routes.push(3);
+            routes
+        });
+
+        // The reader keeps its version until it unpins
+        assert_eq!(old, &[1, 2]);
+        assert_eq!(rcu.read(&guard), &[1, 2, 3]);
+        drop(guard);
+
+        rcu.store(vec![4]);
+        assert_eq!(rcu.read_with(|routes| routes.len()), 1);
+        drop(handle);
+        assert_eq!(rcu.into_inner(), vec![4]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_rcu_foreign_guard() {
+        let rcu = Rcu::new(1);
+        let other = Collector::new();
+        let handle = other.register();
+        rcu.read(&handle.pin());
+    }
+
+    #[test]
+    fn test_rcu_drop() {
+        /// Value that counts its drops
+        struct Tracked(Arc<AtomicUsize>);
+
+        impl Drop for Tracked {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let rcu = Rcu::new(Tracked(dropped.clone()));
+        for _ in 0..10 {
+            rcu.store(Tracked(dropped.clone()));
+        }
+        drop(rcu);
+        assert_eq!(dropped.load(Ordering::Relaxed), 11);
+    }
+
+    #[test]
+    fn test_rcu_threads() {
+        let rcu: Rcu<BTreeMap<u64, u64>> = Rcu::default();
+
+        // Writers add routes while readers check every version they see is whole:
+        // each writer's routes are added in order, so a version holding route `n`
+        // of a writer holds all of its routes before it
+        thread::scope(|scope| {
+            for thread in 0..2u64 {
+                let rcu = &rcu;
+                scope.spawn(move || {
+                    for i in 0..500 {
+                        rcu.update(|routes| {
+                            let mut routes = routes.clone();
+                            routes.insert(thread << 32 | i, i);
+                            routes
+                        });
+                    }
+                });
+            }
+
+            for _ in 0..2 {
+                let rcu = &rcu;
+                scope.spawn(move || {
+                    let handle = rcu.register();
+                    for _ in 0..2_000 {
+                        let guard = handle.pin();
+                        for thread in 0..2u64 {
+                            let routes = rcu.read(&guard);
+                            let added = routes.range(thread << 32..(thread + 1) << 32)
+                                .count() as u64;
+                            if added > 0 {
+                                let last = thread << 32 | (added - 1);
+                                assert_eq!(routes.get(&last), Some(&(added - 1)));
+                            }
+                        }
+                    }
+                });
+            }
+        });
+
+        assert_eq!(rcu.read_with(|routes| routes.len()), 1_000);
+    }
+}
@@ -0,0 +1,426 @@
+//! Robin Hood variant of `AtomicHashMap` for read-heavy workloads
+//!
+//! Inserting a key steals the slot of any key sitting closer to its home slot than the
+//! new key is to its own, and removing a key shifts the keys after it back towards
+//! their home slots. Probe lengths stay short and even at high load factors, and a
+//! lookup for a missing key stops as soon as it reaches a key closer to home than it
+//! would be, instead of running to the next empty slot.
+//!
+//! Moving entries between slots can't be done with a single compare-exchange, so
+//! writers take a lock while readers never do. Readers are validated by a sequence
+//! counter instead, and a lookup that overlapped a move or a removal is retried.
+//! Writes that don't move any entry, such as replacing the value of an existing key,
+//! leave the counter alone.
+//!
+//! # Memory ordering
+//!
+//! A new key is stored with `Release` after its value, and keys are loaded with
+//! `Acquire`, so a thread that finds a key sees its value. See the `seqlock` module
+//! for the ordering of moves.
+
+use std::hash::{BuildHasher, Hasher};
+use std::marker::PhantomData;
+use std::sync::{Mutex, PoisonError};
+
+use core::sync::atomic::{Ordering, AtomicU64};
+
+use crate::atomichashmap::{AtomicHashMapError, BuildMurmurHasher};
+use crate::pod::PodU64;
+use crate::seqlock::MoveSeq;
+
+/// Key marker for a slot that holds no key. There are no tombstones since removal
+/// shifts entries back instead.
+const EMPTY_KEY: u64 = 0;
+
+/// One slot of the table
+struct Slot {
+    key: AtomicU64,
+    value: AtomicU64
+}
+
+/// Hashmap from keys of type `K` to values of type `V` using Robin Hood hashing
+///
+/// Reads are lock-free and writes are serialized. See the module documentation for
+/// the trade-off against `AtomicHashMap`. Keys are compared by their `PodU64`
+/// representation, and a key of 0 is reserved to mark empty slots.
+pub struct AtomicRobinHoodMap<K: PodU64 = u64, V: PodU64 = u64, S = BuildMurmurHasher> {
+    slots: Box<[Slot]>,
+    size: usize,
+
+    /// Number of keys currently in the table
+    count: AtomicU64,
+
+    /// Lets readers detect a writer moving entries between slots
+    seq: MoveSeq,
+
+    /// Held by the thread currently writing to the table
+    writer: Mutex<()>,
+
+    /// Builds the hasher used to find the home slot of each key
+    hasher: S,
+
+    _types: PhantomData<(K, V)>
+}
+
+unsafe impl<K: PodU64, V: PodU64, S: Send> Send for AtomicRobinHoodMap<K, V, S> {}
+unsafe impl<K: PodU64, V: PodU64, S: Sync> Sync for AtomicRobinHoodMap<K, V, S> {}
+
+impl<K: PodU64, V: PodU64> AtomicRobinHoodMap<K, V> {
+    /// Construct a new AtomicRobinHoodMap with a given size.
+    /// NOTE: Size must be a power of two, otherwise `InvalidCapacity` is returned.
+    pub fn new(size: usize) -> Result<AtomicRobinHoodMap<K, V>, AtomicHashMapError> {
+        AtomicRobinHoodMap::with_hasher(size, BuildMurmurHasher::default())
+    }
+}
+
+impl<K: PodU64, V: PodU64, S: BuildHasher> AtomicRobinHoodMap<K, V, S> {
+    /// Construct a new AtomicRobinHoodMap with a given size, hashing keys with
+    /// `hasher`.
+    /// NOTE: Size must be a power of two, otherwise `InvalidCapacity` is returned.
+    pub fn with_hasher(size: usize, hasher: S)
+            -> Result<AtomicRobinHoodMap<K, V, S>, AtomicHashMapError> {
+        if size < 2 || !size.is_power_of_two() {
+            return Err(AtomicHashMapError::InvalidCapacity);
+        }
+
+        let slots = (0..size).map(|_| Slot {
+            key: AtomicU64::new(EMPTY_KEY),
+            value: AtomicU64::new(0)
+        }).collect();
+
+        Ok(AtomicRobinHoodMap {
+            slots,
+            size,
+            count: AtomicU64::new(0),
+            seq: MoveSeq::new(),
+            writer: Mutex::new(()),
+            hasher,
+            _types: PhantomData
+        })
+    }
+
+    /// Get the raw slot representation of `key`, which must not be the empty marker
+    fn raw_key(&self, key: K) -> Result<u64, AtomicHashMapError> {
+        let key = key.to_u64();
+        if key == EMPTY_KEY {
+            return Err(AtomicHashMapError::InvalidKey);
+        }
+
+        Ok(key)
+    }
+
+    /// Get the index of the home slot of the raw `key`
+    fn home(&self, key: u64) -> usize {
+        let mut hasher = self.hasher.build_hasher();
+        hasher.write_u64(key);
+        hasher.finish() as usize & (self.size - 1)
+    }
+
+    /// Get how far the raw `key` stored at `index` is from its home slot
+    fn distance(&self, key: u64, index: usize) -> usize {
+        index.wrapping_sub(self.home(key)) & (self.size - 1)
+    }
+
+    /// Find the slot holding `key`. Only reliable while no writer moves entries.
+    fn find_slot(&self, key: u64) -> Option<usize> {
+        let mut index = self.home(key);
+        for dist in 0..self.size {
+            let curr_key = self.slots[index].key.load(Ordering::Acquire);
+            if curr_key == key {
+                return Some(index);
+            }
+
+            // Every key is stored before any key closer to its home, so once we pass
+            // one the key can't be further along
+            if curr_key == EMPTY_KEY || self.distance(curr_key, index) < dist {
+                return None;
+            }
+
+            index = (index + 1) & (self.size - 1);
+        }
+
+        None
+    }
+
+    /// Atomically get a value from the hashmap
+    pub fn get(&self, key: &K) -> Option<V> {
+        let key = self.raw_key(*key).ok()?;
+
+        self.seq.read(|| {
+            let index = self.find_slot(key)?;
+            Some(V::from_u64(self.slots[index].value.load(Ordering::Acquire)))
+        })
+    }
+
+    /// Check if a key is in the hashmap
+    pub fn contains_key(&self, key: &K) -> bool {
+        match self.raw_key(*key) {
+            Ok(key) => self.seq.read(|| self.find_slot(key).is_some()),
+            Err(_) => false
+        }
+    }
+
+    /// Set a key:value in the hashmap, displacing keys closer to their home slot than
+    /// the new key is to its own
+    ///
+    /// Returns the value previously stored for this key, swapped out atomically with
+    /// the new value, or `None` if the key was newly inserted.
+    pub fn insert(&self, key: K, value: V) -> Result<Option<V>, AtomicHashMapError> {
+        let key = self.raw_key(key)?;
+        let value = value.to_u64();
+
+        let _writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+
+        // Entries only move under the lock, so no retry is needed here
+        if let Some(index) = self.find_slot(key) {
+            let prev_value = self.slots[index].value.swap(value, Ordering::AcqRel);
+            return Ok(Some(V::from_u64(prev_value)));
+        }
+
+        if self.count.load(Ordering::Relaxed) as usize == self.size {
+            return Err(AtomicHashMapError::Full);
+        }
+
+        // Entry looking for a slot, starting with the new one
+        let (mut key, mut value) = (key, value);
+        let mut index = self.home(key);
+        let mut dist = 0;
+        let mut moving = false;
+        loop {
+            let slot = &self.slots[index];
+            let curr_key = slot.key.load(Ordering::Relaxed);
+            if curr_key == EMPTY_KEY {
+                slot.value.store(value, Ordering::Relaxed);
+                slot.key.store(key, Ordering::Release);
+                break;
+            }
+
+            let curr_dist = self.distance(curr_key, index);
+            if curr_dist < dist {
+                // Take the slot from the richer entry and carry it on instead
+                if !moving {
+                    self.seq.begin_move();
+                    moving = true;
+                }
+
+                let curr_value = slot.value.load(Ordering::Relaxed);
+                slot.value.store(value, Ordering::Relaxed);
+                slot.key.store(key, Ordering::Release);
+                key = curr_key;
+                value = curr_value;
+                dist = curr_dist;
+            }
+
+            index = (index + 1) & (self.size - 1);
+            dist += 1;
+        }
+
+        if moving {
+            self.seq.end_move();
+        }
+
+        self.count.fetch_add(1, Ordering::Relaxed);
+        Ok(None)
+    }
+
+    /// Remove a key from the hashmap, returning its value if it was present
+    ///
+    /// The keys following it in its probe are shifted back by one slot, so removal
+    /// leaves no tombstones behind.
+    pub fn remove(&self, key: K) -> Option<V> {
+        let key = self.raw_key(key).ok()?;
+
+        let _writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let mut index = self.find_slot(key)?;
+        let value = self.slots[index].value.load(Ordering::Relaxed);
+
+        self.seq.begin_move();
+        loop {
+            let next = (index + 1) & (self.size - 1);
+            let next_key = self.slots[next].key.load(Ordering::Relaxed);
+            if next_key == EMPTY_KEY || self.distance(next_key, next) == 0 {
+                // Nothing further along belongs any closer to home
+                self.slots[index].key.store(EMPTY_KEY, Ordering::Relaxed);
+                self.slots[index].value.store(0, Ordering::Relaxed);
+                break;
+            }
+
+            let next_value = self.slots[next].value.load(Ordering::Relaxed);
+            self.slots[index].value.store(next_value, Ordering::Relaxed);
+            self.slots[index].key.store(next_key, Ordering::Relaxed);
+            index = next;
+        }
+        self.seq.end_move();
+
+        self.count.fetch_sub(1, Ordering::Relaxed);
+        Some(V::from_u64(value))
+    }
+
+    /// Get the longest distance of any key from its home slot, which bounds the
+    /// number of slots a lookup checks
+    pub fn max_probe_distance(&self) -> usize {
+        self.seq.read(|| {
+            let mut max = 0;
+            for (index, slot) in self.slots.iter().enumerate() {
+                let key = slot.key.load(Ordering::Acquire);
+                if key != EMPTY_KEY {
+                    max = max.max(self.distance(key, index));
+                }
+            }
+
+            max
+        })
+    }
+
+    /// Collect the `(key, value)` pairs currently in the hashmap, in table order
+    pub fn to_vec(&self) -> Vec<(K, V)> {
+        self.seq.read(|| {
+            self.slots.iter().filter_map(|slot| {
+                let key = slot.key.load(Ordering::Acquire);
+                if key == EMPTY_KEY {
+                    return None;
+                }
+
+                let value = slot.value.load(Ordering::Acquire);
+                Some((K::from_u64(key), V::from_u64(value)))
+            }).collect()
+        })
+    }
+
+    /// Get the number of elements currently in the hashtable
+    pub fn len(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Returns true if the hashtable has no elements
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the total number of slots in the hashtable
+    pub fn capacity(&self) -> usize {
+        self.size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_insert_get_remove() {
+        let hashtable: AtomicRobinHoodMap = AtomicRobinHoodMap::new(1 << 4).unwrap();
+        assert_eq!(hashtable.insert(1, 10), Ok(None));
+        assert_eq!(hashtable.insert(1, 11), Ok(Some(10)));
+        assert_eq!(hashtable.get(&1), Some(11));
+        assert!(hashtable.contains_key(&1));
+
+        assert_eq!(hashtable.insert(0, 1), Err(AtomicHashMapError::InvalidKey));
+        assert_eq!(hashtable.get(&0), None);
+
+        assert_eq!(hashtable.remove(1), Some(11));
+        assert_eq!(hashtable.remove(1), None);
+        assert!(hashtable.is_empty());
+
+        assert!(AtomicRobinHoodMap::<u64, u64>::new(12).is_err());
+    }
+
+    #[test]
+    fn test_full() {
+        let hashtable: AtomicRobinHoodMap = AtomicRobinHoodMap::new(1 << 6).unwrap();
+        for x in 1..=64 {
+            assert_eq!(hashtable.insert(x, x), Ok(None));
+        }
+        assert_eq!(hashtable.insert(65, 65), Err(AtomicHashMapError::Full));
+
+        // Existing keys can still be updated
+        assert_eq!(hashtable.insert(5, 50), Ok(Some(5)));
+
+        for x in 1..=64 {
+            assert_eq!(hashtable.get(&x), Some(if x == 5 { 50 } else { x }));
+        }
+    }
+
+    #[test]
+    fn test_matches_hashmap() {
+        let hashtable: AtomicRobinHoodMap = AtomicRobinHoodMap::new(1 << 10).unwrap();
+        let mut expected = HashMap::new();
+
+        // Churn the table at a high load factor so that plenty of entries are
+        // displaced and shifted back
+        let mut state = 0x9e37_79b9_7f4a_7c15_u64;
+        for _ in 0..20_000 {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+
+            let key = state % 1000 + 1;
+            if state & (1 << 40) == 0 {
+                assert_eq!(hashtable.insert(key, state), Ok(expected.insert(key, state)));
+            } else {
+                assert_eq!(hashtable.remove(key), expected.remove(&key));
+            }
+        }
+
+        assert_eq!(hashtable.len() as usize, expected.len());
+        for key in 1..=1000 {
+            assert_eq!(hashtable.get(&key), expected.get(&key).copied());
+        }
+
+        let mut entries = hashtable.to_vec();
+        entries.sort();
+        let mut expected: Vec<_> = expected.into_iter().collect();
+        expected.sort();
+        assert_eq!(entries, expected);
+    }
+
+    #[test]
+    fn test_readers_during_writes() {
+        use std::sync::Arc;
+        use std::thread;
+
+        let hashtable: Arc<AtomicRobinHoodMap> =
+            Arc::new(AtomicRobinHoodMap::new(1 << 10).unwrap());
+
+        // Keys that are never removed must always be found, no matter how many
+        // entries are moved around them
+        for x in 1..=500 {
+            hashtable.insert(x, x).unwrap();
+        }
+
+        let writer = {
+            let hashtable = hashtable.clone();
+            thread::spawn(move || {
+                for round in 0..20 {
+                    for x in 501..=900 {
+                        hashtable.insert(x, round).unwrap();
+                    }
+                    for x in 501..=900 {
+                        hashtable.remove(x);
+                    }
+                }
+            })
+        };
+
+        let readers: Vec<_> = (0..4).map(|_| {
+            let hashtable = hashtable.clone();
+            thread::spawn(move || {
+                for _ in 0..20 {
+                    for x in 1..=500 {
+                        assert_eq!(hashtable.get(&x), Some(x));
+                    }
+                }
+            })
+        }).collect();
+
+        writer.join().unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        assert_eq!(hashtable.len(), 500);
+    }
+}
@@ -0,0 +1,323 @@
+//! Reader-writer spinlock usable without `std`
+//!
+//! The whole state of the lock is one `AtomicU64`: the number of readers holding
+//! the lock in the low bits, plus a bit set while a writer holds it and a bit set
+//! while a writer waits for it. Readers join by incrementing the count with a
+//! compare-exchange as long as no writer holds the lock, and a writer takes it by
+//! moving the state from no readers and no writer to its own bit.
+//!
+//! By default readers go first: a reader only waits for a writer holding the lock,
+//! so a steady stream of readers can keep writers out. A lock built with
+//! `with_writer_preference` has a waiting writer set the waiting bit, which holds
+//! new readers back until the readers already in leave and the writer gets in.
+//!
+//! Acquiring the lock is an `Acquire` compare-exchange and releasing it a `Release`
+//! read-modify-write, so readers see the writes of the last writer and a writer sees
+//! the writes of every earlier holder. Waiters back off like `SpinLock` waiters.
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::backoff::Backoff;
+
+/// State bit set while a writer holds the lock
+const WRITER: u64 = 1 << 63;
+
+/// State bit set while a writer waits for the lock, with writer preference
+const WRITER_WAITING: u64 = 1 << 62;
+
+/// State bits counting the readers holding the lock
+const READERS: u64 = WRITER_WAITING - 1;
+
+/// Reader-writer lock protecting a value of type `T`, acquired by spinning
+pub struct RwSpinLock<T> {
+    /// Reader count, writer bit and writer waiting bit
+    state: AtomicU64,
+
+    /// Hold new readers back while a writer waits
+    prefer_writers: bool,
+
+    value: UnsafeCell<T>
+}
+
+// SAFETY: The value is only reached through guards, either one writer or any number
+// of readers at a time
+unsafe impl<T: Send> Send for RwSpinLock<T> {}
+unsafe impl<T: Send + Sync> Sync for RwSpinLock<T> {}
+
+impl<T> RwSpinLock<T> {
+    /// Construct an unlocked lock holding `value`, letting readers go first
+    pub const fn new(value: T) -> RwSpinLock<T> {
+        RwSpinLock {
+            state: AtomicU64::new(0),
+            prefer_writers: false,
+            value: UnsafeCell::new(value)
+        }
+    }
+
+    /// Construct an unlocked lock holding `value`, holding new readers back while a
+    /// writer waits
+    pub const fn with_writer_preference(value: T) -> RwSpinLock<T> {
+        RwSpinLock {
+            state: AtomicU64::new(0),
+            prefer_writers: true,
+            value: UnsafeCell::new(value)
+        }
+    }
+
+    /// Returns true if a waiting writer holds new readers back
+    pub fn prefers_writers(&self) -> bool {
+        self.prefer_writers
+    }
+
+    /// Returns true if a reader could join the lock in `state`
+    fn readable(&self, state: u64) -> bool {
+        state & WRITER == 0 && !(self.prefer_writers && state & WRITER_WAITING != 0)
+    }
+
+    /// Acquire the lock for reading, spinning while a writer holds it, or with writer
+    /// preference, waits for it
+    pub fn read(&self) -> RwSpinLockReadGuard<'_, T> {
+        let mut backoff = Backoff::new();
+        loop {
+            if let Some(guard) = self.try_read() {
+                return guard;
+            }
+
+            while !self.readable(self.state.load(Ordering::Relaxed)) {
+                backoff.snooze();
+            }
+        }
+    }
+
+    /// Acquire the lock for reading if no writer holds it, or with writer preference,
+    /// waits for it, without spinning
+    pub fn try_read(&self) -> Option<RwSpinLockReadGuard<'_, T>> {
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            if !self.readable(state) {
+                return None;
+            }
+
+            assert!(state & READERS != READERS, "Too many readers");
+            match self.state.compare_exchange_weak(state, state + 1, Ordering::Acquire,
+                                                   Ordering::Relaxed) {
+                Ok(_) => return Some(RwSpinLockReadGuard { lock: self }),
+                Err(curr) => state = curr
+            }
+        }
+    }
+
+    /// Acquire the lock for writing, spinning while anybody else holds it
+    pub fn write(&self) -> RwSpinLockWriteGuard<'_, T> {
+        let mut backoff = Backoff::new();
+        loop {
+            if let Some(guard) = self.try_write() {
+                return guard;
+            }
+
+            // Announce ourselves so that new readers stay out. Another writer taking
+            // the lock clears the bit, so it is set again on every round.
+            if self.prefer_writers {
+                self.state.fetch_or(WRITER_WAITING, Ordering::Relaxed);
+            }
+
+            backoff.snooze();
+        }
+    }
+
+    /// Acquire the lock for writing if nobody holds it, without spinning
+    pub fn try_write(&self) -> Option<RwSpinLockWriteGuard<'_, T>> {
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            if state & (WRITER | READERS) != 0 {
+                return None;
+            }
+
+            // Taking the lock clears the waiting bit, which the other waiting writers
+            // set again
+            match self.state.compare_exchange_weak(state, WRITER, Ordering::Acquire,
+                                                   Ordering::Relaxed) {
+                Ok(_) => return Some(RwSpinLockWriteGuard { lock: self }),
+                Err(curr) => state = curr
+            }
+        }
+    }
+
+    /// Get the number of readers holding the lock. Only a hint while other threads
+    /// lock and unlock it.
+    pub fn readers(&self) -> usize {
+        (self.state.load(Ordering::Relaxed) & READERS) as usize
+    }
+
+    /// Returns true if a writer holds the lock. Only a hint while other threads lock
+    /// and unlock it.
+    pub fn is_write_locked(&self) -> bool {
+        self.state.load(Ordering::Relaxed) & WRITER != 0
+    }
+
+    /// Get a mutable reference to the value, which no other thread can access
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    /// Take the value out of the lock
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: Default> Default for RwSpinLock<T> {
+    fn default() -> RwSpinLock<T> {
+        RwSpinLock::new(T::default())
+    }
+}
+
+/// Proof that an `RwSpinLock` is held for reading, giving shared access to its value
+/// and leaving the lock when dropped
+pub struct RwSpinLockReadGuard<'a, T> {
+    lock: &'a RwSpinLock<T>
+}
+
+impl<'a, T> Deref for RwSpinLockReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: No writer holds the lock while this guard does
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<'a, T> Drop for RwSpinLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_sub(1, Ordering::Release);
+    }
+}
+
+/// Proof that an `RwSpinLock` is held for writing, giving access to its value and
+/// releasing the lock when dropped
+pub struct RwSpinLockWriteGuard<'a, T> {
+    lock: &'a RwSpinLock<T>
+}
+
+// SAFETY: The guard only hands out references to the value
+unsafe impl<'a, T: Sync> Sync for RwSpinLockWriteGuard<'a, T> {}
+
+impl<'a, T> Deref for RwSpinLockWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: The lock is held by this guard alone
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<'a, T> DerefMut for RwSpinLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: The lock is held by this guard alone
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<'a, T> Drop for RwSpinLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        // Keep the waiting bit of the writers still waiting
+        self.lock.state.fetch_and(!WRITER, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    #[test]
+    fn test_rwspinlock() {
+        let mut lock = RwSpinLock::new(vec![1]);
+        {
+            let first = lock.read();
+            let second = lock.try_read().unwrap();
+            assert_eq!(lock.readers(), 2);
+            assert!(lock.try_write().is_none());
+            assert_eq!(*first, *second);
+        }
+
+        {
+            let mut guard = lock.write();
+            assert!(lock.is_write_locked());
+            assert!(lock.try_read().is_none());
+            assert!(lock.try_write().is_none());
+            guard.push(2);
+        }
+
+        assert!(!lock.is_write_locked());
+        lock.try_write().unwrap().push(3);
+        lock.get_mut().push(4);
+        assert_eq!(lock.into_inner(), vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_rwspinlock_preference() {
+        for prefer_writers in [false, true] {
+            let lock = if prefer_writers {
+                RwSpinLock::with_writer_preference(0)
+            } else {
+                RwSpinLock::new(0)
+            };
+            assert_eq!(lock.prefers_writers(), prefer_writers);
+
+            let reader = lock.read();
+            thread::scope(|scope| {
+                let writer = scope.spawn(|| *lock.write() += 1);
+
+                // Wait for the writer to start waiting on the reader
+                while prefer_writers &&
+                        lock.state.load(Ordering::Relaxed) & WRITER_WAITING == 0 {
+                    thread::yield_now();
+                }
+
+                // Only a lock preferring writers holds new readers back
+                assert_eq!(lock.try_read().is_none(), prefer_writers);
+                drop(reader);
+                writer.join().unwrap();
+            });
+
+            assert_eq!(*lock.read(), 1);
+        }
+    }
+
+    #[test]
+    fn test_rwspinlock_threads() {
+        for lock in [RwSpinLock::new((0u64, 0u64)),
+                     RwSpinLock::with_writer_preference((0u64, 0u64))] {
+            // Readers always see both halves match, writers update both
+            thread::scope(|scope| {
+                for _ in 0..2 {
+                    let lock = &lock;
+                    scope.spawn(move || {
+                        for _ in 0..2_000 {
+                            let mut guard = lock.write();
+                            guard.0 += 1;
+                            guard.1 += 1;
+                        }
+                    });
+                }
+
+                for _ in 0..2 {
+                    let lock = &lock;
+                    scope.spawn(move || {
+                        for _ in 0..2_000 {
+                            let guard = lock.read();
+                            assert_eq!(guard.0, guard.1);
+                        }
+                    });
+                }
+            });
+
+            assert_eq!(lock.into_inner(), (4_000, 4_000));
+        }
+    }
+}
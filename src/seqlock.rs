@@ -0,0 +1,281 @@
+//! Sequence locks letting lock-free readers detect concurrent writers
+//!
+//! `SeqLock` protects a `Copy` value of any size that is read far more often than it
+//! is written, like a block of statistics. `MoveSeq` is the bare counter used by the
+//! maps whose writers move entries between slots under a lock. In both, a writer
+//! makes the sequence odd while it writes and even again once it is done, and a read
+//! that overlapped a write is retried.
+//!
+//! # Memory ordering
+//!
+//! A writer stores the odd sequence, issues a `Release` fence and then writes,
+//! storing the even sequence with `Release` once done. Readers load the sequence
+//! with `Acquire` before reading and again after an `Acquire` fence, so a reader
+//! that sees the same even sequence twice read nothing a writer was writing.
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::ptr;
+
+use core::sync::atomic::{self, Ordering, AtomicUsize};
+#[cfg(feature = "std")]
+use core::sync::atomic::AtomicU64;
+
+/// Releases the lock taken at the even sequence `.1` when dropped, also when the
+/// write section panics
+struct Unlock<'a>(&'a AtomicUsize, usize);
+
+impl Drop for Unlock<'_> {
+    fn drop(&mut self) {
+        self.0.store(self.1.wrapping_add(2), Ordering::Release);
+    }
+}
+
+/// Value of type `T` read without locks and written by one writer at a time
+///
+/// Readers never write to shared memory, so any number of them can read at once
+/// without contending on a cache line, but they retry while a write is in progress.
+/// Writers take turns on the sequence.
+pub struct SeqLock<T: Copy> {
+    /// Odd while a writer holds the lock
+    seq: AtomicUsize,
+
+    value: UnsafeCell<T>
+}
+
+// SAFETY: The value is only written under the lock, and reads check the sequence
+unsafe impl<T: Copy + Send> Sync for SeqLock<T> {}
+
+impl<T: Copy> SeqLock<T> {
+    /// Construct a lock holding `value`
+    pub const fn new(value: T) -> SeqLock<T> {
+        SeqLock { seq: AtomicUsize::new(0), value: UnsafeCell::new(value) }
+    }
+
+    /// Copy the value once, or return `None` if a writer ran during the copy
+    pub fn try_read(&self) -> Option<T> {
+        let seq = self.seq.load(Ordering::Acquire);
+        if seq & 1 == 1 {
+            return None;
+        }
+
+        // SAFETY: The copy may be torn, so it is only used once the sequence shows no
+        // writer ran
+        let value = unsafe {
+            ptr::read_volatile(self.value.get() as *const MaybeUninit<T>)
+        };
+
+        atomic::fence(Ordering::Acquire);
+        if self.seq.load(Ordering::Relaxed) != seq {
+            return None;
+        }
+
+        // SAFETY: No writer ran while the value was copied
+        Some(unsafe { value.assume_init() })
+    }
+
+    /// Copy the value, retrying until no writer runs during the copy
+    pub fn read(&self) -> T {
+        loop {
+            if let Some(value) = self.try_read() {
+                return value;
+            }
+
+            // A writer holds the lock, wait for it to finish
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Run the write section `f` on the value, waiting for other writers to finish
+    /// first. Readers see either the value before `f` or the value after it.
+    ///
+    /// If `f` panics the value is left as it was and the lock is released.
+    pub fn write<R, F: FnOnce(&mut T) -> R>(&self, f: F) -> R {
+        let seq = loop {
+            let seq = self.seq.load(Ordering::Relaxed);
+            if seq & 1 == 0 && self.seq.compare_exchange_weak(seq, seq + 1,
+                                                              Ordering::Acquire,
+                                                              Ordering::Relaxed).is_ok() {
+                break seq;
+            }
+
+            core::hint::spin_loop();
+        };
+
+        let _unlock = Unlock(&self.seq, seq);
+
+        // Order the odd sequence before the write of the value
+        atomic::fence(Ordering::Release);
+
+        // SAFETY: Other writers are excluded by the lock, so the value can't change
+        // under us. Readers may copy it while it is written and throw the copy away.
+        // `f` works on a copy, so a panic in it leaves the value untouched.
+        unsafe {
+            let mut value = ptr::read(self.value.get());
+            let res = f(&mut value);
+            ptr::write_volatile(self.value.get(), value);
+            res
+        }
+    }
+
+    /// Replace the value with `value`
+    pub fn store(&self, value: T) {
+        self.write(|old| *old = value);
+    }
+
+    /// Get a mutable reference to the value, which no other thread can access
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    /// Take the value out of the lock
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: Copy + Default> Default for SeqLock<T> {
+    fn default() -> SeqLock<T> {
+        SeqLock::new(T::default())
+    }
+}
+
+/// Counter of the moves done by the writers of a map
+#[cfg(feature = "std")]
+pub(crate) struct MoveSeq {
+    /// Odd while a writer is moving entries
+    seq: AtomicU64
+}
+
+#[cfg(feature = "std")]
+impl MoveSeq {
+    pub(crate) fn new() -> MoveSeq {
+        MoveSeq { seq: AtomicU64::new(0) }
+    }
+
+    /// Run the read `f`, retrying it until no writer moved entries while it ran
+    pub(crate) fn read<T, F>(&self, mut f: F) -> T where F: FnMut() -> T {
+        loop {
+            let seq = self.seq.load(Ordering::Acquire);
+            if seq & 1 == 1 {
+                // A writer is moving entries, wait for it to finish
+                core::hint::spin_loop();
+                continue;
+            }
+
+            let res = f();
+
+            atomic::fence(Ordering::Acquire);
+            if self.seq.load(Ordering::Relaxed) == seq {
+                return res;
+            }
+        }
+    }
+
+    /// Mark the start of a write that moves entries. Only one writer may move entries
+    /// at a time.
+    pub(crate) fn begin_move(&self) {
+        let seq = self.seq.load(Ordering::Relaxed);
+        self.seq.store(seq + 1, Ordering::Relaxed);
+        atomic::fence(Ordering::Release);
+    }
+
+    /// Mark the end of a write started with `begin_move`
+    pub(crate) fn end_move(&self) {
+        let seq = self.seq.load(Ordering::Relaxed);
+        self.seq.store(seq + 1, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    /// Block of statistics that must always be read whole
+    #[derive(Clone, Copy, Debug, Default, PartialEq)]
+    struct Stats {
+        count: u64,
+        sum: u64,
+        min: u64,
+        max: u64
+    }
+
+    #[test]
+    fn test_seqlock() {
+        let mut lock = SeqLock::<Stats>::default();
+        assert_eq!(lock.read(), Stats::default());
+        assert_eq!(lock.try_read(), Some(Stats::default()));
+
+        let count = lock.write(|stats| {
+            stats.count += 1;
+            stats.sum += 5;
+            stats.count
+        });
+        assert_eq!(count, 1);
+        assert_eq!(lock.read().sum, 5);
+
+        lock.store(Stats { count: 2, sum: 7, min: 2, max: 5 });
+        lock.get_mut().min = 1;
+        assert_eq!(lock.into_inner(), Stats { count: 2, sum: 7, min: 1, max: 5 });
+    }
+
+    #[test]
+    fn test_seqlock_write_panics() {
+        let lock = SeqLock::new(Stats { count: 1, ..Stats::default() });
+
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            lock.write(|stats| {
+                stats.count = 2;
+                panic!("Write section failed");
+            })
+        }));
+        assert!(res.is_err());
+
+        // The value is untouched and neither readers nor writers are locked out
+        assert_eq!(lock.try_read(), Some(Stats { count: 1, ..Stats::default() }));
+        lock.write(|stats| stats.count = 3);
+        assert_eq!(lock.read().count, 3);
+    }
+
+    #[test]
+    fn test_seqlock_threads() {
+        let lock = SeqLock::new(Stats { count: 0, sum: 0, min: u64::MAX, max: 0 });
+
+        // Writers record samples while readers check every block they see is whole
+        thread::scope(|scope| {
+            for _ in 0..2 {
+                let lock = &lock;
+                scope.spawn(move || {
+                    for sample in 1..=10_000 {
+                        lock.write(|stats| {
+                            stats.count += 1;
+                            stats.sum += sample;
+                            stats.min = stats.min.min(sample);
+                            stats.max = stats.max.max(sample);
+                        });
+                    }
+                });
+            }
+
+            for _ in 0..2 {
+                let lock = &lock;
+                scope.spawn(move || {
+                    for _ in 0..100_000 {
+                        let stats = lock.read();
+                        if stats.count > 0 {
+                            assert!(stats.min <= stats.max && stats.max <= stats.sum);
+                            assert!(stats.sum <= stats.count * stats.max);
+                        } else {
+                            assert_eq!((stats.sum, stats.min), (0, u64::MAX));
+                        }
+                    }
+                });
+            }
+        });
+
+        assert_eq!(lock.read(), Stats { count: 20_000, sum: 100_010_000, min: 1,
+                                        max: 10_000 });
+    }
+}
@@ -0,0 +1,172 @@
+//! `Serialize` and `Deserialize` for `AtomicHashMap`, enabled with the `serde` feature
+//!
+//! A map is serialized as its capacity, its raw sentinels and its live entries. The
+//! entries are read one slot at a time like `iter`, so a map written to concurrently
+//! gives a snapshot that may or may not include the concurrent writes. Deserializing
+//! builds a table of the same capacity and sentinels and inserts the entries back.
+//! Since the table is allocated up front, a capacity over `MAX_SPARSE_CAPACITY` is
+//! only accepted for a table at least `1 / MAX_SPARSENESS` full, so that the memory
+//! taken by deserializing stays in proportion to the size of the input.
+//! The hasher is rebuilt with `Default`, and the ordering profile, probe strategy,
+//! probe limit and padding are left at their defaults.
+
+use alloc::vec::Vec;
+use core::hash::BuildHasher;
+
+use serde::de::{self, Deserialize, Deserializer, Unexpected};
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+
+use crate::atomichashmap::{AtomicHashMap, AtomicHashMapBuilder};
+use crate::pod::PodU64;
+use crate::probe::Table;
+
+/// Largest capacity accepted for a snapshot regardless of the number of its entries
+const MAX_SPARSE_CAPACITY: usize = 1 << 16;
+
+/// Largest ratio of capacity to entries accepted for a snapshot over
+/// `MAX_SPARSE_CAPACITY`
+const MAX_SPARSENESS: usize = 16;
+
+/// Serialized form of an `AtomicHashMap` as read back
+#[derive(serde::Deserialize)]
+#[serde(rename = "AtomicHashMap")]
+struct Snapshot<K, V> {
+    capacity: usize,
+
+    /// Sentinels in their `u64` form, since the default tombstone isn't the packing
+    /// of any key narrower than 64 bits
+    sentinels: (u64, u64),
+    entries: Vec<(K, V)>
+}
+
+/// Serializes the live entries of a map as a sequence of pairs
+struct Entries<'a, K: PodU64, V: PodU64, S>(&'a AtomicHashMap<K, V, S>);
+
+impl<'a, K, V, S> Serialize for Entries<'a, K, V, S>
+        where K: PodU64 + Serialize, V: PodU64 + Serialize, S: BuildHasher {
+    fn serialize<Ser: Serializer>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error> {
+        serializer.collect_seq(self.0.iter())
+    }
+}
+
+impl<K, V, S> Serialize for AtomicHashMap<K, V, S>
+        where K: PodU64 + Serialize, V: PodU64 + Serialize, S: BuildHasher {
+    fn serialize<Ser: Serializer>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error> {
+        let mut state = serializer.serialize_struct("AtomicHashMap", 3)?;
+        state.serialize_field("capacity", &self.capacity())?;
+        state.serialize_field("sentinels", &self.raw_sentinels())?;
+        state.serialize_field("entries", &Entries(self))?;
+        state.end()
+    }
+}
+
+impl<'de, K, V, S> Deserialize<'de> for AtomicHashMap<K, V, S>
+        where K: PodU64 + Deserialize<'de>, V: PodU64 + Deserialize<'de>,
+              S: BuildHasher + Default {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let snapshot = Snapshot::<K, V>::deserialize(deserializer)?;
+        if snapshot.capacity > MAX_SPARSE_CAPACITY &&
+                snapshot.capacity / MAX_SPARSENESS > snapshot.entries.len() {
+            return Err(de::Error::invalid_value(
+                Unexpected::Unsigned(snapshot.capacity as u64),
+                &"a capacity in proportion to the number of entries"));
+        }
+
+        let map = AtomicHashMapBuilder::new(snapshot.capacity)
+            .raw_sentinels(snapshot.sentinels)
+            .hasher(S::default())
+            .build()
+            .map_err(de::Error::custom)?;
+
+        for (key, value) in snapshot.entries {
+            map.insert(key, value).map_err(de::Error::custom)?;
+        }
+
+        Ok(map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let hashtable: AtomicHashMap<u64, i32> =
+            AtomicHashMap::with_sentinels(1 << 6, u64::MAX - 1, u64::MAX).unwrap();
+        for x in 0..40 {
+            hashtable.insert(x, -(x as i32)).unwrap();
+        }
+        hashtable.remove(7);
+
+        let json = serde_json::to_string(&hashtable).unwrap();
+        let restored: AtomicHashMap<u64, i32> = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(restored.capacity(), 1 << 6);
+        assert_eq!(restored.sentinels(), (u64::MAX - 1, u64::MAX));
+        assert_eq!(restored.len(), 39);
+
+        let mut entries = restored.to_vec();
+        entries.sort();
+        let mut expected = hashtable.to_vec();
+        expected.sort();
+        assert_eq!(entries, expected);
+    }
+
+    #[test]
+    fn test_roundtrip_narrow_keys() {
+        // -1 isn't the default tombstone of `i32` keys, in the copy either
+        let hashtable: AtomicHashMap<i32, u64> = AtomicHashMap::new(1 << 4).unwrap();
+        hashtable.insert(-1, 5).unwrap();
+
+        let json = serde_json::to_string(&hashtable).unwrap();
+        let restored: AtomicHashMap<i32, u64> = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.get(&-1), Some(5));
+        assert_eq!(restored.insert(-2, 6), Ok(None));
+    }
+
+    #[test]
+    fn test_invalid() {
+        // Capacity isn't a power of two
+        let res: Result<AtomicHashMap, _> = serde_json::from_str(
+            r#"{"capacity":12,"sentinels":[0,18446744073709551615],"entries":[]}"#);
+        assert!(res.is_err());
+
+        // More entries than slots
+        let res: Result<AtomicHashMap, _> = serde_json::from_str(
+            r#"{"capacity":2,"sentinels":[0,18446744073709551615],
+                "entries":[[1,1],[2,2],[3,3]]}"#);
+        assert!(res.is_err());
+
+        // Capacity far too large for the entries is rejected before allocating
+        let res: Result<AtomicHashMap, _> = serde_json::from_str(
+            r#"{"capacity":1152921504606846976,"sentinels":[0,18446744073709551615],
+                "entries":[[1,1]]}"#);
+        assert!(res.is_err());
+
+        let res: Result<AtomicHashMap, _> = serde_json::from_str(
+            r#"{"capacity":131072,"sentinels":[0,18446744073709551615],"entries":[]}"#);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_roundtrip_sparse() {
+        // Small tables round-trip however empty they are, and larger ones as long as
+        // they are full enough
+        let hashtable: AtomicHashMap = AtomicHashMap::new(MAX_SPARSE_CAPACITY).unwrap();
+        hashtable.insert(1, 1).unwrap();
+        let json = serde_json::to_string(&hashtable).unwrap();
+        let restored: AtomicHashMap = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.capacity(), MAX_SPARSE_CAPACITY);
+
+        let hashtable: AtomicHashMap =
+            AtomicHashMap::new(MAX_SPARSE_CAPACITY * 2).unwrap();
+        for x in 1..=(MAX_SPARSE_CAPACITY * 2 / MAX_SPARSENESS) as u64 {
+            hashtable.insert(x, x).unwrap();
+        }
+        let json = serde_json::to_string(&hashtable).unwrap();
+        let restored: AtomicHashMap = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.capacity(), MAX_SPARSE_CAPACITY * 2);
+        assert_eq!(restored.len(), hashtable.len());
+    }
+}
@@ -0,0 +1,229 @@
+//! Lock-free set of keys, with the same probing as `AtomicHashMap` but no values
+//!
+//! Every slot is a single key word, half the size of a slot of `AtomicHashMap`. A key
+//! is in the set from the moment a compare-exchange swaps it into an empty slot, so
+//! there is nothing to publish and `insert` never waits on another thread: of many
+//! threads racing to insert the same key, exactly one gets back `true`.
+//!
+//! Keys can't be removed while the set is shared. Without tombstones a probe can stop
+//! at the first empty slot and two inserts of the same key always meet in the same
+//! slot, which is what makes `insert` exact. `clear` takes the set by `&mut` instead.
+//!
+//! # Memory ordering
+//!
+//! Keys are claimed with an `AcqRel` compare-exchange and loaded with `Acquire`, so a
+//! thread that finds a key sees everything the inserting thread did before inserting
+//! it. The element count is `Relaxed`, as in `AtomicHashMap`.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::hash::{BuildHasher, Hasher};
+use core::marker::PhantomData;
+
+use core::sync::atomic::{Ordering, AtomicU64};
+
+use crate::atomichashmap::{AtomicHashMapError, BuildMurmurHasher};
+use crate::pod::PodU64;
+
+/// Key marker for a slot that has never been claimed
+const EMPTY_KEY: u64 = 0;
+
+/// Lock-free set of keys of type `K`
+///
+/// Keys are stored as the `u64` produced by `PodU64::to_u64`. A key whose
+/// representation is 0 marks empty slots and can't be inserted.
+pub struct AtomicHashSet<K: PodU64 = u64, S = BuildMurmurHasher> {
+    keys: Box<[AtomicU64]>,
+    size: usize,
+
+    /// Number of keys currently in the set
+    count: AtomicU64,
+
+    /// Builds the hasher used to find the start of the probe for each key
+    hasher: S,
+
+    _keys: PhantomData<K>
+}
+
+impl<K: PodU64> AtomicHashSet<K> {
+    /// Construct a new set with room for `size` keys.
+    /// NOTE: Size must be a power of two, otherwise `InvalidCapacity` is returned.
+    pub fn new(size: usize) -> Result<AtomicHashSet<K>, AtomicHashMapError> {
+        AtomicHashSet::with_hasher(size, BuildMurmurHasher::default())
+    }
+}
+
+impl<K: PodU64, S: BuildHasher> AtomicHashSet<K, S> {
+    /// Construct a new set with room for `size` keys, hashing keys with `hasher`.
+    /// NOTE: Size must be a power of two, otherwise `InvalidCapacity` is returned.
+    pub fn with_hasher(size: usize, hasher: S)
+            -> Result<AtomicHashSet<K, S>, AtomicHashMapError> {
+        if size < 2 || !size.is_power_of_two() {
+            return Err(AtomicHashMapError::InvalidCapacity);
+        }
+
+        Ok(AtomicHashSet {
+            keys: (0..size).map(|_| AtomicU64::new(EMPTY_KEY)).collect(),
+            size,
+            count: AtomicU64::new(0),
+            hasher,
+            _keys: PhantomData
+        })
+    }
+
+    /// Get the index of the first slot to probe for the raw `key`
+    fn start_index(&self, key: u64) -> usize {
+        let mut hasher = self.hasher.build_hasher();
+        hasher.write_u64(key);
+        hasher.finish() as usize & (self.size - 1)
+    }
+
+    /// Atomically insert `key` into the set
+    ///
+    /// Returns true if the key was newly inserted, or false if it was already in the
+    /// set.
+    pub fn insert(&self, key: K) -> Result<bool, AtomicHashMapError> {
+        let key = key.to_u64();
+        if key == EMPTY_KEY {
+            return Err(AtomicHashMapError::InvalidKey);
+        }
+
+        let start_index = self.start_index(key);
+        for probe in 0..self.size {
+            let slot = &self.keys[(start_index + probe) & (self.size - 1)];
+
+            let mut curr_key = slot.load(Ordering::Acquire);
+            if curr_key == EMPTY_KEY {
+                match slot.compare_exchange(EMPTY_KEY, key, Ordering::AcqRel,
+                                            Ordering::Acquire) {
+                    Ok(_) => {
+                        self.count.fetch_add(1, Ordering::Relaxed);
+                        return Ok(true);
+                    }
+                    Err(prev_key) => curr_key = prev_key
+                }
+            }
+
+            if curr_key == key {
+                return Ok(false);
+            }
+        }
+
+        Err(AtomicHashMapError::Full)
+    }
+
+    /// Check if `key` is in the set
+    pub fn contains(&self, key: &K) -> bool {
+        let key = key.to_u64();
+        if key == EMPTY_KEY {
+            return false;
+        }
+
+        let start_index = self.start_index(key);
+        for probe in 0..self.size {
+            let slot = &self.keys[(start_index + probe) & (self.size - 1)];
+            match slot.load(Ordering::Acquire) {
+                curr_key if curr_key == key => return true,
+
+                // Keys are never stored past an empty slot, so the key isn't here
+                EMPTY_KEY => return false,
+                _ => {}
+            }
+        }
+
+        false
+    }
+
+    /// Collect the keys currently in the set, in table order
+    pub fn to_vec(&self) -> Vec<K> {
+        self.keys.iter()
+            .map(|slot| slot.load(Ordering::Acquire))
+            .filter(|&key| key != EMPTY_KEY)
+            .map(K::from_u64)
+            .collect()
+    }
+
+    /// Remove every key from the set
+    pub fn clear(&mut self) {
+        for slot in self.keys.iter() {
+            slot.store(EMPTY_KEY, Ordering::Relaxed);
+        }
+        self.count.store(0, Ordering::Relaxed);
+    }
+
+    /// Get the number of keys currently in the set
+    pub fn len(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Returns true if the set has no keys
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the total number of slots in the set
+    pub fn capacity(&self) -> usize {
+        self.size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    #[test]
+    fn test_set() {
+        let mut set: AtomicHashSet = AtomicHashSet::new(1 << 4).unwrap();
+        assert_eq!(set.insert(1), Ok(true));
+        assert_eq!(set.insert(1), Ok(false));
+        assert_eq!(set.insert(u64::MAX), Ok(true));
+        assert_eq!(set.insert(0), Err(AtomicHashMapError::InvalidKey));
+        assert!(set.contains(&1));
+        assert!(set.contains(&u64::MAX));
+        assert!(!set.contains(&2));
+        assert!(!set.contains(&0));
+        assert_eq!(set.len(), 2);
+
+        for key in 2..=15 {
+            assert_eq!(set.insert(key), Ok(true));
+        }
+        assert_eq!(set.insert(16), Err(AtomicHashMapError::Full));
+        assert_eq!(set.insert(15), Ok(false));
+        assert_eq!(set.to_vec().len(), 16);
+
+        set.clear();
+        assert!(set.is_empty());
+        assert!(!set.contains(&1));
+        assert_eq!(set.insert(16), Ok(true));
+
+        assert!(AtomicHashSet::<u64>::new(12).is_err());
+    }
+
+    #[test]
+    fn test_set_threads() {
+        let set: AtomicHashSet = AtomicHashSet::new(1 << 12).unwrap();
+        let first = AtomicUsize::new(0);
+
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                let set = &set;
+                let first = &first;
+                scope.spawn(move || {
+                    for key in 1..=2000 {
+                        if set.insert(key).unwrap() {
+                            first.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                });
+            }
+        });
+
+        // Each key was newly inserted by exactly one thread
+        assert_eq!(first.load(Ordering::Relaxed), 2000);
+        assert_eq!(set.len(), 2000);
+        assert!((1..=2000).all(|key| set.contains(&key)));
+    }
+}
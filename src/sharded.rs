@@ -0,0 +1,255 @@
+//! Map split into several `AtomicHashMap` shards, with each key living in exactly one
+//!
+//! Keys are routed to a shard by the 16 bits of their hash just below the 7 bits used
+//! for control tags. Shards take the start of each probe from the low bits of the
+//! same hash, so routing leaves both the tags and the slots within a shard as spread
+//! out as in a single table of any size below 2^41 slots.
+//!
+//! Each shard keeps its own count of keys, which every new key and every removal
+//! updates, so splitting a map spreads that traffic, and the cache lines of a hot
+//! range of keys, over several tables instead of funnelling it into one.
+//!
+//! `ShardedAtomicHashMap::per_numa_node` builds one shard per NUMA node, each
+//! allocated on its own node, so that a large table is spread over the memory of
+//! every node instead of landing on whichever node built it.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::hash::BuildHasher;
+
+use crate::atomichashmap::{AtomicHashMap, AtomicHashMapBuilder, BuildMurmurHasher};
+use crate::error::AtomicHashMapError;
+use crate::pod::PodU64;
+
+/// Position of the hash bits routing a key to its shard
+const ROUTE_SHIFT: u32 = 41;
+
+/// Most shards a map can be split into, one per value of the routing bits
+const MAX_SHARDS: usize = 1 << 16;
+
+/// Lock-free hashmap split into shards of `AtomicHashMap`, with the same operations
+///
+/// Each shard has its own table and count, and may fill up before the others, in
+/// which case inserting a new key routed to it returns `AtomicHashMapError::Full`.
+pub struct ShardedAtomicHashMap<K: PodU64 = u64, V: PodU64 = u64, S = BuildMurmurHasher> {
+    shards: Box<[AtomicHashMap<K, V, S>]>
+}
+
+impl<K: PodU64, V: PodU64> ShardedAtomicHashMap<K, V> {
+    /// Construct a map of `size` slots in total, split evenly into `shards` shards.
+    /// Each shard is rounded up to a power of two.
+    /// NOTE: There must be between 1 and 65536 shards, otherwise `InvalidCapacity` is
+    /// returned.
+    pub fn new(size: usize, shards: usize)
+            -> Result<ShardedAtomicHashMap<K, V>, AtomicHashMapError> {
+        if shards == 0 || shards > MAX_SHARDS {
+            return Err(AtomicHashMapError::InvalidCapacity);
+        }
+
+        ShardedAtomicHashMap::with_hasher(size, shards, BuildMurmurHasher::default())
+    }
+
+    /// Construct a map of `size` slots in total, split into one shard per online NUMA
+    /// node with each shard allocated on its node. Each shard is rounded up to a power
+    /// of two.
+    ///
+    /// Falls back to a single shard if the nodes can't be read.
+    #[cfg(all(feature = "numa", target_os = "linux"))]
+    pub fn per_numa_node(size: usize)
+            -> Result<ShardedAtomicHashMap<K, V>, AtomicHashMapError> {
+        let nodes = crate::placement::online_nodes();
+
+        let shard_size = size.div_ceil(nodes.len());
+        ShardedAtomicHashMap::from_builders(nodes.len(), |shard| {
+            AtomicHashMap::builder(shard_size).round_up(true).numa_node(nodes[shard])
+        })
+    }
+
+}
+
+impl<K: PodU64, V: PodU64, S: BuildHasher + Clone> ShardedAtomicHashMap<K, V, S> {
+    /// Construct a map of `size` slots in total, split evenly into `shards` shards
+    /// that all hash keys with `hasher`. Each shard is rounded up to a power of two.
+    /// NOTE: There must be between 1 and 65536 shards, otherwise `InvalidCapacity` is
+    /// returned.
+    pub fn with_hasher(size: usize, shards: usize, hasher: S)
+            -> Result<ShardedAtomicHashMap<K, V, S>, AtomicHashMapError> {
+        if shards == 0 || shards > MAX_SHARDS {
+            return Err(AtomicHashMapError::InvalidCapacity);
+        }
+
+        let shard_size = size.div_ceil(shards);
+        ShardedAtomicHashMap::from_builders(shards, |_| {
+            AtomicHashMap::builder(shard_size).round_up(true).hasher(hasher.clone())
+        })
+    }
+}
+
+impl<K: PodU64, V: PodU64, S: BuildHasher> ShardedAtomicHashMap<K, V, S> {
+    /// Build each of the `shards` shards from the builder `builder` gives for it
+    fn from_builders<F>(shards: usize, mut builder: F)
+            -> Result<ShardedAtomicHashMap<K, V, S>, AtomicHashMapError>
+            where F: FnMut(usize) -> AtomicHashMapBuilder<K, V, S> {
+        let shards = (0..shards).map(|shard| builder(shard).build())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(ShardedAtomicHashMap { shards: shards.into_boxed_slice() })
+    }
+
+    /// Get the shard `key` is routed to
+    fn shard(&self, key: &K) -> &AtomicHashMap<K, V, S> {
+        // Every shard hashes keys the same way
+        let hash = self.shards[0].hash(key.to_u64());
+        let route = (hash >> ROUTE_SHIFT) as usize & (MAX_SHARDS - 1);
+        &self.shards[route * self.shards.len() / MAX_SHARDS]
+    }
+
+    /// Get the shards of the map
+    pub fn shards(&self) -> &[AtomicHashMap<K, V, S>] {
+        &self.shards
+    }
+
+    /// Atomically set a key:value in the hashmap, see `AtomicHashMap::insert`
+    pub fn insert(&self, key: K, value: V) -> Result<Option<V>, AtomicHashMapError> {
+        self.shard(&key).insert(key, value)
+    }
+
+    /// Atomically get a value from the hashmap
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.shard(key).get(key)
+    }
+
+    /// Check if a key is in the hashmap
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.shard(key).contains_key(key)
+    }
+
+    /// Get the value for `key`, inserting `default` if the key isn't in the hashmap
+    pub fn get_or_insert(&self, key: K, default: V) -> Result<V, AtomicHashMapError> {
+        self.shard(&key).get_or_insert(key, default)
+    }
+
+    /// Atomically replace the value of `key` with `f` of its current value, see
+    /// `AtomicHashMap::update`
+    pub fn update<F>(&self, key: K, f: F) -> Option<V> where F: FnMut(V) -> V {
+        self.shard(&key).update(key, f)
+    }
+
+    /// Atomically remove a key from the hashmap, returning its value if it was present
+    pub fn remove(&self, key: K) -> Option<V> {
+        self.shard(&key).remove(key)
+    }
+
+    /// Iterate over the `(key, value)` pairs currently in the hashmap, one shard after
+    /// the other
+    pub fn iter(&self) -> impl Iterator<Item = (K, V)> + '_ {
+        self.shards.iter().flat_map(|shard| shard.iter())
+    }
+
+    /// Copy the `(key, value)` pairs currently in the hashmap into a `Vec`
+    pub fn to_vec(&self) -> Vec<(K, V)> {
+        self.iter().collect()
+    }
+
+    /// Get the number of elements currently in the hashtable, summed over the shards
+    pub fn len(&self) -> u64 {
+        self.shards.iter().map(|shard| shard.len()).sum()
+    }
+
+    /// Returns true if the hashtable has no elements
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the total number of slots in the hashtable, summed over the shards
+    pub fn capacity(&self) -> usize {
+        self.shards.iter().map(|shard| shard.capacity()).sum()
+    }
+}
+
+impl<K: PodU64, S: BuildHasher> ShardedAtomicHashMap<K, u64, S> {
+    /// Atomically add `delta` to the value for `key`, see `AtomicHashMap::add_to`
+    pub fn add_to(&self, key: K, delta: u64) -> Result<u64, AtomicHashMapError> {
+        self.shard(&key).add_to(key, delta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sharded() {
+        let hashtable: ShardedAtomicHashMap = ShardedAtomicHashMap::new(1 << 12, 4).unwrap();
+        assert_eq!(hashtable.shards().len(), 4);
+        assert_eq!(hashtable.capacity(), 1 << 12);
+
+        for x in 1..=2000 {
+            assert_eq!(hashtable.insert(x, x * 2), Ok(None));
+        }
+        assert_eq!(hashtable.len(), 2000);
+        assert_eq!(hashtable.get_or_insert(7, 0), Ok(14));
+        assert_eq!(hashtable.remove(8), Some(16));
+        assert!(!hashtable.contains_key(&8));
+        for x in 9..=2000 {
+            assert_eq!(hashtable.get(&x), Some(x * 2));
+        }
+
+        // Keys are spread over every shard
+        for shard in hashtable.shards() {
+            assert!(shard.len() > 400, "Shard holds {} keys", shard.len());
+        }
+
+        // Shard sizes are rounded up
+        let hashtable: ShardedAtomicHashMap = ShardedAtomicHashMap::new(1000, 3).unwrap();
+        assert_eq!(hashtable.capacity(), 3 * 512);
+
+        assert!(ShardedAtomicHashMap::<u64>::new(1024, 0).is_err());
+    }
+
+    #[test]
+    fn test_hot_range() {
+        use std::sync::Arc;
+        use std::thread;
+
+        let hashtable: Arc<ShardedAtomicHashMap> =
+            Arc::new(ShardedAtomicHashMap::new(1 << 12, 16).unwrap());
+
+        // Every thread hammers the same small range of counters
+        let threads: Vec<_> = (0..8).map(|_| {
+            let hashtable = hashtable.clone();
+            thread::spawn(move || {
+                for round in 0..1000 {
+                    hashtable.add_to(round % 64 + 1, 1).unwrap();
+                }
+            })
+        }).collect();
+
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        let mut entries = hashtable.to_vec();
+        entries.sort();
+        let expected: Vec<_> = (1..=64).map(|x| (x, if x <= 1000 % 64 { 128 } else { 120 }))
+            .collect();
+        assert_eq!(entries, expected);
+        assert_eq!(hashtable.update(1, |value| value * 2), Some(128));
+        assert_eq!(hashtable.get(&1), Some(256));
+    }
+
+    #[test]
+    #[cfg(all(feature = "numa", target_os = "linux"))]
+    fn test_per_numa_node() {
+        let hashtable: ShardedAtomicHashMap = ShardedAtomicHashMap::per_numa_node(1 << 16)
+            .unwrap();
+        assert_eq!(hashtable.shards().len(), crate::placement::online_nodes().len());
+
+        for x in 1..=1000 {
+            hashtable.insert(x, x).unwrap();
+        }
+        for x in 1..=1000 {
+            assert_eq!(hashtable.get(&x), Some(x));
+        }
+    }
+}
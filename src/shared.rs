@@ -0,0 +1,689 @@
+//! `AtomicHashMap` laid out over a caller-provided memory region
+//!
+//! `AtomicSharedHashMap` keeps a small header, followed by its keys, values and slot
+//! states, in a region of bytes it doesn't own, such as a `MAP_SHARED` mmap.
+//! Forked children inherit a map built over such a region and any other process can
+//! attach to it by mapping the same memory, so they all read and write one lock-free
+//! table. Entries are claimed, published and removed with the same protocol as
+//! `AtomicHashMap`, with keys hashed by `hash_key` so every process agrees on
+//! where they live.
+//!
+//! # Layout
+//!
+//! The region starts with a `#[repr(C)]` header of six `u64`: the magic number, the
+//! layout version, the capacity, the two sentinels and the number of keys. It is
+//! followed by the `u64` keys, the `u64` values and the `u64` states of every slot,
+//! each state holding a published bit, a pending bit and the number of times the
+//! slot was unpublished above them. Everything is native endian, so processes sharing a
+//! map must run on the same architecture.
+//!
+//! # Memory ordering
+//!
+//! Same as `AtomicHashMap` with the default `OrderingProfile::AcquireRelease`. The
+//! magic number is written last with `Release` when a region is initialized, so a
+//! process that attaches after seeing it also sees the empty table.
+
+use core::convert::TryFrom;
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
+use core::slice;
+
+use core::sync::atomic::{Ordering, AtomicU64};
+
+use crate::error::AtomicHashMapError;
+use crate::hasher::hash_key;
+use crate::pod::PodU64;
+use crate::probe::{self, Linear, Slot, Table};
+
+/// Marks a region holding an initialized map: "ATOMHMAP" in little endian
+const MAGIC: u64 = u64::from_le_bytes(*b"ATOMHMAP");
+
+/// Version of the layout, bumped whenever the header, the slots or the packing of keys
+/// into them change
+pub(crate) const VERSION: u64 = 4;
+
+/// Number of bytes of region taken by each slot: its key, value and state
+const SLOT_SIZE: usize = 3 * size_of::<u64>();
+
+/// Default key marker for a slot that has never been claimed
+const EMPTY_KEY: u64 = 0;
+
+/// Default key marker for a slot whose entry has been removed
+const TOMBSTONE_KEY: u64 = u64::MAX;
+
+/// Start of a region holding a map
+#[repr(C)]
+struct Header {
+    /// `MAGIC` once the rest of the region is initialized
+    magic: AtomicU64,
+
+    /// `VERSION` of the layout the region was initialized with
+    version: AtomicU64,
+
+    /// Number of slots, always a power of two
+    capacity: AtomicU64,
+
+    /// Raw key marking a slot that has never been claimed
+    empty_key: AtomicU64,
+
+    /// Raw key marking a slot whose entry has been removed
+    tombstone_key: AtomicU64,
+
+    /// Number of keys currently in the table
+    count: AtomicU64
+}
+
+/// Lock-free hashmap stored in a memory region that may be shared between processes
+///
+/// The region is built with `init`, which writes an empty table into it, or with
+/// `from_raw_parts` for memory only reachable through a pointer. Any other view of
+/// the same memory is opened with `attach`. Keys whose `PodU64`
+/// representation is 0 or `u64::MAX` are reserved to mark empty and removed slots
+/// unless other sentinels are given.
+///
+/// ```
+/// use atomics_rs::AtomicSharedHashMap;
+///
+/// // Stands in for a `MAP_SHARED` mmap, which must be 8-byte aligned
+/// let mut memory = vec![0u64; AtomicSharedHashMap::<u64>::region_size(64) / 8];
+/// let region = unsafe {
+///     std::slice::from_raw_parts_mut(memory.as_mut_ptr() as *mut u8, memory.len() * 8)
+/// };
+///
+/// let hashtable: AtomicSharedHashMap = AtomicSharedHashMap::init(region, 64).unwrap();
+/// hashtable.insert(1, 2).unwrap();
+/// assert_eq!(hashtable.get(&1), Some(2));
+/// ```
+pub struct AtomicSharedHashMap<'a, K: PodU64 = u64, V: PodU64 = u64> {
+    keys: &'a [AtomicU64],
+    values: &'a [AtomicU64],
+
+    /// `PUBLISHED` once the first value of a claimed slot has been written, and the
+    /// number of times the slot was unpublished above its state bits
+    states: &'a [AtomicU64],
+
+    /// Header at the start of the region, holding the number of keys in the table
+    header: &'a Header,
+
+    /// Raw key marking a slot that has never been claimed
+    empty_key: u64,
+
+    /// Raw key marking a slot whose entry has been removed
+    tombstone_key: u64,
+
+    _types: PhantomData<(K, V)>
+}
+
+impl<'a, K: PodU64, V: PodU64> AtomicSharedHashMap<'a, K, V> {
+    /// Get the number of bytes of region needed by a map of `capacity` slots
+    pub const fn region_size(capacity: usize) -> usize {
+        size_of::<Header>() + capacity * SLOT_SIZE
+    }
+
+    /// Write an empty map of `capacity` slots into `region` and open it
+    ///
+    /// NOTE: `capacity` must be a power of two, otherwise `InvalidCapacity` is
+    /// returned. `region` must be 8-byte aligned and hold at least
+    /// `region_size(capacity)` bytes, otherwise `InvalidRegion` is returned.
+    pub fn init(region: &'a mut [u8], capacity: usize)
+            -> Result<AtomicSharedHashMap<'a, K, V>, AtomicHashMapError> {
+        // SAFETY: The region is borrowed mutably for 'a
+        let map = unsafe {
+            Self::format(region.as_mut_ptr(), region.len(), capacity, EMPTY_KEY,
+                         TOMBSTONE_KEY)?
+        };
+        map.publish();
+
+        Ok(map)
+    }
+
+    /// Write an empty map of `capacity` slots into `region` and open it, using
+    /// `empty_key` and `tombstone_key` to mark empty and removed slots instead of 0
+    /// and `u64::MAX`
+    ///
+    /// NOTE: `capacity` must be a power of two, otherwise `InvalidCapacity` is
+    /// returned. `region` must be 8-byte aligned and hold at least
+    /// `region_size(capacity)` bytes, otherwise `InvalidRegion` is returned.
+    pub fn init_with_sentinels(region: &'a mut [u8], capacity: usize, empty_key: K,
+                               tombstone_key: K)
+            -> Result<AtomicSharedHashMap<'a, K, V>, AtomicHashMapError> {
+        // SAFETY: The region is borrowed mutably for 'a
+        let map = unsafe {
+            Self::format(region.as_mut_ptr(), region.len(), capacity, empty_key.to_u64(),
+                         tombstone_key.to_u64())?
+        };
+        map.publish();
+
+        Ok(map)
+    }
+
+    /// Write an empty map of `capacity` slots into the `len` bytes at `region` and
+    /// open it
+    ///
+    /// This places the table in memory that can't be borrowed as a slice, such as
+    /// huge pages, DMA-able memory or a guest-physical region, allocated by whatever
+    /// allocator owns it. The map never frees the region.
+    ///
+    /// NOTE: `capacity` must be a power of two, otherwise `InvalidCapacity` is
+    /// returned. `region` must be 8-byte aligned and hold at least
+    /// `region_size(capacity)` bytes, otherwise `InvalidRegion` is returned.
+    ///
+    /// # Safety
+    ///
+    /// The `len` bytes at `region` must stay valid for `'a`, and must only be
+    /// accessed through `AtomicSharedHashMap`s or other atomic operations while they
+    /// are.
+    pub unsafe fn from_raw_parts(region: *mut u8, len: usize, capacity: usize)
+            -> Result<AtomicSharedHashMap<'a, K, V>, AtomicHashMapError> {
+        if region.is_null() {
+            return Err(AtomicHashMapError::InvalidRegion);
+        }
+
+        let map = Self::format(region, len, capacity, EMPTY_KEY, TOMBSTONE_KEY)?;
+        map.publish();
+
+        Ok(map)
+    }
+
+    /// Write an empty map of `capacity` slots into the `len` bytes at `region`,
+    /// marking empty and removed slots with the raw keys `empty_key` and
+    /// `tombstone_key` and leaving the magic number cleared until `publish` is called
+    ///
+    /// # Safety
+    ///
+    /// The `len` bytes at `region` must be valid for `'a` and not accessed by anything
+    /// else until the map is published.
+    pub(crate) unsafe fn format(region: *mut u8, len: usize, capacity: usize,
+                                empty_key: u64, tombstone_key: u64)
+            -> Result<AtomicSharedHashMap<'a, K, V>, AtomicHashMapError> {
+        if capacity < 2 || !capacity.is_power_of_two() {
+            return Err(AtomicHashMapError::InvalidCapacity);
+        }
+
+        if empty_key == tombstone_key {
+            return Err(AtomicHashMapError::InvalidKey);
+        }
+
+        if !Self::fits(region, len, capacity) {
+            return Err(AtomicHashMapError::InvalidRegion);
+        }
+
+        let map = Self::from_region(region, capacity, empty_key, tombstone_key);
+
+        // Nothing else can see the region until the magic number is written
+        let header = map.header;
+        header.magic.store(0, Ordering::Relaxed);
+        header.version.store(VERSION, Ordering::Relaxed);
+        header.capacity.store(capacity as u64, Ordering::Relaxed);
+        header.empty_key.store(empty_key, Ordering::Relaxed);
+        header.tombstone_key.store(tombstone_key, Ordering::Relaxed);
+        header.count.store(0, Ordering::Relaxed);
+        for index in 0..capacity {
+            map.keys[index].store(empty_key, Ordering::Relaxed);
+            map.values[index].store(0, Ordering::Relaxed);
+            map.states[index].store(0, Ordering::Relaxed);
+        }
+
+        Ok(map)
+    }
+
+    /// Write the magic number marking the region as holding an initialized map
+    pub(crate) fn publish(&self) {
+        self.header.magic.store(MAGIC, Ordering::Release);
+    }
+
+    /// Open a map previously written by `init` into the `len` bytes at `region`
+    ///
+    /// Returns `InvalidRegion` if the region is misaligned, doesn't start with an
+    /// initialized header of the current layout version, or is too small for the
+    /// capacity recorded in it.
+    ///
+    /// # Safety
+    ///
+    /// The `len` bytes at `region` must stay mapped and valid for `'a`, and must only
+    /// be accessed through `AtomicSharedHashMap`s or other atomic operations while
+    /// they are.
+    pub unsafe fn attach(region: *mut u8, len: usize)
+            -> Result<AtomicSharedHashMap<'a, K, V>, AtomicHashMapError> {
+        if region.is_null() || !(region as usize).is_multiple_of(align_of::<Header>()) ||
+                len < size_of::<Header>() {
+            return Err(AtomicHashMapError::InvalidRegion);
+        }
+
+        let header = &*(region as *const Header);
+        if header.magic.load(Ordering::Acquire) != MAGIC ||
+                header.version.load(Ordering::Relaxed) != VERSION {
+            return Err(AtomicHashMapError::InvalidRegion);
+        }
+
+        let capacity = usize::try_from(header.capacity.load(Ordering::Relaxed))
+            .map_err(|_| AtomicHashMapError::InvalidRegion)?;
+        let empty_key = header.empty_key.load(Ordering::Relaxed);
+        let tombstone_key = header.tombstone_key.load(Ordering::Relaxed);
+        if capacity < 2 || !capacity.is_power_of_two() || empty_key == tombstone_key ||
+                !Self::fits(region, len, capacity) {
+            return Err(AtomicHashMapError::InvalidRegion);
+        }
+
+        Ok(Self::from_region(region, capacity, empty_key, tombstone_key))
+    }
+
+    /// Returns true if the `len` bytes at `region` are aligned for the header and can
+    /// hold a map of `capacity` slots
+    fn fits(region: *const u8, len: usize, capacity: usize) -> bool {
+        let needed = capacity.checked_mul(SLOT_SIZE)
+            .and_then(|slots| slots.checked_add(size_of::<Header>()));
+
+        (region as usize).is_multiple_of(align_of::<Header>()) &&
+            matches!(needed, Some(needed) if needed <= len)
+    }
+
+    /// Build a view of the header and slots of the map of `capacity` slots at `region`
+    ///
+    /// # Safety
+    ///
+    /// `region` must be aligned for the header, hold `region_size(capacity)` bytes
+    /// valid for `'a`, and only be accessed atomically while the view is alive.
+    unsafe fn from_region(region: *mut u8, capacity: usize, empty_key: u64,
+                          tombstone_key: u64) -> AtomicSharedHashMap<'a, K, V> {
+        let header = &*(region as *const Header);
+        let keys = region.add(size_of::<Header>()) as *const AtomicU64;
+        let values = keys.add(capacity);
+        let states = values.add(capacity);
+
+        AtomicSharedHashMap {
+            keys: slice::from_raw_parts(keys, capacity),
+            values: slice::from_raw_parts(values, capacity),
+            states: slice::from_raw_parts(states, capacity),
+            header,
+            empty_key,
+            tombstone_key,
+            _types: PhantomData
+        }
+    }
+
+    /// Get the `(empty, tombstone)` sentinel keys marking empty and removed slots,
+    /// truncated to the key type like `AtomicHashMap::sentinels`
+    pub fn sentinels(&self) -> (K, K) {
+        (K::from_u64(self.empty_key), K::from_u64(self.tombstone_key))
+    }
+
+    /// Returns true if the raw key read from a slot is a stored key rather than one of
+    /// the sentinels
+    fn is_live(&self, key: u64) -> bool {
+        key != self.empty_key && key != self.tombstone_key
+    }
+
+    /// Get the raw `u64` of `key`, rejecting the sentinels
+    fn raw_key(&self, key: K) -> Result<u64, AtomicHashMapError> {
+        let key = key.to_u64();
+        if !self.is_live(key) {
+            return Err(AtomicHashMapError::InvalidKey);
+        }
+
+        Ok(key)
+    }
+
+    /// Atomically set a key:value in the hashmap
+    ///
+    /// Returns the value previously stored for this key, or `None` if the key was
+    /// newly inserted. A new key becomes visible to other threads and processes
+    /// together with its value.
+    pub fn insert(&self, key: K, value: V) -> Result<Option<V>, AtomicHashMapError> {
+        let key = self.raw_key(key)?;
+
+        match self.claim_slot(key)? {
+            Slot::Found(index) => {
+                let prev_value = self.values[index].swap(value.to_u64(), Ordering::AcqRel);
+                Ok(Some(V::from_u64(prev_value)))
+            }
+            Slot::Claimed(index) => {
+                self.values[index].store(value.to_u64(), Ordering::Release);
+                probe::publish(self, index);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Get the value for `key`, inserting `default` if the key isn't in the hashmap
+    pub fn get_or_insert(&self, key: K, default: V) -> Result<V, AtomicHashMapError> {
+        let key = self.raw_key(key)?;
+
+        match self.claim_slot(key)? {
+            Slot::Found(index) => {
+                match self.read_value(index, key) {
+                    Some(value) => Ok(value),
+                    // Removed since it was found, look for the key again
+                    None => self.get_or_insert(K::from_u64(key), default)
+                }
+            }
+            Slot::Claimed(index) => {
+                self.values[index].store(default.to_u64(), Ordering::Release);
+                probe::publish(self, index);
+                Ok(default)
+            }
+        }
+    }
+
+    /// Find the published slot holding `key`, claiming a tombstone or an empty slot
+    /// for it if it isn't in the table yet. A claimed slot must be published by the
+    /// caller once its value is written.
+    fn claim_slot(&self, key: u64) -> Result<Slot, AtomicHashMapError> {
+        probe::claim_slot(self, key, hash_key(key))
+    }
+
+    /// Find the slot currently holding `key`
+    fn find_slot(&self, key: u64) -> Option<usize> {
+        probe::find_slot(self, key, hash_key(key))
+    }
+
+    /// Read the value of the published slot at `index` holding `key`. Returns `None`
+    /// if the entry was removed while reading it, since the value read may then be the
+    /// reset done by the removal.
+    fn read_value(&self, index: usize, key: u64) -> Option<V> {
+        let value = self.values[index].load(Ordering::Acquire);
+        if !probe::is_published(self, index)
+                || self.keys[index].load(Ordering::Acquire) != key {
+            return None;
+        }
+
+        Some(V::from_u64(value))
+    }
+
+    /// Atomically get a value from the hashmap
+    pub fn get(&self, key: &K) -> Option<V> {
+        let key = self.raw_key(*key).ok()?;
+
+        let index = self.find_slot(key)?;
+        self.read_value(index, key)
+    }
+
+    /// Check if a key is in the hashmap
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Atomically remove a key from the hashmap, returning its value if it was present
+    pub fn remove(&self, key: K) -> Option<V> {
+        let key = self.raw_key(key).ok()?;
+
+        let index = self.find_slot(key)?;
+
+        // Only the thread that unpublishes the slot may release it
+        if !probe::unpublish(self, index, key) {
+            return None;
+        }
+
+        // Take the value before releasing the key, same as `AtomicHashMap::remove`
+        let value = self.values[index].swap(0, Ordering::AcqRel);
+        probe::release(self, index, key);
+        Some(V::from_u64(value))
+    }
+
+    /// Iterate over the `(key, value)` pairs currently in the hashmap, in table order,
+    /// without allocating
+    pub fn iter(&self) -> impl Iterator<Item = (K, V)> + '_ {
+        (0..self.keys.len()).filter_map(move |index| {
+            let key = self.keys[index].load(Ordering::Acquire);
+            if !self.is_live(key) || !probe::is_published(self, index) {
+                return None;
+            }
+
+            Some((K::from_u64(key), self.read_value(index, key)?))
+        })
+    }
+
+    /// Get the number of elements currently in the hashtable
+    pub fn len(&self) -> usize {
+        self.header.count.load(Ordering::Relaxed) as usize
+    }
+
+    /// Returns true if the hashtable has no elements
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the total number of slots in the hashtable
+    pub fn capacity(&self) -> usize {
+        self.keys.len()
+    }
+}
+
+impl<'a, K: PodU64> AtomicSharedHashMap<'a, K, u64> {
+    /// Atomically add `delta` to the value for `key`, inserting the key with a value of
+    /// `delta` if it isn't in the hashmap yet. The addition wraps around on overflow.
+    ///
+    /// Returns the value before the addition, which is 0 for a newly inserted key.
+    pub fn add_to(&self, key: K, delta: u64) -> Result<u64, AtomicHashMapError> {
+        let key = self.raw_key(key)?;
+
+        match self.claim_slot(key)? {
+            Slot::Found(index) => Ok(self.values[index].fetch_add(delta, Ordering::AcqRel)),
+            Slot::Claimed(index) => {
+                let prev_value = self.values[index].fetch_add(delta, Ordering::AcqRel);
+                probe::publish(self, index);
+                Ok(prev_value)
+            }
+        }
+    }
+}
+
+impl<'a, K: PodU64, V: PodU64> Table for AtomicSharedHashMap<'a, K, V> {
+    type Key = AtomicU64;
+    type State = AtomicU64;
+    type Probe<'b> = Linear where Self: 'b;
+
+    #[inline]
+    fn key_word(&self, index: usize) -> &AtomicU64 {
+        &self.keys[index]
+    }
+
+    #[inline]
+    fn state_word(&self, index: usize) -> &AtomicU64 {
+        &self.states[index]
+    }
+
+    fn raw_sentinels(&self) -> (u64, u64) {
+        (self.empty_key, self.tombstone_key)
+    }
+
+    fn probe_slots(&self, hash: u64, _all: bool) -> Linear {
+        Linear::new(hash, self.keys.len())
+    }
+
+    fn claimed(&self, _index: usize, _hash: u64) {
+        self.header.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn released(&self, _index: usize) {
+        self.header.count.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Allocate an 8-byte aligned, zeroed region of at least `len` bytes
+    fn region(len: usize) -> Vec<u64> {
+        vec![0u64; len.div_ceil(8)]
+    }
+
+    fn as_bytes(memory: &mut [u64]) -> &mut [u8] {
+        let len = memory.len() * 8;
+        unsafe { slice::from_raw_parts_mut(memory.as_mut_ptr() as *mut u8, len) }
+    }
+
+    #[test]
+    fn test_init_attach() {
+        let mut memory = region(AtomicSharedHashMap::<u64>::region_size(16));
+        let len = memory.len() * 8;
+        let ptr = memory.as_mut_ptr() as *mut u8;
+
+        let hashtable: AtomicSharedHashMap<u64, i32> =
+            AtomicSharedHashMap::init_with_sentinels(as_bytes(&mut memory), 16, 1, 2)
+                .unwrap();
+        assert_eq!(hashtable.insert(3, -3), Ok(None));
+        assert_eq!(hashtable.get_or_insert(4, -4), Ok(-4));
+        assert_eq!(hashtable.insert(1, 0), Err(AtomicHashMapError::InvalidKey));
+
+        // A second view of the same memory sees the same table
+        let attached: AtomicSharedHashMap<u64, i32> =
+            unsafe { AtomicSharedHashMap::attach(ptr, len) }.unwrap();
+        assert_eq!(attached.capacity(), 16);
+        assert_eq!(attached.sentinels(), (1, 2));
+        assert_eq!(attached.get(&3), Some(-3));
+        assert_eq!(attached.remove(4), Some(-4));
+        attached.insert(5, -5).unwrap();
+
+        assert_eq!(hashtable.get(&4), None);
+        assert_eq!(hashtable.get(&5), Some(-5));
+        assert_eq!(hashtable.len(), 2);
+
+        let mut entries: Vec<_> = hashtable.iter().collect();
+        entries.sort();
+        assert_eq!(entries, vec![(3, -3), (5, -5)]);
+
+        for x in 6..20 {
+            assert_eq!(hashtable.insert(x, 0), Ok(None));
+        }
+        assert_eq!(hashtable.insert(20, 0), Err(AtomicHashMapError::Full));
+    }
+
+    #[test]
+    fn test_from_raw_parts() {
+        use std::alloc::{alloc_zeroed, dealloc, Layout};
+
+        // Page aligned, as huge pages or DMA buffers would be
+        let len = AtomicSharedHashMap::<u64>::region_size(256);
+        let layout = Layout::from_size_align(len, 4096).unwrap();
+        let region = unsafe { alloc_zeroed(layout) };
+
+        {
+            let hashtable: AtomicSharedHashMap =
+                unsafe { AtomicSharedHashMap::from_raw_parts(region, len, 256) }.unwrap();
+            for x in 1..=100 {
+                hashtable.insert(x, x * 2).unwrap();
+            }
+            assert_eq!(hashtable.len(), 100);
+            assert_eq!(hashtable.get(&50), Some(100));
+
+            let res = unsafe { AtomicSharedHashMap::<u64>::from_raw_parts(region, len, 512) };
+            assert_eq!(res.err(), Some(AtomicHashMapError::InvalidRegion));
+        }
+
+        let res = unsafe {
+            AtomicSharedHashMap::<u64>::from_raw_parts(core::ptr::null_mut(), len, 256)
+        };
+        assert_eq!(res.err(), Some(AtomicHashMapError::InvalidRegion));
+
+        unsafe { dealloc(region, layout) };
+    }
+
+    #[test]
+    fn test_invalid_region() {
+        let mut memory = region(AtomicSharedHashMap::<u64>::region_size(16));
+        let len = memory.len() * 8;
+        let ptr = memory.as_mut_ptr() as *mut u8;
+
+        // Nothing was initialized yet
+        let res = unsafe { AtomicSharedHashMap::<u64>::attach(ptr, len) };
+        assert_eq!(res.err(), Some(AtomicHashMapError::InvalidRegion));
+
+        let bytes = as_bytes(&mut memory);
+        assert_eq!(AtomicSharedHashMap::<u64>::init(bytes, 12).err(),
+                   Some(AtomicHashMapError::InvalidCapacity));
+        assert_eq!(AtomicSharedHashMap::<u64>::init(bytes, 32).err(),
+                   Some(AtomicHashMapError::InvalidRegion));
+        assert_eq!(AtomicSharedHashMap::<u64>::init(&mut bytes[1..], 8).err(),
+                   Some(AtomicHashMapError::InvalidRegion));
+
+        AtomicSharedHashMap::<u64>::init(bytes, 16).unwrap();
+
+        // The region is shorter than the recorded capacity needs
+        let res = unsafe { AtomicSharedHashMap::<u64>::attach(ptr, len - 8) };
+        assert_eq!(res.err(), Some(AtomicHashMapError::InvalidRegion));
+        let res = unsafe { AtomicSharedHashMap::<u64>::attach(ptr.add(1), len - 1) };
+        assert_eq!(res.err(), Some(AtomicHashMapError::InvalidRegion));
+    }
+
+    #[test]
+    fn test_shared_threads() {
+        use std::thread;
+
+        let mut memory = region(AtomicSharedHashMap::<u64>::region_size(1024));
+        let len = memory.len() * 8;
+        let ptr = memory.as_mut_ptr() as usize;
+        AtomicSharedHashMap::<u32>::init(as_bytes(&mut memory), 1024).unwrap();
+
+        // Each thread attaches its own view, like a separate process would
+        let threads: Vec<_> = (0..8).map(|_| {
+            thread::spawn(move || {
+                let hashtable: AtomicSharedHashMap<u32> =
+                    unsafe { AtomicSharedHashMap::attach(ptr as *mut u8, len) }.unwrap();
+                for x in 1..=512 {
+                    hashtable.add_to(x, 1).unwrap();
+                }
+            })
+        }).collect();
+
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        let hashtable: AtomicSharedHashMap<u32> =
+            unsafe { AtomicSharedHashMap::attach(ptr as *mut u8, len) }.unwrap();
+        assert_eq!(hashtable.len(), 512);
+        for x in 1..=512 {
+            assert_eq!(hashtable.get(&x), Some(8));
+        }
+    }
+
+    #[test]
+    fn test_threads_no_duplicates() {
+        use std::thread;
+
+        let mut memory = region(AtomicSharedHashMap::<u64>::region_size(64));
+        let hashtable: AtomicSharedHashMap =
+            AtomicSharedHashMap::init(as_bytes(&mut memory), 64).unwrap();
+
+        // Keys all starting their probe at the same slot, so that removing one key
+        // opens a tombstone in front of inserts of all the others
+        let keys: Vec<u64> = (1..).filter(|&key| hash_key(key) & 63 == 0).take(24)
+            .collect();
+        let (inserted, removed) = keys.split_at(8);
+
+        for round in 0..200 {
+            for &key in removed {
+                hashtable.insert(key, round).unwrap();
+            }
+
+            // Two threads insert the same keys while a third removes the keys in front
+            // of them, and each key still ends up in a single slot
+            thread::scope(|scope| {
+                for _ in 0..2 {
+                    scope.spawn(|| {
+                        for &key in inserted {
+                            hashtable.insert(key, round).unwrap();
+                        }
+                    });
+                }
+
+                scope.spawn(|| {
+                    for &key in removed {
+                        assert_eq!(hashtable.remove(key), Some(round));
+                    }
+                });
+            });
+
+            let entries: Vec<_> = hashtable.iter().collect();
+            assert_eq!(entries.len(), 8);
+            assert_eq!(hashtable.len(), 8);
+            for &key in inserted {
+                assert_eq!(entries.iter().filter(|&&(x, _)| x == key).count(), 1);
+                assert_eq!(hashtable.remove(key), Some(round));
+                assert_eq!(hashtable.remove(key), None);
+            }
+        }
+    }
+}
@@ -0,0 +1,388 @@
+//! Lock-free skip list map from `u64` keys to `u64` values, kept in key order
+//!
+//! Nodes live in a fixed pool allocated up front and are linked by 32-bit index, the
+//! same way as the nodes of `AtomicHashMultiMap`. A node is taken from the pool by
+//! bumping a counter, filled in while private, and enters the map once a
+//! compare-exchange links it into the bottom level, which releases its key and value
+//! to every thread that follows the link with `Acquire`. Upper levels are linked
+//! after that, each with its own compare-exchange, and only speed up searches.
+//!
+//! Keys can't be removed, so a node is never unlinked or reused once it is in the
+//! list and searches never have to deal with nodes disappearing under them. A node is
+//! only wasted when an insert loses the race to link a new key to another insert of
+//! the same key.
+//!
+//! The height of a node is taken from the hash of its key rather than drawn at
+//! random, so that inserting needs no shared random state. `hash_key` is a
+//! bijection, so heights are distributed as if drawn at random for any set of keys.
+
+use alloc::boxed::Box;
+use core::ops::{Bound, RangeBounds};
+
+use core::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+use crate::error::AtomicHashMapError;
+use crate::hasher::hash_key;
+
+/// Most levels a node can be linked into
+const MAX_LEVEL: usize = 16;
+
+/// Index marking the end of a level
+const NIL: u32 = u32::MAX;
+
+/// Index of the head node, which comes before every key and is linked into every
+/// level
+const HEAD: u32 = 0;
+
+/// One node of the pool
+struct Node {
+    key: AtomicU64,
+    value: AtomicU64,
+
+    /// Index of the next node on each level the node is linked into
+    next: [AtomicU32; MAX_LEVEL]
+}
+
+/// Nodes before and after a key on every level
+struct Position {
+    /// Last node with a smaller key on each level
+    preds: [u32; MAX_LEVEL],
+
+    /// First node with a key at least as large on each level
+    succs: [u32; MAX_LEVEL]
+}
+
+/// Lock-free ordered map from `u64` keys to `u64` values, holding up to `capacity`
+/// keys
+pub struct AtomicSkipListMap {
+    nodes: Box<[Node]>,
+
+    /// Index of the next node to hand out
+    next_node: AtomicUsize,
+
+    /// Number of keys in the map
+    count: AtomicUsize
+}
+
+impl AtomicSkipListMap {
+    /// Construct an empty map with room for `capacity` keys. Returns
+    /// `InvalidCapacity` if `capacity` doesn't fit in a 32-bit index.
+    pub fn new(capacity: usize) -> Result<AtomicSkipListMap, AtomicHashMapError> {
+        if capacity >= NIL as usize {
+            return Err(AtomicHashMapError::InvalidCapacity);
+        }
+
+        let nodes = (0..=capacity).map(|_| Node {
+            key: AtomicU64::new(0),
+            value: AtomicU64::new(0),
+            next: core::array::from_fn(|_| AtomicU32::new(NIL))
+        }).collect();
+
+        Ok(AtomicSkipListMap {
+            nodes,
+            next_node: AtomicUsize::new(HEAD as usize + 1),
+            count: AtomicUsize::new(0)
+        })
+    }
+
+    fn node(&self, index: u32) -> &Node {
+        &self.nodes[index as usize]
+    }
+
+    /// Get the key of a node reached by following a link
+    fn key_of(&self, index: u32) -> u64 {
+        self.node(index).key.load(Ordering::Relaxed)
+    }
+
+    /// Get the number of levels the node of `key` is linked into
+    fn height(key: u64) -> usize {
+        1 + (hash_key(key).trailing_ones() as usize).min(MAX_LEVEL - 1)
+    }
+
+    /// Find the nodes around `key` on every level
+    fn find(&self, key: u64) -> Position {
+        let mut position = Position { preds: [HEAD; MAX_LEVEL], succs: [NIL; MAX_LEVEL] };
+
+        let mut pred = HEAD;
+        for level in (0..MAX_LEVEL).rev() {
+            loop {
+                let next = self.node(pred).next[level].load(Ordering::Acquire);
+                if next != NIL && self.key_of(next) < key {
+                    pred = next;
+                    continue;
+                }
+
+                position.preds[level] = pred;
+                position.succs[level] = next;
+                break;
+            }
+        }
+
+        position
+    }
+
+    /// Find the node holding `key`
+    fn find_node(&self, key: u64) -> Option<u32> {
+        let succ = self.find(key).succs[0];
+        (succ != NIL && self.key_of(succ) == key).then_some(succ)
+    }
+
+    /// Atomically set a key:value in the map
+    ///
+    /// Returns the value previously stored for this key, or `None` if the key was
+    /// newly inserted. Returns `AtomicHashMapError::Full` if the pool of nodes has run
+    /// out.
+    pub fn insert(&self, key: u64, value: u64)
+            -> Result<Option<u64>, AtomicHashMapError> {
+        let mut position = self.find(key);
+        let mut new_node = None;
+
+        loop {
+            let succ = position.succs[0];
+            if succ != NIL && self.key_of(succ) == key {
+                return Ok(Some(self.node(succ).value.swap(value, Ordering::AcqRel)));
+            }
+
+            // Take a node the first time the key is found missing and keep it across
+            // retries
+            let index = match new_node {
+                Some(index) => index,
+                None => {
+                    let index = self.next_node.fetch_add(1, Ordering::Relaxed);
+                    if index >= self.nodes.len() {
+                        // Keep the counter from wrapping around after many failures
+                        self.next_node.store(self.nodes.len(), Ordering::Relaxed);
+                        return Err(AtomicHashMapError::Full);
+                    }
+
+                    let node = &self.nodes[index];
+                    node.key.store(key, Ordering::Relaxed);
+                    node.value.store(value, Ordering::Relaxed);
+                    new_node = Some(index as u32);
+                    index as u32
+                }
+            };
+
+            // Linking the bottom level puts the key in the map
+            self.node(index).next[0].store(succ, Ordering::Relaxed);
+            let link = &self.node(position.preds[0]).next[0];
+            if link.compare_exchange(succ, index, Ordering::Release, Ordering::Relaxed)
+                    .is_ok() {
+                self.count.fetch_add(1, Ordering::Relaxed);
+                self.link_upper_levels(index, key, position);
+                return Ok(None);
+            }
+
+            position = self.find(key);
+        }
+    }
+
+    /// Link the node at `index`, already in the bottom level, into the upper levels
+    /// of its height
+    fn link_upper_levels(&self, index: u32, key: u64, mut position: Position) {
+        for level in 1..AtomicSkipListMap::height(key) {
+            loop {
+                let succ = position.succs[level];
+                self.node(index).next[level].store(succ, Ordering::Relaxed);
+                let link = &self.node(position.preds[level]).next[level];
+                if link.compare_exchange(succ, index, Ordering::Release,
+                                         Ordering::Relaxed).is_ok() {
+                    break;
+                }
+
+                position = self.find(key);
+            }
+        }
+    }
+
+    /// Atomically get the value of a key from the map
+    pub fn get(&self, key: &u64) -> Option<u64> {
+        let index = self.find_node(*key)?;
+        Some(self.node(index).value.load(Ordering::Acquire))
+    }
+
+    /// Check if a key is in the map
+    pub fn contains_key(&self, key: &u64) -> bool {
+        self.find_node(*key).is_some()
+    }
+
+    /// Get the entry of node `index`
+    fn entry(&self, index: u32) -> (u64, u64) {
+        (self.key_of(index), self.node(index).value.load(Ordering::Acquire))
+    }
+
+    /// Get the entry with the smallest key
+    pub fn first(&self) -> Option<(u64, u64)> {
+        let first = self.node(HEAD).next[0].load(Ordering::Acquire);
+        (first != NIL).then(|| self.entry(first))
+    }
+
+    /// Get the entry with the largest key
+    pub fn last(&self) -> Option<(u64, u64)> {
+        let mut pred = HEAD;
+        for level in (0..MAX_LEVEL).rev() {
+            loop {
+                match self.node(pred).next[level].load(Ordering::Acquire) {
+                    NIL => break,
+                    next => pred = next
+                }
+            }
+        }
+
+        (pred != HEAD).then(|| self.entry(pred))
+    }
+
+    /// Get the entry with the largest key less than or equal to `key`
+    pub fn floor(&self, key: u64) -> Option<(u64, u64)> {
+        let position = self.find(key);
+        let succ = position.succs[0];
+        if succ != NIL && self.key_of(succ) == key {
+            return Some(self.entry(succ));
+        }
+
+        let pred = position.preds[0];
+        (pred != HEAD).then(|| self.entry(pred))
+    }
+
+    /// Get the entry with the smallest key greater than or equal to `key`
+    pub fn ceiling(&self, key: u64) -> Option<(u64, u64)> {
+        let succ = self.find(key).succs[0];
+        (succ != NIL).then(|| self.entry(succ))
+    }
+
+    /// Iterate over the entries in increasing key order
+    ///
+    /// Keys inserted during the iteration are seen if they land after the current
+    /// position of the iterator.
+    pub fn iter(&self) -> Range<'_> {
+        self.range(..)
+    }
+
+    /// Iterate over the entries whose keys are within `range`, in increasing key order
+    pub fn range<R: RangeBounds<u64>>(&self, range: R) -> Range<'_> {
+        let next = match range.start_bound() {
+            Bound::Included(&start) => self.find(start).succs[0],
+            Bound::Excluded(&start) => match start.checked_add(1) {
+                Some(start) => self.find(start).succs[0],
+                None => NIL
+            },
+            Bound::Unbounded => self.node(HEAD).next[0].load(Ordering::Acquire)
+        };
+
+        Range { map: self, next, end: range.end_bound().cloned() }
+    }
+
+    /// Get the number of keys in the map
+    pub fn len(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Returns true if the map is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the number of keys the map has room for
+    pub fn capacity(&self) -> usize {
+        self.nodes.len() - 1
+    }
+}
+
+/// Iterator over the entries of an `AtomicSkipListMap` in key order, created by
+/// `AtomicSkipListMap::iter` and `AtomicSkipListMap::range`
+pub struct Range<'a> {
+    map: &'a AtomicSkipListMap,
+
+    /// Index of the next node to visit
+    next: u32,
+
+    /// Bound past which to stop
+    end: Bound<u64>
+}
+
+impl<'a> Iterator for Range<'a> {
+    type Item = (u64, u64);
+
+    fn next(&mut self) -> Option<(u64, u64)> {
+        if self.next == NIL {
+            return None;
+        }
+
+        let (key, value) = self.map.entry(self.next);
+        let in_range = match self.end {
+            Bound::Included(end) => key <= end,
+            Bound::Excluded(end) => key < end,
+            Bound::Unbounded => true
+        };
+        if !in_range {
+            self.next = NIL;
+            return None;
+        }
+
+        self.next = self.map.node(self.next).next[0].load(Ordering::Acquire);
+        Some((key, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    #[test]
+    fn test_skiplist() {
+        let map = AtomicSkipListMap::new(64).unwrap();
+        assert_eq!(map.first(), None);
+        assert_eq!(map.last(), None);
+
+        for key in [50, 10, 40, 0, u64::MAX, 30, 20] {
+            assert_eq!(map.insert(key, key.wrapping_add(1)), Ok(None));
+        }
+        assert_eq!(map.insert(30, 3), Ok(Some(31)));
+        assert_eq!(map.get(&30), Some(3));
+        assert_eq!(map.get(&35), None);
+        assert!(map.contains_key(&0));
+        assert_eq!(map.len(), 7);
+
+        assert_eq!(map.iter().map(|(key, _)| key).collect::<Vec<_>>(),
+                   vec![0, 10, 20, 30, 40, 50, u64::MAX]);
+        assert_eq!(map.range(15..40).collect::<Vec<_>>(), vec![(20, 21), (30, 3)]);
+        assert_eq!(map.range(20..=40).count(), 3);
+        assert_eq!(map.range((Bound::Excluded(u64::MAX), Bound::Unbounded)).count(), 0);
+        assert_eq!(map.range(..=0).collect::<Vec<_>>(), vec![(0, 1)]);
+
+        assert_eq!(map.first(), Some((0, 1)));
+        assert_eq!(map.last(), Some((u64::MAX, 0)));
+        assert_eq!(map.floor(35), Some((30, 3)));
+        assert_eq!(map.floor(40), Some((40, 41)));
+        assert_eq!(map.ceiling(35), Some((40, 41)));
+        assert_eq!(map.ceiling(51).map(|(key, _)| key), Some(u64::MAX));
+
+        let empty = AtomicSkipListMap::new(0).unwrap();
+        assert_eq!(empty.insert(1, 1), Err(AtomicHashMapError::Full));
+        assert_eq!(empty.floor(1), None);
+    }
+
+    #[test]
+    fn test_skiplist_threads() {
+        let map = AtomicSkipListMap::new(16_000).unwrap();
+
+        // Threads insert interleaved keys, some of them twice
+        thread::scope(|scope| {
+            for thread in 0..4 {
+                let map = &map;
+                scope.spawn(move || {
+                    for key in (thread..8000).step_by(4) {
+                        map.insert(key, key * 2).unwrap();
+                        map.insert(7999 - key, (7999 - key) * 2).unwrap();
+                    }
+                });
+            }
+        });
+
+        assert_eq!(map.len(), 8000);
+        assert!(map.iter().map(|(key, _)| key).eq(0..8000));
+        assert!(map.iter().all(|(key, value)| value == key * 2));
+    }
+}
@@ -0,0 +1,313 @@
+//! Lock-free slab of values addressed by stable `u32` handles
+//!
+//! A fixed pool of slots allocated up front, with the slots not in use linked into
+//! a free list headed like the lists of `AtomicStack`: the index of the first slot
+//! and a version tag packed into one `AtomicU64`, the tag bumped by every successful
+//! compare-exchange so that a slot popped and pushed back under a thread fails its
+//! compare-exchange. `alloc` pops a slot off the free list and moves a value into
+//! it, `free` moves the value out and pushes the slot back, and neither allocates.
+//!
+//! The handle of a value is the index of its slot, which never moves, so a value
+//! too wide for a map can be stored here and referenced by its handle from a map
+//! value. A handle is only valid until its value is freed, after which the slot can
+//! be handed out again under the same handle.
+//!
+//! Each slot has a state word holding an occupied bit and the number of `SlabRef`s
+//! reading its value. `get` only counts itself in while the bit is set, and `free`
+//! clears the bit and then waits for the readers already in to leave before moving
+//! the value out, so a value is never read while or after it is freed. The slot is
+//! published to readers with a `Release` store of the occupied bit and to the next
+//! `alloc` through the free list, with `Release` and `Acquire` like `AtomicStack`.
+
+use alloc::boxed::Box;
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::ops::Deref;
+use core::ptr;
+
+use core::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+use crate::backoff::Backoff;
+use crate::error::AtomicHashMapError;
+use crate::stack::{pack, unpack, NIL};
+
+/// State bit of a slot holding a value. The bits below count the readers.
+const OCCUPIED: u32 = 1 << 31;
+
+/// One slot of the pool
+struct Slot<T> {
+    /// Occupied bit and number of readers
+    state: AtomicU32,
+
+    /// Index of the next slot of the free list while this one is on it
+    next: AtomicU32,
+
+    value: UnsafeCell<MaybeUninit<T>>
+}
+
+/// Fixed-capacity pool of values of type `T` addressed by `u32` handles
+pub struct AtomicSlab<T> {
+    slots: Box<[Slot<T>]>,
+
+    /// First slot of the free list, tagged
+    free: AtomicU64,
+
+    /// Number of values in the slab
+    len: AtomicUsize
+}
+
+// SAFETY: Values are moved in and out by one thread at a time, and only shared
+// through `SlabRef`s
+unsafe impl<T: Send> Send for AtomicSlab<T> {}
+unsafe impl<T: Send + Sync> Sync for AtomicSlab<T> {}
+
+impl<T> AtomicSlab<T> {
+    /// Construct an empty slab holding up to `capacity` values. Returns
+    /// `InvalidCapacity` if `capacity` doesn't fit in a 32-bit handle.
+    pub fn new(capacity: usize) -> Result<AtomicSlab<T>, AtomicHashMapError> {
+        if capacity >= NIL as usize {
+            return Err(AtomicHashMapError::InvalidCapacity);
+        }
+
+        // Every slot starts on the free list, in order
+        let slots = (0..capacity).map(|index| {
+            let next = if index + 1 < capacity { index as u32 + 1 } else { NIL };
+            Slot {
+                state: AtomicU32::new(0),
+                next: AtomicU32::new(next),
+                value: UnsafeCell::new(MaybeUninit::uninit())
+            }
+        }).collect();
+
+        Ok(AtomicSlab {
+            slots,
+            free: AtomicU64::new(pack(if capacity > 0 { 0 } else { NIL }, 0)),
+            len: AtomicUsize::new(0)
+        })
+    }
+
+    /// Move `value` into a free slot, returning its handle, or give `value` back if
+    /// the slab holds `capacity` values
+    pub fn alloc(&self, value: T) -> Result<u32, T> {
+        let mut head = self.free.load(Ordering::Acquire);
+        let index = loop {
+            let (index, tag) = unpack(head);
+            if index == NIL {
+                return Err(value);
+            }
+
+            let next = self.slots[index as usize].next.load(Ordering::Relaxed);
+            match self.free.compare_exchange_weak(head, pack(next, tag.wrapping_add(1)),
+                                                  Ordering::Acquire, Ordering::Acquire) {
+                Ok(_) => break index,
+                Err(curr) => head = curr
+            }
+        };
+
+        let slot = &self.slots[index as usize];
+
+        // SAFETY: The slot was taken off the free list by this thread alone, and has no
+        // readers since its occupied bit is clear
+        unsafe { (*slot.value.get()).as_mut_ptr().write(value); }
+        slot.state.store(OCCUPIED, Ordering::Release);
+        self.len.fetch_add(1, Ordering::Relaxed);
+        Ok(index)
+    }
+
+    /// Get a reference to the value of `handle`, or `None` if it is free. The value
+    /// can't be freed until the reference is dropped.
+    pub fn get(&self, handle: u32) -> Option<SlabRef<'_, T>> {
+        let slot = self.slots.get(handle as usize)?;
+        let mut state = slot.state.load(Ordering::Relaxed);
+        loop {
+            if state & OCCUPIED == 0 {
+                return None;
+            }
+
+            match slot.state.compare_exchange_weak(state, state + 1, Ordering::Acquire,
+                                                   Ordering::Relaxed) {
+                Ok(_) => return Some(SlabRef { slot }),
+                Err(curr) => state = curr
+            }
+        }
+    }
+
+    /// Returns true if `handle` refers to a value
+    pub fn contains(&self, handle: u32) -> bool {
+        self.slots.get(handle as usize)
+            .is_some_and(|slot| slot.state.load(Ordering::Acquire) & OCCUPIED != 0)
+    }
+
+    /// Move the value of `handle` out and give its slot back, or return `None` if it
+    /// is already free
+    ///
+    /// Waits for the `SlabRef`s of the value to be dropped, so freeing a value while
+    /// holding a reference to it on the same thread never returns.
+    pub fn free(&self, handle: u32) -> Option<T> {
+        let slot = self.slots.get(handle as usize)?;
+        if slot.state.fetch_and(!OCCUPIED, Ordering::Acquire) & OCCUPIED == 0 {
+            return None;
+        }
+
+        // No new reader can get in, wait for those already in to leave
+        let mut backoff = Backoff::new();
+        while slot.state.load(Ordering::Acquire) != 0 {
+            backoff.snooze();
+        }
+
+        // SAFETY: The value was written by `alloc` and this thread alone cleared the
+        // occupied bit, so nobody else reads it or moves it out
+        let value = unsafe { ptr::read((*slot.value.get()).as_ptr()) };
+
+        // Release the slot to whoever allocates it next
+        let mut head = self.free.load(Ordering::Relaxed);
+        loop {
+            let (next, tag) = unpack(head);
+            slot.next.store(next, Ordering::Relaxed);
+            match self.free.compare_exchange_weak(head, pack(handle, tag.wrapping_add(1)),
+                                                  Ordering::Release, Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(curr) => head = curr
+            }
+        }
+
+        self.len.fetch_sub(1, Ordering::Relaxed);
+        Some(value)
+    }
+
+    /// Get the number of values in the slab. Only a hint while other threads
+    /// allocate and free values.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    /// Returns true if the slab holds no values. Only a hint while other threads
+    /// allocate and free values.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the number of values the slab holds at most
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+}
+
+impl<T> Drop for AtomicSlab<T> {
+    fn drop(&mut self) {
+        for slot in self.slots.iter_mut() {
+            if *slot.state.get_mut() & OCCUPIED != 0 {
+                // SAFETY: Occupied slots hold a value written by `alloc`
+                unsafe { ptr::drop_in_place(slot.value.get_mut().as_mut_ptr()); }
+            }
+        }
+    }
+}
+
+/// Reference to a value of an `AtomicSlab`, keeping it from being freed until
+/// dropped
+pub struct SlabRef<'a, T> {
+    slot: &'a Slot<T>
+}
+
+impl<'a, T> Deref for SlabRef<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: The value stays in place while this reader is counted in its slot
+        unsafe { &*(*self.slot.value.get()).as_ptr() }
+    }
+}
+
+impl<'a, T> Drop for SlabRef<'a, T> {
+    fn drop(&mut self) {
+        self.slot.state.fetch_sub(1, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    #[test]
+    fn test_slab() {
+        let slab = AtomicSlab::new(2).unwrap();
+        assert!(slab.is_empty());
+
+        let a = slab.alloc([1u64; 4]).unwrap();
+        let b = slab.alloc([2u64; 4]).unwrap();
+        assert_eq!(slab.alloc([3u64; 4]), Err([3u64; 4]));
+        assert_eq!(slab.len(), 2);
+
+        assert_eq!(*slab.get(a).unwrap(), [1; 4]);
+        assert!(slab.contains(b));
+        assert_eq!(slab.free(b), Some([2; 4]));
+        assert_eq!(slab.free(b), None);
+        assert!(slab.get(b).is_none());
+        assert!(slab.get(7).is_none());
+
+        // The freed slot is handed out again
+        assert_eq!(slab.alloc([4u64; 4]), Ok(b));
+        assert_eq!(*slab.get(b).unwrap(), [4; 4]);
+        assert_eq!(slab.capacity(), 2);
+
+        let empty: AtomicSlab<u8> = AtomicSlab::new(0).unwrap();
+        assert_eq!(empty.alloc(1), Err(1));
+    }
+
+    #[test]
+    fn test_slab_drop() {
+        let value = std::sync::Arc::new(());
+        {
+            let slab = AtomicSlab::new(4).unwrap();
+            for _ in 0..3 {
+                slab.alloc(value.clone()).unwrap();
+            }
+            drop(slab.free(1));
+            assert_eq!(std::sync::Arc::strong_count(&value), 3);
+        }
+
+        // The values still in the slab are dropped with it
+        assert_eq!(std::sync::Arc::strong_count(&value), 1);
+    }
+
+    #[test]
+    fn test_slab_threads() {
+        let slab = AtomicSlab::new(8).unwrap();
+
+        // Threads allocate, read and free values of their own while others read
+        // whatever handle they pick, which is never a freed value
+        thread::scope(|scope| {
+            for thread in 0..2u64 {
+                let slab = &slab;
+                scope.spawn(move || {
+                    for i in 0..5_000 {
+                        let value = thread << 32 | i;
+                        let handle = slab.alloc(Box::new(value)).unwrap();
+                        assert_eq!(**slab.get(handle).unwrap(), value);
+                        assert_eq!(slab.free(handle).map(|value| *value), Some(value));
+                    }
+                });
+            }
+
+            for _ in 0..2 {
+                let slab = &slab;
+                scope.spawn(move || {
+                    for i in 0..5_000 {
+                        if let Some(value) = slab.get(i % 8) {
+                            assert!(**value & 0xffff_ffff < 5_000);
+                        }
+                    }
+                });
+            }
+        });
+
+        assert!(slab.is_empty());
+        let mut handles = (0..8).map(|i| slab.alloc(Box::new(i)).unwrap())
+            .collect::<Vec<_>>();
+        handles.sort();
+        assert_eq!(handles, (0..8).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,157 @@
+//! Spinlock for short critical sections, usable without `std`
+//!
+//! A test-and-test-and-set lock: a thread waiting for the lock spins on plain loads,
+//! which stay in its own cache while the lock is held, and only tries the
+//! compare-exchange once it sees the lock free. Failed attempts snooze on a
+//! `Backoff`, doubling the number of spins every time, so that a crowd of waiters
+//! doesn't hammer the cache line of the lock the moment it is released. With `std`, a
+//! waiter that has backed off all the way yields its time slice instead, in case the
+//! holder was preempted.
+//!
+//! Acquiring the lock is an `Acquire` compare-exchange and releasing it a `Release`
+//! store, so each holder sees the writes of the previous one.
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::backoff::Backoff;
+
+/// Lock protecting a value of type `T`, acquired by spinning
+pub struct SpinLock<T> {
+    locked: AtomicBool,
+    value: UnsafeCell<T>
+}
+
+// SAFETY: The value is only reached through a guard, of which there is at most one
+unsafe impl<T: Send> Send for SpinLock<T> {}
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    /// Construct an unlocked lock holding `value`
+    pub const fn new(value: T) -> SpinLock<T> {
+        SpinLock { locked: AtomicBool::new(false), value: UnsafeCell::new(value) }
+    }
+
+    /// Acquire the lock, spinning until it is free
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        let mut backoff = Backoff::new();
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+
+            // Wait for the lock to look free before trying again
+            while self.locked.load(Ordering::Relaxed) {
+                backoff.snooze();
+            }
+        }
+    }
+
+    /// Acquire the lock if it is free, without spinning
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+
+        Some(SpinLockGuard { lock: self })
+    }
+
+    /// Returns true if the lock is held. Only a hint while other threads lock and
+    /// unlock it.
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    /// Get a mutable reference to the value, which no other thread can access
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    /// Take the value out of the lock
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: Default> Default for SpinLock<T> {
+    fn default() -> SpinLock<T> {
+        SpinLock::new(T::default())
+    }
+}
+
+/// Proof that a `SpinLock` is held, giving access to its value and releasing the
+/// lock when dropped
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>
+}
+
+// SAFETY: The guard only hands out references to the value
+unsafe impl<'a, T: Sync> Sync for SpinLockGuard<'a, T> {}
+
+impl<'a, T> Deref for SpinLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: The lock is held by this guard
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<'a, T> DerefMut for SpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: The lock is held by this guard
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<'a, T> Drop for SpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    #[test]
+    fn test_spinlock() {
+        let mut lock = SpinLock::new(vec![1]);
+        {
+            let mut guard = lock.lock();
+            assert!(lock.is_locked());
+            assert!(lock.try_lock().is_none());
+            guard.push(2);
+        }
+
+        assert!(!lock.is_locked());
+        lock.try_lock().unwrap().push(3);
+        lock.get_mut().push(4);
+        assert_eq!(lock.into_inner(), vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_spinlock_threads() {
+        let lock = SpinLock::new((0u64, 0u64));
+
+        // Both halves are updated under the lock, so they always match
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                let lock = &lock;
+                scope.spawn(move || {
+                    for _ in 0..10_000 {
+                        let mut guard = lock.lock();
+                        assert_eq!(guard.0, guard.1);
+                        guard.0 += 1;
+                        guard.1 += 1;
+                    }
+                });
+            }
+        });
+
+        assert_eq!(lock.into_inner(), (40_000, 40_000));
+    }
+}
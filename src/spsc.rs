@@ -0,0 +1,212 @@
+//! Bounded single-producer single-consumer ring buffer
+//!
+//! With one producer and one consumer, neither cursor needs a compare-exchange: only
+//! the producer moves the tail and only the consumer moves the head, each publishing
+//! its cursor with `Release` once the slot behind it is written or read. Each side
+//! also keeps the last cursor it read from the other side, and only loads the shared
+//! one again when the cached value says the ring is full or empty. In a steady
+//! stream each side then only touches the cache line of the other once per lap
+//! rather than once per value, and the two cursors sit on separate cache lines.
+//!
+//! The ring is used through the `Producer` and `Consumer` handed out by `split`,
+//! which borrows it mutably so that there can only be one of each at a time.
+
+use alloc::boxed::Box;
+use core::marker::PhantomData;
+
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::error::AtomicHashMapError;
+use crate::pod::PodU64;
+
+/// Cursor on a cache line of its own
+#[repr(align(64))]
+struct Padded(AtomicUsize);
+
+/// Bounded SPSC ring buffer of values of type `T`
+pub struct SpscRing<T: PodU64 = u64> {
+    slots: Box<[AtomicU64]>,
+
+    /// Position of the next pop, only moved by the consumer
+    head: Padded,
+
+    /// Position of the next push, only moved by the producer
+    tail: Padded,
+
+    _values: PhantomData<T>
+}
+
+impl<T: PodU64> SpscRing<T> {
+    /// Construct an empty ring holding up to `capacity` values.
+    /// NOTE: Capacity must be a power of two, otherwise `InvalidCapacity` is returned.
+    pub fn new(capacity: usize) -> Result<SpscRing<T>, AtomicHashMapError> {
+        if capacity < 2 || !capacity.is_power_of_two() {
+            return Err(AtomicHashMapError::InvalidCapacity);
+        }
+
+        Ok(SpscRing {
+            slots: (0..capacity).map(|_| AtomicU64::new(0)).collect(),
+            head: Padded(AtomicUsize::new(0)),
+            tail: Padded(AtomicUsize::new(0)),
+            _values: PhantomData
+        })
+    }
+
+    /// Split the ring into its producer and consumer, to be moved to the threads
+    /// pushing and popping. Values left in the ring by earlier handles are kept.
+    pub fn split(&mut self) -> (Producer<'_, T>, Consumer<'_, T>) {
+        let head = self.head.0.load(Ordering::Relaxed);
+        let tail = self.tail.0.load(Ordering::Relaxed);
+
+        (Producer { ring: self, tail, cached_head: head },
+         Consumer { ring: self, head, cached_tail: tail })
+    }
+
+    /// Get the slot of position `pos`
+    fn slot(&self, pos: usize) -> &AtomicU64 {
+        &self.slots[pos & (self.slots.len() - 1)]
+    }
+
+    /// Get the number of values in the ring. Only a hint while it is pushed to or
+    /// popped from.
+    pub fn len(&self) -> usize {
+        let head = self.head.0.load(Ordering::Acquire);
+        let tail = self.tail.0.load(Ordering::Acquire);
+        tail.wrapping_sub(head).min(self.slots.len())
+    }
+
+    /// Returns true if the ring holds no values
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the number of values the ring holds at most
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+}
+
+/// Pushing half of an `SpscRing`
+pub struct Producer<'a, T: PodU64> {
+    ring: &'a SpscRing<T>,
+
+    /// Position of the next push, owned by this producer
+    tail: usize,
+
+    /// Head as last read from the consumer
+    cached_head: usize
+}
+
+impl<'a, T: PodU64> Producer<'a, T> {
+    /// Push `value` to the back of the ring, or return `AtomicHashMapError::Full` if
+    /// it holds `capacity` values
+    pub fn push(&mut self, value: T) -> Result<(), AtomicHashMapError> {
+        let capacity = self.ring.slots.len();
+        if self.tail.wrapping_sub(self.cached_head) == capacity {
+            // Full as far as we know, see how far the consumer got
+            self.cached_head = self.ring.head.0.load(Ordering::Acquire);
+            if self.tail.wrapping_sub(self.cached_head) == capacity {
+                return Err(AtomicHashMapError::Full);
+            }
+        }
+
+        self.ring.slot(self.tail).store(value.to_u64(), Ordering::Relaxed);
+        self.tail = self.tail.wrapping_add(1);
+        self.ring.tail.0.store(self.tail, Ordering::Release);
+        Ok(())
+    }
+}
+
+/// Popping half of an `SpscRing`
+pub struct Consumer<'a, T: PodU64> {
+    ring: &'a SpscRing<T>,
+
+    /// Position of the next pop, owned by this consumer
+    head: usize,
+
+    /// Tail as last read from the producer
+    cached_tail: usize
+}
+
+impl<'a, T: PodU64> Consumer<'a, T> {
+    /// Pop the value at the front of the ring, or `None` if it is empty
+    pub fn pop(&mut self) -> Option<T> {
+        if self.head == self.cached_tail {
+            // Empty as far as we know, see how far the producer got
+            self.cached_tail = self.ring.tail.0.load(Ordering::Acquire);
+            if self.head == self.cached_tail {
+                return None;
+            }
+        }
+
+        let value = self.ring.slot(self.head).load(Ordering::Relaxed);
+        self.head = self.head.wrapping_add(1);
+        self.ring.head.0.store(self.head, Ordering::Release);
+        Some(T::from_u64(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    #[test]
+    fn test_spsc() {
+        let mut ring: SpscRing<i32> = SpscRing::new(4).unwrap();
+        {
+            let (mut producer, mut consumer) = ring.split();
+            assert_eq!(consumer.pop(), None);
+
+            for value in [1, -2, 3, -4] {
+                producer.push(value).unwrap();
+            }
+            assert_eq!(producer.push(5), Err(AtomicHashMapError::Full));
+            assert_eq!(consumer.pop(), Some(1));
+            producer.push(5).unwrap();
+            assert_eq!(consumer.pop(), Some(-2));
+        }
+
+        // New handles pick up where the old ones left off
+        assert_eq!(ring.len(), 3);
+        let (_, mut consumer) = ring.split();
+        assert_eq!(consumer.pop(), Some(3));
+        assert_eq!(consumer.pop(), Some(-4));
+        assert_eq!(consumer.pop(), Some(5));
+        assert_eq!(consumer.pop(), None);
+
+        assert!(SpscRing::<u64>::new(3).is_err());
+    }
+
+    #[test]
+    fn test_spsc_threads() {
+        let mut ring: SpscRing = SpscRing::new(64).unwrap();
+        let (mut producer, mut consumer) = ring.split();
+
+        thread::scope(|scope| {
+            scope.spawn(move || {
+                for value in 1..=100_000 {
+                    while producer.push(value).is_err() {
+                        thread::yield_now();
+                    }
+                }
+            });
+
+            // Values arrive in order, none lost or repeated
+            for expected in 1..=100_000 {
+                loop {
+                    match consumer.pop() {
+                        Some(value) => {
+                            assert_eq!(value, expected);
+                            break;
+                        }
+                        None => thread::yield_now()
+                    }
+                }
+            }
+        });
+
+        assert!(ring.is_empty());
+    }
+}
@@ -0,0 +1,197 @@
+//! Lock-free LIFO stack over a preallocated pool of nodes
+//!
+//! A Treiber stack whose nodes are slots of a fixed pool, linked by 32-bit index
+//! rather than by pointer. The pool holds two lists: the stack itself and a free list
+//! of the nodes not in use. `push` pops a node off the free list, fills it and pushes
+//! it onto the stack, and `pop` does the reverse, so neither ever allocates.
+//!
+//! The head of each list packs the index of its first node with a version tag into
+//! one `AtomicU64`, and every successful compare-exchange of a head bumps its tag.
+//! A thread that read a head, lost its node to a pop and saw it pushed back by the
+//! time of its own compare-exchange still fails, since the tag has moved on even
+//! though the index is the same. That rules out the ABA problem of a plain Treiber
+//! stack short of 2^32 updates of one head during a single push or pop. Nodes are
+//! never freed, so reading the link of a node that was popped in the meantime is
+//! harmless: the value read is only used if the compare-exchange proves it current.
+
+use alloc::boxed::Box;
+use core::marker::PhantomData;
+
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use crate::error::AtomicHashMapError;
+use crate::pod::PodU64;
+
+/// Index marking the end of a list
+pub(crate) const NIL: u32 = u32::MAX;
+
+/// Pack a node index and a version tag into a list head
+pub(crate) fn pack(index: u32, tag: u32) -> u64 {
+    u64::from(tag) << 32 | u64::from(index)
+}
+
+/// Split a list head into its node index and version tag
+pub(crate) fn unpack(head: u64) -> (u32, u32) {
+    (head as u32, (head >> 32) as u32)
+}
+
+/// One node of the pool
+struct Node {
+    value: AtomicU64,
+
+    /// Index of the next node of the list holding this one
+    next: AtomicU32
+}
+
+/// Lock-free LIFO stack of up to `capacity` values of type `T`
+pub struct AtomicStack<T: PodU64 = u64> {
+    nodes: Box<[Node]>,
+
+    /// First node of the stack, tagged
+    head: AtomicU64,
+
+    /// First node of the free list, tagged
+    free: AtomicU64,
+
+    _values: PhantomData<T>
+}
+
+impl<T: PodU64> AtomicStack<T> {
+    /// Construct an empty stack holding up to `capacity` values. Returns
+    /// `InvalidCapacity` if `capacity` doesn't fit in a 32-bit index.
+    pub fn new(capacity: usize) -> Result<AtomicStack<T>, AtomicHashMapError> {
+        if capacity >= NIL as usize {
+            return Err(AtomicHashMapError::InvalidCapacity);
+        }
+
+        // Every node starts on the free list, in order
+        let nodes = (0..capacity).map(|index| {
+            let next = if index + 1 < capacity { index as u32 + 1 } else { NIL };
+            Node { value: AtomicU64::new(0), next: AtomicU32::new(next) }
+        }).collect();
+
+        Ok(AtomicStack {
+            nodes,
+            head: AtomicU64::new(pack(NIL, 0)),
+            free: AtomicU64::new(pack(if capacity > 0 { 0 } else { NIL }, 0)),
+            _values: PhantomData
+        })
+    }
+
+    /// Pop the first node off the list headed by `list`, returning its index
+    fn take(&self, list: &AtomicU64) -> Option<u32> {
+        let mut head = list.load(Ordering::Acquire);
+        loop {
+            let (index, tag) = unpack(head);
+            if index == NIL {
+                return None;
+            }
+
+            let next = self.nodes[index as usize].next.load(Ordering::Relaxed);
+            match list.compare_exchange_weak(head, pack(next, tag.wrapping_add(1)),
+                                             Ordering::Acquire, Ordering::Acquire) {
+                Ok(_) => return Some(index),
+                Err(curr) => head = curr
+            }
+        }
+    }
+
+    /// Push the node at `index` onto the list headed by `list`
+    fn put(&self, list: &AtomicU64, index: u32) {
+        let node = &self.nodes[index as usize];
+        let mut head = list.load(Ordering::Relaxed);
+        loop {
+            let (next, tag) = unpack(head);
+            node.next.store(next, Ordering::Relaxed);
+
+            // Release the link and value of the node to whoever takes it next
+            match list.compare_exchange_weak(head, pack(index, tag.wrapping_add(1)),
+                                             Ordering::Release, Ordering::Relaxed) {
+                Ok(_) => return,
+                Err(curr) => head = curr
+            }
+        }
+    }
+
+    /// Push `value` onto the stack, or return `AtomicHashMapError::Full` if it holds
+    /// `capacity` values
+    pub fn push(&self, value: T) -> Result<(), AtomicHashMapError> {
+        let index = self.take(&self.free).ok_or(AtomicHashMapError::Full)?;
+        self.nodes[index as usize].value.store(value.to_u64(), Ordering::Relaxed);
+        self.put(&self.head, index);
+        Ok(())
+    }
+
+    /// Pop the value on top of the stack, or `None` if it is empty
+    pub fn pop(&self) -> Option<T> {
+        let index = self.take(&self.head)?;
+        let value = self.nodes[index as usize].value.load(Ordering::Relaxed);
+        self.put(&self.free, index);
+        Some(T::from_u64(value))
+    }
+
+    /// Returns true if the stack holds no values
+    pub fn is_empty(&self) -> bool {
+        unpack(self.head.load(Ordering::Relaxed)).0 == NIL
+    }
+
+    /// Get the number of values the stack holds at most
+    pub fn capacity(&self) -> usize {
+        self.nodes.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    #[test]
+    fn test_stack() {
+        let stack: AtomicStack<i32> = AtomicStack::new(3).unwrap();
+        assert!(stack.is_empty());
+        assert_eq!(stack.pop(), None);
+
+        stack.push(1).unwrap();
+        stack.push(-2).unwrap();
+        stack.push(3).unwrap();
+        assert_eq!(stack.push(4), Err(AtomicHashMapError::Full));
+
+        assert_eq!(stack.pop(), Some(3));
+        stack.push(4).unwrap();
+        assert_eq!(stack.pop(), Some(4));
+        assert_eq!(stack.pop(), Some(-2));
+        assert_eq!(stack.pop(), Some(1));
+        assert_eq!(stack.pop(), None);
+
+        let empty: AtomicStack = AtomicStack::new(0).unwrap();
+        assert_eq!(empty.push(1), Err(AtomicHashMapError::Full));
+    }
+
+    #[test]
+    fn test_stack_threads() {
+        let stack: AtomicStack = AtomicStack::new(16).unwrap();
+        for value in 0..8 {
+            stack.push(value).unwrap();
+        }
+
+        // Threads hammer the same few nodes, which is where ABA would strike
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                let stack = &stack;
+                scope.spawn(move || {
+                    for _ in 0..20_000 {
+                        if let Some(value) = stack.pop() {
+                            stack.push(value).unwrap();
+                        }
+                    }
+                });
+            }
+        });
+
+        let mut values = core::iter::from_fn(|| stack.pop()).collect::<Vec<_>>();
+        values.sort();
+        assert_eq!(values, (0..8).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,435 @@
+//! Heap-free `AtomicHashMap` with its slots stored inline
+//!
+//! `AtomicStaticHashMap<N>` keeps its keys, values and slot states in inline
+//! arrays of `N` atomics, so it never allocates and can be built by a `const fn`
+//! into a `static`, or placed in memory shared with another process or a guest.
+//! Entries are claimed, published and removed with the same protocol as
+//! `AtomicHashMap`, with keys hashed by `hash_key` and probed one slot at a time.
+//!
+//! # Memory ordering
+//!
+//! Same as `AtomicHashMap` with the default `OrderingProfile::AcquireRelease`.
+
+use core::marker::PhantomData;
+
+use core::sync::atomic::{Ordering, AtomicU64, AtomicUsize};
+
+use crate::error::AtomicHashMapError;
+use crate::hasher::hash_key;
+use crate::pod::PodU64;
+use crate::probe::{self, Linear, Slot, Table};
+
+/// Default key marker for a slot that has never been claimed
+const EMPTY_KEY: u64 = 0;
+
+/// Default key marker for a slot whose entry has been removed
+const TOMBSTONE_KEY: u64 = u64::MAX;
+
+/// Lock-free hashmap of `N` slots stored inline, without any allocation
+///
+/// `N` must be a power of two, which is checked when the map is built; in a `static`
+/// a bad `N` fails to compile. Keys whose `PodU64` representation is 0 or `u64::MAX`
+/// are reserved to mark empty and removed slots unless other sentinels are given.
+///
+/// Every constructor is a `const fn`, so the map can live in a `static` without
+/// `lazy_static` or `OnceLock`:
+///
+/// ```
+/// use atomics_rs::AtomicStaticHashMap;
+///
+/// static MAP: AtomicStaticHashMap<64> = AtomicStaticHashMap::new();
+/// static SHIFTED: AtomicStaticHashMap<64> =
+///     AtomicStaticHashMap::with_sentinels(u64::MAX - 1, u64::MAX);
+///
+/// MAP.insert(1, 2).unwrap();
+/// assert_eq!(MAP.get(&1), Some(2));
+///
+/// SHIFTED.insert(0, 3).unwrap();
+/// assert_eq!(SHIFTED.get(&0), Some(3));
+/// ```
+#[repr(C)]
+pub struct AtomicStaticHashMap<const N: usize, K: PodU64 = u64, V: PodU64 = u64> {
+    keys: [AtomicU64; N],
+    values: [AtomicU64; N],
+
+    /// `PUBLISHED` once the first value of a claimed slot has been written, and the
+    /// number of times the slot was unpublished above its state bits
+    states: [AtomicU64; N],
+
+    /// Number of keys currently in the table
+    count: AtomicUsize,
+
+    /// Raw key marking a slot that has never been claimed
+    empty_key: u64,
+
+    /// Raw key marking a slot whose entry has been removed
+    tombstone_key: u64,
+
+    _types: PhantomData<(K, V)>
+}
+
+impl<const N: usize, K: PodU64, V: PodU64> AtomicStaticHashMap<N, K, V> {
+    /// Construct a new, empty AtomicStaticHashMap.
+    /// NOTE: `N` must be a power of two, otherwise this panics.
+    pub const fn new() -> AtomicStaticHashMap<N, K, V> {
+        AtomicStaticHashMap::with_sentinels(EMPTY_KEY, TOMBSTONE_KEY)
+    }
+
+    /// Construct a new, empty AtomicStaticHashMap using the keys whose `PodU64`
+    /// representations are `empty_key` and `tombstone_key` to mark empty and removed
+    /// slots instead of 0 and `u64::MAX`. The sentinels are given in their raw form
+    /// since `PodU64` conversions can't run in a `const fn`.
+    /// NOTE: `N` must be a power of two and the sentinels must differ, otherwise this
+    /// panics.
+    pub const fn with_sentinels(empty_key: u64, tombstone_key: u64)
+            -> AtomicStaticHashMap<N, K, V> {
+        assert!(N >= 2 && N.is_power_of_two(),
+                "size of AtomicStaticHashMap must be a power of two");
+        assert!(empty_key != tombstone_key,
+                "sentinels of AtomicStaticHashMap must differ");
+
+        let mut keys = [const { AtomicU64::new(0) }; N];
+        let mut index = 0;
+        while index < N {
+            keys[index] = AtomicU64::new(empty_key);
+            index += 1;
+        }
+
+        AtomicStaticHashMap {
+            keys,
+            values: [const { AtomicU64::new(0) }; N],
+            states: [const { AtomicU64::new(0) }; N],
+            count: AtomicUsize::new(0),
+            empty_key,
+            tombstone_key,
+            _types: PhantomData
+        }
+    }
+
+    /// Get the `(empty, tombstone)` sentinel keys marking empty and removed slots
+    pub fn sentinels(&self) -> (K, K) {
+        (K::from_u64(self.empty_key), K::from_u64(self.tombstone_key))
+    }
+
+    /// Returns true if the raw key read from a slot is a stored key rather than one of
+    /// the sentinels
+    fn is_live(&self, key: u64) -> bool {
+        key != self.empty_key && key != self.tombstone_key
+    }
+
+    /// Get the raw `u64` of `key`, rejecting the sentinels
+    fn raw_key(&self, key: K) -> Result<u64, AtomicHashMapError> {
+        let key = key.to_u64();
+        if !self.is_live(key) {
+            return Err(AtomicHashMapError::InvalidKey);
+        }
+
+        Ok(key)
+    }
+
+    /// Atomically set a key:value in the hashmap
+    ///
+    /// Returns the value previously stored for this key, or `None` if the key was
+    /// newly inserted. A new key becomes visible to other threads together with its
+    /// value.
+    pub fn insert(&self, key: K, value: V) -> Result<Option<V>, AtomicHashMapError> {
+        let key = self.raw_key(key)?;
+
+        match self.claim_slot(key)? {
+            Slot::Found(index) => {
+                let prev_value = self.values[index].swap(value.to_u64(), Ordering::AcqRel);
+                Ok(Some(V::from_u64(prev_value)))
+            }
+            Slot::Claimed(index) => {
+                self.values[index].store(value.to_u64(), Ordering::Release);
+                probe::publish(self, index);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Get the value for `key`, inserting `default` if the key isn't in the hashmap
+    pub fn get_or_insert(&self, key: K, default: V) -> Result<V, AtomicHashMapError> {
+        let key = self.raw_key(key)?;
+
+        match self.claim_slot(key)? {
+            Slot::Found(index) => {
+                match self.read_value(index, key) {
+                    Some(value) => Ok(value),
+                    // Removed since it was found, look for the key again
+                    None => self.get_or_insert(K::from_u64(key), default)
+                }
+            }
+            Slot::Claimed(index) => {
+                self.values[index].store(default.to_u64(), Ordering::Release);
+                probe::publish(self, index);
+                Ok(default)
+            }
+        }
+    }
+
+    /// Find the published slot holding `key`, claiming a tombstone or an empty slot
+    /// for it if it isn't in the table yet. A claimed slot must be published by the
+    /// caller once its value is written.
+    fn claim_slot(&self, key: u64) -> Result<Slot, AtomicHashMapError> {
+        probe::claim_slot(self, key, hash_key(key))
+    }
+
+    /// Find the slot currently holding `key`
+    fn find_slot(&self, key: u64) -> Option<usize> {
+        probe::find_slot(self, key, hash_key(key))
+    }
+
+    /// Read the value of the published slot at `index` holding `key`. Returns `None`
+    /// if the entry was removed while reading it, since the value read may then be the
+    /// reset done by the removal.
+    fn read_value(&self, index: usize, key: u64) -> Option<V> {
+        let value = self.values[index].load(Ordering::Acquire);
+        if !probe::is_published(self, index)
+                || self.keys[index].load(Ordering::Acquire) != key {
+            return None;
+        }
+
+        Some(V::from_u64(value))
+    }
+
+    /// Atomically get a value from the hashmap
+    pub fn get(&self, key: &K) -> Option<V> {
+        let key = self.raw_key(*key).ok()?;
+
+        let index = self.find_slot(key)?;
+        self.read_value(index, key)
+    }
+
+    /// Check if a key is in the hashmap
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Atomically remove a key from the hashmap, returning its value if it was present
+    pub fn remove(&self, key: K) -> Option<V> {
+        let key = self.raw_key(key).ok()?;
+
+        let index = self.find_slot(key)?;
+
+        // Only the thread that unpublishes the slot may release it
+        if !probe::unpublish(self, index, key) {
+            return None;
+        }
+
+        // Take the value before releasing the key, same as `AtomicHashMap::remove`
+        let value = self.values[index].swap(0, Ordering::AcqRel);
+        probe::release(self, index, key);
+        Some(V::from_u64(value))
+    }
+
+    /// Iterate over the `(key, value)` pairs currently in the hashmap, in table order,
+    /// without allocating
+    pub fn iter(&self) -> impl Iterator<Item = (K, V)> + '_ {
+        (0..N).filter_map(move |index| {
+            let key = self.keys[index].load(Ordering::Acquire);
+            if !self.is_live(key) || !probe::is_published(self, index) {
+                return None;
+            }
+
+            Some((K::from_u64(key), self.read_value(index, key)?))
+        })
+    }
+
+    /// Get the number of elements currently in the hashtable
+    pub fn len(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Returns true if the hashtable has no elements
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the total number of slots in the hashtable
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+}
+
+impl<const N: usize, K: PodU64> AtomicStaticHashMap<N, K, u64> {
+    /// Atomically add `delta` to the value for `key`, inserting the key with a value of
+    /// `delta` if it isn't in the hashmap yet. The addition wraps around on overflow.
+    ///
+    /// Returns the value before the addition, which is 0 for a newly inserted key.
+    pub fn add_to(&self, key: K, delta: u64) -> Result<u64, AtomicHashMapError> {
+        let key = self.raw_key(key)?;
+
+        match self.claim_slot(key)? {
+            Slot::Found(index) => Ok(self.values[index].fetch_add(delta, Ordering::AcqRel)),
+            Slot::Claimed(index) => {
+                let prev_value = self.values[index].fetch_add(delta, Ordering::AcqRel);
+                probe::publish(self, index);
+                Ok(prev_value)
+            }
+        }
+    }
+}
+
+impl<const N: usize, K: PodU64, V: PodU64> Table for AtomicStaticHashMap<N, K, V> {
+    type Key = AtomicU64;
+    type State = AtomicU64;
+    type Probe<'a> = Linear where Self: 'a;
+
+    #[inline]
+    fn key_word(&self, index: usize) -> &AtomicU64 {
+        &self.keys[index]
+    }
+
+    #[inline]
+    fn state_word(&self, index: usize) -> &AtomicU64 {
+        &self.states[index]
+    }
+
+    fn raw_sentinels(&self) -> (u64, u64) {
+        (self.empty_key, self.tombstone_key)
+    }
+
+    fn probe_slots(&self, hash: u64, _all: bool) -> Linear {
+        Linear::new(hash, N)
+    }
+
+    fn claimed(&self, _index: usize, _hash: u64) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn released(&self, _index: usize) {
+        self.count.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl<const N: usize, K: PodU64, V: PodU64> Default for AtomicStaticHashMap<N, K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_get_remove() {
+        let hashtable: AtomicStaticHashMap<16> = AtomicStaticHashMap::new();
+        assert_eq!(hashtable.insert(1, 10), Ok(None));
+        assert_eq!(hashtable.insert(1, 11), Ok(Some(10)));
+        assert_eq!(hashtable.get(&1), Some(11));
+        assert_eq!(hashtable.get_or_insert(2, 5), Ok(5));
+        assert_eq!(hashtable.len(), 2);
+        assert_eq!(hashtable.capacity(), 16);
+
+        assert_eq!(hashtable.insert(0, 1), Err(AtomicHashMapError::InvalidKey));
+        assert_eq!(hashtable.remove(1), Some(11));
+        assert!(!hashtable.contains_key(&1));
+
+        let entries: Vec<_> = hashtable.iter().collect();
+        assert_eq!(entries, vec![(2, 5)]);
+
+        for x in 3..=17 {
+            assert_eq!(hashtable.insert(x, x), Ok(None));
+        }
+        assert_eq!(hashtable.insert(18, 18), Err(AtomicHashMapError::Full));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_invalid_size() {
+        let _hashtable: AtomicStaticHashMap<12> = AtomicStaticHashMap::new();
+    }
+
+    #[test]
+    fn test_const_sentinels() {
+        static HASHTABLE: AtomicStaticHashMap<16, i64> =
+            AtomicStaticHashMap::with_sentinels(i64::MIN as u64, i64::MAX as u64);
+
+        let hashtable = &HASHTABLE;
+        assert_eq!(hashtable.sentinels(), (i64::MIN, i64::MAX));
+        assert_eq!(hashtable.insert(0, 1), Ok(None));
+        assert_eq!(hashtable.insert(-1, 2), Ok(None));
+        assert_eq!(hashtable.insert(i64::MIN, 3), Err(AtomicHashMapError::InvalidKey));
+        assert_eq!(hashtable.remove(0), Some(1));
+        assert_eq!(hashtable.get(&-1), Some(2));
+        assert_eq!(hashtable.len(), 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_invalid_sentinels() {
+        let _hashtable: AtomicStaticHashMap<16> = AtomicStaticHashMap::with_sentinels(7, 7);
+    }
+
+    #[test]
+    fn test_static_threads() {
+        use std::thread;
+
+        static HASHTABLE: AtomicStaticHashMap<1024, u32> = AtomicStaticHashMap::new();
+
+        let threads: Vec<_> = (0..8).map(|_| {
+            thread::spawn(|| {
+                for x in 1..=512 {
+                    HASHTABLE.add_to(x, 1).unwrap();
+                }
+            })
+        }).collect();
+
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        assert_eq!(HASHTABLE.len(), 512);
+        for x in 1..=512 {
+            assert_eq!(HASHTABLE.get(&x), Some(8));
+        }
+    }
+
+    #[test]
+    fn test_threads_no_duplicates() {
+        use std::thread;
+
+        static HASHTABLE: AtomicStaticHashMap<64> = AtomicStaticHashMap::new();
+
+        // Keys all starting their probe at the same slot, so that removing one key
+        // opens a tombstone in front of inserts of all the others
+        let keys: Vec<u64> = (1..).filter(|&key| hash_key(key) & 63 == 0).take(24)
+            .collect();
+        let (inserted, removed) = keys.split_at(8);
+
+        for round in 0..200 {
+            for &key in removed {
+                HASHTABLE.insert(key, round).unwrap();
+            }
+
+            // Two threads insert the same keys while a third removes the keys in front
+            // of them, and each key still ends up in a single slot
+            thread::scope(|scope| {
+                for _ in 0..2 {
+                    scope.spawn(|| {
+                        for &key in inserted {
+                            HASHTABLE.insert(key, round).unwrap();
+                        }
+                    });
+                }
+
+                scope.spawn(|| {
+                    for &key in removed {
+                        assert_eq!(HASHTABLE.remove(key), Some(round));
+                    }
+                });
+            });
+
+            let entries: Vec<_> = HASHTABLE.iter().collect();
+            assert_eq!(entries.len(), 8);
+            assert_eq!(HASHTABLE.len(), 8);
+            for &key in inserted {
+                assert_eq!(entries.iter().filter(|&&(x, _)| x == key).count(), 1);
+                assert_eq!(HASHTABLE.remove(key), Some(round));
+                assert_eq!(HASHTABLE.remove(key), None);
+            }
+        }
+    }
+}
@@ -0,0 +1,188 @@
+//! Randomized stress test of `AtomicHashMap`, enabled with the `stress` feature
+//!
+//! Writer threads run random inserts, removes, updates and `get_or_insert`s on the
+//! keys they own while reader threads look up and iterate over every key. Invariants
+//! are checked after every operation:
+//!
+//! * A writer always reads back what it last wrote to its own keys, so no key or
+//!   value is lost.
+//! * Every value a reader sees for a key was written for that key, and is no newer
+//!   than the last value written for it.
+//! * Once every thread is done, the map holds exactly what the writers think it does.
+//!
+//! The run is configured with environment variables, all optional:
+//! `STRESS_SEED` (printed at the start of every run, to reproduce a failure),
+//! `STRESS_SECS` (default 5), `STRESS_WRITERS` (default 4) and `STRESS_READERS`
+//! (default 4).
+//!
+//! ```text
+//! STRESS_SECS=600 STRESS_SEED=1234 cargo test --release --features stress stress
+//! ```
+
+use std::collections::HashMap;
+use std::env;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::atomichashmap::AtomicHashMap;
+use crate::rng::Rng;
+
+/// Number of keys shared between the writers
+const KEYS: u64 = 1024;
+
+/// Read a numeric setting from the environment
+fn setting(name: &str, default: u64) -> u64 {
+    env::var(name).ok()
+        .map(|val| val.parse().unwrap_or_else(|_| panic!("{} must be a number", name)))
+        .unwrap_or(default)
+}
+
+/// Pack a value for `key` with sequence number `seq`, so readers can tell which key
+/// and write it came from
+fn pack(key: u64, seq: u64) -> u64 {
+    (key << 32) | seq
+}
+
+/// State shared by every thread of a run
+struct Shared {
+    map: AtomicHashMap,
+
+    /// Sequence number of the newest value written for each key, bumped before the
+    /// value is written
+    latest: Vec<AtomicU64>,
+
+    /// Set once the run is over
+    done: AtomicBool
+}
+
+impl Shared {
+    /// Check a value a reader saw for `key`
+    fn check_seen(&self, key: u64, value: u64) {
+        assert_eq!(value >> 32, key, "Value {:#x} was never written for key {}", value, key);
+
+        let seq = value & 0xffff_ffff;
+        let latest = self.latest[key as usize].load(Ordering::Acquire);
+        assert!(seq <= latest, "Key {} has value {} newer than its last write {}",
+                key, seq, latest);
+    }
+}
+
+/// Run random writes on the keys owned by writer `id` until the run is over. Returns
+/// what the writer expects to find in the map for each of its keys.
+fn writer(shared: &Shared, id: u64, writers: u64, seed: u64) -> HashMap<u64, u64> {
+    let mut rng = Rng::new(seed);
+    let mut expected: HashMap<u64, u64> = HashMap::new();
+
+    // Keys are owned by one writer each, skipping 0 which is reserved
+    let owned: Vec<u64> = (1..=KEYS).filter(|key| key % writers == id).collect();
+
+    while !shared.done.load(Ordering::Relaxed) {
+        let key = owned[rng.below(owned.len() as u64) as usize];
+        let seq = shared.latest[key as usize].fetch_add(1, Ordering::AcqRel) + 1;
+        let value = pack(key, seq);
+        let prev = expected.get(&key).copied();
+
+        match rng.below(4) {
+            0 => {
+                assert_eq!(shared.map.insert(key, value).unwrap(), prev);
+                expected.insert(key, value);
+            }
+            1 => {
+                assert_eq!(shared.map.remove(key), prev);
+                expected.remove(&key);
+            }
+            2 => {
+                let curr = shared.map.get_or_insert(key, value).unwrap();
+                assert_eq!(curr, *expected.entry(key).or_insert(value));
+            }
+            _ => {
+                assert_eq!(shared.map.update(key, |_| value), prev);
+                if prev.is_some() {
+                    expected.insert(key, value);
+                }
+            }
+        }
+
+        assert_eq!(shared.map.get(&key), expected.get(&key).copied(),
+                   "Lost the last write to key {}", key);
+    }
+
+    expected
+}
+
+/// Look up and iterate over random keys until the run is over
+fn reader(shared: &Shared, seed: u64) {
+    let mut rng = Rng::new(seed);
+
+    while !shared.done.load(Ordering::Relaxed) {
+        if rng.below(256) == 0 {
+            for (key, value) in shared.map.iter() {
+                shared.check_seen(key, value);
+            }
+            continue;
+        }
+
+        let key = rng.below(KEYS) + 1;
+        if let Some(value) = shared.map.get(&key) {
+            shared.check_seen(key, value);
+        }
+    }
+}
+
+#[test]
+fn stress_insert_get_remove() {
+    let seed = setting("STRESS_SEED", SystemTime::now().duration_since(UNIX_EPOCH)
+                                                     .unwrap().as_nanos() as u64);
+    let secs = setting("STRESS_SECS", 5);
+    let writers = setting("STRESS_WRITERS", 4);
+    let readers = setting("STRESS_READERS", 4);
+    eprintln!("stress: STRESS_SEED={} STRESS_SECS={} STRESS_WRITERS={} STRESS_READERS={}",
+              seed, secs, writers, readers);
+
+    let shared = Arc::new(Shared {
+        map: AtomicHashMap::new((KEYS * 4) as usize).unwrap(),
+        latest: (0..=KEYS).map(|_| AtomicU64::new(0)).collect(),
+        done: AtomicBool::new(false)
+    });
+
+    // Give every thread its own stream of the seed
+    let mut rng = Rng::new(seed);
+
+    let writer_threads: Vec<_> = (0..writers).map(|id| {
+        let shared = shared.clone();
+        let seed = rng.next();
+        thread::spawn(move || writer(&shared, id, writers, seed))
+    }).collect();
+
+    let reader_threads: Vec<_> = (0..readers).map(|_| {
+        let shared = shared.clone();
+        let seed = rng.next();
+        thread::spawn(move || reader(&shared, seed))
+    }).collect();
+
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(secs) {
+        thread::sleep(Duration::from_millis(100));
+
+        // Stop early if a thread already failed
+        if writer_threads.iter().any(|t| t.is_finished())
+                || reader_threads.iter().any(|t| t.is_finished()) {
+            break;
+        }
+    }
+    shared.done.store(true, Ordering::Relaxed);
+
+    let mut expected = HashMap::new();
+    for t in writer_threads {
+        expected.extend(t.join().expect("Writer failed, rerun with the seed above"));
+    }
+    for t in reader_threads {
+        t.join().expect("Reader failed, rerun with the seed above");
+    }
+
+    let found: HashMap<u64, u64> = shared.map.iter().collect();
+    assert_eq!(found, expected);
+    assert_eq!(shared.map.len(), expected.len() as u64);
+}
@@ -0,0 +1,19 @@
+//! Atomics used by `AtomicHashMap`, swapped for the model-checked ones of `loom` when
+//! the crate is built with `--cfg loom`
+
+#[cfg(loom)]
+pub(crate) use loom::sync::atomic;
+
+#[cfg(not(loom))]
+pub(crate) use core::sync::atomic;
+
+/// Back off while waiting on another thread. Under loom this yields to the model's
+/// scheduler, which otherwise never runs the thread being waited on.
+#[inline]
+pub(crate) fn spin_loop() {
+    #[cfg(loom)]
+    loom::thread::yield_now();
+
+    #[cfg(not(loom))]
+    core::hint::spin_loop();
+}
@@ -0,0 +1,341 @@
+//! Atomic pointers paired with a version tag, the usual defense against ABA
+//!
+//! A lock-free list that pops a node by compare-exchanging its head from the node to
+//! the node's successor can be fooled if, between reading the head and the
+//! compare-exchange, the node is popped, something else is pushed, and the node is
+//! pushed back. The head holds the same pointer again, the compare-exchange succeeds
+//! and the list is corrupted with a stale successor. Pairing the pointer with a tag
+//! that every update bumps makes that compare-exchange fail, since the tag moved on
+//! even though the pointer is the same. `AtomicStack` does the same with node
+//! indices; these types do it for pointers.
+//!
+//! * `TaggedAtomicPtr` packs a 16-bit tag into the top bits of an `AtomicU64`, above
+//!   the 48 bits of address used by x86_64 and aarch64. It is as cheap as an
+//!   `AtomicPtr`, but a thread stalled across exactly a multiple of 2^16 updates of
+//!   the same pointer can still be fooled.
+//! * `VersionedAtomicPtr` keeps a 64-bit version next to the whole pointer in an
+//!   `AtomicU128`, which wraps around too late to matter, at the cost of a
+//!   double-word compare-exchange or its sequence lock fallback.
+//!
+//! Loads are `Acquire`, stores `Release` and successful compare-exchanges `AcqRel`,
+//! so the thread that swaps a pointer in publishes the node it points to. Neither
+//! type owns or dereferences its pointer.
+
+use core::fmt;
+use core::marker::PhantomData;
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::atomic128::AtomicU128;
+
+/// Number of low bits of a `TaggedAtomicPtr` holding the address
+const ADDR_BITS: u32 = 48;
+
+/// Mask of the address in a `TaggedAtomicPtr`
+const ADDR_MASK: u64 = (1 << ADDR_BITS) - 1;
+
+/// Pack `ptr` and `tag` into one word. Panics if the address doesn't fit in 48 bits.
+fn pack<T>(ptr: *mut T, tag: u16) -> u64 {
+    assert!(TaggedAtomicPtr::fits(ptr), "Pointer {:p} doesn't fit in 48 bits", ptr);
+    u64::from(tag) << ADDR_BITS | ptr as usize as u64
+}
+
+/// Split a word packed by `pack` into its pointer and tag
+fn unpack<T>(word: u64) -> (*mut T, u16) {
+    ((word & ADDR_MASK) as usize as *mut T, (word >> ADDR_BITS) as u16)
+}
+
+/// Atomic `*mut T` with a 16-bit tag packed into the same word
+pub struct TaggedAtomicPtr<T> {
+    word: AtomicU64,
+    _ptr: PhantomData<*mut T>
+}
+
+// SAFETY: Like `AtomicPtr`, the pointer is never dereferenced
+unsafe impl<T> Send for TaggedAtomicPtr<T> {}
+unsafe impl<T> Sync for TaggedAtomicPtr<T> {}
+
+impl<T> TaggedAtomicPtr<T> {
+    /// Construct a tagged pointer holding `ptr` and `tag`. Panics if the address of
+    /// `ptr` doesn't fit in 48 bits.
+    pub fn new(ptr: *mut T, tag: u16) -> TaggedAtomicPtr<T> {
+        TaggedAtomicPtr { word: AtomicU64::new(pack(ptr, tag)), _ptr: PhantomData }
+    }
+
+    /// Construct a null pointer with a tag of 0
+    pub const fn null() -> TaggedAtomicPtr<T> {
+        TaggedAtomicPtr { word: AtomicU64::new(0), _ptr: PhantomData }
+    }
+
+    /// Returns true if the address of `ptr` fits in the 48 bits left by the tag,
+    /// which holds for every pointer to memory of a user-space program on x86_64 and
+    /// aarch64
+    pub fn fits(ptr: *mut T) -> bool {
+        (ptr as usize as u64) & !ADDR_MASK == 0
+    }
+
+    /// Get the pointer and its tag
+    pub fn load(&self) -> (*mut T, u16) {
+        unpack(self.word.load(Ordering::Acquire))
+    }
+
+    /// Store `ptr` and `tag`
+    pub fn store(&self, ptr: *mut T, tag: u16) {
+        self.word.store(pack(ptr, tag), Ordering::Release);
+    }
+
+    /// Store `ptr` and `tag`, returning the pointer and tag they replaced
+    pub fn swap(&self, ptr: *mut T, tag: u16) -> (*mut T, u16) {
+        unpack(self.word.swap(pack(ptr, tag), Ordering::AcqRel))
+    }
+
+    /// Store `new` if both the pointer and the tag are those of `current`. Returns the
+    /// previous pointer and tag, as `Ok` if they were replaced and `Err` otherwise.
+    pub fn compare_exchange(&self, current: (*mut T, u16), new: (*mut T, u16))
+            -> Result<(*mut T, u16), (*mut T, u16)> {
+        self.word.compare_exchange(pack(current.0, current.1), pack(new.0, new.1),
+                                   Ordering::AcqRel, Ordering::Acquire)
+            .map(unpack)
+            .map_err(unpack)
+    }
+
+    /// Store `ptr` with the tag after that of `current` if both the pointer and the
+    /// tag are those of `current`, the usual update of a list head
+    pub fn compare_exchange_bump(&self, current: (*mut T, u16), ptr: *mut T)
+            -> Result<(*mut T, u16), (*mut T, u16)> {
+        self.compare_exchange(current, (ptr, current.1.wrapping_add(1)))
+    }
+
+    /// Take the pointer and its tag out of the atomic
+    pub fn into_inner(self) -> (*mut T, u16) {
+        unpack(self.word.into_inner())
+    }
+}
+
+impl<T> Default for TaggedAtomicPtr<T> {
+    fn default() -> TaggedAtomicPtr<T> {
+        TaggedAtomicPtr::null()
+    }
+}
+
+impl<T> fmt::Debug for TaggedAtomicPtr<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (ptr, tag) = self.load();
+        f.debug_struct("TaggedAtomicPtr").field("ptr", &ptr).field("tag", &tag).finish()
+    }
+}
+
+/// Atomic `*mut T` with a 64-bit version stored next to it
+pub struct VersionedAtomicPtr<T> {
+    /// Version in the high half, address in the low half
+    word: AtomicU128,
+    _ptr: PhantomData<*mut T>
+}
+
+// SAFETY: Like `AtomicPtr`, the pointer is never dereferenced
+unsafe impl<T> Send for VersionedAtomicPtr<T> {}
+unsafe impl<T> Sync for VersionedAtomicPtr<T> {}
+
+/// Pack `ptr` and `version` into one double word
+fn pack_versioned<T>(ptr: *mut T, version: u64) -> u128 {
+    u128::from(version) << 64 | ptr as usize as u128
+}
+
+/// Split a double word packed by `pack_versioned` into its pointer and version
+fn unpack_versioned<T>(word: u128) -> (*mut T, u64) {
+    (word as u64 as usize as *mut T, (word >> 64) as u64)
+}
+
+impl<T> VersionedAtomicPtr<T> {
+    /// Construct a versioned pointer holding `ptr` and `version`
+    pub fn new(ptr: *mut T, version: u64) -> VersionedAtomicPtr<T> {
+        VersionedAtomicPtr {
+            word: AtomicU128::new(pack_versioned(ptr, version)),
+            _ptr: PhantomData
+        }
+    }
+
+    /// Construct a null pointer with a version of 0
+    pub const fn null() -> VersionedAtomicPtr<T> {
+        VersionedAtomicPtr { word: AtomicU128::new(0), _ptr: PhantomData }
+    }
+
+    /// Get the pointer and its version
+    pub fn load(&self) -> (*mut T, u64) {
+        unpack_versioned(self.word.load())
+    }
+
+    /// Store `ptr` and `version`
+    pub fn store(&self, ptr: *mut T, version: u64) {
+        self.word.store(pack_versioned(ptr, version));
+    }
+
+    /// Store `ptr` and `version`, returning the pointer and version they replaced
+    pub fn swap(&self, ptr: *mut T, version: u64) -> (*mut T, u64) {
+        unpack_versioned(self.word.swap(pack_versioned(ptr, version)))
+    }
+
+    /// Store `new` if both the pointer and the version are those of `current`. Returns
+    /// the previous pointer and version, as `Ok` if they were replaced and `Err`
+    /// otherwise.
+    pub fn compare_exchange(&self, current: (*mut T, u64), new: (*mut T, u64))
+            -> Result<(*mut T, u64), (*mut T, u64)> {
+        self.word.compare_exchange(pack_versioned(current.0, current.1),
+                                   pack_versioned(new.0, new.1))
+            .map(unpack_versioned)
+            .map_err(unpack_versioned)
+    }
+
+    /// Store `ptr` with the version after that of `current` if both the pointer and
+    /// the version are those of `current`, the usual update of a list head
+    pub fn compare_exchange_bump(&self, current: (*mut T, u64), ptr: *mut T)
+            -> Result<(*mut T, u64), (*mut T, u64)> {
+        self.compare_exchange(current, (ptr, current.1.wrapping_add(1)))
+    }
+
+    /// Take the pointer and its version out of the atomic
+    pub fn into_inner(self) -> (*mut T, u64) {
+        unpack_versioned(self.word.into_inner())
+    }
+}
+
+impl<T> Default for VersionedAtomicPtr<T> {
+    fn default() -> VersionedAtomicPtr<T> {
+        VersionedAtomicPtr::null()
+    }
+}
+
+impl<T> fmt::Debug for VersionedAtomicPtr<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (ptr, version) = self.load();
+        f.debug_struct("VersionedAtomicPtr")
+            .field("ptr", &ptr)
+            .field("version", &version)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    use core::ptr;
+    use core::sync::atomic::AtomicPtr;
+
+    /// Node of the stacks the tagged pointers head in the tests
+    struct Node {
+        value: u64,
+        next: AtomicPtr<Node>
+    }
+
+    /// Threads pop nodes off a stack headed by `$head` and push them back, so the
+    /// same few nodes keep coming back to the head, which is where ABA would strike
+    macro_rules! stack_test {
+        ($name:ident, $head:ty) => {
+            #[test]
+            fn $name() {
+                let mut nodes = (0..8).map(|value| {
+                    Node { value, next: AtomicPtr::new(ptr::null_mut()) }
+                }).collect::<Vec<_>>();
+
+                let head = <$head>::null();
+                let push = |node: *mut Node| {
+                    let mut curr = head.load();
+                    loop {
+                        // SAFETY: The nodes outlive the threads
+                        unsafe { (*node).next.store(curr.0, Ordering::Relaxed); }
+                        match head.compare_exchange_bump(curr, node) {
+                            Ok(_) => return,
+                            Err(new) => curr = new
+                        }
+                    }
+                };
+                let pop = || {
+                    let mut curr = head.load();
+                    while !curr.0.is_null() {
+                        // SAFETY: As above. A stale link fails the compare-exchange.
+                        let next = unsafe { (*curr.0).next.load(Ordering::Relaxed) };
+                        match head.compare_exchange_bump(curr, next) {
+                            Ok(_) => return Some(curr.0),
+                            Err(new) => curr = new
+                        }
+                    }
+                    None
+                };
+
+                for node in nodes.iter_mut() {
+                    push(node);
+                }
+
+                thread::scope(|scope| {
+                    for _ in 0..4 {
+                        scope.spawn(|| {
+                            for _ in 0..10_000 {
+                                if let Some(node) = pop() {
+                                    push(node);
+                                }
+                            }
+                        });
+                    }
+                });
+
+                // SAFETY: As above
+                let mut values = core::iter::from_fn(pop)
+                    .map(|node| unsafe { (*node).value })
+                    .collect::<Vec<_>>();
+                values.sort();
+                assert_eq!(values, (0..8).collect::<Vec<_>>());
+            }
+        }
+    }
+
+    stack_test!(test_tagged_stack, TaggedAtomicPtr<Node>);
+    stack_test!(test_versioned_stack, VersionedAtomicPtr<Node>);
+
+    #[test]
+    fn test_tagged_ptr() {
+        let mut values = [1u32, 2];
+        let (a, b): (*mut u32, *mut u32) = (&mut values[0], &mut values[1]);
+
+        let ptr = TaggedAtomicPtr::new(a, u16::MAX);
+        assert_eq!(ptr.load(), (a, u16::MAX));
+
+        // A matching pointer with a stale tag fails
+        assert_eq!(ptr.compare_exchange((a, 0), (b, 0)), Err((a, u16::MAX)));
+        assert_eq!(ptr.compare_exchange_bump((a, u16::MAX), b), Ok((a, u16::MAX)));
+        assert_eq!(ptr.load(), (b, 0));
+
+        assert_eq!(ptr.swap(a, 7), (b, 0));
+        ptr.store(ptr::null_mut(), 3);
+        assert_eq!(ptr.into_inner(), (ptr::null_mut(), 3));
+        assert_eq!(TaggedAtomicPtr::<u32>::default().load(), (ptr::null_mut(), 0));
+        assert!(TaggedAtomicPtr::fits(a));
+    }
+
+    #[test]
+    fn test_versioned_ptr() {
+        let mut values = [1u32, 2];
+        let (a, b): (*mut u32, *mut u32) = (&mut values[0], &mut values[1]);
+
+        let ptr = VersionedAtomicPtr::new(a, u64::MAX);
+        assert_eq!(ptr.load(), (a, u64::MAX));
+
+        assert_eq!(ptr.compare_exchange((a, 0), (b, 0)), Err((a, u64::MAX)));
+        assert_eq!(ptr.compare_exchange_bump((a, u64::MAX), b), Ok((a, u64::MAX)));
+        assert_eq!(ptr.load(), (b, 0));
+
+        assert_eq!(ptr.swap(a, 7), (b, 0));
+        ptr.store(ptr::null_mut(), 3);
+        assert_eq!(ptr.into_inner(), (ptr::null_mut(), 3));
+        assert_eq!(VersionedAtomicPtr::<u32>::default().load(), (ptr::null_mut(), 0));
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    #[should_panic]
+    fn test_tagged_ptr_too_wide() {
+        TaggedAtomicPtr::new((1usize << 60) as *mut u8, 0);
+    }
+}
@@ -1,5 +1,208 @@
+//! Model-checked tests of `AtomicHashMap` and `WorkStealingDeque` under `--cfg loom`
+//!
+//! Each test runs a couple of threads against a tiny table or deque and lets loom
+//! explore every interleaving of their atomic operations, checking the outcome of
+//! each one.
+
 use loom::sync::Arc;
-use loom::sync::atomic::AtomicUsize;
-use loom::sync::atomic::Ordering::{Acquire, Release, Relaxed};
 use loom::thread;
 
+use crate::atomichashmap::AtomicHashMap;
+use crate::deque::WorkStealingDeque;
+
+/// Build a table small enough for loom to explore exhaustively
+fn tiny_map() -> Arc<AtomicHashMap> {
+    Arc::new(AtomicHashMap::new(2).unwrap())
+}
+
+#[test]
+fn loom_insert_same_key() {
+    loom::model(|| {
+        let hashtable = tiny_map();
+
+        let hashtable_t = hashtable.clone();
+        let t = thread::spawn(move || hashtable_t.insert(1, 10).unwrap());
+        let prev = hashtable.insert(1, 20).unwrap();
+        let prev_t = t.join().unwrap();
+
+        // Exactly one insert claimed the key and the other replaced its value
+        match (prev, prev_t) {
+            (None, Some(20)) => assert_eq!(hashtable.get(&1), Some(10)),
+            (Some(10), None) => assert_eq!(hashtable.get(&1), Some(20)),
+            res => panic!("Both inserts claimed the key: {:?}", res)
+        }
+        assert_eq!(hashtable.len(), 1);
+    });
+}
+
+#[test]
+fn loom_get_sees_whole_insert() {
+    loom::model(|| {
+        let hashtable = tiny_map();
+
+        let hashtable_t = hashtable.clone();
+        let t = thread::spawn(move || hashtable_t.insert(1, 10).unwrap());
+
+        // A key is never seen before its value is written
+        let value = hashtable.get(&1);
+        assert!(matches!(value, None | Some(10)), "Saw {:?}", value);
+
+        t.join().unwrap();
+        assert_eq!(hashtable.get(&1), Some(10));
+    });
+}
+
+#[test]
+fn loom_insert_remove() {
+    loom::model(|| {
+        let hashtable = tiny_map();
+
+        let hashtable_t = hashtable.clone();
+        let t = thread::spawn(move || hashtable_t.insert(1, 10).unwrap());
+        let removed = hashtable.remove(1);
+        t.join().unwrap();
+
+        // The remove either ran first and found nothing, or took the whole entry
+        match removed {
+            None => assert_eq!(hashtable.get(&1), Some(10)),
+            Some(10) => assert_eq!(hashtable.get(&1), None),
+            Some(value) => panic!("Removed a value never inserted: {}", value)
+        }
+    });
+}
+
+#[test]
+fn loom_remove_and_reuse_tombstone() {
+    loom::model(|| {
+        let hashtable = tiny_map();
+        hashtable.insert(1, 10).unwrap();
+
+        // Key 2 may claim the tombstone left by key 1 while it is being removed
+        let hashtable_t = hashtable.clone();
+        let t = thread::spawn(move || hashtable_t.insert(2, 20).unwrap());
+        assert_eq!(hashtable.remove(1), Some(10));
+        assert_eq!(t.join().unwrap(), None);
+
+        assert_eq!(hashtable.get(&1), None);
+        assert_eq!(hashtable.get(&2), Some(20));
+        assert_eq!(hashtable.len(), 1);
+    });
+}
+
+#[test]
+fn loom_retain_remove() {
+    loom::model(|| {
+        let hashtable = tiny_map();
+        hashtable.insert(1, 0).unwrap();
+
+        // Retain drops the entry while it holds 0 but gives it another value first, so
+        // it loses the check of the value and leaves the entry in place
+        let hashtable_t = hashtable.clone();
+        let t = thread::spawn(move || {
+            let mut inserted = None;
+            hashtable_t.retain(|_, value| {
+                if value == 0 {
+                    inserted = Some(hashtable_t.insert(1, 1).unwrap());
+                }
+                value != 0
+            });
+            inserted
+        });
+
+        // The key is never seen missing while retain checks it, and the removal
+        // isn't lost to it
+        let value = hashtable.get(&1);
+        assert!(matches!(value, Some(0) | Some(1)), "Saw {:?}", value);
+        let removed = hashtable.remove(1);
+        assert!(matches!(removed, Some(0) | Some(1)), "Removed {:?}", removed);
+
+        // Only an insert by retain after the removal leaves the key in the map
+        match t.join().unwrap() {
+            Some(None) => assert_eq!(hashtable.get(&1), Some(1)),
+            _ => assert_eq!(hashtable.get(&1), None)
+        }
+    });
+}
+
+#[test]
+fn loom_concurrent_removes() {
+    loom::model(|| {
+        let hashtable = tiny_map();
+        hashtable.insert(1, 10).unwrap();
+
+        let hashtable_t = hashtable.clone();
+        let t = thread::spawn(move || hashtable_t.remove(1));
+        let removed = hashtable.remove(1);
+        let removed_t = t.join().unwrap();
+
+        // Only one remove wins the entry
+        match (removed, removed_t) {
+            (Some(10), None) | (None, Some(10)) => {}
+            res => panic!("Entry removed {:?}", res)
+        }
+        assert!(hashtable.is_empty());
+    });
+}
+
+#[test]
+fn loom_add_to() {
+    loom::model(|| {
+        let hashtable = tiny_map();
+
+        let hashtable_t = hashtable.clone();
+        let t = thread::spawn(move || hashtable_t.add_to(1, 1).unwrap());
+        let prev = hashtable.add_to(1, 1).unwrap();
+        let prev_t = t.join().unwrap();
+
+        // No increment is lost, including the one racing to claim the key
+        assert_eq!(prev + prev_t, 1);
+        assert_eq!(hashtable.get(&1), Some(2));
+    });
+}
+
+#[test]
+fn loom_deque_pop_steal_last() {
+    loom::model(|| {
+        let deque: Arc<WorkStealingDeque> = Arc::new(WorkStealingDeque::new(2).unwrap());
+        let mut worker = deque.worker().unwrap();
+        worker.push(1).unwrap();
+
+        let deque_t = deque.clone();
+        let t = thread::spawn(move || deque_t.steal());
+        let popped = worker.pop();
+        let stolen = t.join().unwrap();
+
+        // The owner and the thief race for the last item and exactly one gets it
+        match (popped, stolen) {
+            (Some(1), None) | (None, Some(1)) => {}
+            res => panic!("Last item taken {:?}", res)
+        }
+        assert!(deque.is_empty());
+    });
+}
+
+#[test]
+fn loom_deque_push_steal() {
+    loom::model(|| {
+        let deque: Arc<WorkStealingDeque> = Arc::new(WorkStealingDeque::new(2).unwrap());
+        let mut worker = deque.worker().unwrap();
+
+        let deque_t = deque.clone();
+        let t = thread::spawn(move || deque_t.steal());
+        worker.push(1).unwrap();
+        worker.push(2).unwrap();
+        let stolen = t.join().unwrap();
+
+        // A thief only sees fully pushed items, oldest first, and never one the owner
+        // gets back too
+        match stolen {
+            None => {
+                assert_eq!(worker.pop(), Some(2));
+                assert_eq!(worker.pop(), Some(1));
+            }
+            Some(1) => assert_eq!(worker.pop(), Some(2)),
+            Some(item) => panic!("Stole {} before 1", item)
+        }
+        assert_eq!(worker.pop(), None);
+    });
+}